@@ -0,0 +1,206 @@
+//! Which networks games let players in from, for servers that only want players from some
+//! networks, or none from others. The lists are kept in the `[access]` table of the server's
+//! config file, and can be reloaded while the game runs:
+//!
+//! ```toml
+//! [access]
+//! allow = ["10.0.0.0/8", "192.168.1.0/24"]
+//! deny = ["10.0.13.0/24", "::1"]
+//! ```
+
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// `ip`, or the IPv4 address it maps if it's an IPv4-mapped IPv6 address, like `::ffff:10.0.0.1`.
+/// Dual-stack sockets see IPv4 peers as those, so they're turned back into the IPv4 addresses
+/// lists are written with.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => {
+                let [.., a, b, c, d] = v6.octets();
+                IpAddr::from([a, b, c, d])
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// A network in CIDR notation, like `10.0.0.0/8`. A bare address is a network of just that
+/// address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses are compared as the top bits, so that the same masks work for both.
+        let (network, ip) = match (self.addr, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (
+                u128::from(u32::from(network)) << 96,
+                u128::from(u32::from(ip)) << 96,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip)),
+            _ => return false,
+        };
+        let mask = match self.prefix_len {
+            0 => 0,
+            prefix_len => !0 << (128 - u32::from(prefix_len)),
+        };
+        network & mask == ip & mask
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("bad address in network {:?}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max)
+                .ok_or_else(|| {
+                    format!(
+                        "bad prefix length in network {:?}; expected 0 to {}",
+                        s, max
+                    )
+                })?,
+            None => max,
+        };
+        // A network of IPv4-mapped addresses is the IPv4 network they map.
+        let mapped = canonical(addr);
+        if mapped != addr && prefix_len >= 96 {
+            return Ok(Network {
+                addr: mapped,
+                prefix_len: prefix_len - 96,
+            });
+        }
+        Ok(Network { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The networks a game lets players in from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLists {
+    /// If any, players are only let in from these networks.
+    #[serde(default)]
+    pub allow: Vec<Network>,
+    /// Players are never let in from these networks, even if they're allowed.
+    #[serde(default)]
+    pub deny: Vec<Network>,
+}
+
+impl AccessLists {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip));
+        allowed && !self.deny.iter().any(|n| n.contains(ip))
+    }
+}
+
+/// Reads the access lists from the `[access]` table of the config file at `path`, ignoring the
+/// rest of it. A config file without one lets everyone in.
+pub fn load(path: &Path) -> io::Result<AccessLists> {
+    #[derive(Deserialize)]
+    struct ConfigFile {
+        #[serde(default)]
+        access: AccessLists,
+    }
+
+    let config: ConfigFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(config.access)
+}
+
+/// A game's access lists, reloadable from its config file if it has one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Access {
+    lists: Arc<RwLock<AccessLists>>,
+    config: Option<PathBuf>,
+}
+
+impl Access {
+    pub(crate) fn new(lists: AccessLists, config: Option<PathBuf>) -> Self {
+        Access {
+            lists: Arc::new(RwLock::new(lists)),
+            config,
+        }
+    }
+
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        self.lists.read().unwrap().permits(ip)
+    }
+
+    /// Reloads the lists from the config file, returning them. The old lists stay if the file
+    /// can't be read.
+    pub(crate) fn reload(&self) -> io::Result<AccessLists> {
+        let path = self.config.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "the server has no config file")
+        })?;
+        let lists = load(path)?;
+        *self.lists.write().unwrap() = lists.clone();
+        Ok(lists)
+    }
+}
+
+#[test]
+fn access_lists_match_networks() {
+    let lists = AccessLists {
+        allow: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+        deny: vec!["10.0.13.0/24".parse().unwrap()],
+    };
+    let ip = |ip: &str| ip.parse().unwrap();
+    assert!(lists.permits(ip("10.1.2.3")));
+    assert!(lists.permits(ip("::1")));
+    assert!(!lists.permits(ip("10.0.13.7")));
+    assert!(!lists.permits(ip("192.168.0.1")));
+    assert!(!lists.permits(ip("::2")));
+    assert!(lists.permits(ip("::ffff:10.1.2.3")));
+    assert!(!lists.permits(ip("::ffff:10.0.13.7")));
+    assert!(AccessLists::default().permits(ip("192.168.0.1")));
+
+    assert!("0.0.0.0/0"
+        .parse::<Network>()
+        .unwrap()
+        .contains(ip("1.2.3.4")));
+    assert_eq!(
+        "::ffff:10.0.0.0/104".parse::<Network>().unwrap(),
+        "10.0.0.0/8".parse::<Network>().unwrap()
+    );
+    assert!("10.0.0.0/33".parse::<Network>().is_err());
+    assert!("10.0.0/8".parse::<Network>().is_err());
+}
@@ -0,0 +1,84 @@
+//! The admin log: what the server's operator and moderators did to the game, and moderation the
+//! server did itself, like throttling players, with who did it and when. Kept for the server's
+//! operator to review from the console, and logged as it happens.
+
+use log::info;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// How many of the latest entries are kept.
+const MAX_ENTRIES: usize = 500;
+
+/// Who did something in the admin log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Actor {
+    /// The server's operator, from the console or a [`crate::server::Admin`].
+    Operator,
+    /// The moderator with this name.
+    Moderator(String),
+    /// The server, on its own.
+    Server,
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Actor::Operator => write!(f, "the operator"),
+            Actor::Moderator(name) => write!(f, "moderator {}", name),
+            Actor::Server => write!(f, "the server"),
+        }
+    }
+}
+
+/// Something that happened, for the server's operator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub at: SystemTime,
+    pub actor: Actor,
+    /// What the actor did, like `kicked 10.0.0.1:52000`.
+    pub event: String,
+}
+
+/// The latest entries in the admin log, shared by everything that records them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AdminLog(Arc<Mutex<VecDeque<Entry>>>);
+
+impl AdminLog {
+    /// Records that `actor` did `event` just now.
+    pub(crate) fn record(&self, actor: Actor, event: String) {
+        info!(target: "admin", "{} {}", actor, event);
+        let mut entries = self.0.lock().unwrap();
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            at: SystemTime::now(),
+            actor,
+            event,
+        });
+    }
+
+    /// The entries kept, oldest first.
+    pub(crate) fn entries(&self) -> Vec<Entry> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[test]
+fn admin_log_keeps_the_latest_entries() {
+    let log = AdminLog::default();
+    for i in 0..MAX_ENTRIES + 2 {
+        log.record(Actor::Server, i.to_string());
+    }
+    let entries = log.entries();
+    assert_eq!(entries.len(), MAX_ENTRIES);
+    assert_eq!(entries[0].event, "2");
+    assert_eq!(
+        entries[MAX_ENTRIES - 1].event,
+        (MAX_ENTRIES + 1).to_string()
+    );
+}
@@ -0,0 +1,105 @@
+//! Banned addresses, which games refuse players from. The ban list can be kept in a file, one
+//! address per line, so that bans survive restarts. IPv4-mapped IPv6 addresses are kept as the
+//! IPv4 addresses they map, so a ban holds however the player's address is seen.
+
+use crate::access::canonical;
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Parses a ban list: one address per line. Blank lines, and anything after a `#`, are ignored.
+pub fn parse(list: &str) -> Result<BTreeSet<IpAddr>, String> {
+    list.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse()
+                .map(canonical)
+                .map_err(|e| format!("bad address {:?} in ban list: {}", line, e))
+        })
+        .collect()
+}
+
+/// Loads the ban list at `path`. A missing file is an empty list.
+pub fn load(path: &Path) -> io::Result<BTreeSet<IpAddr>> {
+    match fs::read_to_string(path) {
+        Ok(list) => parse(&list).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The addresses a game has banned, saved to the ban list on every change if it has one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Bans {
+    banned: Arc<Mutex<BTreeSet<IpAddr>>>,
+    path: Option<PathBuf>,
+}
+
+impl Bans {
+    pub(crate) fn new(banned: BTreeSet<IpAddr>, path: Option<PathBuf>) -> Self {
+        Bans {
+            banned: Arc::new(Mutex::new(banned)),
+            path,
+        }
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(&canonical(ip))
+    }
+
+    /// Every banned address, in order.
+    pub(crate) fn list(&self) -> Vec<IpAddr> {
+        self.banned.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Bans `ip`. The ban takes effect even if it can't be saved.
+    pub(crate) fn ban(&self, ip: IpAddr) -> io::Result<()> {
+        let mut banned = self.banned.lock().unwrap();
+        banned.insert(canonical(ip));
+        self.save(&banned)
+    }
+
+    /// Lifts the ban on `ip`, returning whether it was banned.
+    pub(crate) fn unban(&self, ip: IpAddr) -> io::Result<bool> {
+        let mut banned = self.banned.lock().unwrap();
+        let was_banned = banned.remove(&canonical(ip));
+        self.save(&banned)?;
+        Ok(was_banned)
+    }
+
+    fn save(&self, banned: &BTreeSet<IpAddr>) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        for ip in banned {
+            writeln!(file, "{}", ip)?;
+        }
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+}
+
+#[test]
+fn ban_lists_parse() {
+    let banned = parse("10.0.0.1\n\n# spammer\n::1 # loopback\n::ffff:10.0.0.1\n").unwrap();
+    let expected: BTreeSet<IpAddr> = vec![[10, 0, 0, 1].into(), "::1".parse().unwrap()]
+        .into_iter()
+        .collect();
+    assert_eq!(banned, expected);
+    assert!(parse("10.0.0.1:7777").is_err());
+
+    let bans = Bans::new(BTreeSet::new(), None);
+    bans.ban("::ffff:10.0.0.2".parse().unwrap()).unwrap();
+    assert!(bans.contains([10, 0, 0, 2].into()));
+    assert!(bans.contains("::ffff:10.0.0.2".parse().unwrap()));
+    assert_eq!(bans.list(), vec![IpAddr::from([10, 0, 0, 2])]);
+}
@@ -1,23 +1,322 @@
-use clap::{App, Arg};
-use fakeblok::client;
-use std::{io, net::SocketAddr};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use fakeblok::{
+    camera, client, diagnostics,
+    game_list::MatchPreferences,
+    lan,
+    palette::{Palette, Style},
+    transport, tui, window, Error,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-fn main() -> io::Result<()> {
-    pretty_env_logger::init();
+fn main() -> Result<(), Error> {
     let flags = App::new("Fakeblok")
         .version("0.1")
         .author("Tim <tikue@google.com>")
         .author("Adam <aawright@google.com>")
         .about("Say hello!")
         .arg(Arg::from_usage(
-            "--server_addr <address> Sets the server address to connect to.",
+            "--server_addr [address] Joins the game at this host and port (7777 by default), \
+             instead of showing the main menu.",
         ))
+        .arg(
+            Arg::from_usage(
+                "--quickplay [game_list_address] Joins the best game listed by this game list.",
+            )
+            .conflicts_with("server_addr"),
+        )
+        .arg(Arg::from_usage(
+            "--lan Lists games announced on the local network, then exits.",
+        ))
+        .arg(Arg::from_usage(
+            "--region [region] With --quickplay, prefers games hosted in this region.",
+        ))
+        .arg(
+            Arg::from_usage("--tag [tag]... With --quickplay, only joins games with this tag.")
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::from_usage(
+                "--interpolation_delay_ms [millis] How far behind the server other players are drawn.",
+            )
+            .default_value("100"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--camera_half_life_ms [millis] How long the view takes to catch up halfway.",
+            )
+            .default_value("100"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--camera_deadzone [fraction] How much of the view the player moves in freely.",
+            )
+            .default_value("0.25"),
+        )
+        .arg(Arg::from_usage(
+            "--font [path] Draws text with this TrueType font instead of the bundled one.",
+        ))
+        .arg(Arg::from_usage(
+            "--name [name] The name shown above the player.",
+        ))
+        .arg(Arg::from_usage(
+            "--color [RRGGBB] The color of the player's square, in hex. Random by default.",
+        ))
+        .arg(
+            Arg::from_usage(
+                "--palette [name] Colors entities in standard, colorblind or high-contrast colors.",
+            )
+            .default_value("standard"),
+        )
+        .arg(Arg::from_usage(
+            "--outlines Draws an outline around every entity.",
+        ))
+        .arg(Arg::from_usage(
+            "--reduced_motion Keeps the view stiller, for players sensitive to motion.",
+        ))
+        .arg(
+            Arg::from_usage("--resolution [WxH] The size of the window, when not fullscreen.")
+                .default_value("512x512"),
+        )
+        .arg(Arg::from_usage(
+            "--fullscreen Starts fullscreen. F11 switches between fullscreen and a window.",
+        ))
+        .arg(
+            Arg::from_usage("--max_fps [fps] The most frames to draw per second.")
+                .default_value("60"),
+        )
+        .arg(Arg::from_usage(
+            "--vsync Waits for the screen to refresh before showing each frame.",
+        ))
+        .arg(Arg::from_usage(
+            "--lazy Only draws frames when keys are pressed, to save power.",
+        ))
+        .arg(Arg::from_usage(
+            "--udp Receives game state and sends inputs over UDP.",
+        ))
+        .arg(Arg::from_usage(
+            "--join_token [token] Joins games that require a token with this one.",
+        ))
+        .arg(Arg::from_usage(
+            "--password [password] Joins games that require a password with this one, or asks.",
+        ))
+        .arg(Arg::from_usage(
+            "--moderator_key [key] Moderates with chat commands, like /kick 3, with this key.",
+        ))
+        .arg(Arg::from_usage(
+            "--resume_session [id] Rejoins as the same player after being disconnected.",
+        ))
+        .arg(Arg::from_usage(
+            "--record [path] Records the games played to this file, to watch with replay.",
+        ))
+        .arg(
+            Arg::from_usage(
+                "--screenshot_dir [dir] Where F12 saves screenshots, and F9 diagnostics bundles.",
+            )
+            .default_value("."),
+        )
+        .arg(Arg::from_usage(
+            "--log_file [path] Also appends logs to this file.",
+        ))
+        .args(&transport::Config::flags())
+        .subcommand(SubCommand::with_name("tui-play").about("Plays the game in the terminal"))
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Watches a game recorded with --record")
+                .arg(Arg::from_usage("<path> The recording to watch.")),
+        )
         .get_matches();
+    diagnostics::init_logging(flags.value_of("log_file").map(Path::new))?;
 
-    let server_addr = flags.value_of("server_addr").unwrap();
-    let server_addr: SocketAddr = server_addr
-        .parse()
-        .unwrap_or_else(|e| panic!(r#"--server_addr value "{}" invalid: {}"#, server_addr, e));
-    client::run_ui(server_addr)?;
+    if flags.is_present("lan") {
+        let games =
+            tokio::runtime::Runtime::new()?.block_on(lan::discover(3 * lan::ANNOUNCE_INTERVAL))?;
+        println!("Games on the local network:");
+        for (addr, announcement) in games {
+            println!("{}: {:?}", addr, announcement);
+        }
+        return Ok(());
+    }
+    let transport_config = transport::Config::from_flags(&flags)?;
+    let server_addrs = match flags.value_of("quickplay") {
+        Some(game_list_addr) => {
+            let game_list_addr = transport::resolve(game_list_addr)
+                .map_err(|e| Error::invalid_flag("quickplay", game_list_addr, e))?;
+            let preferences = MatchPreferences {
+                protocol_version: fakeblok::PROTOCOL_VERSION,
+                region: flags.value_of("region").map(String::from),
+                tags: flags
+                    .values_of("tag")
+                    .into_iter()
+                    .flatten()
+                    .map(String::from)
+                    .collect(),
+            };
+            tokio::runtime::Runtime::new()?
+                .block_on(find_match(game_list_addr, &transport_config, preferences))?
+                .map(|server_addr| vec![server_addr])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No game to join"))?
+        }
+        None => match flags.value_of("server_addr") {
+            Some(server_addr) => transport::resolve_all(server_addr, transport::DEFAULT_PORT)
+                .map_err(|e| Error::invalid_flag("server_addr", server_addr, e))?,
+            None => vec![],
+        },
+    };
+    let interpolation_delay: u64 = flag(&flags, "interpolation_delay_ms")?;
+    let half_life: u64 = flag(&flags, "camera_half_life_ms")?;
+    let deadzone: f32 = flag(&flags, "camera_deadzone")?;
+    let resolution: client::Resolution = flag(&flags, "resolution")?;
+    let max_fps: u64 = flag(&flags, "max_fps")?;
+    let palette: Palette = flag(&flags, "palette")?;
+    let resume_session = match flags.value_of("resume_session") {
+        Some(id) => Some(
+            id.parse()
+                .map_err(|e| Error::invalid_flag("resume_session", id, e))?,
+        ),
+        None => None,
+    };
+    let color = match flags.value_of("color") {
+        Some(color) => {
+            Some(parse_color(color).map_err(|e| Error::invalid_flag("color", color, e))?)
+        }
+        None => None,
+    };
+    let settings = client::Settings {
+        interpolation_delay: Duration::from_millis(interpolation_delay),
+        datagrams: flags.is_present("udp"),
+        join_token: flags.value_of("join_token").map(String::from),
+        moderator_key: flags.value_of("moderator_key").map(String::from),
+        password: match flags.value_of("password") {
+            Some(password) => Some(String::from(password)),
+            None if flags.is_present("password") => Some(ask_password()?),
+            None => None,
+        },
+        resume_session,
+        camera: camera::Settings {
+            half_life: Duration::from_millis(half_life),
+            deadzone,
+        },
+        name: flags.value_of("name").map(String::from),
+        font: flags.value_of("font").map(PathBuf::from),
+        color,
+        display: client::Display {
+            resolution,
+            fullscreen: flags.is_present("fullscreen"),
+            vsync: flags.is_present("vsync"),
+            max_fps,
+            lazy: flags.is_present("lazy"),
+        },
+        style: Style {
+            palette,
+            outlines: flags.is_present("outlines"),
+            reduced_motion: flags.is_present("reduced_motion"),
+        },
+        record: flags.value_of("record").map(PathBuf::from),
+        screenshot_dir: PathBuf::from(flags.value_of("screenshot_dir").unwrap()),
+    };
+    if let Some(replay) = flags.subcommand_matches("replay") {
+        let path = Path::new(replay.value_of("path").unwrap());
+        window::run_replay(path, settings)?;
+    } else if flags.subcommand_matches("tui-play").is_some() {
+        // The terminal client has no menu to pick a game from.
+        if server_addrs.is_empty() {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tui-play needs --server_addr or --quickplay",
+            );
+            return Err(e.into());
+        }
+        tui::run(&server_addrs, transport_config, settings)?;
+    } else {
+        window::run_ui(&server_addrs, transport_config, settings)?;
+    }
     Ok(())
 }
+
+/// The value of the flag `name`, which was given on the command line or has a default.
+fn flag<T>(flags: &ArgMatches, name: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = flags.value_of(name).unwrap();
+    value
+        .parse()
+        .map_err(|e| Error::invalid_flag(name, value, e))
+}
+
+/// Asks for the password to join with on the terminal, without showing it as it's typed. Reads a
+/// line as is when standard input isn't a terminal.
+fn ask_password() -> io::Result<String> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    if terminal::enable_raw_mode().is_err() {
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password)?;
+        return Ok(String::from(password.trim_end_matches(&['\r', '\n'][..])));
+    }
+    let password = read_hidden();
+    // Put the terminal back the way it was even if reading failed.
+    terminal::disable_raw_mode()?;
+    eprintln!();
+    password
+}
+
+/// Reads keys typed on the terminal, which is in raw mode, until enter is pressed.
+fn read_hidden() -> io::Result<String> {
+    let mut typed = String::new();
+    loop {
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Enter => return Ok(typed),
+            KeyCode::Backspace => {
+                typed.pop();
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "stopped asking for the password",
+                ));
+            }
+            KeyCode::Char(c) => typed.push(c),
+            _ => {}
+        }
+    }
+}
+
+/// Parses a color written like `ff8000`, or `#ff8000`.
+fn parse_color(hex: &str) -> Result<[f32; 3], String> {
+    let digits = hex.trim_start_matches('#');
+    if digits.len() != 6 {
+        return Err(String::from("expected six hex digits, like ff8000"));
+    }
+    let rgb = u32::from_str_radix(digits, 16).map_err(|e| e.to_string())?;
+    let channel = |shift: u32| ((rgb >> shift) & 0xff) as f32 / 255.;
+    Ok([channel(16), channel(8), channel(0)])
+}
+
+async fn find_match(
+    game_list_addr: SocketAddr,
+    transport_config: &transport::Config,
+    preferences: MatchPreferences,
+) -> io::Result<Option<SocketAddr>> {
+    let transport = transport::connect(&game_list_addr, transport_config).await?;
+    let client = fakeblok::GamesClient::new(tarpc::client::Config::default(), transport).spawn()?;
+    client
+        .find_match(tarpc::context::current(), preferences)
+        .await
+}
@@ -1,9 +1,14 @@
 use clap::{App, Arg};
+use fakeblok::{game_list::HealthCheckSettings, metrics, transport, Error};
 use log::info;
-use std::{env, io, net::SocketAddr};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 
-fn main() -> io::Result<()> {
+fn main() -> Result<(), Error> {
     let mut logger = pretty_env_logger::formatted_timed_builder();
     if let Ok(filter) = env::var("RUST_LOG") {
         logger.parse_filters(&filter);
@@ -15,31 +20,96 @@ fn main() -> io::Result<()> {
         .author("Tim <tikue@google.com>")
         .author("Adam <aawright@google.com>")
         .about("Run a fakeblok listings server that clients can use to list running games")
+        .arg(
+            Arg::from_usage("--bind [address] Sets the IP address to listen on, IPv4 or IPv6")
+                .default_value("0.0.0.0"),
+        )
         .arg(Arg::from_usage(
             "-r --registration_port <number> Sets the port number the registration server listens on",
         ))
         .arg(Arg::from_usage(
             "-l --list_port <number> Sets the port number the listings server listens on",
         ))
+        .arg(
+            Arg::from_usage("--health_check_interval [seconds] How often to check on each game")
+                .default_value("5"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--health_check_timeout [seconds] How long games have to answer each check",
+            )
+            .default_value("10"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--health_check_failures [count] Unregisters games after this many failures in a row",
+            )
+            .default_value("3"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--heartbeat_ttl [seconds] Unregisters games that go this long without a heartbeat",
+            )
+            .default_value("15"),
+        )
+        .arg(Arg::from_usage(
+            "--registration_key [key] Only lets games presenting this key register",
+        ))
+        .arg(Arg::from_usage(
+            "--metrics_port [number] Serves Prometheus metrics over HTTP on this port",
+        ))
+        .args(&transport::Config::flags())
         .get_matches();
 
+    let bind = flags.value_of("bind").unwrap();
+    let bind: IpAddr = bind
+        .parse()
+        .map_err(|e| Error::invalid_flag("bind", bind, e))?;
+
     let registration_port = flags.value_of("registration_port").unwrap();
     let registration_port: u16 = registration_port
         .parse()
-        .unwrap_or_else(|e| panic!(r#"--r value "{}" invalid: {}"#, registration_port, e));
-    let registration_addr: SocketAddr = ([0, 0, 0, 0u8], registration_port).into();
+        .map_err(|e| Error::invalid_flag("registration_port", registration_port, e))?;
+    let registration_addr = SocketAddr::new(bind, registration_port);
 
     let list_port = flags.value_of("list_port").unwrap();
     let list_port: u16 = list_port
         .parse()
-        .unwrap_or_else(|e| panic!(r#"--l value "{}" invalid: {}"#, list_port, e));
-    let list_addr: SocketAddr = ([0, 0, 0, 0u8], list_port).into();
+        .map_err(|e| Error::invalid_flag("list_port", list_port, e))?;
+    let list_addr = SocketAddr::new(bind, list_port);
+
+    if let Some(port) = flags.value_of("metrics_port") {
+        let port: u16 = port
+            .parse()
+            .map_err(|e| Error::invalid_flag("metrics_port", port, e))?;
+        metrics::spawn_endpoint(SocketAddr::new(bind, port));
+    }
+
+    let transport_config = transport::Config::from_flags(&flags)?;
+
+    let seconds = |flag| -> Result<Duration, Error> {
+        let seconds = flags.value_of(flag).unwrap();
+        let seconds: u64 = seconds
+            .parse()
+            .map_err(|e| Error::invalid_flag(flag, seconds, e))?;
+        Ok(Duration::from_secs(seconds))
+    };
+    let max_failures = flags.value_of("health_check_failures").unwrap();
+    let health_check = HealthCheckSettings {
+        interval: seconds("health_check_interval")?,
+        timeout: seconds("health_check_timeout")?,
+        max_failures: max_failures
+            .parse()
+            .map_err(|e| Error::invalid_flag("health_check_failures", max_failures, e))?,
+        heartbeat_ttl: seconds("heartbeat_ttl")?,
+    };
 
     info!("Starting game list server.");
-    Runtime::new()
-        .unwrap()
-        .block_on(fakeblok::game_list::GameList::run(
-            registration_addr,
-            list_addr,
-        ))
+    Runtime::new()?.block_on(fakeblok::game_list::GameList::run(
+        registration_addr,
+        list_addr,
+        transport_config,
+        health_check,
+        flags.value_of("registration_key").map(String::from),
+    ))
 }
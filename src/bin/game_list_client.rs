@@ -1,9 +1,15 @@
 use clap::{App, Arg};
+use fakeblok::{browser, game_list::ListingChange, transport, Error};
 use log::info;
-use std::{io, net::SocketAddr};
-use tokio_serde::formats::Json;
+use std::{
+    env, io,
+    net::SocketAddr,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, SystemTime},
+};
 
-fn main() -> io::Result<()> {
+fn main() -> Result<(), Error> {
     pretty_env_logger::init();
     let flags = App::new("Fakeblok")
         .version("0.1")
@@ -11,29 +17,73 @@ fn main() -> io::Result<()> {
         .author("Adam <aawright@google.com>")
         .about("Say hello!")
         .arg(Arg::from_usage(
-            "--server_addr <address> Sets the server address to connect to.",
+            "--server_addr <address> Sets the server host and port to connect to.",
         ))
+        .arg(Arg::from_usage(
+            "--watch Prints changes to the games as they happen, instead of browsing them.",
+        ))
+        .arg(Arg::from_usage(
+            "--client [path] The game client to join games with, if not the one alongside this.",
+        ))
+        .args(&transport::Config::flags())
         .get_matches();
 
     let server_addr = flags.value_of("server_addr").unwrap();
-    let server_addr: SocketAddr = server_addr
-        .parse()
-        .unwrap_or_else(|e| panic!(r#"--server_addr value "{}" invalid: {}"#, server_addr, e));
+    let server_addr = transport::resolve(server_addr)
+        .map_err(|e| Error::invalid_flag("server_addr", server_addr, e))?;
 
-    tokio::runtime::Runtime::new()
-        .unwrap()
-        .block_on(async move {
-            let client = create_client(server_addr).await.unwrap();
-            println!(
-                "Available games: {:?}",
-                client.list(tarpc::context::current()).await.unwrap()
-            );
+    let transport_config = transport::Config::from_flags(&flags)?;
+
+    if flags.is_present("watch") {
+        return tokio::runtime::Runtime::new()?.block_on(async move {
+            let client = create_client(server_addr, &transport_config).await?;
+            watch_games(&client).await
         });
+    }
+
+    let client_path = match flags.value_of("client") {
+        Some(path) => PathBuf::from(path),
+        None => env::current_exe()?.with_file_name("fakeblok"),
+    };
+    browser::run(server_addr, transport_config, &|addr| {
+        let mut client = Command::new(&client_path);
+        client.arg("--server_addr").arg(addr.to_string());
+        client
+    })?;
     Ok(())
 }
 
-async fn create_client(server_addr: SocketAddr) -> io::Result<fakeblok::GamesClient> {
+/// Prints changes to the games until getting them fails.
+async fn watch_games(client: &fakeblok::GamesClient) -> Result<(), Error> {
+    let (mut since, mut page_token) = (None, None);
+    loop {
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(60);
+        let changes = match client.subscribe(ctx, since, page_token).await {
+            Ok(changes) => changes,
+            // Nothing changed for a while.
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if changes.reset {
+            println!("Available games:");
+        }
+        for change in changes.changes {
+            match change {
+                ListingChange::Updated(addr, info) => println!("{}: {:?}", addr, info),
+                ListingChange::Unregistered(addr) => println!("{}: unregistered", addr),
+            }
+        }
+        since = Some(changes.version);
+        page_token = changes.next_page_token;
+    }
+}
+
+async fn create_client(
+    server_addr: SocketAddr,
+    transport_config: &transport::Config,
+) -> io::Result<fakeblok::GamesClient> {
     info!("Creating client to {}", server_addr);
-    let transport = tarpc::serde_transport::tcp::connect(&server_addr, Json::default()).await?;
+    let transport = transport::connect(&server_addr, transport_config).await?;
     fakeblok::GamesClient::new(tarpc::client::Config::default(), transport).spawn()
 }
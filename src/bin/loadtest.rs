@@ -0,0 +1,289 @@
+use clap::{App, Arg, ArgMatches};
+use fakeblok::{
+    game::{Component, Input, Sign},
+    transport, Error, StateUpdate,
+};
+use futures::{future, prelude::*};
+use log::{info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::BTreeMap,
+    env, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tarpc::context;
+use tokio::{runtime::Runtime, time};
+
+/// How often each client pings the server.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Settings {
+    server_addr: SocketAddr,
+    transport_config: transport::Config,
+    join_token: Option<String>,
+    password: Option<String>,
+    poll_interval: Duration,
+    input_interval: Duration,
+    duration: Duration,
+}
+
+/// How long the clients' calls took, by method, and how many failed.
+#[derive(Default)]
+struct Latencies {
+    durations: BTreeMap<&'static str, Vec<Duration>>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Latencies {
+    fn report(&self) {
+        println!(
+            "{:<24}{:>8}{:>8}{:>12}{:>12}{:>12}{:>12}",
+            "method", "calls", "errors", "p50", "p90", "p99", "max"
+        );
+        let mut methods: Vec<_> = self.durations.keys().chain(self.errors.keys()).collect();
+        methods.sort();
+        methods.dedup();
+        for method in methods {
+            let mut durations = self.durations.get(method).cloned().unwrap_or_default();
+            durations.sort();
+            let percentile = |p: f64| match durations.len() {
+                0 => String::from("-"),
+                len => format!("{:.1?}", durations[((len - 1) as f64 * p).round() as usize]),
+            };
+            println!(
+                "{:<24}{:>8}{:>8}{:>12}{:>12}{:>12}{:>12}",
+                method,
+                durations.len(),
+                self.errors.get(method).copied().unwrap_or_default(),
+                percentile(0.5),
+                percentile(0.9),
+                percentile(0.99),
+                percentile(1.),
+            );
+        }
+    }
+}
+
+/// Makes a call to `method`, recording how long it took or that it failed.
+async fn timed<T>(
+    latencies: &Mutex<Latencies>,
+    method: &'static str,
+    call: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let started = Instant::now();
+    let result = call.await;
+    let elapsed = started.elapsed();
+    let mut latencies = latencies.lock().unwrap();
+    match &result {
+        Ok(_) => latencies.durations.entry(method).or_default().push(elapsed),
+        Err(_) => *latencies.errors.entry(method).or_default() += 1,
+    }
+    result
+}
+
+fn main() -> Result<(), Error> {
+    let mut logger = pretty_env_logger::formatted_timed_builder();
+    if let Ok(filter) = env::var("RUST_LOG") {
+        logger.parse_filters(&filter);
+    }
+    logger.init();
+
+    let flags = App::new("Fakeblok Load Test")
+        .version("0.1")
+        .author("Tim <tikue@google.com>")
+        .author("Adam <aawright@google.com>")
+        .about(
+            "Plays a fakeblok game with many headless clients at once, then reports how long the \
+             server took to answer them. Polls wait for the next state, so they take as long as \
+             the server's broadcast interval at least.",
+        )
+        .arg(Arg::from_usage(
+            "--server_addr <address> Sets the server host and port to connect to",
+        ))
+        .arg(Arg::from_usage("--clients [count] How many clients to play with").default_value("10"))
+        .arg(
+            Arg::from_usage("--ramp_up [seconds] Spreads the clients joining over this long")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::from_usage("--duration [seconds] How long each client plays for")
+                .default_value("60"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--poll_rate [per second] How often each client polls for the game's state",
+            )
+            .default_value("20"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--input_rate [per second] How often each client moves; the server disconnects \
+                 players who go over 30",
+            )
+            .default_value("10"),
+        )
+        .arg(Arg::from_usage(
+            "--join_token [token] Authenticates with this token before joining",
+        ))
+        .arg(Arg::from_usage(
+            "--password [password] Joins the game with this password",
+        ))
+        .args(&transport::Config::flags())
+        .get_matches();
+
+    let server_addr = flags.value_of("server_addr").unwrap();
+    let server_addr = transport::resolve(server_addr)
+        .map_err(|e| Error::invalid_flag("server_addr", server_addr, e))?;
+    let clients: u32 = number(&flags, "clients")?;
+    let ramp_up = Duration::from_secs(number(&flags, "ramp_up")?);
+    let interval = |flag| -> Result<Duration, Error> {
+        Ok(Duration::from_secs(1) / number::<u32>(&flags, flag)?.max(1))
+    };
+    let settings = Arc::new(Settings {
+        server_addr,
+        transport_config: transport::Config::from_flags(&flags)?,
+        join_token: flags.value_of("join_token").map(String::from),
+        password: flags.value_of("password").map(String::from),
+        poll_interval: interval("poll_rate")?,
+        input_interval: interval("input_rate")?,
+        duration: Duration::from_secs(number(&flags, "duration")?),
+    });
+
+    let latencies = Arc::new(Mutex::new(Latencies::default()));
+    let finished = Runtime::new()?.block_on(future::join_all((0..clients).map(|n| {
+        let settings = settings.clone();
+        let latencies = latencies.clone();
+        tokio::spawn(async move {
+            time::delay_for(ramp_up * n / clients.max(1)).await;
+            let played = play(n, &settings, &latencies).await;
+            if let Err(e) = &played {
+                warn!("Client {} stopped playing: {}", n, e);
+            }
+            played.is_ok()
+        })
+    })));
+    let finished = finished
+        .into_iter()
+        .filter(|finished| *finished.as_ref().unwrap_or(&false))
+        .count();
+    println!(
+        "{} of {} clients played for {:?}",
+        finished, clients, settings.duration
+    );
+    latencies.lock().unwrap().report();
+    Ok(())
+}
+
+fn number<T>(flags: &ArgMatches, flag: &str) -> Result<T, Error>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = flags.value_of(flag).unwrap();
+    value
+        .parse()
+        .map_err(|e| Error::invalid_flag(flag, value, e))
+}
+
+/// Joins the game as client `n`, then polls for states, moves around at random and pings the
+/// server until the load test is over.
+async fn play(n: u32, settings: &Settings, latencies: &Mutex<Latencies>) -> io::Result<()> {
+    let transport = transport::connect(&settings.server_addr, &settings.transport_config).await?;
+    let client = fakeblok::GameClient::new(tarpc::client::Config::default(), transport).spawn()?;
+    if let Some(token) = &settings.join_token {
+        let authenticating = client.authenticate(context::current(), token.clone());
+        if !timed(latencies, "authenticate", authenticating).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "join token rejected",
+            ));
+        }
+    }
+    let name = format!("load test {}", n);
+    let joining = client.join(
+        context::current(),
+        None,
+        Some(name),
+        settings.password.clone(),
+    );
+    timed(latencies, "join", joining)
+        .await?
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+    info!("Client {} joined", n);
+
+    // The latest tick seen, which inputs are made at.
+    let tick = AtomicU64::new(0);
+    let playing = future::try_join3(
+        poll(&client, settings.poll_interval, &tick, latencies),
+        move_around(&client, settings.input_interval, &tick, latencies),
+        ping(&client, latencies),
+    );
+    match time::timeout(settings.duration, playing).await {
+        Ok(Err(e)) => Err(e),
+        // Nothing stops playing but an error or the time running out.
+        _ => Ok(()),
+    }
+}
+
+/// Polls for the game's state every `interval`, keeping `tick` up to date.
+async fn poll(
+    client: &fakeblok::GameClient,
+    interval: Duration,
+    tick: &AtomicU64,
+    latencies: &Mutex<Latencies>,
+) -> io::Result<()> {
+    let mut polls = time::interval(interval);
+    let mut last_seen = None;
+    loop {
+        polls.tick().await;
+        let polling = client.poll_game_state(context::current(), last_seen);
+        let seen = match timed(latencies, "poll_game_state", polling).await? {
+            StateUpdate::Full(game) => game.ticks(),
+            StateUpdate::Delta(delta) => delta.tick,
+        };
+        tick.store(seen, Ordering::SeqCst);
+        last_seen = Some(seen);
+    }
+}
+
+/// Heads off in a random direction, or stops, every `interval`.
+async fn move_around(
+    client: &fakeblok::GameClient,
+    interval: Duration,
+    tick: &AtomicU64,
+    latencies: &Mutex<Latencies>,
+) -> io::Result<()> {
+    let mut rng = StdRng::from_entropy();
+    let mut inputs = time::interval(interval);
+    for sequence in 1.. {
+        inputs.tick().await;
+        let component = if rng.gen() {
+            Component::X
+        } else {
+            Component::Y
+        };
+        let sign = match rng.gen_range(0, 3) {
+            0 => Some(Sign::Positive),
+            1 => Some(Sign::Negative),
+            _ => None,
+        };
+        let input = Input::Move(component, sign);
+        let tick = tick.load(Ordering::SeqCst);
+        let pushing = client.push_input(context::current(), sequence, tick, input);
+        timed(latencies, "push_input", pushing).await?;
+    }
+    Ok(())
+}
+
+async fn ping(client: &fakeblok::GameClient, latencies: &Mutex<Latencies>) -> io::Result<()> {
+    let mut pings = time::interval(PING_INTERVAL);
+    loop {
+        pings.tick().await;
+        timed(latencies, "ping", client.ping(context::current())).await?;
+    }
+}
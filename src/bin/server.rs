@@ -1,9 +1,122 @@
-use clap::{App, Arg};
-use fakeblok::server::Server;
+use clap::{App, Arg, ArgMatches};
+use fakeblok::{
+    access::AccessLists,
+    bans,
+    game::{GameInt, Point},
+    map, metrics,
+    mode::Mode,
+    moderators,
+    replay::Replay,
+    schedule::Scheduled,
+    script::Script,
+    server::{Server, Settings},
+    snapshot, transport, Error,
+};
 use log::info;
-use std::{env, io, net::SocketAddr};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-fn main() -> io::Result<()> {
+/// Settings read from the file given with `--config`. Flags given on the command line override
+/// them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    port: Option<u16>,
+    name: Option<String>,
+    registry_addr: Option<String>,
+    max_players: Option<usize>,
+    password: Option<String>,
+    min_players: Option<usize>,
+    chat_rate: Option<f64>,
+    chat_burst: Option<u32>,
+    chat_repeat_window: Option<u64>,
+    tick_rate: Option<u64>,
+    broadcast_rate: Option<u64>,
+    world_width: Option<GameInt>,
+    world_height: Option<GameInt>,
+    square_size: Option<GameInt>,
+    autosave: Option<PathBuf>,
+    autosave_interval: Option<u64>,
+    stats: Option<PathBuf>,
+    ban_list: Option<PathBuf>,
+    moderator_list: Option<PathBuf>,
+    access: Option<AccessLists>,
+    map: Option<PathBuf>,
+    map_rotation: Option<Vec<PathBuf>>,
+    mode: Option<Mode>,
+    script: Option<PathBuf>,
+    schedule: Option<Vec<Scheduled>>,
+}
+
+impl ConfigFile {
+    fn read(path: &str) -> Result<Self, Error> {
+        let config = fs::read_to_string(path).map_err(|e| Error::config(path, e))?;
+        let config: Self = toml::from_str(&config).map_err(|e| Error::config(path, e))?;
+        if config.map.is_some() && config.map_rotation.is_some() {
+            return Err(Error::config(
+                path,
+                "set either a map or a map_rotation, not both",
+            ));
+        }
+        for scheduled in config.schedule.iter().flatten() {
+            scheduled.validate().map_err(|e| Error::config(path, e))?;
+        }
+        Ok(config)
+    }
+}
+
+/// The value of the flag `name` if it was given on the command line, or else `configured` if
+/// there is a value from the config file, or else the flag's default.
+fn flag_or<T>(flags: &ArgMatches, name: &str, configured: Option<T>) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match configured {
+        Some(configured) if flags.occurrences_of(name) == 0 => Ok(Some(configured)),
+        _ => flags
+            .value_of(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| Error::invalid_flag(name, value, e))
+            })
+            .transpose(),
+    }
+}
+
+/// The rate set by the flag `name` or `configured`, like [`flag_or`], checked to be one a game
+/// can run at.
+fn rate_flag(flags: &ArgMatches, name: &str, configured: Option<u64>) -> Result<u64, Error> {
+    let rate: u64 = flag_or(flags, name, configured)?.unwrap();
+    if rate == 0 || rate > map::MAX_RATE {
+        return Err(Error::invalid_flag(
+            name,
+            &rate.to_string(),
+            format!("must be from 1 to {}", map::MAX_RATE),
+        ));
+    }
+    Ok(rate)
+}
+
+/// The value of the flag `name`, which was given on the command line or has a default.
+fn flag<T>(flags: &ArgMatches, name: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Ok(flag_or(flags, name, None)?.unwrap())
+}
+
+fn main() -> Result<(), Error> {
     let mut logger = pretty_env_logger::formatted_timed_builder();
     if let Ok(filter) = env::var("RUST_LOG") {
         logger.parse_filters(&filter);
@@ -12,28 +125,356 @@ fn main() -> io::Result<()> {
 
     info!("Hello");
 
+    let default_port = transport::DEFAULT_PORT.to_string();
     let flags = App::new("Fakeblok Server")
         .version("0.1")
         .author("Tim <tikue@google.com>")
         .author("Adam <aawright@google.com>")
         .about("Run a fakeblok server that clients can connect to")
         .arg(Arg::from_usage(
-            "-p --port <number> Sets the port number to listen on",
+            "--config [path] Reads settings from this TOML file. Flags override the file.",
+        ))
+        .arg(
+            Arg::from_usage("-p --port [number] Sets the port number to listen on")
+                .default_value(&default_port),
+        )
+        .arg(Arg::from_usage(
+            "-n --name [string] Sets the name of the game",
+        ))
+        .arg(
+            Arg::from_usage("--bind [address] Sets the IP address to listen on, IPv4 or IPv6")
+                .default_value("0.0.0.0"),
+        )
+        .arg(Arg::from_usage(
+            "--websocket_port [number] Also accepts WebSocket connections on this port",
+        ))
+        .arg(Arg::from_usage(
+            "--status_port [number] Serves the game status over HTTP on this port",
+        ))
+        .arg(Arg::from_usage(
+            "--metrics_port [number] Serves Prometheus metrics over HTTP on this port",
+        ))
+        .arg(
+            Arg::from_usage(
+                "--join_token [token]... Only lets in players with one of these tokens",
+            )
+            .number_of_values(1),
+        )
+        .arg(Arg::from_usage(
+            "--password [password] Only lets in players who give this password",
+        ))
+        .arg(
+            Arg::from_usage("--max_players [number] Sets how many players can play at once")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::from_usage("--min_players [number] Adds bots while fewer people than this play")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--idle_timeout [seconds] Disconnects players who make no requests for this long",
+            )
+            .default_value("10"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--afk_timeout [seconds] Marks players who make no inputs for this long as away",
+            )
+            .default_value("60"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--afk_removal_timeout [seconds] Disconnects players who stay away for this long",
+            )
+            .default_value("240"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--chat_rate [number] Sets how many messages a second players can chat",
+            )
+            .default_value("1"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--chat_burst [number] Sets how many messages players can chat at once",
+            )
+            .default_value("5"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--chat_repeat_window [seconds] Drops messages players repeat within this long",
+            )
+            .default_value("30"),
+        )
+        .arg(Arg::from_usage(
+            "--region [region] Tells the game list where the game is hosted",
+        ))
+        .arg(
+            Arg::from_usage("--tag [tag]... Tells the game list what describes the game")
+                .number_of_values(1),
+        )
+        .arg(Arg::from_usage(
+            "--motd [message] Shows players this message in listings and when they join",
+        ))
+        .arg(Arg::from_usage(
+            "--lan Announces the game on the local network, for players without the game list",
+        ))
+        .arg(Arg::from_usage(
+            "--registration_key [key] Registers the game with this key, if the game list needs one",
+        ))
+        .arg(Arg::from_usage(
+            "--heartbeat_interval [seconds] Sends heartbeats instead of being health checked",
+        ))
+        .arg(Arg::from_usage(
+            "--generate_join_token Generates and prints a token that players must join with",
+        ))
+        .arg(
+            Arg::from_usage("--registry_addr [address] Registers the game with the game list here")
+                .default_value("0.0.0.0:23304"),
+        )
+        .arg(
+            Arg::from_usage("--tick_rate [number] Sets how many times a second the game ticks")
+                .default_value("200"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--broadcast_rate [number] Sets how many times a second states are sent",
+            )
+            .default_value("200"),
+        )
+        .arg(
+            Arg::from_usage("--world_width [number] Sets the width of the world")
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::from_usage("--world_height [number] Sets the height of the world")
+                .default_value("500"),
+        )
+        .arg(
+            Arg::from_usage("--square_size [number] Sets the size of squares in the world")
+                .default_value("50"),
+        )
+        .arg(Arg::from_usage(
+            "--load [snapshot] Starts with the world saved in this snapshot",
+        ))
+        .arg(Arg::from_usage(
+            "--autosave [path] Saves the world here periodically and when the server stops",
+        ))
+        .arg(
+            Arg::from_usage("--autosave_interval [seconds] Sets how often the world is saved")
+                .default_value("300"),
+        )
+        .arg(Arg::from_usage(
+            "--stats [path] Keeps players' stats in a database here, across restarts",
+        ))
+        .arg(Arg::from_usage(
+            "--record [path] Records every input to this file, to watch the match again",
+        ))
+        .arg(
+            Arg::from_usage("--playback [replay] Plays this recording back for players to watch")
+                .conflicts_with_all(&["load", "autosave", "record"]),
+        )
+        .arg(Arg::from_usage(
+            "--ban_list [path] Refuses players from addresses in this file, and saves bans here",
         ))
         .arg(Arg::from_usage(
-            "-n --name <string> Sets the name of the game",
+            "--moderator_list [path] Reads moderators from this file, and saves them here",
         ))
+        .arg(
+            Arg::from_usage(
+                "--map [path] Lays the world out with this map, reloading it on changes",
+            )
+            .conflicts_with("playback"),
+        )
+        .arg(
+            Arg::from_usage("--map_rotation [path]... Cycles through these maps, one each round")
+                .number_of_values(1)
+                .conflicts_with_all(&["map", "playback"]),
+        )
+        .arg(
+            Arg::from_usage("--mode [mode] Sets the rules of the match")
+                .possible_values(&["free_for_all", "tag", "capture_the_flag"])
+                .default_value("free_for_all"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--script [path] Hooks this Rhai script into the game to change its rules",
+            )
+            .conflicts_with("playback"),
+        )
+        .args(&transport::Config::flags())
         .get_matches();
 
-    let port = flags.value_of("port").unwrap();
-    let port: u16 = port
-        .parse()
-        .unwrap_or_else(|e| panic!(r#"--port value "{}" invalid: {}"#, port, e));
-    let server_addr: SocketAddr = ([0, 0, 0, 0u8], port).into();
+    let config = flags
+        .value_of("config")
+        .map(ConfigFile::read)
+        .transpose()?
+        .unwrap_or_default();
+    let port: u16 = flag_or(&flags, "port", config.port)?.unwrap();
+    let bind: IpAddr = flag(&flags, "bind")?;
+    let server_addr = SocketAddr::new(bind, port);
+    let websocket_addr =
+        flag_or(&flags, "websocket_port", None)?.map(|port: u16| SocketAddr::new(bind, port));
+    let status_addr =
+        flag_or(&flags, "status_port", None)?.map(|port: u16| SocketAddr::new(bind, port));
+    if let Some(port) = flag_or::<u16>(&flags, "metrics_port", None)? {
+        metrics::spawn_endpoint(SocketAddr::new(bind, port));
+    }
+
+    let name: String = flag_or(&flags, "name", config.name)?.ok_or_else(|| {
+        Error::invalid_flag(
+            "name",
+            "",
+            "the game needs a name, from --name or the config file",
+        )
+    })?;
+    let registry_addr: String = flag_or(&flags, "registry_addr", config.registry_addr)?.unwrap();
+    let registry_addr = transport::resolve(&registry_addr)
+        .map_err(|e| Error::invalid_flag("registry_addr", &registry_addr, e))?;
+
+    let transport_config = transport::Config::from_flags(&flags)?;
+
+    let mut join_tokens: HashSet<String> = flags
+        .values_of("join_token")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    if flags.is_present("generate_join_token") {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .collect();
+        println!("Join token: {}", token);
+        join_tokens.insert(token);
+    }
+
+    let ban_list: Option<PathBuf> = flag_or(&flags, "ban_list", config.ban_list)?;
+    let banned = match &ban_list {
+        Some(path) => bans::load(path)?,
+        None => Default::default(),
+    };
 
-    let name = flags.value_of("name").unwrap();
+    let moderator_list: Option<PathBuf> = flag_or(&flags, "moderator_list", config.moderator_list)?;
+    let moderators = match &moderator_list {
+        Some(path) => moderators::load(path)?,
+        None => Default::default(),
+    };
+
+    let max_players: usize = flag_or(&flags, "max_players", config.max_players)?.unwrap();
+    let min_players: usize = flag_or(&flags, "min_players", config.min_players)?.unwrap();
+    let chat_rate: f64 = flag_or(&flags, "chat_rate", config.chat_rate)?.unwrap();
+    if !chat_rate.is_finite() || chat_rate <= 0. {
+        return Err(Error::invalid_flag(
+            "chat_rate",
+            &chat_rate.to_string(),
+            "must be more than 0",
+        ));
+    }
+    let chat_burst: u32 = flag_or(&flags, "chat_burst", config.chat_burst)?.unwrap();
+    let chat_repeat_window: u64 =
+        flag_or(&flags, "chat_repeat_window", config.chat_repeat_window)?.unwrap();
+    let tick_rate = rate_flag(&flags, "tick_rate", config.tick_rate)?;
+    let broadcast_rate = rate_flag(&flags, "broadcast_rate", config.broadcast_rate)?;
+    let world_width: GameInt = flag_or(&flags, "world_width", config.world_width)?.unwrap();
+    let world_height: GameInt = flag_or(&flags, "world_height", config.world_height)?.unwrap();
+    let square_size: GameInt = flag_or(&flags, "square_size", config.square_size)?.unwrap();
+
+    let world = flags
+        .value_of("load")
+        .map(|path| snapshot::load(Path::new(path)))
+        .transpose()?;
+    let playback = flags
+        .value_of("playback")
+        .map(|path| Replay::load(Path::new(path)))
+        .transpose()?;
+    let script = flag_or(&flags, "script", config.script)?
+        .map(|path: PathBuf| Script::load(&path))
+        .transpose()?;
+    let autosave = flag_or(&flags, "autosave", config.autosave)?;
+    let autosave_interval: u64 =
+        flag_or(&flags, "autosave_interval", config.autosave_interval)?.unwrap();
+
+    let afk_timeout: u64 = flag(&flags, "afk_timeout")?;
+    let afk_removal_timeout: u64 = flag(&flags, "afk_removal_timeout")?;
+    let idle_timeout: u64 = flag(&flags, "idle_timeout")?;
+    let heartbeat_interval: Option<u64> = flag_or(&flags, "heartbeat_interval", None)?;
+    let stats = flag_or(&flags, "stats", config.stats)?;
+    let map = flag_or(&flags, "map", config.map)?;
+    let map_rotation: Vec<PathBuf> = match flags.values_of("map_rotation") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => config.map_rotation.unwrap_or_default(),
+    };
+    // Neither the flags nor the config file can set both, but one can set each.
+    if let (Some(map), false) = (&map, map_rotation.is_empty()) {
+        return Err(match flags.values_of("map_rotation") {
+            Some(paths) => Error::invalid_flag(
+                "map_rotation",
+                &paths.collect::<Vec<_>>().join(" "),
+                "the config file sets a map",
+            ),
+            None => Error::invalid_flag(
+                "map",
+                &map.display().to_string(),
+                "the config file sets a map_rotation",
+            ),
+        });
+    }
+    let mode = flag_or(&flags, "mode", config.mode)?.unwrap();
 
     info!("Starting game.");
-    Server::run_game(server_addr, name.into())?;
+    Server::run_game(
+        server_addr,
+        websocket_addr,
+        status_addr,
+        name,
+        transport_config,
+        Settings {
+            join_tokens,
+            password: flag_or(&flags, "password", config.password)?,
+            banned,
+            ban_list,
+            access: config.access.unwrap_or_default(),
+            moderators,
+            moderator_list,
+            config: flags.value_of("config").map(PathBuf::from),
+            max_players,
+            min_players,
+            region: flags.value_of("region").map(String::from),
+            tags: flags
+                .values_of("tag")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
+            motd: flags.value_of("motd").map(String::from),
+            idle_timeout: Duration::from_secs(idle_timeout),
+            afk_timeout: Duration::from_secs(afk_timeout),
+            afk_removal_timeout: Duration::from_secs(afk_removal_timeout),
+            chat_rate,
+            chat_burst,
+            chat_repeat_window: Duration::from_secs(chat_repeat_window),
+            lan: flags.is_present("lan"),
+            registration_key: flags.value_of("registration_key").map(String::from),
+            heartbeat_interval: heartbeat_interval.map(Duration::from_secs),
+            registry_addr,
+            world_size: Point::new(world_width, world_height),
+            square_size,
+            tick_rate,
+            broadcast_rate,
+            world,
+            autosave,
+            autosave_interval: Duration::from_secs(autosave_interval),
+            stats,
+            record: flags.value_of("record").map(PathBuf::from),
+            playback,
+            map,
+            map_rotation,
+            mode,
+            script,
+            schedule: config.schedule.unwrap_or_default(),
+        },
+    )?;
     Ok(())
 }
@@ -0,0 +1,100 @@
+//! Bots that keep a game at a minimum number of players, so that small games don't feel empty.
+//! Bots wander around the world, and leave as people join.
+
+use crate::{
+    game::{Component, EntityId, Input, Sign},
+    rollback::{Command, History},
+};
+use log::info;
+use rand::Rng;
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+/// How likely each bot is to change direction each time it's steered.
+const TURN_CHANCE: f64 = 0.25;
+
+/// A game's bots.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Bots {
+    ids: Arc<Mutex<BTreeSet<EntityId>>>,
+    /// How many players, people and bots, the game keeps.
+    min_players: usize,
+}
+
+impl Bots {
+    pub(crate) fn new(min_players: usize) -> Self {
+        Bots {
+            ids: Arc::default(),
+            min_players,
+        }
+    }
+
+    pub(crate) fn contains(&self, id: EntityId) -> bool {
+        self.ids.lock().unwrap().contains(&id)
+    }
+
+    /// Adds bots while there are too few players, and removes them while there are more than
+    /// enough people to do without them.
+    pub(crate) fn backfill(&self, history: &mut History) {
+        let mut ids = self.ids.lock().unwrap();
+        ids.retain(|&id| history.game().contains(id));
+        let people = history
+            .game()
+            .players()
+            .filter(|id| !ids.contains(id))
+            .count();
+        let wanted = self.min_players.saturating_sub(people);
+        while ids.len() < wanted {
+            let id = history.add_player(history.game().new_player_square());
+            history.apply(Command::NamePlayer(id, format!("bot {}", id)));
+            info!("Added bot {}", id);
+            ids.insert(id);
+        }
+        while ids.len() > wanted {
+            // The newest bots leave first.
+            let id = *ids.iter().next_back().unwrap();
+            ids.remove(&id);
+            history.apply(Command::RemovePlayer(id));
+            info!("Removed bot {}", id);
+        }
+    }
+
+    /// Has some of the bots head off in a new direction, or stop.
+    pub(crate) fn steer(&self, history: &mut History, rng: &mut impl Rng) {
+        for &id in self.ids.lock().unwrap().iter() {
+            if !rng.gen_bool(TURN_CHANCE) {
+                continue;
+            }
+            for &component in &[Component::X, Component::Y] {
+                let sign = match rng.gen_range(0, 3) {
+                    0 => Some(Sign::Positive),
+                    1 => Some(Sign::Negative),
+                    _ => None,
+                };
+                history.apply(Command::Input(id, Input::Move(component, sign)));
+            }
+        }
+    }
+}
+
+#[test]
+fn bots_make_way_for_people() {
+    use crate::game::{Game, Point};
+
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let person = game.insert_new_player_square();
+    let mut history = History::new(game, 10);
+    let bots = Bots::new(3);
+    bots.backfill(&mut history);
+    assert_eq!(history.game().players().count(), 3);
+    assert!(!bots.contains(person));
+
+    let newcomer = history.game().new_player_square();
+    let newcomer = history.add_player(newcomer);
+    bots.backfill(&mut history);
+    assert_eq!(history.game().players().count(), 3);
+    assert!(history.game().contains(person) && history.game().contains(newcomer));
+    assert_eq!(bots.ids.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,280 @@
+//! A terminal server browser, listing the games registered with a game list as they change so that
+//! players can pick one to join.
+
+use crate::{
+    game_list::{GameInfo, Health, Reachability},
+    transport,
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute, terminal,
+};
+use log::error;
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::Constraint,
+    style::{Modifier, Style},
+    widgets::{Block, Borders, Row, Table, TableState},
+    Frame, Terminal,
+};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    io::{self, Stdout},
+    net::SocketAddr,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+use tokio::runtime::Runtime;
+
+/// How often the list is redrawn to show changes, when no keys are pressed.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const COLUMN_WIDTHS: [Constraint; 6] = [
+    Constraint::Length(22),
+    Constraint::Percentage(25),
+    Constraint::Length(12),
+    Constraint::Length(8),
+    Constraint::Length(24),
+    Constraint::Percentage(25),
+];
+
+/// What the browser has heard from the game list.
+#[derive(Default)]
+struct Listing {
+    games: BTreeMap<SocketAddr, GameInfo>,
+    /// Why the browser stopped hearing from the game list, if it did.
+    error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortBy {
+    Address,
+    Name,
+    /// Most players first.
+    Players,
+    Region,
+}
+
+impl SortBy {
+    fn next(self) -> SortBy {
+        match self {
+            SortBy::Address => SortBy::Name,
+            SortBy::Name => SortBy::Players,
+            SortBy::Players => SortBy::Region,
+            SortBy::Region => SortBy::Address,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortBy::Address => "address",
+            SortBy::Name => "name",
+            SortBy::Players => "players",
+            SortBy::Region => "region",
+        }
+    }
+
+    /// Sorts `games`, which are ordered by address. Sorting is stable, so games that tie stay
+    /// ordered by address.
+    fn sort(self, games: &mut [(SocketAddr, GameInfo)]) {
+        match self {
+            SortBy::Address => {}
+            SortBy::Name => games.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name)),
+            SortBy::Players => games.sort_by_key(|(_, info)| {
+                Reverse(info.status.as_ref().map_or(0, |status| status.players))
+            }),
+            SortBy::Region => games.sort_by(|(_, a), (_, b)| a.region.cmp(&b.region)),
+        }
+    }
+}
+
+/// Keeps `listing` up to date with the games registered with the game list at `game_list_addr`.
+async fn follow(
+    game_list_addr: SocketAddr,
+    transport_config: transport::Config,
+    listing: &Mutex<Listing>,
+) -> io::Result<()> {
+    let transport = transport::connect(&game_list_addr, &transport_config).await?;
+    let client = crate::GamesClient::new(tarpc::client::Config::default(), transport).spawn()?;
+    let (mut since, mut page_token) = (None, None);
+    loop {
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(60);
+        let changes = match client.subscribe(ctx, since, page_token).await {
+            Ok(changes) => changes,
+            // Nothing changed for a while.
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        since = Some(changes.version);
+        page_token = changes.next_page_token;
+        changes.apply(&mut listing.lock().unwrap().games);
+    }
+}
+
+fn health(info: &GameInfo) -> String {
+    let health = match info.health {
+        Health::Unknown => String::from("unknown"),
+        Health::Healthy => String::from("healthy"),
+        Health::Failing {
+            successive_failures,
+        } => format!("failing ({})", successive_failures),
+    };
+    match info.reachability {
+        Reachability::Unreachable => health + ", unreachable",
+        Reachability::Unknown | Reachability::Reachable => health,
+    }
+}
+
+fn draw(
+    frame: &mut Frame<CrosstermBackend<Stdout>>,
+    games: &[(SocketAddr, GameInfo)],
+    sort_by: SortBy,
+    notice: Option<&str>,
+    selected: &mut TableState,
+) {
+    let mut title = format!(
+        "games by {} (s to sort, enter to join, q to quit)",
+        sort_by.name()
+    );
+    if let Some(notice) = notice {
+        title += &format!(": {}", notice);
+    }
+    let header = Row::new(vec![
+        "Address", "Name", "Region", "Players", "Health", "Tags",
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = games.iter().map(|(addr, info)| {
+        let mut name = info.name.clone();
+        if info.locked {
+            name += " (locked)";
+        }
+        let players = match &info.status {
+            Some(status) => format!("{}/{}", status.players, status.max_players),
+            None => String::from("?"),
+        };
+        Row::new(vec![
+            addr.to_string(),
+            name,
+            info.region.clone().unwrap_or_default(),
+            players,
+            health(info),
+            info.tags.join(", "),
+        ])
+    });
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .widths(&COLUMN_WIDTHS)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, frame.size(), selected);
+}
+
+fn enter_terminal() -> io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    terminal::enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, terminal::EnterAlternateScreen)?;
+    Terminal::new(CrosstermBackend::new(stdout))
+}
+
+fn leave_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> io::Result<()> {
+    terminal::disable_raw_mode()?;
+    execute!(terminal.backend_mut(), terminal::LeaveAlternateScreen)?;
+    terminal.show_cursor()
+}
+
+fn browse(
+    listing: &Mutex<Listing>,
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    client: &dyn Fn(SocketAddr) -> Command,
+) -> io::Result<()> {
+    let mut sort_by = SortBy::Address;
+    let mut selected = TableState::default();
+    // Why the latest game launched couldn't be played.
+    let mut launch_error = None;
+
+    loop {
+        let (mut games, listing_error) = {
+            let listing = listing.lock().unwrap();
+            let games: Vec<_> = listing.games.clone().into_iter().collect();
+            (games, listing.error.clone())
+        };
+        sort_by.sort(&mut games);
+        selected.select(match selected.selected() {
+            _ if games.is_empty() => None,
+            Some(i) => Some(i.min(games.len() - 1)),
+            None => Some(0),
+        });
+        // Errors matter more than the selected game's message of the day.
+        let motd = selected.selected().and_then(|i| games[i].1.motd.as_deref());
+        let notice = listing_error
+            .as_deref()
+            .or(launch_error.as_deref())
+            .or(motd);
+        terminal.draw(|frame| draw(frame, &games, sort_by, notice, &mut selected))?;
+
+        if !event::poll(REFRESH_INTERVAL)? {
+            continue;
+        }
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Char('s') => sort_by = sort_by.next(),
+            KeyCode::Up | KeyCode::Char('k') => {
+                selected.select(selected.selected().map(|i| i.saturating_sub(1)));
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                selected.select(selected.selected().map(|i| i + 1));
+            }
+            KeyCode::Enter => {
+                let addr = match selected.selected() {
+                    Some(i) => games[i].0,
+                    None => continue,
+                };
+                // Give the terminal to the client until it exits.
+                leave_terminal(terminal)?;
+                let played = client(addr).status();
+                *terminal = enter_terminal()?;
+                launch_error = match played {
+                    Ok(status) if status.success() => None,
+                    Ok(status) => Some(format!("client for {} failed: {}", addr, status)),
+                    Err(e) => Some(format!("couldn't start client for {}: {}", addr, e)),
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Browses the games registered with the game list at `game_list_addr` until the player quits.
+/// Games the player picks are joined by running the command `client` returns for their address.
+pub fn run(
+    game_list_addr: SocketAddr,
+    transport_config: transport::Config,
+    client: &dyn Fn(SocketAddr) -> Command,
+) -> io::Result<()> {
+    let listing = Arc::new(Mutex::new(Listing::default()));
+    let following = listing.clone();
+    thread::spawn(move || {
+        let followed =
+            Runtime::new()
+                .unwrap()
+                .block_on(follow(game_list_addr, transport_config, &following));
+        if let Err(e) = followed {
+            error!("Stopped following game list: {:?}", e);
+            following.lock().unwrap().error = Some(format!("lost the game list: {}", e));
+        }
+    });
+
+    let mut terminal = enter_terminal()?;
+    let result = browse(&listing, &mut terminal, client);
+    // Put the terminal back the way it was even if browsing failed.
+    leave_terminal(&mut terminal)?;
+    result
+}
@@ -0,0 +1,193 @@
+//! A camera that follows the player without jerking the view around every time their position is
+//! corrected by the server.
+
+use crate::game::{EntityId, Game, GameInt, Point};
+use std::time::Duration;
+
+/// The least the camera's half-life and deadzone are for players who asked for reduced motion.
+const REDUCED_MOTION_HALF_LIFE: Duration = Duration::from_millis(300);
+const REDUCED_MOTION_DEADZONE: GameInt = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// How long the camera takes to close half the distance to where it should be. Zero keeps it
+    /// there every frame.
+    pub half_life: Duration,
+    /// The size of the box in the middle of the view, as a fraction of the view, that the player
+    /// can move around in without the camera panning. Zero keeps the player centered.
+    pub deadzone: GameInt,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            half_life: Duration::from_millis(100),
+            deadzone: 0.25,
+        }
+    }
+}
+
+impl Settings {
+    /// These settings, with the camera panning less often and more gently, for players sensitive
+    /// to motion.
+    pub fn with_reduced_motion(self) -> Self {
+        Settings {
+            half_life: self.half_life.max(REDUCED_MOTION_HALF_LIFE),
+            deadzone: self.deadzone.max(REDUCED_MOTION_DEADZONE),
+        }
+    }
+}
+
+/// `a - b` along an axis of length `size` that wraps around, taking the shorter way around.
+fn wrapped_difference(a: GameInt, b: GameInt, size: GameInt) -> GameInt {
+    let difference = (a - b) % size;
+    if difference > size / 2. {
+        difference - size
+    } else if difference < -size / 2. {
+        difference + size
+    } else {
+        difference
+    }
+}
+
+/// How far the camera needs to pan along an axis to bring a player `offset` from the center of
+/// the view back inside a deadzone reaching `reach` from the center.
+fn overshoot(offset: GameInt, reach: GameInt) -> GameInt {
+    if offset > reach {
+        offset - reach
+    } else if offset < -reach {
+        offset + reach
+    } else {
+        0.
+    }
+}
+
+/// Where the center of the view is. Starts on the player the first time it follows them.
+#[derive(Debug)]
+pub struct Camera {
+    settings: Settings,
+    center: Option<Point>,
+}
+
+impl Camera {
+    pub fn new(settings: Settings) -> Self {
+        Camera {
+            settings,
+            center: None,
+        }
+    }
+
+    /// Changes how the camera moves from now on, keeping it where it is.
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    /// Moves the camera `dt` closer to entity `pov_id` in a view of `view_size`, returning the
+    /// new center of the view. The camera stays put while the entity is gone, like after the
+    /// player died.
+    pub fn follow(
+        &mut self,
+        game: &Game,
+        pov_id: EntityId,
+        view_size: Point,
+        dt: Duration,
+    ) -> Point {
+        if !game.contains(pov_id) {
+            return self.center.unwrap_or_default();
+        }
+        let position = game.entity(pov_id).position;
+        let target = Point::new(
+            (position.top_left.x + position.width / 2.) % game.width(),
+            (position.top_left.y + position.height / 2.) % game.height(),
+        );
+        self.pan(
+            target,
+            Point::new(game.width(), game.height()),
+            view_size,
+            dt,
+        )
+    }
+
+    /// Moves the camera `dt` closer to `target`, in a game of `game_size`.
+    fn pan(&mut self, target: Point, game_size: Point, view_size: Point, dt: Duration) -> Point {
+        let center = match self.center {
+            Some(center) => center,
+            None => {
+                self.center = Some(target);
+                return target;
+            }
+        };
+
+        let reach = view_size * (self.settings.deadzone / 2.);
+        let pan = Point::new(
+            overshoot(wrapped_difference(target.x, center.x, game_size.x), reach.x),
+            overshoot(wrapped_difference(target.y, center.y, game_size.y), reach.y),
+        );
+        let half_life = self.settings.half_life.as_secs_f32();
+        let fraction = if half_life > 0. {
+            1. - 0.5f32.powf(dt.as_secs_f32() / half_life)
+        } else {
+            1.
+        };
+        let center = Point::new(
+            (center.x + pan.x * fraction + game_size.x) % game_size.x,
+            (center.y + pan.y * fraction + game_size.y) % game_size.y,
+        );
+        self.center = Some(center);
+        center
+    }
+}
+
+#[test]
+fn camera_pans_only_outside_deadzone() {
+    let game_size = Point::new(1000., 1000.);
+    let view_size = Point::new(200., 200.);
+    let no_time = Duration::from_secs(0);
+    let mut camera = Camera::new(Settings {
+        half_life: no_time,
+        deadzone: 0.5,
+    });
+    let start = Point::new(990., 500.);
+    assert_eq!(camera.pan(start, game_size, view_size, no_time), start);
+
+    // Within 50 units of the center, the camera stays put.
+    let target = Point::new(30., 500.);
+    assert_eq!(camera.pan(target, game_size, view_size, no_time), start);
+
+    // Past that, the camera keeps the target at the edge of the deadzone, wrapping around.
+    let target = Point::new(60., 500.);
+    assert_eq!(
+        camera.pan(target, game_size, view_size, no_time),
+        Point::new(10., 500.)
+    );
+}
+
+#[test]
+fn camera_smooths_panning() {
+    let game_size = Point::new(1000., 1000.);
+    let view_size = Point::new(200., 200.);
+    let mut camera = Camera::new(Settings {
+        half_life: Duration::from_secs(1),
+        deadzone: 0.,
+    });
+    let start = Point::new(500., 500.);
+    camera.pan(start, game_size, view_size, Duration::from_secs(0));
+
+    let target = Point::new(500., 400.);
+    let center = camera.pan(target, game_size, view_size, Duration::from_secs(1));
+    assert!((center.y - 450.).abs() < 1e-3);
+    let center = camera.pan(target, game_size, view_size, Duration::from_secs(1));
+    assert!((center.y - 425.).abs() < 1e-3);
+}
+
+#[test]
+fn reduced_motion_only_stiffens_the_camera() {
+    let reduced = Settings::default().with_reduced_motion();
+    assert_eq!(reduced.half_life, REDUCED_MOTION_HALF_LIFE);
+    assert_eq!(reduced.deadzone, REDUCED_MOTION_DEADZONE);
+    let stiff = Settings {
+        half_life: Duration::from_secs(1),
+        deadzone: 0.8,
+    };
+    assert_eq!(stiff.with_reduced_motion(), stiff);
+}
@@ -0,0 +1,158 @@
+//! Chatting in game: the box players type messages into, and the recent messages shown over the
+//! game.
+
+use crate::{
+    client::UPDATES_PER_SECOND,
+    game::{Event, Game, GameInt},
+    server::Moderation,
+};
+
+/// The most messages shown at once.
+pub const MAX_MESSAGES_SHOWN: usize = 5;
+/// How long messages are shown for, including fading out.
+const DISPLAY_TICKS: u64 = 10 * UPDATES_PER_SECOND;
+/// How long messages take to fade out at the end of being shown.
+const FADE_TICKS: u64 = 2 * UPDATES_PER_SECOND;
+
+/// A recent chat message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// Who said what.
+    pub text: String,
+    /// From 1 while the message is fresh, down to 0 once it has faded out.
+    pub opacity: GameInt,
+}
+
+/// The latest messages, and announcements from the server's operator, still being shown in
+/// `game`, oldest first.
+pub fn recent(game: &Game) -> Vec<Message> {
+    let mut messages: Vec<_> = game
+        .events()
+        .filter_map(|logged| {
+            let text = match &logged.event {
+                Event::Chat(id, message) => match game.name(*id) {
+                    Some(name) => format!("{}: {}", name, message),
+                    None => format!("player {}: {}", id, message),
+                },
+                Event::Announcement(message) => format!("server: {}", message),
+                _ => return None,
+            };
+            let age = game.ticks().saturating_sub(logged.tick);
+            if age >= DISPLAY_TICKS {
+                return None;
+            }
+            let left = DISPLAY_TICKS - age;
+            Some(Message {
+                text,
+                opacity: (left as GameInt / FADE_TICKS as GameInt).min(1.),
+            })
+        })
+        .collect();
+    let shown = messages.len().saturating_sub(MAX_MESSAGES_SHOWN);
+    messages.drain(..shown);
+    messages
+}
+
+/// The moderation a chat message asks for, if it's a command: `/mute`, `/unmute` or `/kick` and
+/// the id of a player's entity, as the debug overlay shows them, or `/pause` or `/resume`. Other
+/// messages starting with `/` are mistyped commands, rather than chat.
+pub fn command(message: &str) -> Option<Result<Moderation, String>> {
+    let message = message.trim();
+    if !message.starts_with('/') {
+        return None;
+    }
+    let mut words = message[1..].split_whitespace();
+    let (name, id) = (words.next().unwrap_or_default(), words.next());
+    if words.next().is_some() {
+        return Some(Err(format!("too much after /{}", name)));
+    }
+    let entity_id = || match id.map(str::parse) {
+        Some(Ok(id)) => Ok(id),
+        _ => Err(format!("expected /{} <entity id>, like /{} 3", name, name)),
+    };
+    Some(match (name, id) {
+        ("mute", _) => entity_id().map(Moderation::Mute),
+        ("unmute", _) => entity_id().map(Moderation::Unmute),
+        ("kick", _) => entity_id().map(Moderation::Kick),
+        ("pause", None) => Ok(Moderation::Pause),
+        ("resume", None) => Ok(Moderation::Resume),
+        _ => Err(format!(
+            "unknown command {:?}; moderators can /mute, /unmute, /kick, /pause and /resume",
+            message
+        )),
+    })
+}
+
+/// The box players type chat messages into. While it's open, keys type into it instead of moving
+/// the player.
+#[derive(Debug, Default)]
+pub struct ChatBox {
+    /// What's been typed so far, if the box is open.
+    text: Option<String>,
+}
+
+impl ChatBox {
+    pub fn open(&mut self) {
+        self.text.get_or_insert_with(String::new);
+    }
+
+    /// What's been typed so far, if the box is open.
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// Types `typed` into the box, if it's open. Control characters, like newlines, are left out.
+    pub fn type_text(&mut self, typed: &str) {
+        if let Some(text) = &mut self.text {
+            text.extend(typed.chars().filter(|c| !c.is_control()));
+        }
+    }
+
+    /// Deletes the last character typed.
+    pub fn backspace(&mut self) {
+        if let Some(text) = &mut self.text {
+            text.pop();
+        }
+    }
+
+    /// Closes the box, returning the message typed to send, unless nothing was typed.
+    pub fn submit(&mut self) -> Option<String> {
+        self.text.take().filter(|text| !text.trim().is_empty())
+    }
+
+    /// Closes the box without sending anything.
+    pub fn cancel(&mut self) {
+        self.text = None;
+    }
+}
+
+#[test]
+fn chat_box_sends_what_was_typed() {
+    let mut chat_box = ChatBox::default();
+    chat_box.type_text("ignored");
+    assert_eq!(chat_box.text(), None);
+
+    chat_box.open();
+    chat_box.type_text("hi\r");
+    chat_box.type_text("!?");
+    chat_box.backspace();
+    assert_eq!(chat_box.text(), Some("hi!"));
+    assert_eq!(chat_box.submit(), Some(String::from("hi!")));
+    assert_eq!(chat_box.text(), None);
+
+    chat_box.open();
+    chat_box.type_text("  ");
+    assert_eq!(chat_box.submit(), None);
+}
+
+#[test]
+fn moderation_commands_parse() {
+    assert_eq!(command("gg"), None);
+    assert_eq!(command(" /kick 3 "), Some(Ok(Moderation::Kick(3))));
+    assert_eq!(command("/mute 4"), Some(Ok(Moderation::Mute(4))));
+    assert_eq!(command("/pause"), Some(Ok(Moderation::Pause)));
+    assert!(command("/kick").unwrap().is_err());
+    assert!(command("/kick someone").unwrap().is_err());
+    assert!(command("/pause 3").unwrap().is_err());
+    assert!(command("/shrug").unwrap().is_err());
+}
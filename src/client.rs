@@ -1,74 +1,497 @@
-use crate::game::{self, EntityId};
-use futures::{channel::mpsc, prelude::*};
-use log::{debug, error, info};
-use piston_window::{
-    clear, Button, ButtonArgs, ButtonState, Event, EventLoop, EventSettings, Events, Input, Key,
-    Loop, OpenGL, PistonWindow, WindowSettings,
+use crate::{
+    camera, chat,
+    clock::ClockSync,
+    datagram::{self, ClientDatagram, ServerDatagram},
+    diagnostics,
+    error::Error,
+    game::{self, EntityId},
+    hud::Hud,
+    palette::Style,
+    server::{JoinError, KickReason, Moderation},
+    transport,
 };
+use futures::{channel::mpsc, prelude::*};
+use log::{debug, error, info, warn};
 use std::{
-    convert::TryFrom,
-    io,
+    collections::VecDeque,
+    fmt, io,
     net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
     sync::{Arc, Condvar, Mutex},
     thread,
     time::{Duration, Instant, SystemTime},
 };
 use tarpc::client::{self, NewClient};
 use tarpc::context;
-use tokio::runtime::Runtime;
-use tokio_serde::formats::Json;
+use tokio::{
+    net::{
+        udp::{RecvHalf, SendHalf},
+        UdpSocket,
+    },
+    runtime::Runtime,
+    time,
+};
+
+pub(crate) const UPDATES_PER_SECOND: u64 = 200;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before reconnecting after losing the server. Each failed attempt in a row
+/// doubles the wait, up to [`MAX_RECONNECT_BACKOFF`].
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// How long the game's message of the day is shown for after joining.
+const BANNER_DISPLAY_TIME: Duration = Duration::from_secs(10);
+/// Round trips at least this long, and at least twice the recent average, are logged.
+const RTT_SPIKE_THRESHOLD: Duration = Duration::from_millis(100);
+/// How often to send a datagram when there are no new inputs, to acknowledge game states and
+/// resend lost inputs.
+const DATAGRAM_RESEND_INTERVAL: Duration = Duration::from_millis(50);
+/// How many of the latest game states received are kept for diagnostics bundles.
+const DIAGNOSTIC_SNAPSHOTS: usize = 20;
+
+/// Client settings.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How far behind the latest server state remote entities are rendered, so that they can be
+    /// interpolated between the two states around that time. Zero disables interpolation.
+    pub interpolation_delay: Duration,
+    /// Whether to receive game states and send inputs over UDP instead of RPCs.
+    pub datagrams: bool,
+    /// The token to join games that require one with.
+    pub join_token: Option<String>,
+    /// The password to join games that require one with.
+    pub password: Option<String>,
+    /// The key to moderate games with, for players the server's operator made moderators. See
+    /// [`chat::command`].
+    pub moderator_key: Option<String>,
+    /// A session to resume, from [`Connection::session_id`], to rejoin as the same player after
+    /// being disconnected.
+    pub resume_session: Option<u64>,
+    /// The name shown above the player, if not resuming a session.
+    pub name: Option<String>,
+    /// How the view follows the player.
+    pub camera: camera::Settings,
+    /// A TrueType font to draw text in the window with, instead of the bundled one.
+    pub font: Option<PathBuf>,
+    /// How the window is shown.
+    pub display: Display,
+    /// How entities are drawn.
+    pub style: Style,
+    /// The color of the player's square, each channel from 0 to 1. Without one, the server picks
+    /// a color at random.
+    pub color: Option<[game::GameInt; 3]>,
+    /// A file to record the games played to, to watch later with
+    /// [`crate::window::run_replay`].
+    pub record: Option<PathBuf>,
+    /// Where F12 saves screenshots, and F9 saves diagnostics bundles, to.
+    pub screenshot_dir: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            interpolation_delay: Duration::from_millis(100),
+            datagrams: false,
+            join_token: None,
+            password: None,
+            moderator_key: None,
+            resume_session: None,
+            name: None,
+            camera: camera::Settings::default(),
+            font: None,
+            display: Display::default(),
+            style: Style::default(),
+            color: None,
+            record: None,
+            screenshot_dir: PathBuf::from("."),
+        }
+    }
+}
+
+impl Settings {
+    /// How the view follows the player, with reduced motion if they asked for it.
+    pub(crate) fn camera_settings(&self) -> camera::Settings {
+        if self.style.reduced_motion {
+            self.camera.with_reduced_motion()
+        } else {
+            self.camera
+        }
+    }
+}
+
+/// The size of a window, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    pub(crate) fn size(self) -> [f64; 2] {
+        [f64::from(self.width), f64::from(self.height)]
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Resolution, String> {
+        let invalid = || format!("expected WIDTHxHEIGHT, like 800x600, got {}", s);
+        let mut parts = s.splitn(2, 'x');
+        let mut dimension = || -> Result<u32, String> {
+            match parts.next().map(str::parse) {
+                Some(Ok(dimension)) if dimension > 0 => Ok(dimension),
+                _ => Err(invalid()),
+            }
+        };
+        Ok(Resolution {
+            width: dimension()?,
+            height: dimension()?,
+        })
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// How the window is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Display {
+    /// The size of the window, when it isn't fullscreen.
+    pub resolution: Resolution,
+    pub fullscreen: bool,
+    /// Whether to wait for the screen to refresh before showing each frame.
+    pub vsync: bool,
+    /// The most frames to render per second.
+    pub max_fps: u64,
+    /// Whether to only render when there's input, instead of continuously. Other players and the
+    /// server's updates aren't shown until then.
+    pub lazy: bool,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display {
+            resolution: Resolution {
+                width: 512,
+                height: 512,
+            },
+            fullscreen: false,
+            vsync: false,
+            max_fps: 60,
+            lazy: false,
+        }
+    }
+}
+
+/// The most recent game states received from the server, and when they were received.
+struct Snapshots {
+    delay: Duration,
+    snapshots: VecDeque<(Instant, Box<game::Game>)>,
+}
+
+impl Snapshots {
+    fn new(delay: Duration) -> Self {
+        Snapshots {
+            delay,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, game: Box<game::Game>) {
+        let now = Instant::now();
+        self.snapshots.push_back((now, game));
+        // Only the newest snapshot from before the render time is needed to interpolate from,
+        // but a few more are kept for diagnostics.
+        while self.snapshots.len() > DIAGNOSTIC_SNAPSHOTS.max(2)
+            && self.snapshots[1].0 + self.delay <= now
+        {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Moves the remote entities in `game` to where they were `delay` ago.
+    fn interpolate(&self, game: &mut game::Game, pov_id: EntityId) {
+        if self.delay == Duration::from_secs(0) || self.snapshots.is_empty() {
+            return;
+        }
+        let render_time = match Instant::now().checked_sub(self.delay) {
+            Some(render_time) => render_time,
+            None => return,
+        };
+        let newer = self
+            .snapshots
+            .iter()
+            .position(|(received, _)| *received > render_time);
+        let (from, to, alpha) = match newer {
+            Some(0) => (&self.snapshots[0], &self.snapshots[0], 0.),
+            Some(i) => {
+                let (from, to) = (&self.snapshots[i - 1], &self.snapshots[i]);
+                let alpha = (render_time - from.0).as_secs_f32() / (to.0 - from.0).as_secs_f32();
+                (from, to, alpha)
+            }
+            None => {
+                let last = self.snapshots.back().unwrap();
+                (last, last, 1.)
+            }
+        };
+        game.interpolate(&from.1, &to.1, alpha, Some(pov_id));
+    }
+
+    /// The latest game states received, and how long ago each was received.
+    fn recent(&self) -> Vec<(Duration, Box<game::Game>)> {
+        self.snapshots
+            .iter()
+            .map(|(received, game)| (received.elapsed(), game.clone()))
+            .collect()
+    }
+
+    /// How long ago the latest game state was received.
+    fn age(&self) -> Option<Duration> {
+        self.snapshots
+            .back()
+            .map(|(received, _)| received.elapsed())
+    }
+}
+
+/// The player's entity and session in the game they joined.
+#[derive(Clone, Copy, Debug)]
+struct Joined {
+    entity_id: EntityId,
+    session_id: u64,
+}
+
+/// Whether the client is connected to the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// The connection was lost, and the client is trying to get it back.
+    Reconnecting {
+        /// Why the connection was lost, or why the latest attempt to get it back failed.
+        error: String,
+        /// How many attempts to reconnect have failed in a row.
+        failures: u32,
+        next_attempt: Instant,
+    },
+    /// The player was kicked, so the client won't reconnect.
+    Kicked(KickReason),
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionStatus::Connected => write!(f, "connected"),
+            ConnectionStatus::Reconnecting {
+                error,
+                next_attempt,
+                ..
+            } => {
+                let wait = next_attempt.saturating_duration_since(Instant::now());
+                write!(
+                    f,
+                    "connection lost ({}), reconnecting in {}s",
+                    error,
+                    wait.as_secs() + 1
+                )
+            }
+            ConnectionStatus::Kicked(reason) => write!(f, "disconnected: {}", reason),
+        }
+    }
+}
+
+/// The state a `Connection` shares with the tasks servicing it.
+#[derive(Clone)]
+struct Shared {
+    /// The latest state from the server, ticked locally between updates.
+    game: Arc<Mutex<Box<game::Game>>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    clock: Arc<Mutex<ClockSync>>,
+    /// The game's message of the day, set when the client joins.
+    motd: Arc<Mutex<Option<String>>>,
+    /// The latest game joined. Reconnecting can change the player's entity and session, if the
+    /// server no longer had the old session.
+    joined: Arc<Mutex<Option<Joined>>>,
+    status: Arc<Mutex<ConnectionStatus>>,
+    /// The color the player picked for their square, if they did.
+    color: Arc<Mutex<Option<[game::GameInt; 3]>>>,
+}
+
+impl Shared {
+    fn new(settings: &Settings) -> Self {
+        Shared {
+            game: Arc::new(Mutex::new(Box::new(game::Game::default()))),
+            snapshots: Arc::new(Mutex::new(Snapshots::new(settings.interpolation_delay))),
+            clock: Arc::new(Mutex::new(ClockSync::default())),
+            motd: Arc::new(Mutex::new(None)),
+            joined: Arc::new(Mutex::new(None)),
+            status: Arc::new(Mutex::new(ConnectionStatus::Connected)),
+            color: Arc::new(Mutex::new(settings.color)),
+        }
+    }
+}
+
+/// Where the tasks servicing a `Connection` report joining the game, or failing to.
+#[derive(Clone)]
+struct Started {
+    /// Set once the client has first joined the game, or failed to.
+    first: Arc<(Mutex<Option<Result<Joined, Error>>>, Condvar)>,
+    joined: Arc<Mutex<Option<Joined>>>,
+    status: Arc<Mutex<ConnectionStatus>>,
+}
 
-const UPDATES_PER_SECOND: u64 = 200;
+/// Records the game joined, and wakes the main thread if it's waiting for the game to start.
+fn notify_started(started: &Started, result: Result<Joined, Error>) {
+    if let Ok(joined) = &result {
+        *started.joined.lock().unwrap() = Some(*joined);
+        *started.status.lock().unwrap() = ConnectionStatus::Connected;
+    }
+    let (lock, cvar) = &*started.first;
+    let mut first = lock.lock().unwrap();
+    if first.is_none() {
+        *first = Some(result);
+        cvar.notify_one();
+    }
+}
 
 /// A task that pushes player inputs to the server.
-struct InputPusher {
+struct InputPusher<'a> {
     client: crate::GameClient,
-    inputs: mpsc::UnboundedReceiver<game::Input>,
+    /// Inputs from the main thread, with the tick of the game the player saw when making them.
+    inputs: &'a mut mpsc::UnboundedReceiver<(u64, game::Input)>,
+    /// The sequence of the last input received from the main thread.
+    sequence: u64,
+    /// Inputs the server hasn't acknowledged yet with their sequence and tick, oldest first.
+    unacked: VecDeque<(u64, u64, game::Input)>,
 }
 
+/// How long requests get to come back, besides however long the server is expected to wait
+/// before answering.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(150);
+
 fn new_context() -> context::Context {
+    context_with_timeout(REQUEST_TIMEOUT)
+}
+
+fn context_with_timeout(timeout: Duration) -> context::Context {
     let mut ctx = context::current();
-    ctx.deadline = SystemTime::now() + Duration::from_millis(150);
+    ctx.deadline = SystemTime::now() + timeout;
     ctx
 }
 
-impl InputPusher {
+impl<'a> InputPusher<'a> {
+    fn new(
+        client: crate::GameClient,
+        inputs: &'a mut mpsc::UnboundedReceiver<(u64, game::Input)>,
+    ) -> Self {
+        InputPusher {
+            client,
+            inputs,
+            sequence: 0,
+            unacked: VecDeque::new(),
+        }
+    }
+
     async fn run(mut self) {
-        while let Some(input) = self.inputs.next().await {
-            debug!("push_input({:?})", input);
-            if let Err(err) = self.client.push_input(new_context(), input).await {
-                error!("Error setting keys, {:?}: {:?}", input, err);
+        while let Some((tick, input)) = self.inputs.next().await {
+            self.sequence += 1;
+            self.unacked.push_back((self.sequence, tick, input));
+            if let Err(err) = self.push_unacked().await {
+                error!("Giving up pushing inputs: {:?}", err);
+                return;
             }
         }
     }
+
+    /// Sends every unacknowledged input, in order, retrying failures a few times. The server
+    /// drops inputs it has already applied, so resending is safe.
+    async fn push_unacked(&mut self) -> io::Result<()> {
+        const MAX_ATTEMPTS: u32 = 5;
+        const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+        let mut failures = 0;
+        while let Some(&(sequence, tick, input)) = self.unacked.front() {
+            debug!("push_input({}, {}, {:?})", sequence, tick, input);
+            match self
+                .client
+                .push_input(new_context(), sequence, tick, input)
+                .await
+            {
+                Ok(acked) if acked < sequence => {
+                    // The server dropped the input because too many were pushed at once.
+                    debug!("Input {} dropped by the server, retrying", sequence);
+                    time::delay_for(RETRY_DELAY).await;
+                }
+                Ok(acked) => {
+                    failures = 0;
+                    while let Some(&(sequence, _, _)) = self.unacked.front() {
+                        if sequence > acked {
+                            break;
+                        }
+                        self.unacked.pop_front();
+                    }
+                }
+                Err(err) => {
+                    failures += 1;
+                    warn!("Error pushing input {}, {:?}: {:?}", sequence, input, err);
+                    if failures >= MAX_ATTEMPTS {
+                        return Err(err);
+                    }
+                    time::delay_for(RETRY_DELAY).await;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// A task that repeatedly polls game state and updates the main thread's game state.
 /// Also is responsible for waking the main thread after the first poll.
 struct StatePoller {
     client: crate::GameClient,
+    session_id: u64,
+    /// How long the server goes between publishing states, which polls wait for.
+    broadcast_interval: Duration,
     game: Arc<Mutex<Box<game::Game>>>,
-    client_id: Arc<(Mutex<Option<EntityId>>, Condvar)>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    started: Started,
 }
 
 impl StatePoller {
     async fn run(self) {
-        let game_state = self.client.poll_game_state(context::current());
+        let game_state = self.client.poll_game_state(context::current(), None);
         let client_id = self.client.get_entity_id(context::current());
 
+        // The latest state received from the server, which deltas are applied to. Kept separate
+        // from the main thread's game, which is ticked locally between polls.
+        let mut server_game = Box::new(game::Game::default());
+
         info!("Getting initial game state:");
         match future::join(game_state, client_id).await {
-            (Ok(game_state), Ok(client_id)) => {
+            (Ok(update), Ok(client_id)) => {
+                if let Err(e) = server_game.apply_update(update) {
+                    error!("Could not initialize client: {}", e);
+                    notify_started(&self.started, Err(e.into()));
+                    return;
+                }
                 // First poll notifies the main thread.
-                *self.game.lock().unwrap() = game_state;
+                *self.game.lock().unwrap() = server_game.clone();
+                self.snapshots.lock().unwrap().push(server_game.clone());
 
                 // Let the main thread know we've started.
-                let (lock, cvar) = &*self.client_id;
-                *lock.lock().unwrap() = Some(client_id);
-                cvar.notify_one();
+                let joined = Joined {
+                    entity_id: client_id,
+                    session_id: self.session_id,
+                };
+                notify_started(&self.started, Ok(joined));
             }
             (Err(e), _) | (_, Err(e)) => {
                 error!("Could not initialize client: {}", e);
+                notify_started(&self.started, Err(e.into()));
                 return;
             }
         }
@@ -76,8 +499,26 @@ impl StatePoller {
         loop {
             let now = Instant::now();
 
-            match self.client.poll_game_state(new_context()).await {
-                Ok(new_game) => *self.game.lock().unwrap() = new_game,
+            match self
+                .client
+                .poll_game_state(
+                    context_with_timeout(self.broadcast_interval + REQUEST_TIMEOUT),
+                    Some(server_game.ticks()),
+                )
+                .await
+            {
+                Ok(update) => match server_game.apply_update(update) {
+                    Ok(()) => {
+                        *self.game.lock().unwrap() = server_game.clone();
+                        self.snapshots.lock().unwrap().push(server_game.clone());
+                        if let Some(reason) = server_game.closing_reason() {
+                            warn!("Server closing: {}", reason);
+                            break;
+                        }
+                    }
+                    // The next poll will fall back to a full state.
+                    Err(e) => warn!("Dropping game state delta: {:?}", e),
+                },
                 Err(e) => {
                     error!("Failed to poll game state: {}", e);
                     break;
@@ -93,159 +534,718 @@ impl StatePoller {
     }
 }
 
+/// Something the main thread asks the server to do for the player, besides inputs.
+#[derive(Debug)]
+enum Request {
+    Chat(String),
+    SetColor([game::GameInt; 3]),
+    /// Moderates the game with a moderator's key.
+    Moderate(String, Moderation),
+}
+
+/// Sends requests from the main thread to the server, in order.
+async fn send_requests(client: crate::GameClient, requests: &mut mpsc::UnboundedReceiver<Request>) {
+    while let Some(request) = requests.next().await {
+        match request {
+            Request::Chat(message) => match client.chat(context::current(), message).await {
+                Ok(true) => {}
+                Ok(false) => warn!("The server dropped a chat message"),
+                Err(e) => warn!("Failed to send chat message: {}", e),
+            },
+            Request::SetColor(rgb) => match client.set_color(context::current(), rgb).await {
+                Ok(true) => {}
+                Ok(false) => warn!("The server didn't change the player's color"),
+                Err(e) => warn!("Failed to change the player's color: {}", e),
+            },
+            Request::Moderate(key, action) => {
+                match client.moderate(context::current(), key, action).await {
+                    Ok(Ok(())) => info!("Moderated the game: {:?}", action),
+                    Ok(Err(e)) => warn!("Couldn't moderate the game: {}", e),
+                    Err(e) => warn!("Failed to moderate the game: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// A task that periodically samples the server's clock.
+struct ClockSyncer {
+    client: crate::GameClient,
+    clock: Arc<Mutex<ClockSync>>,
+}
+
+impl ClockSyncer {
+    async fn run(self) {
+        loop {
+            let sent = SystemTime::now();
+            match self.client.ping(new_context()).await {
+                Ok(server_time) => {
+                    let mut clock = self.clock.lock().unwrap();
+                    clock.record(sent, SystemTime::now(), server_time);
+                    debug!(
+                        "Server at tick {:?}, clock offset {:?}s, rtt {:?}",
+                        clock.latest_tick(),
+                        clock.offset(),
+                        clock.rtt()
+                    );
+                    if let (Some(latest), Some(mean)) = (clock.latest_rtt(), clock.rtt()) {
+                        if latest >= RTT_SPIKE_THRESHOLD && latest >= mean * 2 {
+                            warn!("Ping took {:?}, {:?} on average", latest, mean);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to get server time: {}", e);
+                    break;
+                }
+            }
+            time::delay_for(CLOCK_SYNC_INTERVAL).await;
+        }
+    }
+}
+
+/// A task that receives game states and pushes inputs over a datagram channel, doing the work of
+/// both `StatePoller` and `InputPusher`.
+struct DatagramChannel<'a> {
+    client: crate::GameClient,
+    session_id: u64,
+    server_addr: SocketAddr,
+    game: Arc<Mutex<Box<game::Game>>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    started: Started,
+    inputs: &'a mut mpsc::UnboundedReceiver<(u64, game::Input)>,
+}
+
+impl DatagramChannel<'_> {
+    async fn run(self) {
+        let DatagramChannel {
+            client,
+            session_id,
+            server_addr,
+            game,
+            snapshots,
+            started,
+            inputs,
+        } = self;
+        let opened = async {
+            let (client_id, token) = future::try_join(
+                client.get_entity_id(context::current()),
+                client.open_datagram_channel(context::current()),
+            )
+            .await?;
+            let local_addr: SocketAddr = if server_addr.is_ipv4() {
+                ([0, 0, 0, 0u8], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let socket = UdpSocket::bind(local_addr).await?;
+            socket.connect(server_addr).await?;
+            Ok::<_, io::Error>((client_id, token, socket))
+        };
+        let (client_id, token, socket) = match opened.await {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!("Could not open datagram channel: {}", e);
+                notify_started(&started, Err(e.into()));
+                return;
+            }
+        };
+        info!("Opened datagram channel to {}", server_addr);
+
+        let (recv, send) = socket.split();
+        let outgoing = Arc::new(Mutex::new(ClientDatagram {
+            token,
+            number: 0,
+            ack: None,
+            inputs: vec![],
+            signature: Default::default(),
+        }));
+        let joined = Joined {
+            entity_id: client_id,
+            session_id,
+        };
+        let receive = receive_states(recv, outgoing.clone(), joined, game, snapshots, started);
+        let signer = datagram::Signer::new(session_id);
+        let send = send_inputs(send, outgoing, signer, inputs);
+        future::select(Box::pin(receive), Box::pin(send)).await;
+    }
+}
+
+/// Receives game states until the server goes quiet, and acknowledges them and the inputs applied
+/// in the next datagram sent.
+async fn receive_states(
+    mut socket: RecvHalf,
+    outgoing: Arc<Mutex<ClientDatagram>>,
+    joined: Joined,
+    game: Arc<Mutex<Box<game::Game>>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    started: Started,
+) {
+    let mut buf = vec![0; datagram::MAX_DATAGRAM_SIZE];
+    let mut recent = datagram::RecentStates::default();
+    loop {
+        let len = match time::timeout(CONNECT_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) => {
+                error!("Failed to receive game state: {}", e);
+                notify_started(&started, Err(e.into()));
+                return;
+            }
+            Err(_) => {
+                let e = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no game state received in {:?}", CONNECT_TIMEOUT),
+                );
+                error!("{}", e);
+                notify_started(&started, Err(e.into()));
+                return;
+            }
+        };
+        let datagram: ServerDatagram = match datagram::decode(&buf[..len]) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("Dropping bad datagram: {}", e);
+                continue;
+            }
+        };
+        let mut outgoing = outgoing.lock().unwrap();
+        outgoing
+            .inputs
+            .retain(|&(sequence, _, _)| sequence > datagram.input_ack);
+        if let Some(server_game) = recent.apply(datagram.update) {
+            outgoing.ack = Some(server_game.ticks());
+            let server_game = Box::new(server_game.clone());
+            *game.lock().unwrap() = server_game.clone();
+            if let Some(reason) = server_game.closing_reason() {
+                warn!("Server closing: {}", reason);
+                return;
+            }
+            snapshots.lock().unwrap().push(server_game);
+            notify_started(&started, Ok(joined));
+        }
+    }
+}
+
+/// Sends every unacknowledged input whenever there is a new one, and periodically otherwise.
+async fn send_inputs(
+    mut socket: SendHalf,
+    outgoing: Arc<Mutex<ClientDatagram>>,
+    signer: datagram::Signer,
+    inputs: &mut mpsc::UnboundedReceiver<(u64, game::Input)>,
+) {
+    let mut sequence = 0;
+    loop {
+        let bytes = {
+            let mut outgoing = outgoing.lock().unwrap();
+            outgoing.number += 1;
+            signer
+                .sign(&mut outgoing)
+                .and_then(|()| datagram::encode(&*outgoing))
+        };
+        let sent = match bytes {
+            Ok(bytes) => socket.send(&bytes).await.map(drop),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            error!("Giving up pushing inputs: {}", e);
+            return;
+        }
+        match time::timeout(DATAGRAM_RESEND_INTERVAL, inputs.next()).await {
+            Ok(Some((tick, input))) => {
+                sequence += 1;
+                outgoing
+                    .lock()
+                    .unwrap()
+                    .inputs
+                    .push((sequence, tick, input));
+            }
+            Ok(None) => return,
+            Err(_) => {}
+        }
+    }
+}
+
 async fn create_client(
     server_addr: SocketAddr,
+    transport_config: &transport::Config,
 ) -> io::Result<(crate::GameClient, impl Future<Output = ()>)> {
     info!("Creating client to {}", server_addr);
 
-    let transport = tarpc::serde_transport::tcp::connect(&server_addr, Json::default()).await?;
+    let transport = time::timeout(
+        CONNECT_TIMEOUT,
+        transport::connect(&server_addr, transport_config),
+    )
+    .await
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out connecting to {}", server_addr),
+        )
+    })??;
     let NewClient { client, dispatch } =
         crate::GameClient::new(client::Config::default(), transport);
     let dispatch = dispatch.unwrap_or_else(move |e| error!("Connection broken: {}", e));
     Ok((client, dispatch))
 }
 
+/// Joins the game and services the connection until it's lost, or the server closes.
 async fn run_tasks(
     server_addr: SocketAddr,
-    game: Arc<Mutex<Box<game::Game>>>,
-    client_id: Arc<(Mutex<Option<EntityId>>, Condvar)>,
-    inputs: mpsc::UnboundedReceiver<game::Input>,
-) -> io::Result<()> {
-    let (client, dispatch) = create_client(server_addr).await?;
-    let (r1, r2, r3) = future::join3(
-        tokio::spawn(dispatch),
-        tokio::spawn(
-            StatePoller {
+    transport_config: &transport::Config,
+    settings: &Settings,
+    shared: Shared,
+    started: Started,
+    inputs: &mut mpsc::UnboundedReceiver<(u64, game::Input)>,
+    requests: &mut mpsc::UnboundedReceiver<Request>,
+) -> Result<(), Error> {
+    let (client, dispatch) = create_client(server_addr, transport_config).await?;
+    // Stops once the tasks below are done with the client.
+    tokio::spawn(dispatch);
+    if let Some(join_token) = &settings.join_token {
+        if !client
+            .authenticate(context::current(), join_token.clone())
+            .await?
+        {
+            let e = io::Error::new(io::ErrorKind::PermissionDenied, "join token rejected");
+            return Err(e.into());
+        }
+    }
+    let welcome = client
+        .join(
+            context::current(),
+            settings.resume_session,
+            settings.name.clone(),
+            settings.password.clone(),
+        )
+        .await??;
+    let session_id = welcome.session_id;
+    info!("Joined with session {}", session_id);
+    let Shared {
+        game,
+        snapshots,
+        clock,
+        motd,
+        color,
+        ..
+    } = shared;
+    // Joining as a new player, instead of resuming a session, starts with a random color.
+    let rgb = *color.lock().unwrap();
+    if let Some(rgb) = rgb {
+        if !client.set_color(context::current(), rgb).await? {
+            warn!("The server didn't change the player's color");
+        }
+    }
+    *motd.lock().unwrap() = welcome.motd;
+    let updates: Pin<Box<dyn Future<Output = ()> + '_>> = if settings.datagrams {
+        Box::pin(
+            DatagramChannel {
                 client: client.clone(),
-                client_id,
-                game: game.clone(),
+                session_id,
+                server_addr,
+                game,
+                snapshots,
+                started,
+                inputs,
             }
             .run(),
-        ),
-        tokio::spawn(InputPusher { client, inputs }.run()),
-    )
+        )
+    } else {
+        let poller = StatePoller {
+            client: client.clone(),
+            session_id,
+            broadcast_interval: welcome.broadcast_interval,
+            started,
+            game,
+            snapshots,
+        };
+        let pusher = InputPusher::new(client.clone(), inputs);
+        Box::pin(future::select(Box::pin(poller.run()), Box::pin(pusher.run())).map(drop))
+    };
+    // Each of these only stops once the connection is lost, or the server closes.
+    future::select_all(vec![
+        updates,
+        Box::pin(send_requests(client.clone(), requests)),
+        Box::pin(ClockSyncer { client, clock }.run()),
+    ])
     .await;
-    r1.and(r2)
-        .and(r3)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-}
-
-impl TryFrom<(&ButtonState, &Key)> for game::Input {
-    type Error = game::InvalidKeyError;
-
-    fn try_from((state, key): (&ButtonState, &Key)) -> Result<game::Input, game::InvalidKeyError> {
-        use game::{Component, Input, Sign};
-        Ok(match (*state, *key) {
-            (ButtonState::Press, Key::W) => Input::Move(Component::Y, Some(Sign::Negative)),
-            (ButtonState::Press, Key::A) => Input::Move(Component::X, Some(Sign::Negative)),
-            (ButtonState::Press, Key::S) => Input::Move(Component::Y, Some(Sign::Positive)),
-            (ButtonState::Press, Key::D) => Input::Move(Component::X, Some(Sign::Positive)),
-            (ButtonState::Press, Key::Space) => Input::Shoot,
-            (ButtonState::Release, Key::W) => Input::Move(Component::Y, None),
-            (ButtonState::Release, Key::A) => Input::Move(Component::X, None),
-            (ButtonState::Release, Key::S) => Input::Move(Component::Y, None),
-            (ButtonState::Release, Key::D) => Input::Move(Component::X, None),
-            _ => return Err(game::InvalidKeyError),
-        })
-    }
+    Ok(())
 }
 
-pub fn run_ui(server_addr: SocketAddr) -> io::Result<()> {
-    let mut resolution = [512.; 2];
-    let mut window: PistonWindow = WindowSettings::new("shapes", resolution)
-        .exit_on_esc(true)
-        .graphics_api(OpenGL::V3_2)
-        .build()
-        .unwrap();
-    window.set_lazy(true);
-
-    info!("Connecting to server");
-    let game = Arc::new(Mutex::new(Box::new(game::Game::default())));
-    let client_id = Arc::new((Mutex::new(None), Condvar::new()));
-    let (inputs, rx) = mpsc::unbounded();
-
-    let game2 = game.clone();
-    let client_id2 = client_id.clone();
+/// Drops inputs that weren't sent before the connection was lost, which are stale by now. Returns
+/// false if the `Connection` was dropped, so there's nothing to reconnect for.
+fn discard_stale_inputs(inputs: &mut mpsc::UnboundedReceiver<(u64, game::Input)>) -> bool {
+    loop {
+        match inputs.try_next() {
+            Ok(Some(_)) => {}
+            Ok(None) => return false,
+            Err(_) => return true,
+        }
+    }
+}
 
-    thread::spawn(move || {
-        Runtime::new().unwrap().block_on(async move {
-            if let Err(e) = run_tasks(server_addr, game2, client_id2, rx).await {
+/// Services the connection, reconnecting with backoff whenever it's lost until the server
+/// closes or the `Connection` is dropped. Reconnecting resumes the player's session, or joins
+/// again if the server no longer has it.
+async fn stay_connected(
+    server_addr: SocketAddr,
+    transport_config: transport::Config,
+    mut settings: Settings,
+    shared: Shared,
+    started: Started,
+    mut inputs: mpsc::UnboundedReceiver<(u64, game::Input)>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) {
+    loop {
+        let result = run_tasks(
+            server_addr,
+            &transport_config,
+            &settings,
+            shared.clone(),
+            started.clone(),
+            &mut inputs,
+            &mut requests,
+        )
+        .await;
+        if !discard_stale_inputs(&mut inputs) {
+            return;
+        }
+        let joined = *shared.joined.lock().unwrap();
+        let joined = match joined {
+            Some(joined) => joined,
+            // Failing to join the first time is reported to the main thread instead.
+            None => {
+                let e = result.err().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::ConnectionAborted, "lost the server").into()
+                });
                 error!("{}", e);
-            };
-        });
-    });
+                notify_started(&started, Err(e));
+                return;
+            }
+        };
+        if let Err(Error::Join(JoinError::Kicked(reason))) = result {
+            info!("Not reconnecting, since the player was kicked: {}", reason);
+            *shared.status.lock().unwrap() = ConnectionStatus::Kicked(reason);
+            return;
+        }
+        if let Some(reason) = shared.game.lock().unwrap().closing_reason() {
+            info!("Not reconnecting, since the server closed: {}", reason);
+            return;
+        }
+        settings.resume_session = Some(joined.session_id);
 
-    // Wait for game state to be initialized.
-    let (lock, cvar) = &*client_id;
-    let mut client_id = lock.lock().unwrap();
-    let client_id = loop {
-        match *client_id {
-            Some(client_id) => break client_id,
-            None => client_id = cvar.wait(client_id).unwrap(),
+        let backoff = {
+            let mut status = shared.status.lock().unwrap();
+            let failures = match &*status {
+                ConnectionStatus::Connected | ConnectionStatus::Kicked(_) => 0,
+                ConnectionStatus::Reconnecting { failures, .. } => failures + 1,
+            };
+            let backoff = MIN_RECONNECT_BACKOFF * 2u32.pow(failures.min(5));
+            let backoff = backoff.min(MAX_RECONNECT_BACKOFF);
+            let error = match result {
+                Ok(()) => String::from("the server stopped responding"),
+                Err(e) => e.to_string(),
+            };
+            warn!("Reconnecting in {:?}: {}", backoff, error);
+            *status = ConnectionStatus::Reconnecting {
+                error,
+                failures,
+                next_attempt: Instant::now() + backoff,
+            };
+            backoff
+        };
+        time::delay_for(backoff).await;
+        if !discard_stale_inputs(&mut inputs) {
+            return;
         }
-    };
+    }
+}
 
-    let mut events = Events::new(EventSettings::new().ups(UPDATES_PER_SECOND).ups_reset(0));
-    let mut time_in_current_bucket = 0.;
-    let mut ticks_in_current_bucket = 0;
-    info!("start!");
-
-    while let Some(event) = events.next(&mut window) {
-        match event {
-            Event::Input(ref input, _) => {
-                if let Input::Button(ButtonArgs {
-                    button: Button::Keyboard(key),
-                    state,
-                    ..
-                }) = input
-                {
-                    let mut game = game.lock().unwrap();
-                    if let Ok(input) = game::Input::try_from((state, key)) {
-                        game.process_input(client_id, input);
-                        inputs.unbounded_send(input).unwrap();
-                    }
+/// Where entities were before a local tick.
+struct PreviousTick {
+    positions: game::Positions,
+    ticked_at: Instant,
+    /// How much time the tick advanced the game by, in seconds.
+    dt: f32,
+}
+
+/// A connection to a game server, independent of how the game is drawn.
+pub struct Connection {
+    joined_at: Instant,
+    shared: Shared,
+    /// The game before the latest local tick, to draw frames between ticks from.
+    previous_tick: Mutex<Option<PreviousTick>>,
+    inputs: mpsc::UnboundedSender<(u64, game::Input)>,
+    requests: mpsc::UnboundedSender<Request>,
+    moderator_key: Option<String>,
+}
+
+impl Connection {
+    /// Joins the game at the first of `server_addrs` that can be joined, trying each in order.
+    pub fn connect(
+        server_addrs: &[SocketAddr],
+        transport_config: transport::Config,
+        settings: &Settings,
+    ) -> Result<Connection, Error> {
+        let mut error = Error::from(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no server address to join",
+        ));
+        for &server_addr in server_addrs {
+            match Connection::connect_to(server_addr, transport_config.clone(), settings) {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    warn!("Couldn't join the game at {}: {}", server_addr, e);
+                    error = e;
                 }
             }
-            Event::Loop(Loop::Render(args)) => {
-                fn fuzzy_eq(resolution: [f64; 2], window_size: [f64; 2]) -> bool {
-                    fn fuzzy_eq(f1: f64, f2: f64) -> bool {
-                        (f1 - f2).abs() < f64::EPSILON
-                    }
-                    fuzzy_eq(resolution[0], window_size[0])
-                        && fuzzy_eq(resolution[1], window_size[1])
-                }
-                if !fuzzy_eq(resolution, args.window_size) {
-                    info!("Resizing {:?} => {:?}", resolution, args.window_size);
-                    resolution = args.window_size;
-                    window = WindowSettings::new("shapes", resolution)
-                        .exit_on_esc(true)
-                        .graphics_api(OpenGL::V3_2)
-                        .build()
-                        .unwrap();
-                }
-                window.draw_2d(&event, |c, g, _| {
-                    clear([1.0; 4], g);
-                    game.lock().unwrap().clone().draw(client_id, c, g);
-                });
+        }
+        Err(error)
+    }
+
+    /// Joins the game at `server_addr`, returning once the first game state has arrived. The
+    /// connection is serviced by a background thread.
+    fn connect_to(
+        server_addr: SocketAddr,
+        transport_config: transport::Config,
+        settings: &Settings,
+    ) -> Result<Connection, Error> {
+        info!("Connecting to server");
+        let shared = Shared::new(settings);
+        let started = Started {
+            first: Arc::new((Mutex::new(None), Condvar::new())),
+            joined: shared.joined.clone(),
+            status: shared.status.clone(),
+        };
+        let (inputs, rx) = mpsc::unbounded();
+        let (requests, requests_rx) = mpsc::unbounded();
+
+        let shared2 = shared.clone();
+        let started2 = started.clone();
+        let moderator_key = settings.moderator_key.clone();
+        let settings = settings.clone();
+
+        thread::spawn(move || {
+            Runtime::new().unwrap().block_on(stay_connected(
+                server_addr,
+                transport_config,
+                settings,
+                shared2,
+                started2,
+                rx,
+                requests_rx,
+            ));
+        });
+
+        let (lock, cvar) = &*started.first;
+        let mut first = lock.lock().unwrap();
+        loop {
+            match first.take() {
+                Some(joined) => break joined.map(drop)?,
+                None => first = cvar.wait(first).unwrap(),
             }
-            Event::Loop(ref lp) => {
-                let mut game = game.lock().unwrap();
-                match lp {
-                    Loop::Idle(_) => {}
-                    Loop::Update(args) => {
-                        game.tick(
-                            args.dt as f32,
-                            &mut time_in_current_bucket,
-                            &mut ticks_in_current_bucket,
-                        );
-                    }
-                    Loop::AfterRender(_) => {}
-                    lp => panic!("Didn't expect {:?}", lp),
+        }
+        Ok(Connection {
+            joined_at: Instant::now(),
+            shared,
+            previous_tick: Mutex::new(None),
+            inputs,
+            requests,
+            moderator_key,
+        })
+    }
+
+    fn joined(&self) -> Joined {
+        // Set before `connect` returns.
+        self.shared.joined.lock().unwrap().unwrap()
+    }
+
+    /// The entity controlled by this player.
+    pub fn id(&self) -> EntityId {
+        self.joined().entity_id
+    }
+
+    /// The player's session, which can be resumed for a while after being disconnected; see
+    /// [`Settings::resume_session`].
+    pub fn session_id(&self) -> u64 {
+        self.joined().session_id
+    }
+
+    /// Whether the client is connected to the server. Lost connections are reconnected
+    /// automatically.
+    pub fn status(&self) -> ConnectionStatus {
+        self.shared.status.lock().unwrap().clone()
+    }
+
+    /// The game's message of the day, for a while after joining, to show as a banner.
+    pub fn banner(&self) -> Option<String> {
+        if self.joined_at.elapsed() >= BANNER_DISPLAY_TIME {
+            return None;
+        }
+        self.shared.motd.lock().unwrap().clone()
+    }
+
+    /// The average round-trip time to the server, once measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.shared.clock.lock().unwrap().rtt()
+    }
+
+    /// What the HUD shows, for a renderer drawing `fps` frames per second.
+    pub fn hud(&self, fps: usize) -> Hud {
+        let clock = self.shared.clock.lock().unwrap();
+        Hud {
+            fps,
+            rtt: clock.rtt(),
+            tick_rate: clock.tick_rate(),
+            snapshot_age: self.shared.snapshots.lock().unwrap().age(),
+        }
+    }
+
+    /// What's worth attaching to a bug report about this connection, given the frame rate.
+    pub fn report(&self, fps: usize) -> diagnostics::Report {
+        let stats = format!(
+            "status: {}\nentity: {}\nsession: {}\nconnected for: {:?}\nhud: {}\n",
+            self.status(),
+            self.id(),
+            self.session_id(),
+            self.joined_at.elapsed(),
+            self.hud(fps)
+        );
+        diagnostics::Report {
+            stats,
+            snapshots: self.shared.snapshots.lock().unwrap().recent(),
+        }
+    }
+
+    /// Applies `input` to the local game right away, and sends it to the server.
+    pub fn push_input(&self, input: game::Input) {
+        let mut game = self.shared.game.lock().unwrap();
+        // Dead players can't do anything.
+        if !game.contains(self.id()) {
+            return;
+        }
+        game.process_input(self.id(), input);
+        // Sending only fails once the connection is over for good, like when the player was
+        // kicked or the server closed.
+        let _ = self.inputs.unbounded_send((game.ticks(), input));
+    }
+
+    /// Why the player can't play `game`, from [`Connection::render_state`], if they can't: the
+    /// connection being lost, or their entity being gone.
+    pub fn notice(&self, game: &game::Game) -> Option<String> {
+        match self.status() {
+            ConnectionStatus::Connected if game.contains(self.id()) => None,
+            ConnectionStatus::Connected => Some(String::from("you died")),
+            status => Some(status.to_string()),
+        }
+    }
+
+    /// Says `message` to everyone in the game. Messages show up in the game's events once the
+    /// server has them. Commands like `/kick 3` moderate the game instead, for moderators; see
+    /// [`chat::command`].
+    pub fn chat(&self, message: String) {
+        let request = match chat::command(&message) {
+            None => Request::Chat(message),
+            Some(Ok(action)) => match &self.moderator_key {
+                Some(key) => Request::Moderate(key.clone(), action),
+                None => {
+                    warn!("Not moderating the game without a moderator key");
+                    return;
                 }
+            },
+            Some(Err(e)) => {
+                warn!("Not moderating the game: {}", e);
+                return;
             }
-            _ => {}
+        };
+        // Sending only fails once the connection is lost.
+        let _ = self.requests.unbounded_send(request);
+    }
+
+    /// Changes the color of the player's square to `rgb`, now and whenever they join again after
+    /// reconnecting.
+    pub fn set_color(&self, rgb: [game::GameInt; 3]) {
+        *self.shared.color.lock().unwrap() = Some(rgb);
+        let mut game = self.shared.game.lock().unwrap();
+        if game.contains(self.id()) {
+            game.set_color(self.id(), rgb);
         }
+        let _ = self.requests.unbounded_send(Request::SetColor(rgb));
     }
-    info!("end :(");
-    Ok(())
+
+    /// Advances the local game, so that it keeps moving between updates from the server.
+    pub fn tick(
+        &self,
+        dt: f32,
+        time_in_current_bucket: &mut f32,
+        ticks_in_current_bucket: &mut i32,
+    ) {
+        let mut game = self.shared.game.lock().unwrap();
+        *self.previous_tick.lock().unwrap() = Some(PreviousTick {
+            positions: game.positions(),
+            ticked_at: Instant::now(),
+            dt,
+        });
+        game.tick(dt, time_in_current_bucket, ticks_in_current_bucket);
+    }
+
+    /// The game as it should be drawn now, with other players interpolated according to
+    /// [`Settings::interpolation_delay`]. Frames drawn between ticks are interpolated between the
+    /// game before and after the latest tick, so that movement looks smooth even when ticks or
+    /// updates from the server come unevenly.
+    pub fn render_state(&self) -> Box<game::Game> {
+        let mut game = self.shared.game.lock().unwrap().clone();
+        if let Some(previous) = &*self.previous_tick.lock().unwrap() {
+            let alpha = previous.ticked_at.elapsed().as_secs_f32() / previous.dt;
+            game.interpolate_from(&previous.positions, alpha.min(1.));
+        }
+        self.shared
+            .snapshots
+            .lock()
+            .unwrap()
+            .interpolate(&mut game, self.id());
+        game
+    }
+}
+
+#[test]
+fn resolution_parses_width_and_height() {
+    let resolution: Resolution = "800x600".parse().unwrap();
+    assert_eq!(
+        resolution,
+        Resolution {
+            width: 800,
+            height: 600
+        }
+    );
+    assert_eq!(resolution.to_string(), "800x600");
+    for invalid in &["800", "800x", "0x600", "800x600x32", "wide"] {
+        assert!(invalid.parse::<Resolution>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn inputs_after_the_server_closes_are_dropped() {
+    use game::{Component, Input, Point, Sign};
+
+    let shared = Shared::new(&Settings::default());
+    let mut game = game::Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let entity_id = game.insert_new_player_square();
+    game.announce_closing(String::from("maintenance"));
+    *shared.game.lock().unwrap() = Box::new(game);
+    *shared.joined.lock().unwrap() = Some(Joined {
+        entity_id,
+        session_id: 1,
+    });
+    // Once the server closes, the tasks servicing the connection stop and drop their ends.
+    let (inputs, _) = mpsc::unbounded();
+    let (requests, _) = mpsc::unbounded();
+    let connection = Connection {
+        joined_at: Instant::now(),
+        shared,
+        previous_tick: Mutex::new(None),
+        inputs,
+        requests,
+    };
+    connection.push_input(Input::Move(Component::X, Some(Sign::Positive)));
+    connection.chat(String::from("bye"));
 }
@@ -0,0 +1,134 @@
+//! Synchronizing clients with the server's clock.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The server's clock, as returned by `ping`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerTime {
+    /// The tick of the latest game state published by the server.
+    pub tick: u64,
+    /// The server's wall-clock time, as time since the Unix epoch.
+    pub since_epoch: Duration,
+}
+
+impl ServerTime {
+    pub fn now(tick: u64) -> Self {
+        ServerTime {
+            tick,
+            since_epoch: since_epoch(SystemTime::now()),
+        }
+    }
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    rtt: Duration,
+    /// Seconds the server's clock is ahead of the local clock.
+    offset: f64,
+    tick: u64,
+    /// The server's clock when it reported `tick`.
+    server_time: Duration,
+}
+
+/// Estimates the round-trip time to the server and the offset between its clock and the local
+/// clock from recent pings.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+}
+
+impl ClockSync {
+    const MAX_SAMPLES: usize = 16;
+
+    /// Records a ping that was sent at `sent` and answered at `received`.
+    pub fn record(&mut self, sent: SystemTime, received: SystemTime, server_time: ServerTime) {
+        let rtt = received.duration_since(sent).unwrap_or_default();
+        // Assume the server read its clock halfway through the round trip.
+        let midpoint = since_epoch(sent).as_secs_f64() + rtt.as_secs_f64() / 2.;
+        self.samples.push_back(Sample {
+            rtt,
+            offset: server_time.since_epoch.as_secs_f64() - midpoint,
+            tick: server_time.tick,
+            server_time: server_time.since_epoch,
+        });
+        if self.samples.len() > Self::MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The mean round-trip time of recent pings.
+    pub fn rtt(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let total: Duration = self.samples.iter().map(|sample| sample.rtt).sum();
+        Some(total / self.samples.len() as u32)
+    }
+
+    /// The round-trip time of the latest ping.
+    pub fn latest_rtt(&self) -> Option<Duration> {
+        self.samples.back().map(|sample| sample.rtt)
+    }
+
+    /// How many seconds the server's clock is ahead of the local clock. Taken from the ping with
+    /// the lowest round-trip time, which is the least skewed by queuing delays.
+    pub fn offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.rtt)
+            .map(|sample| sample.offset)
+    }
+
+    /// The latest tick reported by the server.
+    pub fn latest_tick(&self) -> Option<u64> {
+        self.samples.back().map(|sample| sample.tick)
+    }
+
+    /// How many ticks the server runs per second, going by its clock across recent pings.
+    pub fn tick_rate(&self) -> Option<f64> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let elapsed = last.server_time.checked_sub(first.server_time)?;
+        if elapsed == Duration::from_secs(0) {
+            return None;
+        }
+        Some(last.tick.saturating_sub(first.tick) as f64 / elapsed.as_secs_f64())
+    }
+}
+
+#[test]
+fn clock_sync_uses_fastest_sample() {
+    let start = UNIX_EPOCH + Duration::from_secs(1000);
+    let server_time = |secs, tick| ServerTime {
+        tick,
+        since_epoch: Duration::from_secs_f64(secs),
+    };
+    let mut clock = ClockSync::default();
+    assert_eq!(clock.rtt(), None);
+
+    // Server is 5s ahead; this reply was delayed on the way back.
+    clock.record(
+        start,
+        start + Duration::from_secs(2),
+        server_time(1005.2, 1),
+    );
+    // Server is 5s ahead; symmetric 100ms round trip.
+    clock.record(
+        start + Duration::from_secs(3),
+        start + Duration::from_millis(3100),
+        server_time(1008.05, 2),
+    );
+
+    assert_eq!(clock.rtt(), Some(Duration::from_millis(1050)));
+    assert_eq!(clock.latest_rtt(), Some(Duration::from_millis(100)));
+    assert!((clock.offset().unwrap() - 5.).abs() < 1e-6);
+    assert_eq!(clock.latest_tick(), Some(2));
+    assert!((clock.tick_rate().unwrap() - 1. / 2.85).abs() < 1e-6);
+}
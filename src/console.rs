@@ -0,0 +1,249 @@
+//! The server's console: commands its operator types into standard input, to manage the game
+//! without connecting to it.
+
+use crate::server::Admin;
+use log::error;
+use std::{
+    io::{self, BufRead},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::UNIX_EPOCH,
+};
+
+const HELP: &str = "commands: players, scores, kick <address>, ban <ip>, unban <ip>, bans, \
+                    reload, mod <name>, unmod <name>, mods, say <message>, save [path], \
+                    next, log, stop";
+
+/// A console command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    /// Lists the connected players.
+    Players,
+    /// Shows the scores in the match.
+    Scores,
+    Kick(SocketAddr),
+    Ban(IpAddr),
+    Unban(IpAddr),
+    /// Lists the banned addresses.
+    Bans,
+    /// Reloads the access lists from the config file.
+    Reload,
+    /// Makes the player with this name a moderator, printing their key.
+    Mod(String),
+    Unmod(String),
+    /// Lists the moderators.
+    Mods,
+    Say(String),
+    /// Saves the game, where it's autosaved without a path.
+    Save(Option<PathBuf>),
+    /// Ends the round, starting the next one.
+    NextRound,
+    /// Shows the latest entries in the admin log: who did what, and when.
+    Log,
+    Stop,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, rest) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        match (name, rest) {
+            ("players", "") => Ok(Command::Players),
+            ("scores", "") => Ok(Command::Scores),
+            ("kick", addr) => addr
+                .parse()
+                .map(Command::Kick)
+                .map_err(|_| String::from("expected kick <address>, like kick 10.0.0.1:52000")),
+            ("ban", ip) => ip
+                .parse()
+                .map(Command::Ban)
+                .map_err(|_| String::from("expected ban <ip>, like ban 10.0.0.1")),
+            ("unban", ip) => ip
+                .parse()
+                .map(Command::Unban)
+                .map_err(|_| String::from("expected unban <ip>, like unban 10.0.0.1")),
+            ("bans", "") => Ok(Command::Bans),
+            ("reload", "") => Ok(Command::Reload),
+            ("mod", "") => Err(String::from("expected mod <name>")),
+            ("mod", name) => Ok(Command::Mod(String::from(name))),
+            ("unmod", "") => Err(String::from("expected unmod <name>")),
+            ("unmod", name) => Ok(Command::Unmod(String::from(name))),
+            ("mods", "") => Ok(Command::Mods),
+            ("say", "") => Err(String::from("expected say <message>")),
+            ("say", message) => Ok(Command::Say(String::from(message))),
+            ("save", "") => Ok(Command::Save(None)),
+            ("save", path) => Ok(Command::Save(Some(PathBuf::from(path)))),
+            ("next", "") => Ok(Command::NextRound),
+            ("log", "") => Ok(Command::Log),
+            ("stop", "") => Ok(Command::Stop),
+            ("help", "") => Ok(Command::Help),
+            _ => Err(format!("unknown command {:?}; {}", s, HELP)),
+        }
+    }
+}
+
+/// Runs `command`, printing the result.
+fn run(admin: &Admin, command: Command) {
+    match command {
+        Command::Players => {
+            let players = admin.players();
+            if players.is_empty() {
+                println!("no players connected");
+            }
+            for player in players {
+                let entity = player
+                    .entity_id
+                    .map_or_else(|| String::from("-"), |id| id.to_string());
+                let name = player.name.as_deref().unwrap_or("");
+                println!("{}\tentity {}\t{}", player.addr, entity, name);
+            }
+        }
+        Command::Scores => {
+            let scores = admin.scoreboard();
+            if scores.is_empty() {
+                println!("no scores yet");
+            }
+            for score in scores {
+                println!("{}\t{}", score.points, score.name);
+            }
+        }
+        Command::Kick(addr) => {
+            if admin.kick(addr) {
+                println!("kicked {}", addr);
+            } else {
+                println!("no player connected from {}", addr);
+            }
+        }
+        Command::Ban(ip) => match admin.ban(ip) {
+            Ok(kicked) => println!("banned {}, disconnecting {} players", ip, kicked),
+            Err(e) => println!("banned {}, but failed to save the ban list: {}", ip, e),
+        },
+        Command::Unban(ip) => match admin.unban(ip) {
+            Ok(true) => println!("unbanned {}", ip),
+            Ok(false) => println!("{} wasn't banned", ip),
+            Err(e) => println!("unbanned {}, but failed to save the ban list: {}", ip, e),
+        },
+        Command::Bans => {
+            let bans = admin.bans();
+            if bans.is_empty() {
+                println!("no one is banned");
+            }
+            for ip in bans {
+                println!("{}", ip);
+            }
+        }
+        Command::Reload => match admin.reload_access() {
+            Ok((lists, kicked)) => println!(
+                "reloaded the access lists, allowing {} and denying {} networks; \
+                 disconnected {} players",
+                lists.allow.len(),
+                lists.deny.len(),
+                kicked
+            ),
+            Err(e) => println!("failed to reload the access lists: {}", e),
+        },
+        Command::Mod(name) => match admin.add_moderator(&name) {
+            (key, Ok(())) => println!("made {} a moderator, with the key {}", name, key),
+            (key, Err(e)) => println!(
+                "made {} a moderator, with the key {}, but failed to save the moderator list: {}",
+                name, key, e
+            ),
+        },
+        Command::Unmod(name) => match admin.remove_moderator(&name) {
+            Ok(true) => println!("{} isn't a moderator anymore", name),
+            Ok(false) => println!("{} wasn't a moderator", name),
+            Err(e) => println!(
+                "{} isn't a moderator anymore, but failed to save the moderator list: {}",
+                name, e
+            ),
+        },
+        Command::Mods => {
+            let moderators = admin.moderators();
+            if moderators.is_empty() {
+                println!("no moderators");
+            }
+            for name in moderators {
+                println!("{}", name);
+            }
+        }
+        Command::Say(message) => admin.say(&message),
+        Command::Save(path) => match admin.save(path.as_deref()) {
+            Ok(path) => println!("saved the game to {}", path.display()),
+            Err(e) => println!("failed to save the game: {}", e),
+        },
+        Command::Log => {
+            let entries = admin.log();
+            if entries.is_empty() {
+                println!("nothing logged yet");
+            }
+            for entry in entries {
+                let at = entry.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                println!("{}\t{}\t{}", at.as_secs(), entry.actor, entry.event);
+            }
+        }
+        Command::NextRound => admin.next_round(),
+        Command::Stop => admin.shutdown("the server was stopped"),
+        Command::Help => println!("{}", HELP),
+    }
+}
+
+/// Runs the commands typed into standard input on a background thread, until it's closed.
+pub(crate) fn spawn(admin: Admin) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Failed to read the console: {}", e);
+                    return;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(command) => run(&admin, command),
+                Err(e) => println!("{}", e),
+            }
+        }
+    });
+}
+
+#[test]
+fn console_commands_parse() {
+    assert_eq!("players".parse(), Ok(Command::Players));
+    assert_eq!("scores".parse(), Ok(Command::Scores));
+    assert_eq!(
+        " kick 10.0.0.1:52000 ".parse(),
+        Ok(Command::Kick(([10, 0, 0, 1], 52000).into()))
+    );
+    assert_eq!(
+        "say  be nice ".parse(),
+        Ok(Command::Say(String::from("be nice")))
+    );
+    assert_eq!("save".parse(), Ok(Command::Save(None)));
+    assert_eq!("log".parse(), Ok(Command::Log));
+    assert_eq!("next".parse(), Ok(Command::NextRound));
+    assert_eq!(
+        "mod player one".parse(),
+        Ok(Command::Mod(String::from("player one")))
+    );
+    assert!("unmod".parse::<Command>().is_err());
+    assert_eq!(
+        "save world.bin".parse(),
+        Ok(Command::Save(Some(PathBuf::from("world.bin"))))
+    );
+    assert_eq!("ban ::1".parse(), Ok(Command::Ban("::1".parse().unwrap())));
+    assert!("ban 10.0.0.1:52000".parse::<Command>().is_err());
+    assert!("kick someone".parse::<Command>().is_err());
+    assert!("say".parse::<Command>().is_err());
+    assert!("players now".parse::<Command>().is_err());
+}
@@ -0,0 +1,467 @@
+//! An unreliable channel for game states and inputs, so that a lost packet only loses that one
+//! update instead of holding up every update behind it the way it would on TCP.
+//!
+//! A client opens the channel with the `open_datagram_channel` RPC, which returns a token, then
+//! sends [`ClientDatagram`]s carrying that token to the server's UDP socket, which listens on the
+//! same port as its TCP listener. The server sends a [`ServerDatagram`] to the address they came
+//! from after every tick. Joining, registration and everything else still go over TCP.
+//!
+//! Client datagrams are signed with the player's session id, which only ever goes over TCP, so
+//! that seeing a datagram's token isn't enough to push inputs or to have the game states sent
+//! somewhere else.
+
+use crate::{
+    game::{self, EntityId, Game, StateUpdate},
+    metrics,
+    rate_limit::{InputCheck, InputLimit},
+    rollback::History,
+    server::VIEW_DISTANCE,
+    states::Subscriber,
+};
+use futures::prelude::*;
+use log::{debug, warn};
+use ring::{constant_time, hmac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{
+        udp::{RecvHalf, SendHalf},
+        UdpSocket,
+    },
+    time,
+};
+
+/// The largest payload a UDP datagram can carry. Game states that don't fit aren't sent, so very
+/// large games need to be played over TCP.
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// How many recent game states are kept to compute and apply deltas against.
+const MAX_RECENT_STATES: usize = 64;
+/// How many ticks apart full game states are sent to a client that hasn't acknowledged any
+/// recent state. In between, it's sent deltas against the latest full state, which it most
+/// likely has by then.
+const KEYFRAME_INTERVAL: u64 = 32;
+/// How many bytes of a datagram's signature are sent.
+const SIGNATURE_LEN: usize = 16;
+
+/// Sent by the client whenever it has a new input, and periodically otherwise.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientDatagram {
+    /// The token returned by `open_datagram_channel`.
+    pub token: u64,
+    /// Counts up with each datagram sent, so that old ones can't be sent again from elsewhere to
+    /// move the channel.
+    pub number: u64,
+    /// The tick of the latest game state the client has, which the server sends deltas against.
+    pub ack: Option<u64>,
+    /// Every input the server hasn't acknowledged yet, as `(sequence, tick, input)`, oldest first.
+    /// The server drops inputs it has already applied.
+    pub inputs: Vec<(u64, u64, game::Input)>,
+    /// Set by [`Signer::sign`].
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+/// Signs and checks a player's datagrams with their session id.
+pub struct Signer(hmac::Key);
+
+impl Signer {
+    pub fn new(session_id: u64) -> Self {
+        Signer(hmac::Key::new(hmac::HMAC_SHA256, &session_id.to_le_bytes()))
+    }
+
+    fn signature(&self, datagram: &ClientDatagram) -> io::Result<hmac::Tag> {
+        let signed = (
+            datagram.token,
+            datagram.number,
+            datagram.ack,
+            &datagram.inputs,
+        );
+        let bytes = bincode::serialize(&signed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(hmac::sign(&self.0, &bytes))
+    }
+
+    pub fn sign(&self, datagram: &mut ClientDatagram) -> io::Result<()> {
+        let signature = self.signature(datagram)?;
+        datagram
+            .signature
+            .copy_from_slice(&signature.as_ref()[..SIGNATURE_LEN]);
+        Ok(())
+    }
+
+    fn verify(&self, datagram: &ClientDatagram) -> bool {
+        match self.signature(datagram) {
+            Ok(signature) => constant_time::verify_slices_are_equal(
+                &signature.as_ref()[..SIGNATURE_LEN],
+                &datagram.signature,
+            )
+            .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Sent by the server to each client after every tick.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerDatagram {
+    /// The sequence of the latest input applied from the client.
+    pub input_ack: u64,
+    pub update: StateUpdate,
+}
+
+pub fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let bytes =
+        bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if bytes.len() > MAX_DATAGRAM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} byte message doesn't fit in a datagram", bytes.len()),
+        ));
+    }
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The last few game states sent or received, oldest first.
+#[derive(Default)]
+pub struct RecentStates {
+    states: VecDeque<Box<Game>>,
+}
+
+impl RecentStates {
+    pub fn push(&mut self, game: Box<Game>) {
+        self.states.push_back(game);
+        if self.states.len() > MAX_RECENT_STATES {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&Game> {
+        self.states
+            .iter()
+            .find(|game| game.ticks() == tick)
+            .map(|game| &**game)
+    }
+
+    pub fn latest(&self) -> Option<&Game> {
+        self.states.back().map(|game| &**game)
+    }
+
+    /// Applies `update` to the state it was computed against, and returns the resulting state if
+    /// it is newer than every state received so far. Datagrams can arrive out of order or not at
+    /// all, so the base of a delta isn't necessarily the latest state.
+    pub fn apply(&mut self, update: StateUpdate) -> Option<&Game> {
+        let game = match update {
+            StateUpdate::Full(game) => game,
+            StateUpdate::Delta(delta) => {
+                let mut game = Box::new(self.get(delta.base_tick)?.clone());
+                game.apply_delta(delta).ok()?;
+                game
+            }
+        };
+        if let Some(latest) = self.latest() {
+            if game.ticks() <= latest.ticks() {
+                return None;
+            }
+        }
+        self.push(game);
+        self.latest()
+    }
+}
+
+struct Peer {
+    entity_id: EntityId,
+    signer: Signer,
+    /// Where to send game states. Unknown until the client's first datagram arrives, and only
+    /// moved by a signed datagram newer than any before it.
+    addr: Option<SocketAddr>,
+    /// The number of the newest datagram received from the client.
+    last_number: u64,
+    /// The tick of the latest game state the client has.
+    acked_tick: Option<u64>,
+    /// The tick of the latest full game state sent to the client.
+    keyframe_tick: Option<u64>,
+    /// The latest views of the game sent to the client, oldest first, which deltas are computed
+    /// against.
+    sent: VecDeque<Arc<Game>>,
+    /// The sequence of the latest input applied from the client.
+    last_input_sequence: u64,
+    /// The same limit inputs pushed over TCP are held to.
+    input_limit: InputLimit,
+}
+
+/// The clients that have opened a datagram channel, by token.
+#[derive(Clone, Default)]
+pub struct Peers(Arc<Mutex<HashMap<u64, Peer>>>);
+
+impl Peers {
+    /// Opens a channel for the player controlling `entity_id`, whose datagrams are signed with
+    /// `session_id`, and returns its token.
+    pub fn open(&self, entity_id: EntityId, session_id: u64) -> u64 {
+        let mut peers = self.0.lock().unwrap();
+        loop {
+            if let Entry::Vacant(entry) = peers.entry(rand::random()) {
+                let token = *entry.key();
+                entry.insert(Peer {
+                    entity_id,
+                    signer: Signer::new(session_id),
+                    addr: None,
+                    last_number: 0,
+                    acked_tick: None,
+                    keyframe_tick: None,
+                    sent: VecDeque::with_capacity(MAX_RECENT_STATES + 1),
+                    last_input_sequence: 0,
+                    input_limit: InputLimit::new(Instant::now()),
+                });
+                return token;
+            }
+        }
+    }
+
+    /// Closes every channel of the player controlling `entity_id`.
+    pub fn close(&self, entity_id: EntityId) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, peer| peer.entity_id != entity_id);
+    }
+}
+
+/// Applies inputs from, and sends game states to, the clients in `peers` over a UDP socket bound
+/// to `addr`.
+pub async fn serve(
+    addr: SocketAddr,
+    peers: Peers,
+    history: Arc<Mutex<History>>,
+    states: Subscriber,
+) -> io::Result<()> {
+    let (recv, send) = UdpSocket::bind(addr).await?.split();
+    future::try_join(
+        receive_inputs(recv, peers.clone(), history),
+        send_states(send, peers, states),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn receive_inputs(
+    mut socket: RecvHalf,
+    peers: Peers,
+    history: Arc<Mutex<History>>,
+) -> io::Result<()> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let datagram: ClientDatagram = match decode(&buf[..len]) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                debug!("Dropping bad datagram from {}: {}", addr, e);
+                continue;
+            }
+        };
+        let mut peers = peers.0.lock().unwrap();
+        let peer = match peers.get_mut(&datagram.token) {
+            Some(peer) => peer,
+            None => {
+                debug!("Dropping datagram from {} with unknown token", addr);
+                continue;
+            }
+        };
+        if !peer.signer.verify(&datagram) {
+            debug!("Dropping datagram from {} with a bad signature", addr);
+            continue;
+        }
+        if peer.addr != Some(addr) {
+            if datagram.number <= peer.last_number {
+                debug!("Dropping old datagram from {}", addr);
+                continue;
+            }
+            peer.addr = Some(addr);
+        }
+        peer.last_number = peer.last_number.max(datagram.number);
+        peer.acked_tick = peer.acked_tick.max(datagram.ack);
+        let entity_id = peer.entity_id;
+        for (sequence, tick, input) in datagram.inputs {
+            if sequence <= peer.last_input_sequence {
+                continue;
+            }
+            match peer.input_limit.check(Instant::now()) {
+                InputCheck::Apply(wait) if wait > Duration::from_secs(0) => {
+                    // Other peers' datagrams can't wait on this one's, so the input is applied
+                    // later on its own. Later inputs wait longer, so they're still applied in
+                    // order.
+                    let history = history.clone();
+                    tokio::spawn(async move {
+                        time::delay_for(wait).await;
+                        history
+                            .lock()
+                            .unwrap()
+                            .apply_input_at(tick, entity_id, input);
+                    });
+                }
+                InputCheck::Apply(_) => {
+                    history
+                        .lock()
+                        .unwrap()
+                        .apply_input_at(tick, entity_id, input);
+                }
+                // Not acknowledged, so the client sends it again.
+                InputCheck::Drop => break,
+                InputCheck::Flooding => {
+                    warn!("Closing the datagram channel of {}, flooding inputs", addr);
+                    peers.remove(&datagram.token);
+                    break;
+                }
+            }
+            peer.last_input_sequence = sequence;
+        }
+    }
+}
+
+/// Tells apart the views of the game sent to clients, by their tick and the entities in them.
+type ViewKey = (u64, Vec<EntityId>);
+
+async fn send_states(mut socket: SendHalf, peers: Peers, mut states: Subscriber) -> io::Result<()> {
+    while let Some(game) = states.recv().await {
+        // Each player is sent only what they can see, like over TCP. Players who see the same
+        // entities share a view, and updates are the same for every player with the same base
+        // view and view, so each is only made and encoded once.
+        let mut views: HashMap<Vec<EntityId>, Arc<Game>> = HashMap::new();
+        let mut updates: HashMap<(Option<ViewKey>, Vec<EntityId>), Option<Vec<u8>>> =
+            HashMap::new();
+        let datagrams: Vec<_> = peers
+            .0
+            .lock()
+            .unwrap()
+            .values_mut()
+            // Wait for the player's entity to show up before sending them anything.
+            .filter(|peer| game.contains(peer.entity_id))
+            .filter_map(|peer| {
+                let addr = peer.addr?;
+                let visible = game.visible_ids(peer.entity_id, VIEW_DISTANCE);
+                let view = views
+                    .entry(visible.clone())
+                    .or_insert_with(|| Arc::new(game.with_only(&visible)))
+                    .clone();
+                let sent = &peer.sent;
+                let recent_state =
+                    |tick| sent.iter().find(|base: &&Arc<Game>| base.ticks() == tick);
+                let keyframe = peer
+                    .keyframe_tick
+                    .filter(|&tick| game.ticks() - tick < KEYFRAME_INTERVAL)
+                    .and_then(recent_state);
+                let base = peer.acked_tick.and_then(recent_state).or(keyframe);
+                if base.is_none() {
+                    peer.keyframe_tick = Some(game.ticks());
+                }
+                let update = updates
+                    .entry((
+                        base.map(|base| (base.ticks(), base.entity_ids().collect())),
+                        visible,
+                    ))
+                    .or_insert_with(|| {
+                        let update = match base {
+                            Some(base) => StateUpdate::Delta(view.delta_since(base)),
+                            None => StateUpdate::Full(Box::new((*view).clone())),
+                        };
+                        let _timer = metrics::time_phase("serialization");
+                        encode(&update)
+                            .map_err(|e| warn!("Not sending game state: {}", e))
+                            .ok()
+                    })
+                    .as_ref()?;
+                peer.sent.push_back(view);
+                if peer.sent.len() > MAX_RECENT_STATES {
+                    peer.sent.pop_front();
+                }
+                match encode_datagram(peer.last_input_sequence, update) {
+                    Ok(bytes) => Some((addr, bytes)),
+                    Err(e) => {
+                        warn!("Not sending game state to {}: {}", addr, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        for (addr, bytes) in datagrams {
+            if let Err(e) = socket.send_to(&bytes, &addr).await {
+                warn!("Failed to send game state to {}: {}", addr, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodes a [`ServerDatagram`] carrying `update`, already encoded with [`encode`].
+fn encode_datagram(input_ack: u64, update: &[u8]) -> io::Result<Vec<u8>> {
+    // Bincode encodes a struct as its fields, one after another.
+    let mut bytes = encode(&input_ack)?;
+    bytes.extend_from_slice(update);
+    if bytes.len() > MAX_DATAGRAM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} byte message doesn't fit in a datagram", bytes.len()),
+        ));
+    }
+    Ok(bytes)
+}
+
+#[test]
+fn recent_states_apply_delta_against_older_state() {
+    let mut server = Game::default();
+    let mut client = RecentStates::default();
+    let first = server.clone();
+    assert!(client
+        .apply(StateUpdate::Full(Box::new(first.clone())))
+        .is_some());
+
+    server.tick(0.1, &mut 0., &mut 0);
+    let second = server.clone();
+    server.tick(0.1, &mut 0., &mut 0);
+    let third = server.clone();
+
+    // The delta against the first state arrives before the one against the second.
+    let late = StateUpdate::Delta(second.delta_since(&first));
+    let early = StateUpdate::Delta(third.delta_since(&first));
+    assert_eq!(client.apply(early).map(Game::ticks), Some(third.ticks()));
+    assert!(client.apply(late).is_none());
+    assert_eq!(client.latest().map(Game::ticks), Some(third.ticks()));
+}
+
+#[test]
+fn datagrams_are_encoded_around_their_update() {
+    let update = StateUpdate::Full(Box::new(Game::default()));
+    let datagram = ServerDatagram {
+        input_ack: 7,
+        update: update.clone(),
+    };
+    assert_eq!(
+        encode_datagram(7, &encode(&update).unwrap()).unwrap(),
+        encode(&datagram).unwrap()
+    );
+}
+
+#[test]
+fn datagrams_are_signed_with_the_session_id() {
+    let mut datagram = ClientDatagram {
+        token: 1,
+        number: 1,
+        ack: Some(2),
+        inputs: vec![],
+        signature: Default::default(),
+    };
+    let signer = Signer::new(3);
+    signer.sign(&mut datagram).unwrap();
+    assert!(signer.verify(&datagram));
+    assert!(!Signer::new(4).verify(&datagram));
+    datagram.number += 1;
+    assert!(!signer.verify(&datagram));
+}
@@ -0,0 +1,133 @@
+//! Logging to a file as well as the terminal, and bundling the latest logs with what the client
+//! last saw into a zip file to attach to bug reports.
+
+use crate::game::Game;
+use log::{Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::{
+    collections::VecDeque,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zip::{result::ZipError, write::FileOptions, ZipWriter};
+
+/// How many of the latest log lines are kept for diagnostics bundles.
+const RECENT_LOG_LINES: usize = 1000;
+
+static RECENT_LOGS: Lazy<Mutex<VecDeque<String>>> = Lazy::new(Mutex::default);
+
+/// Logs to the terminal, and to a file if there is one, keeping the latest lines.
+struct Logger {
+    terminal: Box<dyn Log>,
+    file: Option<Mutex<LineWriter<File>>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.terminal.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.terminal.log(record);
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {} {}: {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Some(file) = &self.file {
+            // There's nowhere left to report failing to log.
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+        let mut recent = RECENT_LOGS.lock().unwrap();
+        recent.push_back(line);
+        if recent.len() > RECENT_LOG_LINES {
+            recent.pop_front();
+        }
+    }
+
+    fn flush(&self) {
+        self.terminal.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Logs to the terminal as `RUST_LOG` says to, and appends the same logs to `log_file` if
+/// there is one.
+pub fn init_logging(log_file: Option<&Path>) -> io::Result<()> {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filter) = env::var("RUST_LOG") {
+        builder.parse_filters(&filter);
+    }
+    let terminal = builder.build();
+    let max_level = terminal.filter();
+    let file = log_file
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?
+        .map(|file| Mutex::new(LineWriter::new(file)));
+    log::set_boxed_logger(Box::new(Logger {
+        terminal: Box::new(terminal),
+        file,
+    }))
+    .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// What the client knows that's worth attaching to a bug report, besides the logs.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Statistics about the connection and rendering, one per line.
+    pub stats: String,
+    /// The latest game states received from the server, and how long ago each was received.
+    pub snapshots: Vec<(Duration, Box<Game>)>,
+}
+
+fn zip_error(e: ZipError) -> io::Error {
+    match e {
+        ZipError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl Report {
+    /// Saves the report and the latest logs to a new zip file in `dir`, creating `dir` if
+    /// needed. Returns the file's path.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = dir.join(format!("shapes-diagnostics-{}.zip", since_epoch.as_secs()));
+        let mut zip = ZipWriter::new(File::create(&path)?);
+        let options = FileOptions::default();
+
+        zip.start_file("log.txt", options).map_err(zip_error)?;
+        for line in RECENT_LOGS.lock().unwrap().iter() {
+            writeln!(zip, "{}", line)?;
+        }
+        zip.start_file("stats.txt", options).map_err(zip_error)?;
+        zip.write_all(self.stats.as_bytes())?;
+        for (i, (age, game)) in self.snapshots.iter().enumerate() {
+            let name = format!("snapshots/{:02}-{}ms-ago.json", i, age.as_millis());
+            zip.start_file(name, options).map_err(zip_error)?;
+            serde_json::to_writer_pretty(&mut zip, game)?;
+        }
+        zip.finish().map_err(zip_error)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,70 @@
+//! Errors returned from running a client, a game server or the game list.
+
+use crate::{game::StaleDeltaError, game_list::RegistrationError, server::JoinError};
+use std::{fmt, io};
+use thiserror::Error;
+
+/// Why running a client, a game server or the game list failed.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Talking to a peer failed, like when it can't be reached or the connection is lost, or
+    /// reading or writing a file did.
+    #[error(transparent)]
+    Transport(#[from] io::Error),
+    /// The game list didn't register the game.
+    #[error("couldn't register the game: {0}")]
+    Registration(#[from] RegistrationError),
+    /// The server didn't let the player join.
+    #[error("couldn't join the game: {0}")]
+    Join(#[from] JoinError),
+    /// A game state from the server didn't follow the one before it.
+    #[error("game state doesn't apply: {0}")]
+    Game(#[from] StaleDeltaError),
+    /// A command line flag was given a value it can't take.
+    #[error(r#"--{flag} value "{value}" invalid: {reason}"#)]
+    InvalidFlag {
+        flag: String,
+        value: String,
+        reason: String,
+    },
+    /// A config file couldn't be read, or its settings couldn't be.
+    #[error("couldn't read the config file {path}: {reason}")]
+    Config { path: String, reason: String },
+}
+
+impl Error {
+    /// The error for `flag` being given `value`, which is invalid for `reason`.
+    pub fn invalid_flag(flag: &str, value: &str, reason: impl fmt::Display) -> Self {
+        Error::InvalidFlag {
+            flag: String::from(flag),
+            value: String::from(value),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The error for the config file at `path` being unreadable for `reason`.
+    pub fn config(path: &str, reason: impl fmt::Display) -> Self {
+        Error::Config {
+            path: String::from(path),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[test]
+fn invalid_flags_name_the_flag_and_value() {
+    let e = Error::invalid_flag("max_fps", "fast", "invalid digit found in string");
+    assert_eq!(
+        e.to_string(),
+        r#"--max_fps value "fast" invalid: invalid digit found in string"#
+    );
+}
+
+#[test]
+fn config_errors_name_the_file() {
+    let e = Error::config("server.toml", "unknown field `prot`");
+    assert_eq!(
+        e.to_string(),
+        "couldn't read the config file server.toml: unknown field `prot`"
+    );
+}
@@ -1,8 +1,19 @@
+use crate::grid::Grid;
+#[cfg(feature = "client")]
+use crate::palette::Style;
 use log::{debug, info};
-use piston_window::{context::Context, rectangle, types, G2d};
-use rand::Rng;
+#[cfg(feature = "client")]
+use piston_window::{context::Context, line::Line, rectangle, G2d};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use slab::Slab;
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt, mem,
+    time::{Duration, Instant},
+};
 
 pub type GameInt = f32;
 pub type EntityId = usize;
@@ -10,14 +21,22 @@ pub struct InvalidKeyError;
 
 const PENDULUM_FORCE: Point = Point::new(54.4, 54.4);
 const MOVE_VELOCITY: GameInt = 50.;
-
-fn random_color() -> types::Rectangle<GameInt> {
-    let mut rng = rand::thread_rng();
+/// How many of the latest events a game keeps.
+const MAX_EVENTS: usize = 32;
+/// How far ahead of an entity its velocity arrow reaches in the debug overlay, in seconds of
+/// movement.
+#[cfg(feature = "client")]
+const DEBUG_ARROW_SECONDS: GameInt = 0.5;
+const WALL_COLOR: [GameInt; 4] = [0.3, 0.3, 0.3, 1.];
+/// The fewest entities each thread animates during a tick. Splitting smaller games up costs more
+/// than it saves.
+const MIN_ENTITIES_PER_TASK: usize = 256;
+
+fn random_color(rng: &mut impl Rng) -> [GameInt; 4] {
     [0.0, rng.gen(), rng.gen(), rng.gen()]
 }
 
-fn random_point(bottom_right: Point) -> Point {
-    let mut rng = rand::thread_rng();
+fn random_point(rng: &mut impl Rng, bottom_right: Point) -> Point {
     let x: GameInt = rng.gen_range(0., bottom_right.x as GameInt);
     let y: GameInt = rng.gen_range(0., bottom_right.y as GameInt);
     Point { x, y }
@@ -34,6 +53,33 @@ pub enum Animation {
     },
 }
 
+impl Animation {
+    /// The animation after a tick of `dt` seconds, ending at `time`, in which its entity moved by
+    /// `delta`, and the velocity it gives the entity, if it sets one. `None` once the entity
+    /// should disappear.
+    fn after_tick(self, delta: Point, dt: f32, time: f32) -> Option<(Animation, Option<Point>)> {
+        match self {
+            Animation::Pendulum {
+                distance,
+                max_distance,
+            } => {
+                // I don't know what this is doing but it's kind of interesting.
+                let velocity = (max_distance * PENDULUM_FORCE).sqrt()
+                    * ((PENDULUM_FORCE / max_distance).sqrt() * time).sin();
+                let animation = Animation::Pendulum {
+                    distance: distance + delta,
+                    max_distance,
+                };
+                Some((animation, Some(velocity)))
+            }
+            Animation::DisappearAfter { secs } if secs - dt <= 0. => None,
+            Animation::DisappearAfter { secs } => {
+                Some((Animation::DisappearAfter { secs: secs - dt }, None))
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Default, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Point {
     pub x: GameInt,
@@ -193,22 +239,78 @@ impl std::ops::Div for Point {
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Game {
     square_side_length: GameInt,
-    pub bottom_right: Point,
+    bottom_right: Point,
     #[serde(with = "serde_slab")]
-    pub positions: Slab<Rectangle>,
+    positions: Slab<Rectangle>,
     #[serde(with = "serde_slab")]
-    pub velocities: Slab<Point>,
+    velocities: Slab<Point>,
     #[serde(with = "serde_slab")]
-    pub animations: Slab<Option<Animation>>,
+    animations: Slab<Option<Animation>>,
     #[serde(with = "serde_slab")]
-    pub moveable: Slab<bool>,
+    moveable: Slab<bool>,
     #[serde(with = "serde_slab")]
-    pub moved_this_action: Slab<bool>,
+    moved_this_action: Slab<bool>,
     #[serde(with = "serde_slab")]
-    pub colors: Slab<types::Rectangle<GameInt>>,
+    colors: Slab<[GameInt; 4]>,
+    /// Players' names, by their entities.
+    names: BTreeMap<EntityId, String>,
+    /// Players who are away from the keyboard, whom other entities pass through.
+    afk: BTreeSet<EntityId>,
+    /// When each player last made an input, or joined, in seconds of game time. Only kept where
+    /// the game was ticked.
+    #[serde(skip)]
+    last_active: BTreeMap<EntityId, f32>,
+    /// The latest events, oldest first.
+    events: VecDeque<LoggedEvent>,
     time: f32,
+    ticks: u64,
+    /// The walls placed by the current layout, so they can be replaced when it changes.
+    walls: Vec<EntityId>,
+    /// Where players' squares are placed when they join, picked at random. Without any, they're
+    /// placed at the top left of the world.
+    spawn_points: Vec<Point>,
+    /// Where entities overlapped while being moved during the last tick, for the debug overlay.
+    /// Only kept where the game was ticked, not sent to clients.
+    #[serde(skip)]
+    overlaps: Vec<Rectangle>,
+    /// Which entities ran into which during the last tick, the moving one first. Only kept where
+    /// the game was ticked.
+    #[serde(skip)]
+    collisions: Vec<(EntityId, EntityId)>,
+    /// How long the last tick took. Only kept where the game was ticked.
+    #[serde(skip)]
+    timings: TickTimings,
+    /// What the world was generated from, or the latest round started from. Only kept where the
+    /// game was made.
+    #[serde(skip)]
+    seed: u64,
+    /// Where entities are, for finding what a moving entity could run into. Only kept while the
+    /// game ticks.
+    #[serde(skip)]
+    grid: Grid,
 }
 
+/// Walls and spawn points placed in a world, like from a [`crate::map::Map`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    pub walls: Vec<Rectangle>,
+    pub spawn_points: Vec<Point>,
+}
+
+/// How long the phases of a tick took, for profiling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TickTimings {
+    /// Moving entities and pushing them out of each other.
+    pub collisions: Duration,
+    /// Everything else, like updating velocities and animations.
+    pub movement: Duration,
+}
+
+/// Where a game's entities were at some point, to interpolate from without keeping a copy of the
+/// whole game.
+#[derive(Clone, Debug, Default)]
+pub struct Positions(Slab<Rectangle>);
+
 mod serde_slab {
     use serde::{
         de::{MapAccess, Visitor},
@@ -216,15 +318,14 @@ mod serde_slab {
         Deserialize, Deserializer, Serialize, Serializer,
     };
     use slab::Slab;
-    use std::marker::PhantomData;
-    use std::{collections::HashMap, fmt};
+    use std::{fmt, marker::PhantomData};
 
     pub fn serialize<T, S>(slab: &Slab<T>, serializer: S) -> Result<S::Ok, S::Error>
     where
         T: Serialize,
         S: Serializer,
     {
-        let mut map = serializer.serialize_map(Some(slab.capacity()))?;
+        let mut map = serializer.serialize_map(Some(slab.len()))?;
         for (k, v) in slab.iter() {
             map.serialize_entry(&k, v)?;
         }
@@ -255,36 +356,11 @@ mod serde_slab {
             where
                 M: MapAccess<'de>,
             {
-                let mut max_value = 0;
-                let mut hash_map = HashMap::<usize, _>::new();
-                while let Some((key, value)) = access.next_entry()? {
-                    hash_map.insert(key, value);
-                    max_value = max_value.max(key);
+                let mut entries = vec![];
+                while let Some(entry) = access.next_entry()? {
+                    entries.push(entry);
                 }
-
-                let mut map = Slab::with_capacity(max_value + 1);
-                let mut to_delete = Vec::with_capacity(max_value + 1 - hash_map.len());
-                for _ in 0..=max_value {
-                    let entry = map.vacant_entry();
-                    let key = entry.key();
-                    match hash_map.remove(&key) {
-                        Some(v) => {
-                            entry.insert(v);
-                        }
-                        None => {
-                            // The same key will keep being returned by vacant_entry() unless
-                            // we fill it up with something. We just need to delete it later.
-                            entry.insert(T::default());
-                            to_delete.push(key);
-                        }
-                    }
-                }
-                for key in to_delete {
-                    map.remove(key);
-                }
-
-                assert_eq!(0, hash_map.len());
-                Ok(map)
+                Ok(super::slab_from(entries))
             }
         }
         deserializer.deserialize_map(SlabVisitor {
@@ -293,13 +369,14 @@ mod serde_slab {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Entity {
     pub position: Rectangle,
     pub velocity: Point,
     pub animation: Option<Animation>,
     pub moveable: bool,
     pub moved_this_action: bool,
-    pub color: types::Rectangle<GameInt>,
+    pub color: [GameInt; 4],
 }
 
 /// A game input.
@@ -334,6 +411,131 @@ pub enum Sign {
     Negative,
 }
 
+/// The entities that changed between two ticks of a game.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delta {
+    /// The tick this delta must be applied on top of.
+    pub base_tick: u64,
+    /// The tick the game is at after applying this delta.
+    pub tick: u64,
+    time: f32,
+    /// Entities that were added or changed since `base_tick`.
+    pub updated: Vec<(EntityId, Entity)>,
+    /// Entities that were removed since `base_tick`. For games filtered by [`Game::visible_to`],
+    /// this includes entities that went out of view.
+    pub removed: Vec<EntityId>,
+    /// Players named since `base_tick`, including named players that came into view.
+    pub named: Vec<(EntityId, String)>,
+    /// Every player who's away from the keyboard.
+    pub afk: BTreeSet<EntityId>,
+    /// Events since `base_tick`.
+    pub events: Vec<LoggedEvent>,
+}
+
+/// Something that happened in a game, for clients to show players.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    PlayerJoined(EntityId),
+    PlayerLeft(EntityId),
+    /// A player said something.
+    Chat(EntityId, String),
+    /// The server's operator said something.
+    Announcement(String),
+    /// The server is shutting down, for the given reason. No states follow this one.
+    ServerClosing(String),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::PlayerJoined(id) => write!(f, "player {} joined", id),
+            Event::PlayerLeft(id) => write!(f, "player {} left", id),
+            Event::Chat(id, message) => write!(f, "player {}: {}", id, message),
+            Event::Announcement(message) => write!(f, "server: {}", message),
+            Event::ServerClosing(reason) => write!(f, "server closing: {}", reason),
+        }
+    }
+}
+
+/// An event, and the first tick whose state reflects it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    pub tick: u64,
+    pub event: Event,
+}
+
+/// A game state update sent from the server to a client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StateUpdate {
+    /// The entire game state.
+    Full(Box<Game>),
+    /// Only what changed since the last tick the client has seen.
+    Delta(Delta),
+}
+
+/// Returned when a delta's base tick doesn't match the game it is applied to.
+#[derive(Debug, thiserror::Error)]
+#[error("delta from tick {base_tick} applied to tick {current_tick}")]
+pub struct StaleDeltaError {
+    pub base_tick: u64,
+    pub current_tick: u64,
+}
+
+/// Where `start` is `alpha` of the way to `end`, in a world reaching to `bottom_right`.
+fn interpolated(start: &Rectangle, end: &Rectangle, alpha: GameInt, bottom_right: Point) -> Point {
+    let (width, height) = (bottom_right.x, bottom_right.y);
+    let mut delta = end.top_left - start.top_left;
+    // Entities wrap around the edges of the world, so take the short way around.
+    if delta.x.abs() > width / 2. {
+        delta.x -= width.copysign(delta.x);
+    }
+    if delta.y.abs() > height / 2. {
+        delta.y -= height.copysign(delta.y);
+    }
+    let mut interpolated = *start;
+    interpolated.move_(delta * alpha, width, height);
+    interpolated.top_left
+}
+
+/// A slab holding each of `entries` at its key.
+fn slab_from<T: Default>(mut entries: Vec<(usize, T)>) -> Slab<T> {
+    entries.sort_by_key(|&(key, _)| key);
+    entries.dedup_by_key(|&mut (key, _)| key);
+    let mut slab = Slab::with_capacity(entries.last().map_or(0, |&(key, _)| key + 1));
+    let mut fillers = vec![];
+    for (key, value) in entries {
+        // Slab hands out keys in order while none have been removed, so fill the ones we don't
+        // want and free them again afterwards.
+        while slab.vacant_key() < key {
+            fillers.push(slab.insert(T::default()));
+        }
+        slab.insert(value);
+    }
+    for filler in fillers {
+        slab.remove(filler);
+    }
+    slab
+}
+
+/// Inserts `value` at `key`, which must be vacant.
+fn insert_at<T: Default>(slab: &mut Slab<T>, key: usize, value: T) {
+    let mut fillers = vec![];
+    loop {
+        let entry = slab.vacant_entry();
+        if entry.key() == key {
+            entry.insert(value);
+            break;
+        }
+        // Slab hands out vacant keys in its own order, so fill the ones we don't want and
+        // free them again afterwards.
+        fillers.push(entry.key());
+        entry.insert(T::default());
+    }
+    for filler in fillers {
+        slab.remove(filler);
+    }
+}
+
 fn magnitude_of(sign: Option<Sign>) -> GameInt {
     match sign {
         Some(Sign::Positive) => 1.,
@@ -344,6 +546,11 @@ fn magnitude_of(sign: Option<Sign>) -> GameInt {
 
 impl Game {
     pub fn new(bottom_right: Point, square_side_length: GameInt) -> Game {
+        Self::with_seed(bottom_right, square_side_length, rand::thread_rng().gen())
+    }
+
+    /// A new game whose world is the same every time for the same `seed`.
+    pub fn with_seed(bottom_right: Point, square_side_length: GameInt, seed: u64) -> Game {
         let mut game = Game {
             square_side_length,
             bottom_right,
@@ -353,17 +560,37 @@ impl Game {
             moveable: Slab::new(),
             moved_this_action: Slab::new(),
             colors: Slab::new(),
+            names: BTreeMap::new(),
+            afk: BTreeSet::new(),
+            last_active: BTreeMap::new(),
+            events: VecDeque::new(),
             time: 0.,
+            ticks: 0,
+            walls: vec![],
+            spawn_points: vec![],
+            overlaps: vec![],
+            collisions: vec![],
+            timings: TickTimings::default(),
+            grid: Grid::default(),
+            seed,
         };
-        let mut rng = rand::thread_rng();
+        game.scatter_blocks(&mut StdRng::seed_from_u64(seed));
+        game
+    }
+
+    /// Scatters blocks around the world: some swinging, some that can be pushed, and the rest
+    /// fixed in place.
+    fn scatter_blocks(&mut self, rng: &mut impl Rng) {
+        let bottom_right = self.bottom_right;
+        let square_side_length = self.square_side_length;
         for _ in 0..100 {
-            let color = random_color();
+            let color = random_color(rng);
             let square = Rectangle::new(
-                random_point(bottom_right),
+                random_point(rng, bottom_right),
                 square_side_length / 2.,
                 square_side_length / 2.,
             );
-            let id = game.insert_entity(Entity {
+            let id = self.insert_entity(Entity {
                 position: square,
                 velocity: Point::default(),
                 animation: None,
@@ -371,16 +598,16 @@ impl Game {
                 moved_this_action: false,
                 color,
             });
-            game.init_pendulum(id, game.positions[id].top_left + Point::new(-100., 200.));
+            self.init_pendulum(id, self.positions[id].top_left + Point::new(-100., 200.));
         }
         for _ in 0..100 {
-            let color = random_color();
+            let color = random_color(rng);
             let square = Rectangle::new(
-                random_point(bottom_right),
+                random_point(rng, bottom_right),
                 square_side_length / 2.,
                 square_side_length / 2.,
             );
-            game.insert_entity(Entity {
+            self.insert_entity(Entity {
                 position: square,
                 velocity: Point::default(),
                 animation: None,
@@ -389,34 +616,266 @@ impl Game {
                 color,
             });
         }
-        game
     }
 
-    pub fn insert_new_player_square(&mut self) -> EntityId {
+    /// Starts the world over for a new round, with blocks scattered by `seed` and `layout`'s
+    /// walls and spawn points. Players keep their entities, which start from fresh spawns.
+    pub fn new_round(&mut self, seed: u64, layout: &Layout) {
+        self.seed = seed;
+        let rng = &mut StdRng::seed_from_u64(seed);
+        let others: Vec<_> = self
+            .entity_ids()
+            .filter(|&id| !self.is_player(id))
+            .collect();
+        for id in others {
+            self.remove_entity(id);
+        }
+        // The walls were removed with everything else, and their ids may be reused.
+        self.walls.clear();
+        self.scatter_blocks(rng);
+        self.set_layout(layout);
+        let players: Vec<_> = self.players().collect();
+        for id in players {
+            self.positions[id].top_left =
+                self.spawn_points.choose(rng).copied().unwrap_or_default();
+            self.velocities[id] = Point::default();
+            self.colors[id] = random_color(rng);
+        }
+    }
+
+    /// A square for a new player, at one of the spawn points, in a random color. Random from the
+    /// game's seed, so the same game makes the same square.
+    pub fn new_player_square(&self) -> Entity {
+        let seed = self
+            .seed
+            .wrapping_add(self.ticks << 16)
+            .wrapping_add(self.positions.vacant_key() as u64);
+        let rng = &mut StdRng::seed_from_u64(seed);
         let square = Rectangle::new(
-            Point::default(),
+            self.spawn_points.choose(rng).copied().unwrap_or_default(),
             self.square_side_length,
             self.square_side_length,
         );
-        let color = random_color();
-        self.insert_entity(Entity {
+        let color = random_color(rng);
+        Entity {
             position: square,
             velocity: Point::default(),
             animation: None,
             moveable: true,
             moved_this_action: false,
             color,
+        }
+    }
+
+    /// The bottom right corner of the world, whose top left corner is the origin.
+    pub fn world_size(&self) -> Point {
+        self.bottom_right
+    }
+
+    /// The size of players' squares.
+    pub fn square_side_length(&self) -> GameInt {
+        self.square_side_length
+    }
+
+    /// The walls and spawn points the world is laid out with.
+    pub fn layout(&self) -> Layout {
+        Layout {
+            walls: self
+                .walls
+                .iter()
+                .filter(|&&id| self.contains(id))
+                .map(|&id| self.positions[id])
+                .collect(),
+            spawn_points: self.spawn_points.clone(),
+        }
+    }
+
+    /// Replaces the walls and spawn points of the current layout with `layout`'s.
+    pub fn set_layout(&mut self, layout: &Layout) {
+        for id in mem::take(&mut self.walls) {
+            if self.contains(id) {
+                self.remove_entity(id);
+            }
+        }
+        for &wall in &layout.walls {
+            let id = self.insert_entity(Entity {
+                position: wall,
+                velocity: Point::default(),
+                animation: None,
+                moveable: false,
+                moved_this_action: false,
+                color: WALL_COLOR,
+            });
+            self.walls.push(id);
+        }
+        self.spawn_points = layout.spawn_points.clone();
+    }
+
+    pub fn insert_new_player_square(&mut self) -> EntityId {
+        let square = self.new_player_square();
+        self.add_player(square)
+    }
+
+    /// Inserts a player's entity, logging that they joined. They're named `player <id>` until
+    /// they pick a name.
+    pub fn add_player(&mut self, entity: Entity) -> EntityId {
+        let id = self.insert_entity(entity);
+        self.names.insert(id, format!("player {}", id));
+        self.last_active.insert(id, self.time);
+        self.log_event(Event::PlayerJoined(id));
+        id
+    }
+
+    /// Removes a player's entity, logging that they left.
+    pub fn remove_player(&mut self, id: EntityId) {
+        self.remove_entity(id);
+        self.log_event(Event::PlayerLeft(id));
+    }
+
+    /// Names player `id`'s entity.
+    pub fn name_player(&mut self, id: EntityId, name: String) {
+        self.names.insert(id, name);
+    }
+
+    /// The entities of every player, in order.
+    pub fn players(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.names.keys().copied()
+    }
+
+    /// Whether entity `id` is a player's.
+    pub fn is_player(&self, id: EntityId) -> bool {
+        self.names.contains_key(&id)
+    }
+
+    /// Marks player `id` as away from the keyboard or not. Players who are away stop, and other
+    /// entities pass through them until they make an input.
+    pub fn set_afk(&mut self, id: EntityId, afk: bool) {
+        if afk {
+            self.afk.insert(id);
+            self.velocities[id] = Point::default();
+        } else {
+            self.afk.remove(&id);
+        }
+    }
+
+    /// Whether player `id` is away from the keyboard.
+    pub fn is_afk(&self, id: EntityId) -> bool {
+        self.afk.contains(&id)
+    }
+
+    /// How many seconds of game time it's been since player `id` made an input, or joined.
+    pub fn idle_time(&self, id: EntityId) -> f32 {
+        self.last_active
+            .get(&id)
+            .map_or(0., |&last_active| self.time - last_active)
+    }
+
+    /// The transient entities, like projectiles, to remove so that at most `max` are left: the
+    /// ones with the least time left to live.
+    pub fn excess_transients(&self, max: usize) -> Vec<EntityId> {
+        let mut transients: Vec<_> = self
+            .animations
+            .iter()
+            .filter_map(|(id, animation)| match animation {
+                Some(Animation::DisappearAfter { secs }) => Some((id, *secs)),
+                _ => None,
+            })
+            .collect();
+        let excess = transients.len().saturating_sub(max);
+        transients.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+        transients.truncate(excess);
+        transients.into_iter().map(|(id, _)| id).collect()
+    }
+
+    /// The players who joined before the last tick and are still in the game.
+    pub fn joined(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.events
+            .iter()
+            .filter(move |logged| logged.tick == self.ticks)
+            .filter_map(move |logged| match logged.event {
+                Event::PlayerJoined(id) if self.contains(id) => Some(id),
+                _ => None,
+            })
+    }
+
+    /// Player `id`'s name, if they have one.
+    pub fn name(&self, id: EntityId) -> Option<&str> {
+        self.names.get(&id).map(|name| &name[..])
+    }
+
+    /// Moves entity `id` so that its top left corner is at `point`, wrapped around the edges of
+    /// the world like moving entities are.
+    pub fn teleport(&mut self, id: EntityId, point: Point) {
+        let (width, height) = (self.width(), self.height());
+        let position = &mut self.positions[id];
+        position.top_left = Point::default();
+        position.move_(point, width, height);
+    }
+
+    /// Colors entity `id` `rgb`, opaque.
+    pub fn set_color(&mut self, id: EntityId, [r, g, b]: [GameInt; 3]) {
+        self.colors[id] = [r, g, b, 1.];
+    }
+
+    /// Logs that player `id` said `message`.
+    pub fn chat(&mut self, id: EntityId, message: String) {
+        self.log_event(Event::Chat(id, message));
+    }
+
+    /// Logs that the server's operator said `message`.
+    pub fn announce(&mut self, message: String) {
+        self.log_event(Event::Announcement(message));
+    }
+
+    /// Logs that the server is shutting down, for `reason`.
+    pub fn announce_closing(&mut self, reason: String) {
+        self.log_event(Event::ServerClosing(reason));
+    }
+
+    /// Why the server shut down, if it has.
+    pub fn closing_reason(&self) -> Option<&str> {
+        self.events.iter().find_map(|logged| match &logged.event {
+            Event::ServerClosing(reason) => Some(&reason[..]),
+            _ => None,
         })
     }
 
+    fn log_event(&mut self, event: Event) {
+        // Events between ticks show up in the state after the next tick.
+        let tick = self.ticks + 1;
+        self.events.push_back(LoggedEvent { tick, event });
+        if self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    /// The latest events, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &LoggedEvent> {
+        self.events.iter()
+    }
+
+    /// The game without players `ids` or its events, to restore later when no one is playing.
+    pub fn snapshot(&self, ids: impl IntoIterator<Item = EntityId>) -> Game {
+        let mut game = self.clone();
+        for id in ids {
+            game.remove_entity(id);
+        }
+        game.events.clear();
+        game
+    }
+
     pub fn remove_entity(&mut self, entity: EntityId) {
         info!("Removing entity {}", entity);
+        self.grid.remove(entity, self.positions[entity]);
         self.positions.remove(entity);
         self.velocities.remove(entity);
         self.animations.remove(entity);
         self.moveable.remove(entity);
         self.moved_this_action.remove(entity);
         self.colors.remove(entity);
+        self.names.remove(&entity);
+        self.afk.remove(&entity);
+        self.last_active.remove(&entity);
     }
 
     pub fn insert_entity(&mut self, entity: Entity) -> EntityId {
@@ -433,6 +892,230 @@ impl Game {
         entity_id
     }
 
+    pub fn entity(&self, id: EntityId) -> Entity {
+        Entity {
+            position: self.positions[id],
+            velocity: self.velocities[id],
+            animation: self.animations[id],
+            moveable: self.moveable[id],
+            moved_this_action: self.moved_this_action[id],
+            color: self.colors[id],
+        }
+    }
+
+    /// Overwrites the entity with the given id, inserting it if it doesn't exist.
+    fn set_entity(&mut self, id: EntityId, entity: Entity) {
+        if self.positions.contains(id) {
+            self.positions[id] = entity.position;
+            self.velocities[id] = entity.velocity;
+            self.animations[id] = entity.animation;
+            self.moveable[id] = entity.moveable;
+            self.moved_this_action[id] = entity.moved_this_action;
+            self.colors[id] = entity.color;
+        } else {
+            insert_at(&mut self.positions, id, entity.position);
+            insert_at(&mut self.velocities, id, entity.velocity);
+            insert_at(&mut self.animations, id, entity.animation);
+            insert_at(&mut self.moveable, id, entity.moveable);
+            insert_at(&mut self.moved_this_action, id, entity.moved_this_action);
+            insert_at(&mut self.colors, id, entity.color);
+        }
+    }
+
+    /// Whether entity `id` exists.
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.positions.contains(id)
+    }
+
+    /// The ids of every entity in the game.
+    pub fn entity_ids(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.positions.iter().map(|(id, _)| id)
+    }
+
+    /// A copy of this game with only entity `pov_id` and the entities within `distance` of it
+    /// along each axis, so that clients aren't sent entities too far away to see. Entities coming
+    /// into and going out of view show up as updated and removed in deltas between such copies.
+    pub fn visible_to(&self, pov_id: EntityId, distance: Point) -> Game {
+        self.with_only(&self.visible_ids(pov_id, distance))
+    }
+
+    /// Entity `pov_id` and the entities within `distance` of it along each axis, in order, as
+    /// seen by [`Game::visible_to`].
+    pub fn visible_ids(&self, pov_id: EntityId, distance: Point) -> Vec<EntityId> {
+        // The distance between two coordinates, the short way around the world.
+        fn wrapped_distance(a: GameInt, b: GameInt, size: GameInt) -> GameInt {
+            let distance = (a - b).abs() % size;
+            distance.min(size - distance)
+        }
+        // Nothing is visible to an entity that's gone.
+        match self.positions.get(pov_id) {
+            Some(pov) => self
+                .positions
+                .iter()
+                .filter(|&(id, position)| {
+                    id == pov_id
+                        || (wrapped_distance(position.top_left.x, pov.top_left.x, self.width())
+                            <= distance.x
+                            && wrapped_distance(position.top_left.y, pov.top_left.y, self.height())
+                                <= distance.y)
+                })
+                .map(|(id, _)| id)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// A copy of this game with only the entities `visible`.
+    pub fn with_only(&self, visible: &[EntityId]) -> Game {
+        // Only the visible entities are copied, rather than copying the whole game and removing
+        // the rest.
+        fn pick<T: Clone + Default>(slab: &Slab<T>, ids: &[EntityId]) -> Slab<T> {
+            slab_from(ids.iter().map(|&id| (id, slab[id].clone())).collect())
+        }
+        Game {
+            square_side_length: self.square_side_length,
+            bottom_right: self.bottom_right,
+            positions: pick(&self.positions, visible),
+            velocities: pick(&self.velocities, visible),
+            animations: pick(&self.animations, visible),
+            moveable: pick(&self.moveable, visible),
+            moved_this_action: pick(&self.moved_this_action, visible),
+            colors: pick(&self.colors, visible),
+            names: visible
+                .iter()
+                .filter_map(|id| Some((*id, self.names.get(id)?.clone())))
+                .collect(),
+            afk: self.afk.clone(),
+            last_active: BTreeMap::new(),
+            events: self.events.clone(),
+            time: self.time,
+            ticks: self.ticks,
+            walls: self.walls.clone(),
+            spawn_points: self.spawn_points.clone(),
+            overlaps: vec![],
+            collisions: vec![],
+            timings: TickTimings::default(),
+            grid: Grid::default(),
+            seed: self.seed,
+        }
+    }
+
+    /// The number of ticks this game has been running for.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// How many seconds of game time this game has been running for.
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Returns the changes needed to bring `base` up to date with this game.
+    pub fn delta_since(&self, base: &Game) -> Delta {
+        let updated = self
+            .positions
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|&id| !base.positions.contains(id) || base.entity(id) != self.entity(id))
+            .map(|id| (id, self.entity(id)))
+            .collect();
+        let removed = base
+            .positions
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|&id| !self.positions.contains(id))
+            .collect();
+        let named = self
+            .names
+            .iter()
+            .filter(|&(id, name)| base.names.get(id) != Some(name))
+            .map(|(&id, name)| (id, name.clone()))
+            .collect();
+        let events = self
+            .events
+            .iter()
+            .filter(|event| event.tick > base.ticks)
+            .cloned()
+            .collect();
+        Delta {
+            base_tick: base.ticks,
+            tick: self.ticks,
+            time: self.time,
+            updated,
+            removed,
+            named,
+            afk: self.afk.clone(),
+            events,
+        }
+    }
+
+    pub fn apply_delta(&mut self, delta: Delta) -> Result<(), StaleDeltaError> {
+        if delta.base_tick != self.ticks {
+            return Err(StaleDeltaError {
+                base_tick: delta.base_tick,
+                current_tick: self.ticks,
+            });
+        }
+        for id in delta.removed {
+            if self.positions.contains(id) {
+                self.remove_entity(id);
+            }
+        }
+        for (id, entity) in delta.updated {
+            self.set_entity(id, entity);
+        }
+        self.names.extend(delta.named);
+        self.afk = delta.afk;
+        self.events.extend(delta.events);
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.time = delta.time;
+        self.ticks = delta.tick;
+        Ok(())
+    }
+
+    pub fn apply_update(&mut self, update: StateUpdate) -> Result<(), StaleDeltaError> {
+        match update {
+            StateUpdate::Full(game) => {
+                *self = *game;
+                Ok(())
+            }
+            StateUpdate::Delta(delta) => self.apply_delta(delta),
+        }
+    }
+
+    /// Moves every entity except `skip` to where it is `alpha` of the way from its position in
+    /// `from` to its position in `to`. Entities missing from either are left where they are.
+    pub fn interpolate(&mut self, from: &Game, to: &Game, alpha: GameInt, skip: Option<EntityId>) {
+        let bottom_right = self.bottom_right;
+        for (id, position) in self.positions.iter_mut() {
+            if Some(id) == skip {
+                continue;
+            }
+            if let (Some(start), Some(end)) = (from.positions.get(id), to.positions.get(id)) {
+                position.top_left = interpolated(start, end, alpha, bottom_right);
+            }
+        }
+    }
+
+    /// Where every entity is now.
+    pub fn positions(&self) -> Positions {
+        Positions(self.positions.clone())
+    }
+
+    /// Moves every entity to where it is `alpha` of the way from its position in `from` to where
+    /// it is now. Entities missing from `from` are left where they are.
+    pub fn interpolate_from(&mut self, from: &Positions, alpha: GameInt) {
+        let bottom_right = self.bottom_right;
+        for (id, position) in self.positions.iter_mut() {
+            if let Some(start) = from.0.get(id) {
+                let end = *position;
+                position.top_left = interpolated(start, &end, alpha, bottom_right);
+            }
+        }
+    }
+
     fn entity_overlap(&self, entity_segments: &[Rectangle], other: EntityId) -> Point {
         entity_segments
             .iter()
@@ -448,6 +1131,47 @@ impl Game {
             .fold(Point::default(), |first, second| first.max(second))
     }
 
+    /// The entities besides `entity` that could run into it as it moves by `delta`: those within
+    /// `delta` of it, since no entity is pushed further than that. They're returned in order, so
+    /// collisions are resolved the same way every time.
+    fn collision_candidates(&self, entity: EntityId, delta: Point) -> Vec<EntityId> {
+        // The extra unit covers rounding as entities wrap around the edges of the world.
+        let reach = delta.abs() + Point::new(1., 1.);
+        let mut area = self.positions[entity];
+        area.move_(reach * -1., self.width(), self.height());
+        area.width += reach.x * 2.;
+        area.height += reach.y * 2.;
+        let mut area_segments = vec![];
+        area.segments(self.bottom_right, |r| area_segments.push(r));
+        let mut candidates = self.grid.query(area);
+        candidates.retain(|&id| {
+            if id == entity || !self.positions.contains(id) {
+                return false;
+            }
+            let overlap = self.entity_overlap(&area_segments, id);
+            overlap.x > 0. && overlap.y > 0.
+        });
+        candidates
+    }
+
+    /// Places the entities in the grid, which is kept up to date as they move until the next
+    /// time it's built.
+    fn build_grid(&mut self) {
+        self.grid = Grid::build(
+            self.bottom_right,
+            self.square_side_length,
+            self.positions.iter().map(|(id, &position)| (id, position)),
+        );
+    }
+
+    /// Moves `entity` by `delta`, wrapping around the edges of the world.
+    fn shift(&mut self, entity: EntityId, delta: Point) {
+        let position = &mut self.positions[entity];
+        self.grid.remove(entity, *position);
+        position.move_(delta, self.bottom_right.x, self.bottom_right.y);
+        self.grid.insert(entity, *position);
+    }
+
     pub fn start_move_entity(&mut self, entity: EntityId, delta: Point) -> Point {
         for (_, moved) in &mut self.moved_this_action {
             *moved = false;
@@ -457,21 +1181,13 @@ impl Game {
 
     pub fn move_entity(&mut self, entity: EntityId, delta: Point) -> Point {
         self.moved_this_action[entity] = true;
-        let game_width = self.width();
-        let game_height = self.height();
         let bottom_right = self.bottom_right;
-        self.positions[entity].move_(delta, game_width, game_height);
+        self.shift(entity, delta);
         let mut entity_segments = vec![];
         self.positions[entity].segments(bottom_right, |r| entity_segments.push(r));
         let mut overlap = Point::default();
-        for id in 0..self.positions.capacity() {
-            if !self.positions.contains(id) {
-                continue;
-            }
-            if id == entity {
-                continue;
-            }
-            if self.moved_this_action[id] {
+        for id in self.collision_candidates(entity, delta) {
+            if self.moved_this_action[id] || self.afk.contains(&id) {
                 continue;
             }
 
@@ -479,6 +1195,15 @@ impl Game {
             if entity_overlap.x == 0. || entity_overlap.y == 0. {
                 continue;
             }
+            if !self.collisions.contains(&(entity, id)) {
+                self.collisions.push((entity, id));
+            }
+            for entity_segment in &entity_segments {
+                let overlaps = &mut self.overlaps;
+                self.positions[id].segments(bottom_right, |r| {
+                    overlaps.extend(entity_segment.overlap(&r));
+                });
+            }
             if self.moveable[id] {
                 let to_move = entity_overlap.min(delta.abs()).copysign(delta);
                 self.move_entity(id, to_move);
@@ -489,12 +1214,14 @@ impl Game {
         }
         if overlap.x > 0. && overlap.y > 0. {
             let to_move = overlap.min(delta.abs()).copysign(delta) * -1.;
-            self.positions[entity].move_(to_move, game_width, game_height);
+            self.shift(entity, to_move);
         }
         delta - overlap
     }
 
     pub fn process_input(&mut self, id: EntityId, input: Input) {
+        self.last_active.insert(id, self.time);
+        self.afk.remove(&id);
         match input {
             Input::Move(component, sign) => {
                 *component.extract(&mut self.velocities[id]) = MOVE_VELOCITY * magnitude_of(sign);
@@ -532,13 +1259,21 @@ impl Game {
         time_in_current_bucket: &mut f32,
         ticks_in_current_bucket: &mut i32,
     ) {
+        let started = Instant::now();
         self.time += dt;
+        self.ticks += 1;
+        self.overlaps.clear();
+        self.collisions.clear();
+        self.build_grid();
         *time_in_current_bucket += dt;
         *ticks_in_current_bucket += 1;
         if *time_in_current_bucket >= 0.25 {
             *time_in_current_bucket = 0.;
             *ticks_in_current_bucket = 0;
         }
+        // Every entity moves before any is animated, so that each phase is timed once.
+        let moving = Instant::now();
+        let mut deltas = Vec::with_capacity(self.velocities.len());
         for entity in 0..self.velocities.capacity() {
             if !self.velocities.contains(entity) {
                 debug!("Skipping {}", entity);
@@ -549,50 +1284,154 @@ impl Game {
                 delta += self.start_move_entity(entity, self.velocities[entity].at_y(0.) * dt);
                 delta += self.start_move_entity(entity, self.velocities[entity].at_x(0.) * dt);
             }
-            match self.animations[entity] {
-                Some(Animation::Pendulum {
-                    ref mut distance,
-                    max_distance,
-                }) => {
-                    *distance += delta;
-                    // I don't know what this is doing but it's kind of interesting.
-                    self.velocities[entity] = (max_distance * PENDULUM_FORCE).sqrt()
-                        * ((PENDULUM_FORCE / max_distance).sqrt() * self.time).sin();
-                }
-                Some(Animation::DisappearAfter { ref mut secs }) => {
-                    *secs -= dt;
-                    if *secs <= 0. {
-                        self.remove_entity(entity);
+            deltas.push((entity, delta));
+        }
+        let collisions = moving.elapsed();
+        // Entities are animated independently of each other, so they're animated in parallel,
+        // then updated in order so that the game comes out the same however the work was split.
+        let (animations, time) = (&self.animations, self.time);
+        let animated: Vec<_> = deltas
+            .par_iter()
+            .with_min_len(MIN_ENTITIES_PER_TASK)
+            .filter_map(|&(entity, delta)| {
+                Some((entity, animations[entity]?.after_tick(delta, dt, time)))
+            })
+            .collect();
+        for (entity, animated) in animated {
+            match animated {
+                Some((animation, velocity)) => {
+                    self.animations[entity] = Some(animation);
+                    if let Some(velocity) = velocity {
+                        self.velocities[entity] = velocity;
                     }
                 }
-                None => {}
+                None => self.remove_entity(entity),
             }
         }
+        self.timings = TickTimings {
+            collisions,
+            movement: started
+                .elapsed()
+                .checked_sub(collisions)
+                .unwrap_or_default(),
+        };
+    }
+
+    /// Which entities ran into which during the last tick, the moving one first, where the game
+    /// was ticked.
+    pub fn collisions(&self) -> &[(EntityId, EntityId)] {
+        &self.collisions
     }
 
-    pub fn draw(&mut self, pov_id: EntityId, c: Context, g: &mut G2d) {
-        let pov = self.positions[pov_id].top_left;
-        let pov_width = self.positions[pov_id].width;
-        let pov_height = self.positions[pov_id].height;
+    /// How long the phases of the last tick took, where the game was ticked.
+    pub fn tick_timings(&self) -> TickTimings {
+        self.timings
+    }
+
+    /// Draws the game in a view centered on `center`, with entities drawn in `style`.
+    #[cfg(feature = "client")]
+    pub fn draw(&self, center: Point, style: Style, c: Context, g: &mut G2d) {
         let [x, y] = c.get_view_size();
-        for (i, &(mut entity)) in self.positions.iter() {
-            entity.top_left.x = (entity.top_left.x + self.width() + 0.5 as GameInt * x as GameInt
-                - pov.x
-                - pov_width / 2.)
-                % self.width();
-            entity.top_left.y = (entity.top_left.y + self.height() + 0.5 as GameInt * y as GameInt
-                - pov.y
-                - pov_height / 2.)
-                % self.height();
-            entity.segments(self.bottom_right, |rect| {
-                rectangle(
-                    self.colors[i],
-                    <_ as Into<types::Rectangle<f64>>>::into(rect),
-                    c.transform,
-                    g,
-                );
+        let view_size = Point::new(x as GameInt, y as GameInt);
+        let outline = rectangle::Rectangle::new_border([0., 0., 0., 1.], 1.);
+        // Every entity's color is remapped, not just those in view, so that colors don't change
+        // as the view moves.
+        let remap = style
+            .palette
+            .remap(self.colors.iter().map(|(_, &color)| color));
+        self.for_each_visible(center, view_size, |rect, color| {
+            let rect = <_ as Into<[f64; 4]>>::into(rect);
+            rectangle(remap.get(color), rect, c.transform, g);
+            if style.outlines {
+                outline.draw(rect, &c.draw_state, c.transform, g);
+            }
+        });
+    }
+
+    /// Draws what the debug overlay shows over the game, in a view centered on `center`: the
+    /// segments each entity is split into, arrows for their velocities, and where entities
+    /// overlapped during the last tick.
+    #[cfg(feature = "client")]
+    pub fn draw_debug(&self, center: Point, c: Context, g: &mut G2d) {
+        let [x, y] = c.get_view_size();
+        let view_size = Point::new(x as GameInt, y as GameInt);
+        let outline = rectangle::Rectangle::new_border([0., 0., 1., 1.], 0.5);
+        self.for_each_visible(center, view_size, |rect, _| {
+            outline.draw(rect, &c.draw_state, c.transform, g);
+        });
+        for mut overlap in self.overlaps.iter().copied() {
+            overlap.top_left = self.in_view(overlap.top_left, center, view_size);
+            overlap.segments(self.bottom_right, |rect| {
+                rectangle([1., 0., 0., 0.5], rect, c.transform, g);
             });
         }
+        let arrow = Line::new([1., 0., 0., 1.], 0.5);
+        for (id, &velocity) in self.velocities.iter() {
+            if velocity.is_origin() {
+                continue;
+            }
+            let entity = &self.positions[id];
+            let from = self.in_view(entity.top_left, center, view_size)
+                + Point::new(entity.width / 2., entity.height / 2.);
+            let to = from + velocity * DEBUG_ARROW_SECONDS;
+            let line = [
+                f64::from(from.x),
+                f64::from(from.y),
+                f64::from(to.x),
+                f64::from(to.y),
+            ];
+            arrow.draw_arrow(line, 4., &c.draw_state, c.transform, g);
+        }
+    }
+
+    /// Calls `f` with every entity's id and the top left corner of the entity, in a view of
+    /// `view_size` centered on `center`.
+    pub fn for_each_id(&self, center: Point, view_size: Point, mut f: impl FnMut(Point, EntityId)) {
+        for (id, entity) in self.positions.iter() {
+            f(self.in_view(entity.top_left, center, view_size), id);
+        }
+    }
+
+    /// Where `point` in the game is in a view of `view_size` centered on `center`, wrapping
+    /// around the edges of the game.
+    fn in_view(&self, point: Point, center: Point, view_size: Point) -> Point {
+        Point::new(
+            (point.x + self.width() + 0.5 * view_size.x - center.x) % self.width(),
+            (point.y + self.height() + 0.5 * view_size.y - center.y) % self.height(),
+        )
+    }
+
+    /// Calls `f` with every entity and its color, positioned for a view of `view_size` centered
+    /// on `center`. Entities that wrap around the edge of the game are split into pieces.
+    pub fn for_each_visible(
+        &self,
+        center: Point,
+        view_size: Point,
+        mut f: impl FnMut(Rectangle, [GameInt; 4]),
+    ) {
+        for (i, &(mut entity)) in self.positions.iter() {
+            entity.top_left.x =
+                (entity.top_left.x + self.width() + 0.5 * view_size.x - center.x) % self.width();
+            entity.top_left.y =
+                (entity.top_left.y + self.height() + 0.5 * view_size.y - center.y) % self.height();
+            entity.segments(self.bottom_right, |rect| f(rect, self.colors[i]));
+        }
+    }
+
+    /// Calls `f` with every named player's name and where to draw it, centered just above their
+    /// entity, in a view of `view_size` centered on `center`.
+    pub fn for_each_name(&self, center: Point, view_size: Point, mut f: impl FnMut(Point, &str)) {
+        for (&id, name) in &self.names {
+            let entity = match self.positions.get(id) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let x =
+                (entity.top_left.x + self.width() + 0.5 * view_size.x - center.x) % self.width();
+            let y =
+                (entity.top_left.y + self.height() + 0.5 * view_size.y - center.y) % self.height();
+            f(Point::new(x + entity.width / 2., y), name);
+        }
     }
 
     pub fn width(&self) -> GameInt {
@@ -691,8 +1530,8 @@ impl Rectangle {
     }
 }
 
-impl Into<types::Rectangle<f64>> for Rectangle {
-    fn into(self) -> types::Rectangle<f64> {
+impl Into<[f64; 4]> for Rectangle {
+    fn into(self) -> [f64; 4] {
         [
             self.top_left.x as f64,
             self.top_left.y as f64,
@@ -744,3 +1583,231 @@ fn rectangle_move() {
     rect.move_(Point::new(-5., -5.), 10., 10.);
     assert_eq!(rect, Rectangle::new(Point::new(5., 5.), 5., 5.));
 }
+
+#[test]
+fn game_apply_delta() {
+    let mut base = Game::new(Point::new(1000., 500.), 50.);
+    base.name_player(3, String::from("adam"));
+    let mut next = base.clone();
+    let player = next.insert_new_player_square();
+    next.name_player(player, String::from("tim"));
+    next.remove_player(3);
+    next.process_input(player, Input::Move(Component::X, Some(Sign::Positive)));
+    next.tick(0.1, &mut 0., &mut 0);
+
+    let mut client = base.clone();
+    client.apply_delta(next.delta_since(&base)).ok().unwrap();
+    assert_eq!(client.ticks(), next.ticks());
+    assert!(!client.positions.contains(3));
+    assert_eq!(client.name(3), None);
+    assert_eq!(client.name(player), Some("tim"));
+    for (id, _) in next.positions.iter() {
+        assert_eq!(client.entity(id), next.entity(id));
+    }
+    assert_eq!(client.positions.len(), next.positions.len());
+    let events: Vec<_> = client.events().map(|event| event.event.clone()).collect();
+    assert_eq!(
+        events,
+        vec![Event::PlayerJoined(player), Event::PlayerLeft(3)]
+    );
+    assert!(client.apply_delta(next.delta_since(&base)).is_err());
+}
+
+#[test]
+fn game_interpolate_wraps() {
+    let mut from = Game {
+        bottom_right: Point::new(100., 100.),
+        ..Game::default()
+    };
+    let entity = Entity {
+        position: Rectangle::new(Point::new(90., 50.), 5., 5.),
+        velocity: Point::default(),
+        animation: None,
+        moveable: false,
+        moved_this_action: false,
+        color: [0.; 4],
+    };
+    let id = from.insert_entity(entity);
+    let mut to = from.clone();
+    to.positions[id].top_left = Point::new(10., 60.);
+
+    let mut game = to.clone();
+    game.interpolate(&from, &to, 0.5, Some(id + 1));
+    assert_eq!(game.positions[id].top_left, Point::new(0., 55.));
+
+    let mut game = to.clone();
+    game.interpolate_from(&from.positions(), 0.5);
+    assert_eq!(game.positions[id].top_left, Point::new(0., 55.));
+
+    let mut game = to.clone();
+    game.interpolate(&from, &to, 0.5, Some(id));
+    assert_eq!(game.positions[id].top_left, Point::new(10., 60.));
+}
+
+#[test]
+fn game_visible_to_wraps() {
+    let mut game = Game {
+        bottom_right: Point::new(1000., 100.),
+        ..Game::default()
+    };
+    let mut insert_at = |x| {
+        game.insert_entity(Entity {
+            position: Rectangle::new(Point::new(x, 50.), 5., 5.),
+            velocity: Point::default(),
+            animation: None,
+            moveable: false,
+            moved_this_action: false,
+            color: [0.; 4],
+        })
+    };
+    let pov = insert_at(50.);
+    let near = insert_at(150.);
+    let wrapped = insert_at(950.);
+    let far = insert_at(500.);
+
+    let base = game.visible_to(pov, Point::new(100., 100.));
+    let mut ids: Vec<_> = base.entity_ids().collect();
+    ids.sort();
+    assert_eq!(ids, vec![pov, near, wrapped]);
+
+    game.positions[far].top_left.x = 100.;
+    game.positions[near].top_left.x = 300.;
+    let delta = game
+        .visible_to(pov, Point::new(100., 100.))
+        .delta_since(&base);
+    let updated: Vec<_> = delta.updated.iter().map(|&(id, _)| id).collect();
+    assert_eq!(updated, vec![far]);
+    assert_eq!(delta.removed, vec![near]);
+}
+
+#[test]
+fn game_records_overlaps_for_debugging() {
+    let mut game = Game {
+        bottom_right: Point::new(100., 100.),
+        ..Game::default()
+    };
+    let mut insert_at = |x| {
+        game.insert_entity(Entity {
+            position: Rectangle::new(Point::new(x, 50.), 10., 10.),
+            velocity: Point::default(),
+            animation: None,
+            moveable: false,
+            moved_this_action: false,
+            color: [0.; 4],
+        })
+    };
+    let moving = insert_at(10.);
+    insert_at(20.);
+
+    game.start_move_entity(moving, Point::new(4., 0.));
+    assert_eq!(
+        game.overlaps,
+        vec![Rectangle::new(Point::new(20., 50.), 4., 10.)]
+    );
+    game.tick(0.1, &mut 0., &mut 0);
+    assert!(game.overlaps.is_empty());
+}
+
+#[test]
+fn game_finds_excess_transients() {
+    let mut game = Game {
+        bottom_right: Point::new(100., 100.),
+        ..Game::default()
+    };
+    let mut insert_for = |secs| {
+        game.insert_entity(Entity {
+            position: Rectangle::new(Point::default(), 10., 10.),
+            velocity: Point::default(),
+            animation: secs.map(|secs| Animation::DisappearAfter { secs }),
+            moveable: false,
+            moved_this_action: false,
+            color: [0.; 4],
+        })
+    };
+    insert_for(None);
+    let long = insert_for(Some(3.));
+    let short = insert_for(Some(1.));
+    let middle = insert_for(Some(2.));
+
+    assert_eq!(game.excess_transients(3), vec![]);
+    assert_eq!(game.excess_transients(1), vec![short, middle]);
+    assert_eq!(game.excess_transients(0), vec![short, middle, long]);
+}
+
+#[test]
+fn inputs_bring_players_back() {
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let player = game.insert_new_player_square();
+    game.tick(2., &mut 0., &mut 0);
+    assert_eq!(game.idle_time(player), 2.);
+
+    game.set_afk(player, true);
+    assert!(game.is_afk(player));
+    game.process_input(player, Input::Move(Component::X, Some(Sign::Positive)));
+    assert!(!game.is_afk(player));
+    assert_eq!(game.idle_time(player), 0.);
+}
+
+#[test]
+fn collision_candidates_are_within_reach() {
+    let mut game = Game {
+        bottom_right: Point::new(100., 100.),
+        ..Game::default()
+    };
+    let mut insert_at = |x, y| {
+        game.insert_entity(Entity {
+            position: Rectangle::new(Point::new(x, y), 10., 10.),
+            velocity: Point::default(),
+            animation: None,
+            moveable: true,
+            moved_this_action: false,
+            color: [0.; 4],
+        })
+    };
+    let mover = insert_at(0., 0.);
+    let ahead = insert_at(15., 0.);
+    let far = insert_at(50., 50.);
+    // Across the left edge of the world.
+    let behind = insert_at(92., 0.);
+    game.build_grid();
+
+    let candidates = game.collision_candidates(mover, Point::new(6., 0.));
+    assert_eq!(candidates, vec![ahead, behind]);
+    assert!(!candidates.contains(&far));
+}
+
+#[test]
+fn parallel_animation_matches_serial() {
+    let mut game = Game::with_seed(Point::new(5000., 5000.), 50., 7);
+    for i in 0..MIN_ENTITIES_PER_TASK * 4 {
+        let position = Point::new((i % 64) as GameInt * 70., (i / 64) as GameInt * 70.);
+        let id = game.insert_entity(Entity {
+            position: Rectangle::new(position, 10., 10.),
+            velocity: Point::default(),
+            animation: None,
+            moveable: false,
+            moved_this_action: false,
+            color: [0.; 4],
+        });
+        game.init_pendulum(id, position + Point::new(-100., 200.));
+    }
+    let tick = |threads| {
+        let mut game = game.clone();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            for _ in 0..10 {
+                game.tick(0.01, &mut 0., &mut 0);
+            }
+        });
+        game
+    };
+    let (serial, parallel) = (tick(1), tick(4));
+    let ids: Vec<_> = serial.entity_ids().collect();
+    assert_eq!(ids, parallel.entity_ids().collect::<Vec<_>>());
+    for id in ids {
+        assert_eq!(serial.entity(id), parallel.entity(id));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::{error::Error, metrics, status::Status, transport};
 use futures::{
     future::{self, AbortHandle},
     prelude::*,
@@ -5,30 +6,466 @@ use futures::{
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{hash_map, HashMap},
+    collections::{btree_map, BTreeMap, VecDeque},
     io, mem,
     net::SocketAddr,
-    sync::{Arc, RwLock},
-    time::Duration,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use tarpc::{
     context,
     server::{self, Channel},
 };
-use tokio::time;
-use tokio_serde::formats::Json;
+use tokio::{sync::watch, time};
 
 #[derive(Debug)]
 struct GameData {
     name: String,
+    region: Option<String>,
+    tags: Vec<String>,
+    motd: Option<String>,
+    locked: bool,
     abort_health_check: AbortHandle,
     version: u32,
+    /// The game's status as of the latest successful health check.
+    status: Option<Status>,
+    /// How long the latest successful health check took.
+    round_trip: Option<Duration>,
+    /// Whether the game was registered with heartbeats, rather than being health checked.
+    heartbeats: bool,
+    /// When the game last sent a heartbeat, if it was registered with heartbeats.
+    last_heartbeat: Option<Instant>,
+    health: Health,
+    reachability: Reachability,
+    /// What subscribers were last told about the game.
+    listed: Option<GameInfo>,
+}
+
+impl GameData {
+    fn info(&self) -> GameInfo {
+        GameInfo {
+            name: self.name.clone(),
+            region: self.region.clone(),
+            tags: self.tags.clone(),
+            motd: self.motd.clone(),
+            locked: self.locked,
+            status: self.status.clone(),
+            health: self.health,
+            reachability: self.reachability,
+        }
+    }
+
+    /// Whether a player with `preferences` could join the game, as far as the game list knows.
+    fn matches(&self, preferences: &MatchPreferences) -> bool {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return false,
+        };
+        !self.locked
+            && self.health == Health::Healthy
+            && self.reachability == Reachability::Reachable
+            && status.players < status.max_players
+            && status.protocol_version == preferences.protocol_version
+            && preferences.tags.iter().all(|tag| self.tags.contains(tag))
+    }
+}
+
+/// How a registered game fared in its latest health checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    /// The game hasn't been checked yet.
+    Unknown,
+    Healthy,
+    /// The game failed this many checks in a row. It's unregistered once it fails too many.
+    Failing {
+        successive_failures: u32,
+    },
+}
+
+/// Whether players can connect to a registered game, as far as the game list can tell by
+/// connecting to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reachability {
+    /// The game list hasn't connected to the game yet.
+    Unknown,
+    Reachable,
+    /// The game list couldn't connect to the game, like when it's behind NAT or a firewall.
+    /// Players on the same network might still be able to.
+    Unreachable,
+}
+
+/// A registered game, as listed by [`crate::Games::list`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GameInfo {
+    /// The name the game registered with.
+    pub name: String,
+    /// Where the game said it's hosted, like `us-east`.
+    pub region: Option<String>,
+    /// What the game said describes it, like `casual`.
+    pub tags: Vec<String>,
+    /// The game's message of the day.
+    pub motd: Option<String>,
+    /// Whether players need a join token to join the game.
+    pub locked: bool,
+    /// The game's status as of the latest successful health check, or `None` if none has
+    /// succeeded yet.
+    pub status: Option<Status>,
+    pub health: Health,
+    pub reachability: Reachability,
+}
+
+/// What a game is registered with, by [`crate::GameRegistration::register`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub name: String,
+    /// Where the game is hosted, like `us-east`.
+    pub region: Option<String>,
+    /// What describes the game, like `casual`.
+    pub tags: Vec<String>,
+    /// A message describing the game to players, shown in listings and when they join.
+    pub motd: Option<String>,
+    /// Whether players need a join token to join the game.
+    pub locked: bool,
+    /// Whether the game sends heartbeats instead of being health checked. The game list
+    /// connects to games to health check them, which fails for games behind NAT.
+    pub heartbeats: bool,
+}
+
+/// Why a game couldn't be registered.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum RegistrationError {
+    /// The game list requires a registration key, and the game hasn't presented the right one.
+    #[error("the game list requires a registration key")]
+    NotAuthenticated,
+}
+
+/// Where a page of [`crate::Games::list`] picks up from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageToken(SocketAddr);
+
+/// Some of the registered games, ordered by address.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GamePage {
+    pub games: Vec<(SocketAddr, GameInfo)>,
+    /// Where the next page starts, or `None` if this is the last page.
+    pub next_page_token: Option<PageToken>,
+}
+
+/// The most games listed in a page, however many are asked for.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// What a player is looking for in a game, for [`crate::Games::find_match`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MatchPreferences {
+    /// The [`crate::PROTOCOL_VERSION`] the player speaks. Only games speaking it are matched.
+    pub protocol_version: u32,
+    /// Games hosted in this region are preferred, as they're likely closest to the player.
+    pub region: Option<String>,
+    /// Only games with all of these tags are matched.
+    pub tags: Vec<String>,
+}
+
+/// A change to the registered games, as reported by [`crate::Games::subscribe`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ListingChange {
+    /// The game was registered, or what the game list knows about it changed.
+    Updated(SocketAddr, GameInfo),
+    Unregistered(SocketAddr),
+}
+
+/// The changes to the registered games up to `version`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ListingChanges {
+    /// Pass this to the next call to [`crate::Games::subscribe`] to get the changes after these.
+    pub version: u64,
+    /// Whether the changes start from an empty listing, in which case each registered game is
+    /// included as updated, a page at a time. Happens when the subscriber is new or fell too far
+    /// behind.
+    pub reset: bool,
+    pub changes: Vec<ListingChange>,
+    /// Pass this to the next call to [`crate::Games::subscribe`] to get the next page of
+    /// registered games, if there's more of them to send after a reset.
+    pub next_page_token: Option<PageToken>,
+}
+
+impl ListingChanges {
+    /// Applies the changes to `games`, which should hold the games as of the previous changes.
+    pub fn apply(self, games: &mut BTreeMap<SocketAddr, GameInfo>) {
+        if self.reset {
+            games.clear();
+        }
+        for change in self.changes {
+            match change {
+                ListingChange::Updated(addr, info) => {
+                    games.insert(addr, info);
+                }
+                ListingChange::Unregistered(addr) => {
+                    games.remove(&addr);
+                }
+            }
+        }
+    }
+}
+
+/// How many changes are kept for subscribers that are catching up. Subscribers further behind
+/// are sent every registered game instead.
+const MAX_LOGGED_CHANGES: usize = 1000;
+
+/// The registered games, along with the recent changes to them.
+#[derive(Debug)]
+struct Listing {
+    games: BTreeMap<SocketAddr, GameData>,
+    /// Bumped by each change.
+    version: u64,
+    /// The most recent changes, each with the version it bumped the listing to.
+    changes: VecDeque<(u64, ListingChange)>,
+    /// Told the version after each change.
+    changed: watch::Sender<u64>,
+}
+
+impl Listing {
+    fn new() -> (Listing, watch::Receiver<u64>) {
+        let (changed, changed_rx) = watch::channel(0);
+        let listing = Listing {
+            games: BTreeMap::new(),
+            version: 0,
+            changes: VecDeque::new(),
+            changed,
+        };
+        (listing, changed_rx)
+    }
+
+    /// Records that the game at `addr` was registered or changed, unless only its uptime and
+    /// tick rate did, which change with every status. Subscribers can [`crate::Games::list`] the
+    /// games for those.
+    fn updated(&mut self, addr: SocketAddr) {
+        let data = match self.games.get_mut(&addr) {
+            Some(data) => data,
+            None => return,
+        };
+        let info = data.info();
+        let steady = |info: &GameInfo| {
+            let mut info = info.clone();
+            if let Some(status) = &mut info.status {
+                status.uptime_secs = 0;
+                status.tick_rate = 0.;
+            }
+            info
+        };
+        if data.listed.as_ref().map(steady) == Some(steady(&info)) {
+            return;
+        }
+        data.listed = Some(info.clone());
+        self.record(ListingChange::Updated(addr, info));
+    }
+
+    /// Records that the game at `addr` was unregistered.
+    fn unregistered(&mut self, addr: SocketAddr) {
+        self.record(ListingChange::Unregistered(addr));
+    }
+
+    fn record(&mut self, change: ListingChange) {
+        self.version += 1;
+        if self.changes.len() == MAX_LOGGED_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back((self.version, change));
+        let _ = self.changed.broadcast(self.version);
+    }
+
+    /// Up to `page_size` registered games, starting after `page_token` if given, and the token
+    /// for the next page if there's more.
+    fn page(
+        &self,
+        page_token: Option<PageToken>,
+        page_size: usize,
+    ) -> (Vec<(SocketAddr, GameInfo)>, Option<PageToken>) {
+        let start = match page_token {
+            Some(PageToken(addr)) => Bound::Excluded(addr),
+            None => Bound::Unbounded,
+        };
+        let mut page = self
+            .games
+            .range((start, Bound::Unbounded))
+            .map(|(addr, data)| (*addr, data.info()));
+        let games: Vec<_> = page.by_ref().take(page_size).collect();
+        let next_page_token = match (page.next(), games.last()) {
+            (Some(_), Some(&(last, _))) => Some(PageToken(last)),
+            _ => None,
+        };
+        (games, next_page_token)
+    }
+
+    /// Returns the changes after version `since`, or `None` if there are none yet. After a reset,
+    /// the registered games are returned a page at a time, starting after `page_token`, and the
+    /// version stays `since` until the last page. The subscriber catches up on whatever changed
+    /// while paging from there.
+    fn changes_since(
+        &self,
+        since: Option<u64>,
+        page_token: Option<PageToken>,
+    ) -> Option<ListingChanges> {
+        let oldest_logged = self.changes.front().map_or(self.version + 1, |&(v, _)| v);
+        let reset = |version, reset, page_token| {
+            let (games, next_page_token) = self.page(page_token, MAX_PAGE_SIZE);
+            Some(ListingChanges {
+                version,
+                reset,
+                changes: games
+                    .into_iter()
+                    .map(|(addr, info)| ListingChange::Updated(addr, info))
+                    .collect(),
+                next_page_token,
+            })
+        };
+        match since {
+            Some(since) if page_token.is_some() => reset(since, false, page_token),
+            Some(since) if since == self.version => None,
+            // Versions newer than the listing's come from before the game list restarted.
+            Some(since) if since < self.version && since + 1 >= oldest_logged => {
+                Some(ListingChanges {
+                    version: self.version,
+                    reset: false,
+                    changes: self
+                        .changes
+                        .iter()
+                        .filter(|&&(version, _)| version > since)
+                        .map(|(_, change)| change.clone())
+                        .collect(),
+                    next_page_token: None,
+                })
+            }
+            _ => reset(self.version, true, None),
+        }
+    }
+}
+
+/// How the game list checks that registered games are still up.
+#[derive(Clone, Debug)]
+pub struct HealthCheckSettings {
+    /// How long to wait between checks of each game.
+    pub interval: Duration,
+    /// How long a game has to answer each check.
+    pub timeout: Duration,
+    /// How many checks in a row a game can fail before it's unregistered.
+    pub max_failures: u32,
+    /// How long a game registered with heartbeats can go without sending one before it's
+    /// unregistered.
+    pub heartbeat_ttl: Duration,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        HealthCheckSettings {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+            max_failures: 3,
+            heartbeat_ttl: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Records how version `version` of the game at `addr` fared in a health check, along with its
+/// status and how long the check took if it succeeded.
+fn record_health_check(
+    listing: &RwLock<Listing>,
+    addr: SocketAddr,
+    version: u32,
+    health: Health,
+    checked: Option<(Status, Duration)>,
+) {
+    let mut listing = listing.write().unwrap();
+    if let Some(data) = listing.games.get_mut(&addr) {
+        if data.version == version {
+            data.health = health;
+            if let Some((status, round_trip)) = checked {
+                data.status = Some(status);
+                data.round_trip = Some(round_trip);
+                data.reachability = Reachability::Reachable;
+            }
+            listing.updated(addr);
+        }
+    }
+}
+
+/// Connects to the game at `addr` and pings it, the way a player would, within `timeout`.
+async fn ping(
+    addr: SocketAddr,
+    transport_config: &transport::Config,
+    timeout: Duration,
+) -> io::Result<()> {
+    let transport = time::timeout(timeout, transport::connect(&addr, transport_config)).await??;
+    let game_client =
+        crate::GameClient::new(tarpc::client::Config::default(), transport).spawn()?;
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + timeout;
+    game_client.ping(ctx).await?;
+    Ok(())
+}
+
+/// Records whether version `version` of the game at `addr` is reachable.
+fn record_reachability(
+    listing: &RwLock<Listing>,
+    addr: SocketAddr,
+    version: u32,
+    reachability: Reachability,
+) {
+    let mut listing = listing.write().unwrap();
+    if let Some(data) = listing.games.get_mut(&addr) {
+        if data.version == version {
+            data.reachability = reachability;
+            listing.updated(addr);
+        }
+    }
+}
+
+/// Stops health checking the registered games, so that nothing's left running when the game list
+/// exits.
+fn shutdown(listing: &RwLock<Listing>) {
+    let listing = listing.read().unwrap();
+    info!(
+        "Shutting down with {} games registered",
+        listing.games.len()
+    );
+    for data in listing.games.values() {
+        data.abort_health_check.abort();
+    }
+}
+
+/// Waits until version `version` of the game at `addr` goes `ttl` without sending a heartbeat, or
+/// is no longer registered.
+async fn expire(listing: &RwLock<Listing>, addr: SocketAddr, version: u32, ttl: Duration) {
+    let mut last_heard = Instant::now();
+    loop {
+        time::delay_until((last_heard + ttl).into()).await;
+        let last_heartbeat = match listing.read().unwrap().games.get(&addr) {
+            Some(data) if data.version == version => data.last_heartbeat,
+            _ => return,
+        };
+        match last_heartbeat {
+            Some(last_heartbeat) if last_heartbeat > last_heard => last_heard = last_heartbeat,
+            _ => return,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct GameList {
     peer: SocketAddr,
-    games: Arc<RwLock<HashMap<SocketAddr, GameData>>>,
+    listing: Arc<RwLock<Listing>>,
+    /// Told the listing's version after each change.
+    listing_changed: watch::Receiver<u64>,
+    /// Used when connecting to registered games.
+    transport_config: transport::Config,
+    health_check: HealthCheckSettings,
+    /// The key games must register with. If `None`, any game can register.
+    registration_key: Option<String>,
+    /// Whether the client presented the registration key, or none is required.
+    authenticated: Arc<AtomicBool>,
 }
 
 mod markers {
@@ -37,23 +474,59 @@ mod markers {
 }
 
 impl GameList {
-    pub async fn run(registration_addr: SocketAddr, game_list_addr: SocketAddr) -> io::Result<()> {
-        let games = Arc::new(RwLock::new(HashMap::new()));
-        let (r1, r2) = future::join(
+    /// Serves the game list until it's interrupted with ctrl-c or, on Unix, `SIGTERM`, at which
+    /// point it stops health checking games and closes every connection.
+    pub async fn run(
+        registration_addr: SocketAddr,
+        game_list_addr: SocketAddr,
+        transport_config: transport::Config,
+        health_check: HealthCheckSettings,
+        registration_key: Option<String>,
+    ) -> Result<(), Error> {
+        let (listing, listing_changed) = Listing::new();
+        let listing = Arc::new(RwLock::new(listing));
+        let serving = future::join(
             Self::run_server(
                 registration_addr,
-                games.clone(),
+                listing.clone(),
+                listing_changed.clone(),
+                transport_config.clone(),
+                health_check.clone(),
+                registration_key.clone(),
                 crate::GameRegistration::serve,
             ),
-            Self::run_server(game_list_addr, games, crate::Games::serve),
+            Self::run_server(
+                game_list_addr,
+                listing.clone(),
+                listing_changed,
+                transport_config,
+                health_check,
+                registration_key,
+                crate::Games::serve,
+            ),
         )
-        .await;
-        r1.and(r2)
+        .map(|(r1, r2)| r1.and(r2).map_err(Error::from));
+        let stopped = crate::server::stopped();
+        match future::select(Box::pin(serving), Box::pin(stopped)).await {
+            future::Either::Left((served, _)) => served,
+            future::Either::Right((Ok(()), _)) => {
+                shutdown(&listing);
+                Ok(())
+            }
+            future::Either::Right((Err(e), serving)) => {
+                error!("Failed to listen for signals: {}", e);
+                serving.await
+            }
+        }
     }
 
     async fn run_server<Req, Resp, Serve>(
         server_addr: SocketAddr,
-        games: Arc<RwLock<HashMap<SocketAddr, GameData>>>,
+        listing: Arc<RwLock<Listing>>,
+        listing_changed: watch::Receiver<u64>,
+        transport_config: transport::Config,
+        health_check: HealthCheckSettings,
+        registration_key: Option<String>,
         serve: impl FnMut(GameList) -> Serve + Clone,
     ) -> io::Result<()>
     where
@@ -62,19 +535,30 @@ impl GameList {
         Resp: Serialize + Send + 'static + Unpin,
         for<'a> Serve::Fut<'a>: markers::Send<'a>,
     {
-        tarpc::serde_transport::tcp::listen(&server_addr, Json::default)
+        transport::listen(&server_addr)
             .await?
             // Ignore accept errors.
             .filter_map(|r| future::ready(r.ok()))
-            .map(server::BaseChannel::with_defaults)
-            .map(move |channel| {
-                let games = games.clone();
+            .map(move |stream| {
+                let listing = listing.clone();
+                let listing_changed = listing_changed.clone();
+                let transport_config = transport_config.clone();
+                let health_check = health_check.clone();
+                let registration_key = registration_key.clone();
                 let mut serve = serve.clone();
                 async move {
+                    let peer = stream.peer_addr()?;
+                    let transport = transport::accept(stream, &transport_config).await?;
                     let server = GameList {
-                        peer: channel.get_ref().peer_addr()?,
-                        games,
+                        peer,
+                        listing,
+                        listing_changed,
+                        transport_config,
+                        health_check,
+                        authenticated: Arc::new(AtomicBool::new(registration_key.is_none())),
+                        registration_key,
                     };
+                    let channel = server::BaseChannel::with_defaults(transport);
                     channel.execute(serve(server)).await;
                     Ok::<_, io::Error>(())
                 }
@@ -89,53 +573,102 @@ impl GameList {
 
 #[tarpc::server]
 impl crate::GameRegistration for GameList {
+    async fn authenticate(&mut self, _: &mut context::Context, key: String) -> bool {
+        let accepted = self.registration_key.as_ref().map_or(true, |k| *k == key);
+        if accepted {
+            self.authenticated.store(true, Ordering::SeqCst);
+        } else {
+            warn!("Rejected registration key from {}", self.peer);
+        }
+        accepted
+    }
+
     async fn register(
         &mut self,
         _: &mut context::Context,
         port: u16,
-        name: String,
-    ) -> Option<String> {
+        registration: Registration,
+    ) -> Result<Option<String>, RegistrationError> {
+        if !self.authenticated.load(Ordering::SeqCst) {
+            return Err(RegistrationError::NotAuthenticated);
+        }
+        let Registration {
+            name,
+            region,
+            tags,
+            motd,
+            locked,
+            heartbeats,
+        } = registration;
         let mut game_addr = self.peer;
         game_addr.set_port(port);
-        let games = self.games.clone();
+        let listing = self.listing.clone();
+        let transport_config = self.transport_config.clone();
+        let health_check = self.health_check.clone();
         let name2 = name.clone();
         let (abort_health_check, abort_registration) = future::AbortHandle::new_pair();
-        let (previous_game, version) = match self.games.write().unwrap().entry(game_addr) {
-            hash_map::Entry::Occupied(mut entry) => {
+        let mut registered = self.listing.write().unwrap();
+        let (previous_game, version) = match registered.games.entry(game_addr) {
+            btree_map::Entry::Occupied(mut entry) => {
                 entry.get_mut().abort_health_check.abort();
                 entry.get_mut().abort_health_check = abort_health_check;
                 let previous_game_name = mem::replace(&mut entry.get_mut().name, name2);
+                entry.get_mut().region = region;
+                entry.get_mut().tags = tags;
+                entry.get_mut().motd = motd;
+                entry.get_mut().locked = locked;
                 entry.get_mut().version += 1;
+                entry.get_mut().status = None;
+                entry.get_mut().round_trip = None;
+                entry.get_mut().heartbeats = heartbeats;
+                entry.get_mut().last_heartbeat = None;
+                entry.get_mut().health = Health::Unknown;
+                entry.get_mut().reachability = Reachability::Unknown;
                 (Some(previous_game_name), entry.get().version)
             }
-            hash_map::Entry::Vacant(entry) => {
+            btree_map::Entry::Vacant(entry) => {
                 entry.insert(GameData {
                     version: 0,
                     name: name2,
+                    region,
+                    tags,
+                    motd,
+                    locked,
                     abort_health_check,
+                    status: None,
+                    round_trip: None,
+                    heartbeats,
+                    last_heartbeat: None,
+                    health: Health::Unknown,
+                    reachability: Reachability::Unknown,
+                    listed: None,
                 });
+                metrics::REGISTERED_GAMES.inc();
                 (None, 0)
             }
         };
+        registered.updated(game_addr);
+        drop(registered);
         let health_check = future::Abortable::new(
             async move {
                 struct UnregisterGame<'a> {
                     addr: SocketAddr,
                     name: &'a str,
-                    games: Arc<RwLock<HashMap<SocketAddr, GameData>>>,
+                    listing: Arc<RwLock<Listing>>,
                     version: u32,
                 }
                 impl<'a> Drop for UnregisterGame<'a> {
                     fn drop(&mut self) {
-                        if let hash_map::Entry::Occupied(entry) =
-                            self.games.write().unwrap().entry(self.addr)
-                        {
+                        let mut listing = self.listing.write().unwrap();
+                        if let btree_map::Entry::Occupied(entry) = listing.games.entry(self.addr) {
                             if entry.get().version == self.version {
                                 info!(
                                     "Unregistering game {} v{}, \"{}\"",
                                     self.addr, self.version, self.name
                                 );
                                 entry.remove();
+                                listing.unregistered(self.addr);
+                                metrics::REGISTERED_GAMES.dec();
                             } else {
                                 info!(
                                     "Game {} version is different (v{} != v{}); not unregistering",
@@ -150,20 +683,43 @@ impl crate::GameRegistration for GameList {
                 let _unregister = UnregisterGame {
                     addr: game_addr,
                     name: &name,
-                    games,
+                    listing: listing.clone(),
                     version,
                 };
-                let transport =
-                    match tarpc::serde_transport::tcp::connect(&game_addr, Json::default()).await {
-                        Ok(transport) => transport,
-                        Err(e) => {
-                            warn!(
-                                "Failed to connect to game {}, \"{}\": {}",
-                                game_addr, name, e
-                            );
-                            return;
-                        }
+                if heartbeats {
+                    // Games sending heartbeats might be behind NAT, so check that players can
+                    // reach them once, but don't unregister them if not.
+                    let check_reachability = async {
+                        let reachability =
+                            match ping(game_addr, &transport_config, health_check.timeout).await {
+                                Ok(()) => Reachability::Reachable,
+                                Err(e) => {
+                                    info!("Game {}, \"{}\" is unreachable: {}", game_addr, name, e);
+                                    Reachability::Unreachable
+                                }
+                            };
+                        record_reachability(&listing, game_addr, version, reachability);
                     };
+                    let expire = expire(&listing, game_addr, version, health_check.heartbeat_ttl);
+                    future::join(check_reachability, expire).await;
+                    metrics::HEALTH_CHECK_FAILURES.inc();
+                    info!(
+                        "Game {}, \"{}\" stopped sending heartbeats",
+                        game_addr, name
+                    );
+                    return;
+                }
+                let transport = match transport::connect(&game_addr, &transport_config).await {
+                    Ok(transport) => transport,
+                    Err(e) => {
+                        metrics::HEALTH_CHECK_FAILURES.inc();
+                        warn!(
+                            "Failed to connect to game {}, \"{}\": {}",
+                            game_addr, name, e
+                        );
+                        return;
+                    }
+                };
                 let game_client =
                     match crate::GameClient::new(tarpc::client::Config::default(), transport)
                         .spawn()
@@ -177,20 +733,37 @@ impl crate::GameRegistration for GameList {
                             return;
                         }
                     };
-                let mut successive_errors = 0;
+                let mut successive_failures = 0;
                 loop {
-                    time::delay_for(Duration::from_secs(5)).await;
-                    match game_client.ping(context::current()).await {
-                        Ok(()) => successive_errors = 0,
+                    time::delay_for(health_check.interval).await;
+                    let mut ctx = context::current();
+                    ctx.deadline = SystemTime::now() + health_check.timeout;
+                    let sent = Instant::now();
+                    match game_client.status(ctx).await {
+                        Ok(status) => {
+                            successive_failures = 0;
+                            record_health_check(
+                                &listing,
+                                game_addr,
+                                version,
+                                Health::Healthy,
+                                Some((status, sent.elapsed())),
+                            );
+                        }
                         Err(e) => {
+                            metrics::HEALTH_CHECK_FAILURES.inc();
                             info!("Unresponsive game {}, \"{}\": {}", game_addr, name, e);
                             if e.kind() == io::ErrorKind::ConnectionReset {
                                 return;
                             }
-                            successive_errors += 1;
-                            if successive_errors >= 3 {
+                            successive_failures += 1;
+                            if successive_failures >= health_check.max_failures {
                                 return;
                             }
+                            let health = Health::Failing {
+                                successive_failures,
+                            };
+                            record_health_check(&listing, game_addr, version, health, None);
                         }
                     }
                 }
@@ -198,13 +771,40 @@ impl crate::GameRegistration for GameList {
             abort_registration,
         );
         tokio::spawn(health_check);
-        previous_game
+        Ok(previous_game)
+    }
+
+    async fn heartbeat(&mut self, _: &mut context::Context, port: u16, status: Status) -> bool {
+        if !self.authenticated.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut game_addr = self.peer;
+        game_addr.set_port(port);
+        let mut listing = self.listing.write().unwrap();
+        match listing.games.get_mut(&game_addr) {
+            // Games that are health checked send heartbeats only to check they're registered.
+            Some(data) if !data.heartbeats => return true,
+            Some(data) => {
+                data.last_heartbeat = Some(Instant::now());
+                data.status = Some(status);
+                data.health = Health::Healthy;
+            }
+            None => return false,
+        }
+        listing.updated(game_addr);
+        true
     }
 
     async fn unregister(&mut self, _: &mut context::Context, port: u16) -> Option<String> {
+        if !self.authenticated.load(Ordering::SeqCst) {
+            return None;
+        }
         let mut game_addr = self.peer;
         game_addr.set_port(port);
-        self.games.write().unwrap().remove(&game_addr).map(|data| {
+        let mut listing = self.listing.write().unwrap();
+        listing.games.remove(&game_addr).map(|data| {
+            listing.unregistered(game_addr);
+            metrics::REGISTERED_GAMES.dec();
             data.abort_health_check.abort();
             data.name
         })
@@ -213,12 +813,124 @@ impl crate::GameRegistration for GameList {
 
 #[tarpc::server]
 impl crate::Games for GameList {
-    async fn list(&mut self, _: &mut context::Context) -> HashMap<SocketAddr, String> {
-        self.games
-            .read()
-            .unwrap()
+    async fn list(
+        &mut self,
+        _: &mut context::Context,
+        page_token: Option<PageToken>,
+        page_size: usize,
+    ) -> GamePage {
+        let page_size = page_size.min(MAX_PAGE_SIZE).max(1);
+        let (games, next_page_token) = self.listing.read().unwrap().page(page_token, page_size);
+        GamePage {
+            games,
+            next_page_token,
+        }
+    }
+
+    async fn find_match(
+        &mut self,
+        _: &mut context::Context,
+        preferences: MatchPreferences,
+    ) -> Option<SocketAddr> {
+        let listing = self.listing.read().unwrap();
+        listing
+            .games
             .iter()
-            .map(|(addr, data)| (*addr, data.name.clone()))
-            .collect()
+            .filter(|(_, data)| data.matches(&preferences))
+            // Without a way to measure latency to the player, prefer games in the player's region,
+            // then games closest to the game list.
+            .min_by_key(|(_, data)| {
+                let other_region =
+                    preferences.region.is_some() && data.region != preferences.region;
+                // Games sending heartbeats aren't checked, so how far away they are is unknown.
+                (other_region, data.round_trip.is_none(), data.round_trip)
+            })
+            .map(|(addr, _)| *addr)
+    }
+
+    async fn subscribe(
+        &mut self,
+        _: &mut context::Context,
+        since: Option<u64>,
+        page_token: Option<PageToken>,
+    ) -> ListingChanges {
+        loop {
+            let changes = self
+                .listing
+                .read()
+                .unwrap()
+                .changes_since(since, page_token);
+            if let Some(changes) = changes {
+                return changes;
+            }
+            if self.listing_changed.recv().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
     }
 }
+
+#[test]
+fn listing_changes_are_paged_and_only_real_changes_are_recorded() {
+    let (mut listing, _) = Listing::new();
+    let status = |uptime_secs, players| Status {
+        name: String::from("game"),
+        players,
+        max_players: 8,
+        tick_rate: 200.,
+        uptime_secs,
+        protocol_version: crate::PROTOCOL_VERSION,
+    };
+    let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+    for port in 0..=MAX_PAGE_SIZE as u16 {
+        let data = GameData {
+            name: String::from("game"),
+            region: None,
+            tags: vec![],
+            motd: None,
+            locked: false,
+            abort_health_check: AbortHandle::new_pair().0,
+            version: 0,
+            status: Some(status(0, 0)),
+            round_trip: None,
+            heartbeats: true,
+            last_heartbeat: None,
+            health: Health::Healthy,
+            reachability: Reachability::Unknown,
+            listed: None,
+        };
+        listing.games.insert(addr(port), data);
+        listing.updated(addr(port));
+    }
+
+    let mut games = BTreeMap::new();
+    let first = listing.changes_since(None, None).unwrap();
+    assert!(first.reset);
+    assert_eq!(first.changes.len(), MAX_PAGE_SIZE);
+    let version = first.version;
+    let page_token = first.next_page_token;
+    first.apply(&mut games);
+    let last = listing.changes_since(Some(version), page_token).unwrap();
+    assert!(!last.reset);
+    assert_eq!(last.version, version);
+    assert_eq!(last.next_page_token, None);
+    last.apply(&mut games);
+    assert_eq!(games.len(), MAX_PAGE_SIZE + 1);
+    assert_eq!(listing.changes_since(Some(version), None), None);
+
+    // Only the uptime changed.
+    listing.games.get_mut(&addr(0)).unwrap().status = Some(status(60, 0));
+    listing.updated(addr(0));
+    assert_eq!(listing.changes_since(Some(version), None), None);
+
+    listing.games.get_mut(&addr(0)).unwrap().status = Some(status(60, 1));
+    listing.updated(addr(0));
+    listing.games.remove(&addr(1));
+    listing.unregistered(addr(1));
+    let changes = listing.changes_since(Some(version), None).unwrap();
+    assert!(!changes.reset);
+    assert_eq!(changes.changes.len(), 2);
+    changes.apply(&mut games);
+    assert_eq!(games[&addr(0)].status.as_ref().unwrap().players, 1);
+    assert!(!games.contains_key(&addr(1)));
+}
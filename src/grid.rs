@@ -0,0 +1,132 @@
+//! The broad phase of collision detection: a uniform grid over the world, so that an entity
+//! moving only has to be checked against the entities in the cells it reaches, rather than every
+//! entity in the game.
+
+use crate::game::{EntityId, GameInt, Point, Rectangle};
+
+/// How many squares wide and tall each cell is. Bigger cells hold more entities; smaller ones
+/// make each entity take up more cells.
+const CELL_SQUARES: GameInt = 2.;
+
+/// Which entities overlap each cell of the world. Built once per tick and kept up to date as
+/// entities move during it.
+#[derive(Debug, Default)]
+pub(crate) struct Grid {
+    bottom_right: Point,
+    cell_size: GameInt,
+    columns: usize,
+    rows: usize,
+    /// Each cell's entities, row by row.
+    cells: Vec<Vec<EntityId>>,
+}
+
+impl Clone for Grid {
+    /// Grids are only used while a game ticks, and rebuilt at the start of every tick, so copies
+    /// of a game start with an empty one rather than paying to copy it.
+    fn clone(&self) -> Self {
+        Grid::default()
+    }
+}
+
+impl Grid {
+    /// Places `entities` in a world reaching to `bottom_right`, with cells sized for squares of
+    /// `square_side_length`.
+    pub(crate) fn build(
+        bottom_right: Point,
+        square_side_length: GameInt,
+        entities: impl Iterator<Item = (EntityId, Rectangle)>,
+    ) -> Self {
+        let cell_size = (square_side_length * CELL_SQUARES).max(1.);
+        let columns = ((bottom_right.x / cell_size).ceil() as usize).max(1);
+        let rows = ((bottom_right.y / cell_size).ceil() as usize).max(1);
+        let mut grid = Grid {
+            bottom_right,
+            cell_size,
+            columns,
+            rows,
+            cells: vec![vec![]; columns * rows],
+        };
+        for (id, position) in entities {
+            grid.insert(id, position);
+        }
+        grid
+    }
+
+    pub(crate) fn insert(&mut self, id: EntityId, position: Rectangle) {
+        for cell in self.cells_under(position) {
+            self.cells[cell].push(id);
+        }
+    }
+
+    /// Takes `id` out of the cells under `position`, where it was inserted.
+    pub(crate) fn remove(&mut self, id: EntityId, position: Rectangle) {
+        for cell in self.cells_under(position) {
+            let cell = &mut self.cells[cell];
+            if let Some(i) = cell.iter().position(|&other| other == id) {
+                cell.swap_remove(i);
+            }
+        }
+    }
+
+    /// The entities in the cells under `area`, in order, each once.
+    pub(crate) fn query(&self, area: Rectangle) -> Vec<EntityId> {
+        let mut ids: Vec<EntityId> = self
+            .cells_under(area)
+            .into_iter()
+            .flat_map(|cell| self.cells[cell].iter().copied())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// The indices of the cells under `area`, wrapping around the edges of the world.
+    fn cells_under(&self, area: Rectangle) -> Vec<usize> {
+        if self.cells.is_empty() {
+            return vec![];
+        }
+        let mut cells = vec![];
+        area.segments(self.bottom_right, |segment| {
+            let (left, top) = self.cell_at(segment.top_left);
+            let (right, bottom) = self.cell_at(segment.bottom_right());
+            for row in top..=bottom {
+                for column in left..=right {
+                    cells.push(row * self.columns + column);
+                }
+            }
+        });
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    }
+
+    fn cell_at(&self, point: Point) -> (usize, usize) {
+        let cell = |coordinate: GameInt, cells: usize| {
+            ((coordinate / self.cell_size).max(0.) as usize).min(cells - 1)
+        };
+        (cell(point.x, self.columns), cell(point.y, self.rows))
+    }
+}
+
+#[test]
+fn grid_finds_entities_in_reach() {
+    let square = |x, y| Rectangle::new(Point::new(x, y), 10., 10.);
+    let mut grid = Grid::build(
+        Point::new(100., 100.),
+        10.,
+        vec![
+            (0, square(0., 0.)),
+            (1, square(50., 50.)),
+            (2, square(92., 0.)),
+        ]
+        .into_iter(),
+    );
+    // Across the right edge of the world.
+    assert_eq!(grid.query(square(95., 0.)), vec![0, 2]);
+    assert_eq!(grid.query(square(45., 45.)), vec![1]);
+
+    grid.remove(1, square(50., 50.));
+    grid.insert(1, square(5., 5.));
+    assert!(grid.query(square(45., 45.)).is_empty());
+    assert_eq!(grid.query(square(0., 0.)), vec![0, 1]);
+}
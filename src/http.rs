@@ -0,0 +1,35 @@
+//! Tiny HTTP endpoints, for tools that can't speak tarpc.
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
+};
+use std::{convert::Infallible, io, net::SocketAddr};
+
+/// Serves HTTP on `addr`, answering each request with what `respond` returns for it.
+pub(crate) async fn serve<F>(addr: SocketAddr, respond: F) -> io::Result<()>
+where
+    F: Fn(&Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let respond = respond.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    hyper::Server::try_bind(&addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(make_service)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+pub(crate) fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,82 @@
+//! Statistics about rendering and the connection to the server, drawn over the game.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Counts frames rendered over the last second.
+#[derive(Debug, Default)]
+pub struct FrameRate {
+    frames: VecDeque<Instant>,
+}
+
+impl FrameRate {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    /// Records a frame rendered at `now`.
+    pub fn frame(&mut self, now: Instant) {
+        self.frames.push_back(now);
+        while let Some(&oldest) = self.frames.front() {
+            if now.duration_since(oldest) < Self::WINDOW {
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    /// Frames rendered in the second before the latest one.
+    pub fn fps(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+/// What the HUD shows. Statistics that haven't been measured yet are left out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Hud {
+    pub fps: usize,
+    /// The average round-trip time to the server.
+    pub rtt: Option<Duration>,
+    /// How many ticks the server runs per second.
+    pub tick_rate: Option<f64>,
+    /// How long ago the latest game state was received from the server.
+    pub snapshot_age: Option<Duration>,
+}
+
+impl fmt::Display for Hud {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} fps", self.fps)?;
+        if let Some(rtt) = self.rtt {
+            write!(f, " | ping {}ms", rtt.as_millis())?;
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            write!(f, " | {:.0} ticks/s", tick_rate)?;
+        }
+        if let Some(snapshot_age) = self.snapshot_age {
+            write!(f, " | state {}ms old", snapshot_age.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn frame_rate_counts_last_second() {
+    let start = Instant::now();
+    let mut frame_rate = FrameRate::default();
+    for millis in &[0, 400, 800, 1200] {
+        frame_rate.frame(start + Duration::from_millis(*millis));
+    }
+    assert_eq!(frame_rate.fps(), 3);
+}
+
+#[test]
+fn hud_leaves_out_unmeasured_statistics() {
+    let hud = Hud {
+        fps: 60,
+        rtt: Some(Duration::from_millis(35)),
+        tick_rate: None,
+        snapshot_age: Some(Duration::from_millis(12)),
+    };
+    assert_eq!(hud.to_string(), "60 fps | ping 35ms | state 12ms old");
+}
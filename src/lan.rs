@@ -0,0 +1,67 @@
+//! Finding games on the local network without the game list.
+//!
+//! Game servers started with [`crate::server::Settings::lan`] broadcast an [`Announcement`] to
+//! [`PORT`] every [`ANNOUNCE_INTERVAL`]. Clients listen on that port for a while to [`discover`]
+//! them, and join at the address an announcement came from.
+
+use crate::{datagram, status::Status};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time};
+
+/// The port announcements are broadcast to.
+pub const PORT: u16 = 23305;
+/// How often game servers announce themselves.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Broadcast by game servers on the local network.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    /// The port the game accepts players on.
+    pub port: u16,
+    /// Whether players need a join token to join the game.
+    pub locked: bool,
+    pub status: Status,
+}
+
+/// Broadcasts what `announcement` returns every [`ANNOUNCE_INTERVAL`], forever.
+pub(crate) async fn announce<F>(announcement: F) -> io::Result<()>
+where
+    F: Fn() -> Announcement,
+{
+    let mut socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    loop {
+        let bytes = datagram::encode(&announcement())?;
+        // The network might come back, so keep trying.
+        if let Err(e) = socket.send_to(&bytes, (Ipv4Addr::BROADCAST, PORT)).await {
+            warn!("Failed to announce game on the local network: {}", e);
+        }
+        time::delay_for(ANNOUNCE_INTERVAL).await;
+    }
+}
+
+/// Listens for announcements for `duration`, returning each game heard from and the address to
+/// join it at, ordered by address.
+pub async fn discover(duration: Duration) -> io::Result<Vec<(SocketAddr, Announcement)>> {
+    let mut socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).await?;
+    let deadline = time::Instant::now() + duration;
+    let mut games = BTreeMap::new();
+    let mut buf = vec![0; datagram::MAX_DATAGRAM_SIZE];
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        match datagram::decode::<Announcement>(&buf[..len]) {
+            Ok(announcement) => {
+                games.insert(SocketAddr::new(from.ip(), announcement.port), announcement);
+            }
+            Err(e) => debug!("Ignoring invalid announcement from {}: {}", from, e),
+        }
+    }
+    Ok(games.into_iter().collect())
+}
@@ -1,27 +1,134 @@
 #![allow(incomplete_features)]
-#![feature(generic_associated_types, type_alias_impl_trait)]
-
-use std::{collections::HashMap, net::SocketAddr};
+#![feature(type_alias_impl_trait)]
 
+pub mod access;
+pub mod admin_log;
+pub mod bans;
+pub(crate) mod bots;
+pub mod browser;
+pub mod camera;
+pub mod chat;
 pub mod client;
+pub(crate) mod clock;
+pub(crate) mod console;
+pub(crate) mod datagram;
+pub mod diagnostics;
+pub mod error;
 pub mod game;
 pub mod game_list;
+pub(crate) mod grid;
+pub(crate) mod http;
+pub mod hud;
+pub mod lan;
+pub mod map;
+pub mod menu;
+pub mod metrics;
+pub mod mode;
+pub mod moderators;
+pub mod palette;
+pub(crate) mod rate_limit;
+pub(crate) mod registrar;
+pub mod replay;
+pub(crate) mod rollback;
+pub mod schedule;
+#[cfg(feature = "client")]
+pub(crate) mod screenshot;
+pub mod script;
 pub mod server;
+pub(crate) mod session;
+pub mod simulation;
+pub mod snapshot;
+pub(crate) mod states;
+pub mod stats;
+pub mod status;
+#[cfg(feature = "client")]
+pub mod text;
+pub mod tls;
+pub mod transport;
+pub mod tui;
+#[cfg(feature = "client")]
+pub mod window;
+
+pub use crate::{
+    client::Connection,
+    clock::ServerTime,
+    datagram::{ClientDatagram, ServerDatagram},
+    error::Error,
+    game::{Delta, EntityId, Event, Input, LoggedEvent, StateUpdate},
+    server::{JoinError, ServerHandle, Welcome},
+};
+use std::net::SocketAddr;
+
+/// The version of the protocol spoken between clients, game servers and the game list. Bumped
+/// whenever a change breaks compatibility with older versions.
+pub const PROTOCOL_VERSION: u32 = 2;
 
 #[tarpc::service]
 pub trait Game {
-    async fn ping();
+    /// Returns the server's current tick and wall-clock time.
+    async fn ping() -> clock::ServerTime;
+    /// Describes the game, for the game list.
+    async fn status() -> status::Status;
+    /// Presents a join token, returning whether it was accepted. Servers started with join tokens
+    /// close connections that make other calls, besides pings, before authenticating.
+    async fn authenticate(token: String) -> bool;
+    /// Asks to play, returning the player's session id and the game's message of the day. If
+    /// `resume` is the id of a session whose player disconnected recently, the player gets that
+    /// session's entity back, and keeps its name. Otherwise the player is shown as `name`, or a
+    /// name made up for them. Games with a password only let in players who give it. Until this
+    /// succeeds, other calls besides pings and authenticating may close the connection.
+    async fn join(
+        resume: Option<u64>,
+        name: Option<String>,
+        password: Option<String>,
+    ) -> Result<server::Welcome, server::JoinError>;
     async fn get_entity_id() -> game::EntityId;
-    async fn push_input(input: game::Input);
-    async fn poll_game_state() -> Box<game::Game>;
+    /// Applies `input` if `sequence` is greater than that of every input applied so far on this
+    /// connection, and returns the sequence of the latest applied input. `tick` is the tick of the
+    /// game the player saw when making the input; recent enough inputs are applied at that tick.
+    async fn push_input(sequence: u64, tick: u64, input: game::Input) -> u64;
+    /// Waits for the next game state. If `last_seen_tick` matches the state last returned to
+    /// this client, only the changes since then are sent; otherwise the full state is sent.
+    async fn poll_game_state(last_seen_tick: Option<u64>) -> game::StateUpdate;
+    /// Opens a datagram channel for game states and inputs, returning the token to send in each
+    /// datagram. See the `datagram` module.
+    async fn open_datagram_channel() -> u64;
+    /// Says `message` to everyone in the game, returning whether it was said. Players have to
+    /// join before chatting, and messages that are empty or sent too often are dropped.
+    async fn chat(message: String) -> bool;
+    /// Changes the color of the player's square to `rgb`, each channel from 0 to 1, returning
+    /// whether it was changed. Players have to join first.
+    async fn set_color(rgb: [game::GameInt; 3]) -> bool;
+    /// Returns the stats of the player named `name`, kept across the game's restarts, if they've
+    /// played. Players have to join first.
+    async fn get_stats(name: String) -> Option<stats::PlayerStats>;
+    /// Moderates the game as the moderator whose key is `key`, muting, kicking or pausing as
+    /// `action` says. Moderators have to join first, and wrong keys count as wrong passwords.
+    async fn moderate(
+        key: String,
+        action: server::Moderation,
+    ) -> Result<(), server::ModerationError>;
 }
 
 #[tarpc::service]
 pub trait GameRegistration {
-    /// Registers a game associated with the client.
+    /// Presents the game list's registration key, returning whether it was accepted. Game lists
+    /// started with a key don't let clients register or unregister games until they present it.
+    async fn authenticate(key: String) -> bool;
+    /// Registers a game associated with the client, labeled with the region it's hosted in and
+    /// tags describing it, so that players can find nearby or themed games. Locked games only
+    /// let in players with a join token, so are listed as such and never matched.
     /// As there can only be one registered game associated with a client,
     /// unregisters any already-registered game associated with the client.
-    async fn register(port: u16, name: String) -> Option<String>;
+    async fn register(
+        port: u16,
+        registration: game_list::Registration,
+    ) -> Result<Option<String>, game_list::RegistrationError>;
+    /// Tells the game list that a game registered with heartbeats is still up, and how it's
+    /// doing. Returns false if the game isn't registered, like after it missed too many
+    /// heartbeats or the game list restarted, in which case it should register again. Games that
+    /// are health checked can send heartbeats too, just to check that they're registered.
+    async fn heartbeat(port: u16, status: status::Status) -> bool;
     /// Unregisters the game associated with the client.
     /// Returns the name of the game unregistered, if any was registered.
     async fn unregister(port: u16) -> Option<String>;
@@ -29,6 +136,22 @@ pub trait GameRegistration {
 
 #[tarpc::service]
 pub trait Games {
-    /// Lists the names of all registered games and where to find them.
-    async fn list() -> HashMap<SocketAddr, String>;
+    /// Lists up to `page_size` registered games, where to find them, and what the game list last
+    /// heard from them, starting after `page_token` if given. Pass each page's
+    /// `next_page_token` to get the next one.
+    async fn list(
+        page_token: Option<game_list::PageToken>,
+        page_size: usize,
+    ) -> game_list::GamePage;
+    /// Picks a registered game for a player with `preferences` to join, if any is healthy,
+    /// reachable, unlocked, not full and compatible with the player.
+    async fn find_match(preferences: game_list::MatchPreferences) -> Option<SocketAddr>;
+    /// Waits for registered games to change after version `since` of the listing, then returns
+    /// what changed. Subscribers pass each response's `version` and `next_page_token` to the
+    /// next call; without a version, or with one too old to catch up from, every registered game
+    /// is returned, a page at a time.
+    async fn subscribe(
+        since: Option<u64>,
+        page_token: Option<game_list::PageToken>,
+    ) -> game_list::ListingChanges;
 }
@@ -0,0 +1,132 @@
+//! Maps: the walls and spawn points of a world, and how fast its game runs, read from a TOML file.
+//! Servers watch their map for changes and apply them to the running game, so walls can be moved
+//! without restarting it.
+//!
+//! ```toml
+//! tick_rate = 100
+//! spawn_points = [{ x = 0, y = 0 }, { x = 500, y = 0 }]
+//!
+//! [[walls]]
+//! top_left = { x = 200, y = 0 }
+//! width = 20
+//! height = 300
+//! ```
+
+use crate::game::{GameInt, Layout, Point, Rectangle};
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+/// The most times a second a game can tick or broadcast its state, whether set by a map or the
+/// server's flags.
+pub const MAX_RATE: u64 = 1000;
+
+/// A map, as read from its file.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Map {
+    /// How many times a second the game ticks, instead of the server's setting.
+    pub tick_rate: Option<u64>,
+    /// How many times a second the game's state is sent to players, instead of the server's
+    /// setting.
+    pub broadcast_rate: Option<u64>,
+    #[serde(default)]
+    pub spawn_points: Vec<Point>,
+    #[serde(default)]
+    pub walls: Vec<Rectangle>,
+}
+
+impl Map {
+    pub fn parse(map: &str) -> Result<Self, String> {
+        toml::from_str(map).map_err(|e| e.to_string())
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Map::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Checks that the map fits in a world whose bottom right corner is `world_size`, and that
+    /// players' squares of `square_size` fit at its spawn points without overlapping its walls.
+    pub fn validate(&self, world_size: Point, square_size: GameInt) -> Result<(), String> {
+        for (name, rate) in &[
+            ("tick_rate", self.tick_rate),
+            ("broadcast_rate", self.broadcast_rate),
+        ] {
+            match rate {
+                Some(rate) if *rate == 0 || *rate > MAX_RATE => {
+                    return Err(format!("{} must be from 1 to {}", name, MAX_RATE));
+                }
+                _ => {}
+            }
+        }
+        let inside_world = |rectangle: &Rectangle| {
+            rectangle.top_left.x >= 0.
+                && rectangle.top_left.y >= 0.
+                && rectangle.bottom_right().x <= world_size.x
+                && rectangle.bottom_right().y <= world_size.y
+        };
+        for wall in &self.walls {
+            if !(wall.width > 0. && wall.height > 0.) {
+                return Err(format!("the wall at {:?} has no area", wall.top_left));
+            }
+            if !inside_world(wall) {
+                return Err(format!(
+                    "the wall at {:?} is outside the world",
+                    wall.top_left
+                ));
+            }
+        }
+        for &point in &self.spawn_points {
+            let square = Rectangle::new(point, square_size, square_size);
+            if !inside_world(&square) {
+                return Err(format!("the spawn point {:?} is outside the world", point));
+            }
+            if self
+                .walls
+                .iter()
+                .any(|wall| wall.overlap(&square).is_some())
+            {
+                return Err(format!("the spawn point {:?} is inside a wall", point));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout {
+            walls: self.walls.clone(),
+            spawn_points: self.spawn_points.clone(),
+        }
+    }
+}
+
+#[test]
+fn maps_are_validated() {
+    let map = Map::parse(
+        "tick_rate = 100\n\
+         spawn_points = [{ x = 0, y = 0 }, { x = 500, y = 0 }]\n\
+         [[walls]]\n\
+         top_left = { x = 200, y = 0 }\n\
+         width = 20\n\
+         height = 300\n",
+    )
+    .unwrap();
+    assert_eq!(map.tick_rate, Some(100));
+    assert_eq!(
+        map.walls,
+        vec![Rectangle::new(Point::new(200., 0.), 20., 300.)]
+    );
+    let world_size = Point::new(1000., 500.);
+    assert_eq!(map.validate(world_size, 50.), Ok(()));
+
+    let mut inside_wall = map.clone();
+    inside_wall.spawn_points.push(Point::new(190., 100.));
+    assert!(inside_wall.validate(world_size, 50.).is_err());
+    let mut outside_world = map.clone();
+    outside_world.walls[0].top_left.y = 400.;
+    assert!(outside_world.validate(world_size, 50.).is_err());
+    let mut stopped = map;
+    stopped.tick_rate = Some(0);
+    assert!(stopped.validate(world_size, 50.).is_err());
+    assert!(Map::parse("walls = 3").is_err());
+}
@@ -0,0 +1,504 @@
+//! The screens of the windowed client, from the main menu to playing, and how the player moves
+//! between them. Drawing the screens and joining games is left to the client.
+
+use crate::{
+    client::{Display, Resolution},
+    game::GameInt,
+    lan::Announcement,
+    palette::Style,
+};
+use std::net::SocketAddr;
+
+const MAIN_ITEMS: [&str; 4] = ["Server browser", "Direct connect", "Settings", "Quit"];
+/// Fullscreen, resolution, color, palette, outlines, reduced motion, and back.
+const SETTINGS_ITEMS: usize = 7;
+const PAUSE_ITEMS: [&str; 4] = ["Resume", "Settings", "Leave game", "Quit"];
+/// The resolutions the settings screen picks between.
+const RESOLUTIONS: [(u32, u32); 5] = [
+    (512, 512),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1920, 1080),
+];
+/// The colors the settings screen picks between for the player's square.
+const COLORS: [(&str, [GameInt; 3]); 6] = [
+    ("red", [0.9, 0.2, 0.2]),
+    ("orange", [1., 0.6, 0.1]),
+    ("yellow", [0.95, 0.85, 0.1]),
+    ("green", [0.2, 0.75, 0.3]),
+    ("blue", [0.2, 0.4, 0.9]),
+    ("purple", [0.6, 0.3, 0.8]),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Screen {
+    Main {
+        selected: usize,
+    },
+    /// Lists the games on the local network.
+    ServerBrowser {
+        selected: usize,
+    },
+    /// Joins a game at an address typed in.
+    DirectConnect {
+        address: String,
+    },
+    /// Changes how the window and entities are shown, and the player's color.
+    Settings {
+        selected: usize,
+        /// Whether the settings were opened from the pause menu, to go back to.
+        paused: bool,
+    },
+    Playing,
+    /// Over the game, which keeps going without the player.
+    Paused {
+        selected: usize,
+    },
+}
+
+/// What the player asked the menu to do, from the keys they pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Up,
+    Down,
+    Select,
+    /// Goes back to the previous screen, or pauses the game.
+    Back,
+}
+
+/// What the client should do for the player.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// Join the game at `address`, and report how it went with [`Menu::joined`] or
+    /// [`Menu::failed`].
+    Join(String),
+    /// Show the window as [`Menu::display`] now says to.
+    ChangeDisplay,
+    /// Change the player's color to [`Menu::color`].
+    ChangeColor,
+    /// Draw entities as [`Menu::style`] now says to.
+    ChangeStyle,
+    /// Leave the game being played.
+    Leave,
+    Quit,
+}
+
+/// What a screen shows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    pub title: String,
+    pub items: Vec<String>,
+    pub selected: Option<usize>,
+    /// How joining the latest game is going: that it's being joined, or why it couldn't be.
+    pub notice: Option<String>,
+    /// A color to draw a square of next to an item, to preview it.
+    pub preview: Option<(usize, [GameInt; 4])>,
+}
+
+/// How a game on the local network is listed in the server browser.
+fn describe(addr: SocketAddr, announcement: &Announcement) -> String {
+    let status = &announcement.status;
+    let mut description = format!(
+        "{} ({}/{}) at {}",
+        status.name, status.players, status.max_players, addr
+    );
+    if announcement.locked {
+        description += " (locked)";
+    }
+    description
+}
+
+fn labels(items: &[&str]) -> Vec<String> {
+    items.iter().map(|&item| String::from(item)).collect()
+}
+
+/// Moves `selected` up or down a list of `len` items, wrapping around.
+fn step(selected: usize, len: usize, action: Action) -> usize {
+    match action {
+        _ if len == 0 => 0,
+        Action::Up => (selected + len - 1) % len,
+        Action::Down => (selected + 1) % len,
+        Action::Select | Action::Back => selected,
+    }
+}
+
+/// The next resolution after `resolution` to pick in the settings screen.
+fn next_resolution(resolution: Resolution) -> Resolution {
+    let current = (resolution.width, resolution.height);
+    let next = match RESOLUTIONS.iter().position(|&r| r == current) {
+        Some(i) => RESOLUTIONS[(i + 1) % RESOLUTIONS.len()],
+        None => RESOLUTIONS[0],
+    };
+    Resolution {
+        width: next.0,
+        height: next.1,
+    }
+}
+
+/// The next color after `color` to pick in the settings screen.
+fn next_color(color: Option<[GameInt; 3]>) -> [GameInt; 3] {
+    let next = match COLORS.iter().position(|&(_, rgb)| Some(rgb) == color) {
+        Some(i) => (i + 1) % COLORS.len(),
+        None => 0,
+    };
+    COLORS[next].1
+}
+
+#[derive(Debug)]
+pub struct Menu {
+    screen: Screen,
+    notice: Option<String>,
+    display: Display,
+    color: Option<[GameInt; 3]>,
+    style: Style,
+}
+
+impl Menu {
+    /// Starts at the main menu, with the window shown as `display` says, entities drawn in
+    /// `style`, and the player colored `color`, if they picked one.
+    pub fn new(display: Display, color: Option<[GameInt; 3]>, style: Style) -> Self {
+        Menu {
+            screen: Screen::Main { selected: 0 },
+            notice: None,
+            display,
+            color,
+            style,
+        }
+    }
+
+    /// Starts playing a game joined before the menu was shown.
+    pub fn playing(display: Display, color: Option<[GameInt; 3]>, style: Style) -> Self {
+        Menu {
+            screen: Screen::Playing,
+            ..Menu::new(display, color, style)
+        }
+    }
+
+    /// The color the player picked for their square, if they did.
+    pub fn color(&self) -> Option<[GameInt; 3]> {
+        self.color
+    }
+
+    /// How the window should be shown.
+    pub fn display(&self) -> Display {
+        self.display
+    }
+
+    /// How entities should be drawn.
+    pub fn style(&self) -> Style {
+        self.style
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.display.fullscreen = !self.display.fullscreen;
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// Whether keys control the player, instead of the menu.
+    pub fn is_playing(&self) -> bool {
+        self.screen == Screen::Playing
+    }
+
+    /// Handles `action`, given the `games` listed in the server browser.
+    pub fn act(&mut self, action: Action, games: &[(SocketAddr, Announcement)]) -> Option<Outcome> {
+        let (screen, outcome) = match (&self.screen, action) {
+            (Screen::Main { .. }, Action::Back) => return Some(Outcome::Quit),
+            (Screen::Main { selected }, Action::Select) => match selected {
+                0 => (Screen::ServerBrowser { selected: 0 }, None),
+                1 => (
+                    Screen::DirectConnect {
+                        address: String::new(),
+                    },
+                    None,
+                ),
+                2 => (
+                    Screen::Settings {
+                        selected: 0,
+                        paused: false,
+                    },
+                    None,
+                ),
+                _ => return Some(Outcome::Quit),
+            },
+            (Screen::Main { selected }, _) => (
+                Screen::Main {
+                    selected: step(*selected, MAIN_ITEMS.len(), action),
+                },
+                None,
+            ),
+            (Screen::ServerBrowser { selected }, Action::Select) => match games.get(*selected) {
+                Some((addr, _)) => return Some(Outcome::Join(addr.to_string())),
+                None => return None,
+            },
+            (Screen::DirectConnect { address }, Action::Select) => {
+                if address.trim().is_empty() {
+                    return None;
+                }
+                return Some(Outcome::Join(address.trim().to_string()));
+            }
+            (Screen::Settings { selected, paused }, Action::Select) => match selected {
+                0 => {
+                    self.display.fullscreen = !self.display.fullscreen;
+                    return Some(Outcome::ChangeDisplay);
+                }
+                1 => {
+                    self.display.resolution = next_resolution(self.display.resolution);
+                    return Some(Outcome::ChangeDisplay);
+                }
+                2 => {
+                    self.color = Some(next_color(self.color));
+                    return Some(Outcome::ChangeColor);
+                }
+                3 => {
+                    self.style.palette = self.style.palette.next();
+                    return Some(Outcome::ChangeStyle);
+                }
+                4 => {
+                    self.style.outlines = !self.style.outlines;
+                    return Some(Outcome::ChangeStyle);
+                }
+                5 => {
+                    self.style.reduced_motion = !self.style.reduced_motion;
+                    return Some(Outcome::ChangeStyle);
+                }
+                _ if *paused => (Screen::Paused { selected: 1 }, None),
+                _ => (Screen::Main { selected: 0 }, None),
+            },
+            (Screen::Settings { paused: true, .. }, Action::Back) => {
+                (Screen::Paused { selected: 1 }, None)
+            }
+            (Screen::ServerBrowser { .. }, Action::Back)
+            | (Screen::DirectConnect { .. }, Action::Back)
+            | (Screen::Settings { .. }, Action::Back) => (Screen::Main { selected: 0 }, None),
+            (Screen::Settings { selected, paused }, _) => (
+                Screen::Settings {
+                    selected: step(*selected, SETTINGS_ITEMS, action),
+                    paused: *paused,
+                },
+                None,
+            ),
+            (Screen::ServerBrowser { selected }, _) => (
+                Screen::ServerBrowser {
+                    selected: step(*selected, games.len(), action),
+                },
+                None,
+            ),
+            (Screen::DirectConnect { .. }, _) => return None,
+            (Screen::Playing, Action::Back) => (Screen::Paused { selected: 0 }, None),
+            (Screen::Playing, _) => return None,
+            (Screen::Paused { .. }, Action::Back) => (Screen::Playing, None),
+            (Screen::Paused { selected }, Action::Select) => match selected {
+                0 => (Screen::Playing, None),
+                1 => (
+                    Screen::Settings {
+                        selected: 0,
+                        paused: true,
+                    },
+                    None,
+                ),
+                2 => (Screen::Main { selected: 0 }, Some(Outcome::Leave)),
+                _ => return Some(Outcome::Quit),
+            },
+            (Screen::Paused { selected }, _) => (
+                Screen::Paused {
+                    selected: step(*selected, PAUSE_ITEMS.len(), action),
+                },
+                None,
+            ),
+        };
+        if screen != self.screen {
+            self.notice = None;
+        }
+        self.screen = screen;
+        outcome
+    }
+
+    /// Types `typed` into the address being typed, if there is one.
+    pub fn type_text(&mut self, typed: &str) {
+        if let Screen::DirectConnect { address } = &mut self.screen {
+            address.extend(typed.chars().filter(|c| !c.is_control()));
+        }
+    }
+
+    /// Deletes the last character of the address being typed, if there is one.
+    pub fn backspace(&mut self) {
+        if let Screen::DirectConnect { address } = &mut self.screen {
+            address.pop();
+        }
+    }
+
+    /// The game asked for by [`Outcome::Join`] was joined.
+    pub fn joined(&mut self) {
+        self.screen = Screen::Playing;
+        self.notice = None;
+    }
+
+    /// The game asked for by [`Outcome::Join`] is being joined, at `address`.
+    pub fn joining(&mut self, address: &str) {
+        self.notice = Some(format!("joining {}...", address));
+    }
+
+    /// The game asked for by [`Outcome::Join`] couldn't be joined because of `error`.
+    pub fn failed(&mut self, error: String) {
+        self.notice = Some(error);
+    }
+
+    /// What the current screen shows, given the `games` listed in the server browser.
+    pub fn view(&self, games: &[(SocketAddr, Announcement)]) -> View {
+        let mut preview = None;
+        let (title, items, selected) = match &self.screen {
+            Screen::Main { selected } => ("fakeblok", labels(&MAIN_ITEMS), Some(*selected)),
+            Screen::ServerBrowser { selected } => (
+                "games on the local network (esc to go back)",
+                games
+                    .iter()
+                    .map(|(addr, announcement)| describe(*addr, announcement))
+                    .collect(),
+                Some(*selected).filter(|_| !games.is_empty()),
+            ),
+            Screen::DirectConnect { address } => (
+                "server address (esc to go back)",
+                vec![format!("{}_", address)],
+                None,
+            ),
+            Screen::Settings { selected, .. } => {
+                let on_off = |on| if on { "on" } else { "off" };
+                let color = match self.color {
+                    Some(rgb) => match COLORS.iter().find(|&&(_, preset)| preset == rgb) {
+                        Some(&(name, _)) => name,
+                        None => "custom",
+                    },
+                    None => "random",
+                };
+                if let Some([r, g, b]) = self.color {
+                    preview = Some((2, [r, g, b, 1.]));
+                }
+                let items = vec![
+                    format!("Fullscreen: {} (F11)", on_off(self.display.fullscreen)),
+                    format!("Resolution: {}", self.display.resolution),
+                    format!("Color: {}", color),
+                    format!("Palette: {}", self.style.palette),
+                    format!("Outlines: {}", on_off(self.style.outlines)),
+                    format!("Reduced motion: {}", on_off(self.style.reduced_motion)),
+                    String::from("Back"),
+                ];
+                ("settings", items, Some(*selected))
+            }
+            Screen::Playing => ("fakeblok", vec![], None),
+            Screen::Paused { selected } => ("paused", labels(&PAUSE_ITEMS), Some(*selected)),
+        };
+        View {
+            title: String::from(title),
+            items,
+            selected,
+            notice: self.notice.clone(),
+            preview,
+        }
+    }
+}
+
+impl Default for Menu {
+    fn default() -> Self {
+        Menu::new(Display::default(), None, Style::default())
+    }
+}
+
+#[test]
+fn menu_goes_from_main_to_playing_and_back() {
+    let mut menu = Menu::default();
+    assert_eq!(menu.act(Action::Down, &[]), None);
+    assert_eq!(menu.act(Action::Select, &[]), None);
+    menu.type_text("localhost:\n1234");
+    menu.backspace();
+    assert_eq!(
+        menu.act(Action::Select, &[]),
+        Some(Outcome::Join(String::from("localhost:123")))
+    );
+    menu.joining("localhost:123");
+    assert_eq!(
+        menu.view(&[]).notice.as_deref(),
+        Some("joining localhost:123...")
+    );
+    menu.failed(String::from("connection refused"));
+    assert_eq!(menu.view(&[]).notice.as_deref(), Some("connection refused"));
+    menu.act(Action::Select, &[]);
+    menu.joined();
+    assert!(menu.is_playing());
+
+    assert_eq!(menu.act(Action::Back, &[]), None);
+    assert_eq!(menu.screen(), &Screen::Paused { selected: 0 });
+    menu.act(Action::Down, &[]);
+    menu.act(Action::Down, &[]);
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::Leave));
+    assert_eq!(menu.screen(), &Screen::Main { selected: 0 });
+    assert_eq!(menu.act(Action::Back, &[]), Some(Outcome::Quit));
+}
+
+#[test]
+fn settings_change_display() {
+    let mut menu = Menu::default();
+    menu.act(Action::Up, &[]);
+    menu.act(Action::Up, &[]);
+    assert_eq!(menu.act(Action::Select, &[]), None);
+    assert_eq!(
+        menu.screen(),
+        &Screen::Settings {
+            selected: 0,
+            paused: false
+        }
+    );
+
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeDisplay));
+    assert!(menu.display().fullscreen);
+    menu.act(Action::Down, &[]);
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeDisplay));
+    assert_eq!(menu.display().resolution.to_string(), "800x600");
+    menu.act(Action::Back, &[]);
+    assert_eq!(menu.screen(), &Screen::Main { selected: 0 });
+}
+
+#[test]
+fn settings_pick_color_while_paused() {
+    let mut menu = Menu::playing(Display::default(), None, Style::default());
+    menu.act(Action::Back, &[]);
+    menu.act(Action::Down, &[]);
+    menu.act(Action::Select, &[]);
+    menu.act(Action::Down, &[]);
+    menu.act(Action::Down, &[]);
+    assert_eq!(menu.view(&[]).items[2], "Color: random");
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeColor));
+    assert_eq!(menu.color(), Some(COLORS[0].1));
+    let view = menu.view(&[]);
+    assert_eq!(view.items[2], "Color: red");
+    assert_eq!(view.preview, Some((2, [0.9, 0.2, 0.2, 1.])));
+
+    menu.act(Action::Back, &[]);
+    assert_eq!(menu.screen(), &Screen::Paused { selected: 1 });
+}
+
+#[test]
+fn settings_change_style() {
+    let mut menu = Menu::default();
+    menu.act(Action::Up, &[]);
+    menu.act(Action::Up, &[]);
+    menu.act(Action::Select, &[]);
+    for _ in 0..3 {
+        menu.act(Action::Down, &[]);
+    }
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeStyle));
+    assert_eq!(menu.style().palette, crate::palette::Palette::Colorblind);
+    menu.act(Action::Down, &[]);
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeStyle));
+    assert!(menu.style().outlines);
+    let view = menu.view(&[]);
+    assert_eq!(view.items[3], "Palette: colorblind");
+    assert_eq!(view.items[4], "Outlines: on");
+    menu.act(Action::Down, &[]);
+    assert_eq!(menu.act(Action::Select, &[]), Some(Outcome::ChangeStyle));
+    assert!(menu.style().reduced_motion);
+    assert_eq!(menu.view(&[]).items[5], "Reduced motion: on");
+}
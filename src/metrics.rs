@@ -0,0 +1,241 @@
+//! Prometheus metrics for game servers and the game list, served at `GET /metrics`.
+
+use crate::http;
+use hyper::{header::CONTENT_TYPE, Body, Method, Response};
+use log::error;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_gauge, Encoder, Histogram, HistogramTimer, HistogramVec, IntCounter, IntGauge,
+    TextEncoder,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+
+pub(crate) static TICK_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "fakeblok_tick_duration_seconds",
+        "How long each game tick took, including publishing the new state.",
+        exponential_buckets(0.000_1, 2., 12).unwrap()
+    )
+    .unwrap()
+});
+
+/// The parts of running the game that are timed separately: moving entities, pushing them out
+/// of each other, publishing the new state, and serializing it for players.
+pub(crate) const PHASES: [&str; 4] = ["movement", "collisions", "broadcast", "serialization"];
+
+static TICK_PHASE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "fakeblok_tick_phase_duration_seconds",
+        "How long each phase of a game tick took. Serialization is timed for each message sent.",
+        &["phase"],
+        exponential_buckets(0.000_001, 2., 16).unwrap()
+    )
+    .unwrap()
+});
+
+pub(crate) static ENTITIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("fakeblok_entities", "How many entities are in the game.").unwrap()
+});
+
+pub(crate) static CONNECTED_PLAYERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "fakeblok_connected_players",
+        "How many players are connected, including ones being turned away."
+    )
+    .unwrap()
+});
+
+static RPC_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "fakeblok_rpc_duration_seconds",
+        "How long the server took to answer each RPC, including waiting for the next state.",
+        &["method"]
+    )
+    .unwrap()
+});
+
+/// How many state updates are sent for each one measured. Measuring an update takes nearly as
+/// long as serializing it.
+const STATE_UPDATE_SAMPLE_INTERVAL: u64 = 16;
+
+static STATE_UPDATES_SENT: AtomicU64 = AtomicU64::new(0);
+
+static STATE_UPDATE_BYTES: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "fakeblok_state_update_bytes",
+        "The size of a sample of the state updates sent to players, serialized with bincode, \
+         uncompressed.",
+        exponential_buckets(64., 4., 8).unwrap()
+    )
+    .unwrap()
+});
+
+pub(crate) static REGISTERED_GAMES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "fakeblok_registered_games",
+        "How many games are registered with the game list."
+    )
+    .unwrap()
+});
+
+pub(crate) static HEALTH_CHECK_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "fakeblok_health_check_failures_total",
+        "How many times the game list failed to reach a registered game."
+    )
+    .unwrap()
+});
+
+/// Records the size of every [`STATE_UPDATE_SAMPLE_INTERVAL`]th state update sent to a player,
+/// as measured by `size`, which isn't called for the rest.
+pub(crate) fn sample_state_update_size(size: impl FnOnce() -> Option<u64>) {
+    if STATE_UPDATES_SENT.fetch_add(1, Ordering::Relaxed) % STATE_UPDATE_SAMPLE_INTERVAL != 0 {
+        return;
+    }
+    if let Some(size) = size() {
+        STATE_UPDATE_BYTES.observe(size as f64);
+    }
+}
+
+/// Records that `phase`, one of [`PHASES`], took `elapsed`.
+pub(crate) fn observe_phase(phase: &str, elapsed: Duration) {
+    TICK_PHASE_DURATION
+        .with_label_values(&[phase])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Times `phase`, one of [`PHASES`], until the returned timer is dropped.
+pub(crate) fn time_phase(phase: &str) -> HistogramTimer {
+    TICK_PHASE_DURATION
+        .with_label_values(&[phase])
+        .start_timer()
+}
+
+/// The total time spent in each of [`PHASES`] so far, in seconds.
+fn phase_totals() -> [f64; 4] {
+    let mut totals = [0.; 4];
+    for (total, phase) in totals.iter_mut().zip(&PHASES) {
+        *total = TICK_PHASE_DURATION
+            .with_label_values(&[phase])
+            .get_sample_sum();
+    }
+    totals
+}
+
+/// How the game's ticks went since the last report, for a summary in the server's log.
+#[derive(Debug)]
+pub(crate) struct TickSummary {
+    /// How long a tick can take before the game falls behind.
+    budget: Duration,
+    ticks: u32,
+    total: Duration,
+    slowest: Duration,
+    /// How many ticks took longer than the budget.
+    late: u32,
+    /// The time spent in each phase before this report's period, in seconds.
+    phases_before: [f64; 4],
+}
+
+impl TickSummary {
+    pub(crate) fn new(budget: Duration) -> Self {
+        TickSummary {
+            budget,
+            ticks: 0,
+            total: Duration::default(),
+            slowest: Duration::default(),
+            late: 0,
+            phases_before: phase_totals(),
+        }
+    }
+
+    /// Records that a tick took `elapsed`, returning whether it took longer than the budget.
+    pub(crate) fn record(&mut self, elapsed: Duration) -> bool {
+        self.ticks += 1;
+        self.total += elapsed;
+        self.slowest = self.slowest.max(elapsed);
+        let late = elapsed > self.budget;
+        if late {
+            self.late += 1;
+        }
+        late
+    }
+
+    /// Summarizes the ticks recorded since the last report, and starts a new period.
+    pub(crate) fn report(&mut self) -> String {
+        let phases = phase_totals();
+        let spent: Vec<_> = PHASES
+            .iter()
+            .zip(phases.iter().zip(&self.phases_before))
+            .map(|(phase, (now, before))| {
+                let spent = Duration::from_secs_f64((now - before).max(0.));
+                format!("{} {:?}", phase, spent)
+            })
+            .collect();
+        let report = format!(
+            "{} ticks, mean {:?}, slowest {:?}, {} over the {:?} budget; time spent on {}",
+            self.ticks,
+            self.total / self.ticks.max(1),
+            self.slowest,
+            self.late,
+            self.budget,
+            spent.join(", ")
+        );
+        *self = TickSummary::new(self.budget);
+        report
+    }
+}
+
+/// Times an RPC to `method` until the returned timer is dropped.
+pub(crate) fn time_rpc(method: &str) -> HistogramTimer {
+    RPC_DURATION.with_label_values(&[method]).start_timer()
+}
+
+async fn serve(addr: SocketAddr) -> io::Result<()> {
+    http::serve(addr, |request| {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                let encoder = TextEncoder::new();
+                let mut body = vec![];
+                encoder.encode(&prometheus::gather(), &mut body).unwrap();
+                Response::builder()
+                    .header(CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(body))
+                    .unwrap()
+            }
+            _ => http::not_found(),
+        }
+    })
+    .await
+}
+
+/// Serves this process's metrics on `addr` from a background thread.
+pub fn spawn_endpoint(addr: SocketAddr) {
+    thread::spawn(move || {
+        if let Err(e) = Runtime::new().unwrap().block_on(serve(addr)) {
+            error!("Metrics endpoint died: {:?}", e);
+        }
+    });
+}
+
+#[test]
+fn tick_summaries_count_late_ticks() {
+    let mut summary = TickSummary::new(Duration::from_millis(5));
+    assert!(!summary.record(Duration::from_millis(1)));
+    assert!(summary.record(Duration::from_millis(8)));
+    assert!(!summary.record(Duration::from_millis(3)));
+    let report = summary.report();
+    assert!(
+        report.starts_with("3 ticks, mean 4ms, slowest 8ms, 1 over the 5ms budget;"),
+        "{}",
+        report
+    );
+    assert!(summary.report().starts_with("0 ticks,"));
+}
@@ -0,0 +1,725 @@
+//! Game modes: the rules of a match, run by the server after each tick on top of the game's
+//! physics, so that new kinds of matches don't need changes to [`Game::tick`].
+
+use crate::{
+    game::{Entity, EntityId, Game, GameInt, Layout, Point, Rectangle},
+    rollback::{Command, ConfirmedTick},
+    script::{Hooks, Script},
+    stats::Stats,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+/// The rules of a kind of match. Modes change the game through [`Changes`], which take effect
+/// at the next tick.
+pub trait GameMode: Send {
+    /// Sets the world up before the game starts, like placing a flag.
+    fn start(&mut self, _game: &mut Game) {}
+
+    /// Runs after every tick.
+    fn on_tick(&mut self, _game: &Game, _changes: &mut Changes) {}
+
+    /// Runs when `player` joins.
+    fn on_join(&mut self, _game: &Game, _player: EntityId, _changes: &mut Changes) {}
+
+    /// Runs when `player` leaves, before their entity's id can go to another entity, so that
+    /// whatever the mode kept about them doesn't carry over.
+    fn on_leave(&mut self, _player: EntityId) {}
+
+    /// Runs when `mover` runs into `other`, once until they stop running into each other.
+    fn on_collision(
+        &mut self,
+        _game: &Game,
+        _mover: EntityId,
+        _other: EntityId,
+        _changes: &mut Changes,
+    ) {
+    }
+
+    /// Whether the match is over. Modes aren't run once it is, and a new round starts after
+    /// [`INTERMISSION`].
+    fn is_finished(&self, _game: &Game) -> bool {
+        false
+    }
+
+    /// The scores in the match, best first.
+    fn scoreboard(&self, game: &Game) -> Vec<Score>;
+}
+
+/// A player's or team's score.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Score {
+    pub name: String,
+    pub points: u32,
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.points)
+    }
+}
+
+/// Changes a game mode makes to the game.
+#[derive(Debug, Default)]
+pub struct Changes {
+    commands: Vec<Command>,
+    /// Which players got the better of which, for their stats.
+    kills: Vec<(EntityId, EntityId)>,
+}
+
+impl Changes {
+    /// Moves entity `id` so that its top left corner is at `point`.
+    pub fn teleport(&mut self, id: EntityId, point: Point) {
+        self.commands.push(Command::Teleport(id, point));
+    }
+
+    /// Colors entity `id` `rgb`, opaque.
+    pub fn set_color(&mut self, id: EntityId, rgb: [GameInt; 3]) {
+        self.commands.push(Command::SetColor(id, rgb));
+    }
+
+    /// Tells every player `message`.
+    pub fn announce(&mut self, message: String) {
+        self.commands.push(Command::Announce(message));
+    }
+
+    /// Counts a kill for `killer` and a death for `victim` in their stats, like when one tags the
+    /// other.
+    pub fn kill(&mut self, killer: EntityId, victim: EntityId) {
+        self.kills.push((killer, victim));
+    }
+}
+
+/// The built-in game modes, which servers pick from when they start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    FreeForAll,
+    Tag,
+    CaptureTheFlag,
+}
+
+impl Mode {
+    pub fn game_mode(self) -> Box<dyn GameMode> {
+        match self {
+            Mode::FreeForAll => Box::new(FreeForAll::default()),
+            Mode::Tag => Box::new(Tag::default()),
+            Mode::CaptureTheFlag => Box::new(CaptureTheFlag::default()),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free_for_all" => Ok(Mode::FreeForAll),
+            "tag" => Ok(Mode::Tag),
+            "capture_the_flag" => Ok(Mode::CaptureTheFlag),
+            _ => Err(format!(
+                "unknown game mode {:?}; expected free_for_all, tag or capture_the_flag",
+                s
+            )),
+        }
+    }
+}
+
+/// How many seconds of game time pass between the end of a match and the start of the next.
+pub const INTERMISSION: f32 = 10.;
+/// How many ticks away from where a kill was seen its collision can be confirmed at, since late
+/// inputs can move collisions a little.
+const KILL_CONFIRMATION_TICKS: u64 = 5;
+
+/// A kill seen in the latest state, which isn't recorded in the stats until its collision is
+/// confirmed, since a late input can undo it.
+struct UnconfirmedKill {
+    tick: u64,
+    killer: EntityId,
+    victim: EntityId,
+    /// The players' names when the kill was seen, in case they've left by the time it's confirmed.
+    killer_name: String,
+    victim_name: String,
+}
+
+/// How a new round starts, once a match is over.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Round {
+    /// Scatters the world's blocks.
+    pub seed: u64,
+    pub layout: Layout,
+    pub mode: Mode,
+}
+
+impl Round {
+    /// Starts the round in `game`, returning its mode.
+    pub(crate) fn start(&self, game: &mut Game) -> Box<dyn GameMode> {
+        game.new_round(self.seed, &self.layout);
+        let mut mode = self.mode.game_mode();
+        mode.start(game);
+        mode
+    }
+}
+
+/// A game mode, and a script changing its rules, run after each tick of a game. Once a match is
+/// over, the next round starts on the next map in the rotation, or else on the same one.
+pub(crate) struct Rules {
+    kind: Mode,
+    mode: Box<dyn GameMode>,
+    hooks: Option<Hooks>,
+    /// The layouts of the maps the rounds cycle through, if any.
+    rotation: Vec<Layout>,
+    rounds: usize,
+    /// The entities that ran into each other during the last tick, to tell new collisions apart.
+    touching: HashSet<(EntityId, EntityId)>,
+    /// When the match finished, in seconds of game time.
+    finished_at: Option<f32>,
+    /// The players in the game as of the last tick, to tell who left.
+    players: BTreeSet<EntityId>,
+    /// Where players' kills and deaths are recorded, if anywhere.
+    stats: Option<Stats>,
+    unconfirmed_kills: Vec<UnconfirmedKill>,
+    /// The names of the killer and victim of each kill confirmed since the stats were last
+    /// written to.
+    confirmed_kills: Vec<(String, String)>,
+}
+
+impl Rules {
+    pub(crate) fn new(
+        kind: Mode,
+        script: Option<Script>,
+        rotation: Vec<Layout>,
+        stats: Option<Stats>,
+    ) -> Self {
+        Rules {
+            kind,
+            mode: kind.game_mode(),
+            hooks: script.map(Hooks::new),
+            rotation,
+            rounds: 0,
+            touching: HashSet::new(),
+            finished_at: None,
+            players: BTreeSet::new(),
+            stats,
+            unconfirmed_kills: vec![],
+            confirmed_kills: vec![],
+        }
+    }
+
+    /// Sets the world up for the first round, before the game starts.
+    pub(crate) fn start(&mut self, game: &mut Game) {
+        if let Some(layout) = self.rotation.first() {
+            game.set_layout(layout);
+        }
+        self.mode.start(game);
+    }
+
+    /// Runs the mode and the script for the tick `game` just finished, returning the commands
+    /// they made. Once the match is finished, announces the final scores and stops running them
+    /// until the next round starts.
+    pub(crate) fn run(&mut self, game: &Game) -> Vec<Command> {
+        match self.finished_at {
+            Some(finished_at) if game.time() - finished_at >= INTERMISSION => {
+                return self.next_round(game);
+            }
+            Some(_) => return vec![],
+            None => {}
+        }
+        let mut changes = Changes::default();
+        if let Some(hooks) = &mut self.hooks {
+            changes.commands = hooks.run(game);
+        }
+        let players: BTreeSet<_> = game.players().collect();
+        for &id in self.players.difference(&players) {
+            self.mode.on_leave(id);
+        }
+        // Players who joined with the id of one who was here last tick took the id over.
+        for id in game.joined().filter(|id| self.players.contains(id)) {
+            self.mode.on_leave(id);
+        }
+        self.players = players;
+        self.mode.on_tick(game, &mut changes);
+        for id in game.joined() {
+            self.mode.on_join(game, id, &mut changes);
+        }
+        let touching: HashSet<_> = game.collisions().iter().copied().collect();
+        for &(mover, other) in game.collisions() {
+            if !self.touching.contains(&(mover, other)) && game.contains(other) {
+                self.mode.on_collision(game, mover, other, &mut changes);
+            }
+        }
+        self.touching = touching;
+        if self.stats.is_some() {
+            let name = |id| String::from(game.name(id).unwrap_or_default());
+            for &(killer, victim) in &changes.kills {
+                self.unconfirmed_kills.push(UnconfirmedKill {
+                    tick: game.ticks(),
+                    killer,
+                    victim,
+                    killer_name: name(killer),
+                    victim_name: name(victim),
+                });
+            }
+        }
+        if self.mode.is_finished(game) {
+            self.finished_at = Some(game.time());
+            let scores: Vec<_> = self.scoreboard(game).iter().map(Score::to_string).collect();
+            changes.announce(format!("The match is over! {}", scores.join(", ")));
+            changes.announce(format!("The next round starts in {} seconds", INTERMISSION));
+        }
+        changes.commands
+    }
+
+    /// Starts a new round after the one in `game`, with everyone in it rejoining, whether or not
+    /// the match is over.
+    pub(crate) fn next_round(&mut self, game: &Game) -> Vec<Command> {
+        self.rounds += 1;
+        let layout = match self.rotation.len() {
+            0 => game.layout(),
+            maps => self.rotation[self.rounds % maps].clone(),
+        };
+        let round = Round {
+            seed: rand::random(),
+            layout,
+            mode: self.kind,
+        };
+        // The round starts the same way when the command is applied, so the mode's view of it,
+        // like which entity is the flag, holds for the real game.
+        let mut next = game.clone();
+        self.mode = round.start(&mut next);
+        self.touching.clear();
+        self.finished_at = None;
+        let mut changes = Changes::default();
+        changes.commands.push(Command::NewRound(round));
+        changes.announce(format!("Round {} has started", self.rounds + 1));
+        for id in next.players() {
+            self.mode.on_join(&next, id, &mut changes);
+        }
+        changes.commands
+    }
+
+    /// Confirms the kills that `confirmed` bears out, to be recorded in the stats by
+    /// [`Rules::record_kills`], and forgets the ones it's too late for.
+    pub(crate) fn confirm(&mut self, confirmed: &ConfirmedTick) {
+        let confirmed_kills = &mut self.confirmed_kills;
+        self.unconfirmed_kills.retain(|kill| {
+            let near = confirmed.tick + KILL_CONFIRMATION_TICKS >= kill.tick
+                && confirmed.tick <= kill.tick + KILL_CONFIRMATION_TICKS;
+            if near && confirmed.collisions.contains(&(kill.killer, kill.victim)) {
+                confirmed_kills.push((kill.killer_name.clone(), kill.victim_name.clone()));
+                return false;
+            }
+            confirmed.tick < kill.tick + KILL_CONFIRMATION_TICKS
+        });
+    }
+
+    /// Records the kills confirmed so far in the stats. Writing to them can take a while, so
+    /// this is kept apart from [`Rules::confirm`] to be done without holding up the game.
+    pub(crate) fn record_kills(&mut self) {
+        let stats = match &self.stats {
+            Some(stats) => stats,
+            None => return,
+        };
+        for (killer, victim) in self.confirmed_kills.drain(..) {
+            if let Err(e) = stats.record_kill(&killer, &victim) {
+                error!("Failed to record a kill in the stats: {}", e);
+            }
+        }
+    }
+
+    pub(crate) fn scoreboard(&self, game: &Game) -> Vec<Score> {
+        self.mode.scoreboard(game)
+    }
+}
+
+/// The scores of the players in `game`, from their `points`, best first.
+fn player_scores(game: &Game, points: &BTreeMap<EntityId, u32>) -> Vec<Score> {
+    let mut scores: Vec<_> = game
+        .players()
+        .map(|id| Score {
+            name: String::from(game.name(id).unwrap_or_default()),
+            points: points.get(&id).copied().unwrap_or_default(),
+        })
+        .collect();
+    scores.sort_by(|a, b| b.points.cmp(&a.points));
+    scores
+}
+
+fn rgb(entity: &Entity) -> [GameInt; 3] {
+    let [r, g, b, _] = entity.color;
+    [r, g, b]
+}
+
+/// No teams and no end: players score a point for each other player they run into.
+#[derive(Debug, Default)]
+pub struct FreeForAll {
+    bumps: BTreeMap<EntityId, u32>,
+}
+
+impl GameMode for FreeForAll {
+    fn on_leave(&mut self, player: EntityId) {
+        self.bumps.remove(&player);
+    }
+
+    fn on_collision(&mut self, game: &Game, mover: EntityId, other: EntityId, _: &mut Changes) {
+        if game.is_player(mover) && game.is_player(other) {
+            *self.bumps.entry(mover).or_default() += 1;
+        }
+    }
+
+    fn scoreboard(&self, game: &Game) -> Vec<Score> {
+        player_scores(game, &self.bumps)
+    }
+}
+
+/// How many tags win a game of tag.
+const TAGS_TO_WIN: u32 = 10;
+const IT_COLOR: [GameInt; 3] = [1., 0.2, 0.2];
+
+/// One player is it, and passes it on by running into another player. Players score a point for
+/// each tag, and the first to [`TAGS_TO_WIN`] wins.
+#[derive(Debug, Default)]
+pub struct Tag {
+    /// Who's it, with their color from before.
+    it: Option<(EntityId, [GameInt; 3])>,
+    /// Who tagged the player who's it, since there are no tag backs.
+    tagged_by: Option<EntityId>,
+    tags: BTreeMap<EntityId, u32>,
+}
+
+impl Tag {
+    fn make_it(&mut self, game: &Game, id: EntityId, changes: &mut Changes) {
+        self.it = Some((id, rgb(&game.entity(id))));
+        changes.set_color(id, IT_COLOR);
+    }
+}
+
+impl GameMode for Tag {
+    fn on_tick(&mut self, game: &Game, changes: &mut Changes) {
+        match self.it {
+            Some((id, _)) if game.contains(id) => {}
+            _ => {
+                // No one's it yet, or whoever was left, so the first player is it.
+                self.tagged_by = None;
+                self.it = None;
+                if let Some(id) = game.players().next() {
+                    self.make_it(game, id, changes);
+                    let name = game.name(id).unwrap_or_default();
+                    changes.announce(format!("{} is it", name));
+                }
+            }
+        }
+    }
+
+    fn on_leave(&mut self, player: EntityId) {
+        self.tags.remove(&player);
+        if self.tagged_by == Some(player) {
+            self.tagged_by = None;
+        }
+        if self.it.map(|(it, _)| it) == Some(player) {
+            self.it = None;
+        }
+    }
+
+    fn on_collision(
+        &mut self,
+        game: &Game,
+        mover: EntityId,
+        other: EntityId,
+        changes: &mut Changes,
+    ) {
+        let color = match self.it {
+            Some((it, color)) if it == mover => color,
+            _ => return,
+        };
+        if !game.is_player(other) || self.tagged_by == Some(other) {
+            return;
+        }
+        *self.tags.entry(mover).or_default() += 1;
+        changes.set_color(mover, color);
+        self.make_it(game, other, changes);
+        changes.kill(mover, other);
+        self.tagged_by = Some(mover);
+        let name = |id| game.name(id).unwrap_or_default();
+        changes.announce(format!("{} tagged {}", name(mover), name(other)));
+    }
+
+    fn is_finished(&self, _: &Game) -> bool {
+        self.tags.values().any(|&tags| tags >= TAGS_TO_WIN)
+    }
+
+    fn scoreboard(&self, game: &Game) -> Vec<Score> {
+        player_scores(game, &self.tags)
+    }
+}
+
+/// How many captures win a game of capture the flag.
+const CAPTURES_TO_WIN: u32 = 3;
+/// How far from its edge of the world each team's base reaches.
+const BASE_WIDTH: GameInt = 200.;
+const FLAG_COLOR: [GameInt; 3] = [1., 0.85, 0.];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Team {
+    /// Based at the left edge of the world.
+    Red,
+    /// Based at the right edge of the world.
+    Blue,
+}
+
+impl Team {
+    fn name(self) -> &'static str {
+        match self {
+            Team::Red => "red",
+            Team::Blue => "blue",
+        }
+    }
+
+    fn color(self) -> [GameInt; 3] {
+        match self {
+            Team::Red => [0.9, 0.1, 0.1],
+            Team::Blue => [0.1, 0.3, 0.9],
+        }
+    }
+
+    fn at_base(self, game: &Game, position: Rectangle) -> bool {
+        match self {
+            Team::Red => position.top_left.x < BASE_WIDTH,
+            Team::Blue => position.bottom_right().x > game.width() - BASE_WIDTH,
+        }
+    }
+}
+
+/// Two teams, based at opposite edges of the world, race to take the flag in the middle back to
+/// their base. Running into the flag takes it, and running into an opponent who has it sends it
+/// back. The first team to [`CAPTURES_TO_WIN`] wins.
+#[derive(Debug, Default)]
+pub struct CaptureTheFlag {
+    flag: Option<EntityId>,
+    teams: BTreeMap<EntityId, Team>,
+    /// Who has the flag.
+    carrier: Option<EntityId>,
+    captures: BTreeMap<EntityId, u32>,
+    red: u32,
+    blue: u32,
+}
+
+impl CaptureTheFlag {
+    fn score(&mut self, team: Team) -> &mut u32 {
+        match team {
+            Team::Red => &mut self.red,
+            Team::Blue => &mut self.blue,
+        }
+    }
+
+    /// The flag already in `game`, like one in a saved game that was loaded.
+    fn find_flag(game: &Game) -> Option<EntityId> {
+        game.entity_ids().find(|&id| {
+            let entity = game.entity(id);
+            !game.is_player(id) && !entity.moveable && rgb(&entity) == FLAG_COLOR
+        })
+    }
+
+    /// Takes the flag back from its carrier, who gets their team's color back.
+    fn drop_flag(&mut self, changes: &mut Changes) {
+        if let Some(carrier) = self.carrier.take() {
+            if let Some(team) = self.teams.get(&carrier) {
+                changes.set_color(carrier, team.color());
+            }
+        }
+    }
+}
+
+impl GameMode for CaptureTheFlag {
+    fn start(&mut self, game: &mut Game) {
+        if let Some(flag) = CaptureTheFlag::find_flag(game) {
+            self.flag = Some(flag);
+            return;
+        }
+        let mut flag = game.new_player_square();
+        let side = game.square_side_length();
+        let [r, g, b] = FLAG_COLOR;
+        flag.position.top_left =
+            Point::new((game.width() - side) / 2., (game.height() - side) / 2.);
+        flag.moveable = false;
+        flag.color = [r, g, b, 1.];
+        self.flag = Some(game.insert_entity(flag));
+    }
+
+    fn on_tick(&mut self, game: &Game, changes: &mut Changes) {
+        let carrier = match self.carrier {
+            Some(carrier) if game.contains(carrier) => carrier,
+            _ => {
+                self.carrier = None;
+                return;
+            }
+        };
+        let team = self.teams[&carrier];
+        if team.at_base(game, game.entity(carrier).position) {
+            *self.score(team) += 1;
+            *self.captures.entry(carrier).or_default() += 1;
+            self.drop_flag(changes);
+            let name = game.name(carrier).unwrap_or_default();
+            changes.announce(format!("{} captured the flag for {}", name, team.name()));
+        }
+    }
+
+    fn on_leave(&mut self, player: EntityId) {
+        self.teams.remove(&player);
+        self.captures.remove(&player);
+        if self.carrier == Some(player) {
+            self.carrier = None;
+        }
+    }
+
+    fn on_join(&mut self, game: &Game, player: EntityId, changes: &mut Changes) {
+        // Players who are away don't count toward balancing the teams.
+        let playing: Vec<_> = self
+            .teams
+            .iter()
+            .filter(|&(&id, _)| !game.is_afk(id))
+            .map(|(_, &team)| team)
+            .collect();
+        let reds = playing.iter().filter(|&&team| team == Team::Red).count();
+        let team = if reds * 2 <= playing.len() {
+            Team::Red
+        } else {
+            Team::Blue
+        };
+        self.teams.insert(player, team);
+        changes.set_color(player, team.color());
+        let name = game.name(player).unwrap_or_default();
+        changes.announce(format!("{} joined {}", name, team.name()));
+    }
+
+    fn on_collision(
+        &mut self,
+        game: &Game,
+        mover: EntityId,
+        other: EntityId,
+        changes: &mut Changes,
+    ) {
+        let team = match self.teams.get(&mover) {
+            Some(&team) => team,
+            None => return,
+        };
+        let name = |id| game.name(id).unwrap_or_default();
+        if self.carrier.is_none() && Some(other) == self.flag {
+            self.carrier = Some(mover);
+            changes.set_color(mover, FLAG_COLOR);
+            changes.announce(format!("{} took the flag", name(mover)));
+        } else if self.carrier == Some(other) && self.teams.get(&other) != Some(&team) {
+            self.drop_flag(changes);
+            changes.kill(mover, other);
+            changes.announce(format!("{} stopped {}", name(mover), name(other)));
+        }
+    }
+
+    fn is_finished(&self, _: &Game) -> bool {
+        self.red.max(self.blue) >= CAPTURES_TO_WIN
+    }
+
+    fn scoreboard(&self, game: &Game) -> Vec<Score> {
+        let mut teams = vec![
+            Score {
+                name: String::from("red"),
+                points: self.red,
+            },
+            Score {
+                name: String::from("blue"),
+                points: self.blue,
+            },
+        ];
+        teams.sort_by(|a, b| b.points.cmp(&a.points));
+        teams.extend(player_scores(game, &self.captures));
+        teams
+    }
+}
+
+#[test]
+fn tag_passes_on_being_it() {
+    use crate::game::{Component, Input, Sign};
+
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    for id in game.entity_ids().collect::<Vec<_>>() {
+        game.remove_entity(id);
+    }
+    let chaser = game.insert_new_player_square();
+    game.name_player(chaser, String::from("ada"));
+    let runner = game.insert_new_player_square();
+    game.name_player(runner, String::from("grace"));
+    game.teleport(runner, Point::new(100., 0.));
+
+    let stats = Stats::temporary().unwrap();
+    let mut rules = Rules::new(Mode::Tag, None, vec![], Some(stats.clone()));
+    let mut commands = vec![];
+    game.tick(1. / 200., &mut 0., &mut 0);
+    commands.extend(rules.run(&game));
+    assert!(commands.contains(&Command::Announce(String::from("ada is it"))));
+
+    game.process_input(chaser, Input::Move(Component::X, Some(Sign::Positive)));
+    let mut ticks = vec![];
+    for _ in 0..400 {
+        game.tick(1. / 200., &mut 0., &mut 0);
+        commands.extend(rules.run(&game));
+        ticks.push(ConfirmedTick {
+            tick: game.ticks(),
+            collisions: game.collisions().to_vec(),
+        });
+    }
+    assert!(commands.contains(&Command::Announce(String::from("ada tagged grace"))));
+    // The tag isn't in the stats until its tick is confirmed.
+    assert_eq!(stats.get("ada").unwrap(), None);
+    for confirmed in &ticks {
+        rules.confirm(confirmed);
+    }
+    assert_eq!(stats.get("ada").unwrap(), None);
+    rules.record_kills();
+    assert_eq!(stats.get("ada").unwrap().unwrap().kills, 1);
+    assert_eq!(stats.get("grace").unwrap().unwrap().deaths, 1);
+    assert_eq!(
+        rules.scoreboard(&game),
+        vec![
+            Score {
+                name: String::from("ada"),
+                points: 1
+            },
+            Score {
+                name: String::from("grace"),
+                points: 0
+            },
+        ]
+    );
+}
+
+#[test]
+fn rounds_start_over_after_the_intermission() {
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let player = game.insert_new_player_square();
+    let mut rules = Rules::new(Mode::CaptureTheFlag, None, vec![], None);
+    rules.start(&mut game);
+    let entities = game.entity_ids().count();
+    // Starting on a loaded game takes over the flag it has.
+    let mut loaded = game.clone();
+    Rules::new(Mode::CaptureTheFlag, None, vec![], None).start(&mut loaded);
+    assert_eq!(loaded.entity_ids().count(), entities);
+    rules.finished_at = Some(game.time());
+    game.tick(1., &mut 0., &mut 0);
+    assert_eq!(rules.run(&game), vec![]);
+
+    game.tick(INTERMISSION, &mut 0., &mut 0);
+    let commands = rules.run(&game);
+    assert!(rules.finished_at.is_none());
+    assert!(commands.contains(&Command::SetColor(player, Team::Red.color())));
+    for command in commands {
+        command.apply(&mut game);
+    }
+    // The world was scattered again, with the player and a new flag.
+    assert!(game.contains(player));
+    assert_eq!(game.entity_ids().count(), entities);
+}
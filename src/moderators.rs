@@ -0,0 +1,135 @@
+//! Moderators: trusted players who can mute, kick and pause, but not stop the game or change its
+//! map. Each moderator is a name with a key they moderate with, granted by the server's operator.
+//! The moderator list can be kept in a file, one moderator per line as their key and then their
+//! name, so that moderators survive restarts.
+
+use rand::{distributions::Alphanumeric, Rng};
+use ring::constant_time;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// How many characters a moderator's key has.
+const KEY_LENGTH: usize = 24;
+
+/// Parses a moderator list into moderators' keys, by name: one moderator per line, as their key
+/// and then their name. Blank lines, and lines starting with a `#`, are ignored; names can have
+/// `#`s in them.
+pub fn parse(list: &str) -> Result<BTreeMap<String, String>, String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.find(char::is_whitespace) {
+            Some(i) => Ok((String::from(line[i..].trim()), String::from(&line[..i]))),
+            None => Err(format!(
+                "expected a key and a name in moderator list, not {:?}",
+                line
+            )),
+        })
+        .collect()
+}
+
+/// Loads the moderator list at `path`. A missing file is an empty list.
+pub fn load(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    match fs::read_to_string(path) {
+        Ok(list) => parse(&list).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// A game's moderators, saved to the moderator list on every change if it has one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Moderators {
+    /// Each moderator's key, by name.
+    keys: Arc<Mutex<BTreeMap<String, String>>>,
+    path: Option<PathBuf>,
+}
+
+impl Moderators {
+    pub(crate) fn new(keys: BTreeMap<String, String>, path: Option<PathBuf>) -> Self {
+        Moderators {
+            keys: Arc::new(Mutex::new(keys)),
+            path,
+        }
+    }
+
+    /// The name of the moderator whose key is `key`, if any. Every key is compared in full, so
+    /// that how long this takes doesn't give away how much of a key was right.
+    pub(crate) fn name(&self, key: &str) -> Option<String> {
+        let keys = self.keys.lock().unwrap();
+        let mut found = None;
+        for (name, expected) in keys.iter() {
+            if constant_time::verify_slices_are_equal(expected.as_bytes(), key.as_bytes()).is_ok() {
+                found = Some(name.clone());
+            }
+        }
+        found
+    }
+
+    /// Makes `name` a moderator with a new key, replacing any key they had, and returns the key.
+    /// They're a moderator even if the list can't be saved.
+    pub(crate) fn grant(&self, name: &str) -> (String, io::Result<()>) {
+        let key: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LENGTH)
+            .collect();
+        let mut keys = self.keys.lock().unwrap();
+        keys.insert(String::from(name), key.clone());
+        (key, self.save(&keys))
+    }
+
+    /// Takes moderating away from `name`, returning whether they were a moderator.
+    pub(crate) fn revoke(&self, name: &str) -> io::Result<bool> {
+        let mut keys = self.keys.lock().unwrap();
+        let was_moderator = keys.remove(name).is_some();
+        self.save(&keys)?;
+        Ok(was_moderator)
+    }
+
+    /// Every moderator's name, in order.
+    pub(crate) fn list(&self) -> Vec<String> {
+        self.keys.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn save(&self, keys: &BTreeMap<String, String>) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        for (name, key) in keys {
+            writeln!(file, "{} {}", key, name)?;
+        }
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+}
+
+#[test]
+fn moderator_lists_parse() {
+    let keys = parse("abc123 player one\n\n# retired\nxyz789 #two\n").unwrap();
+    let expected: BTreeMap<_, _> = vec![
+        (String::from("player one"), String::from("abc123")),
+        (String::from("#two"), String::from("xyz789")),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(keys, expected);
+    assert!(parse("abc123").is_err());
+
+    let moderators = Moderators::new(keys, None);
+    assert_eq!(moderators.name("abc123").as_deref(), Some("player one"));
+    assert_eq!(moderators.name("abc12"), None);
+    let (key, saved) = moderators.grant("#two");
+    saved.unwrap();
+    assert_eq!(moderators.name("xyz789"), None);
+    assert_eq!(moderators.name(&key).as_deref(), Some("#two"));
+    assert!(moderators.revoke("#two").unwrap());
+    assert_eq!(moderators.list(), vec![String::from("player one")]);
+}
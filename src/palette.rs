@@ -0,0 +1,219 @@
+//! How entities are colored when drawn: palettes that swap entities' own colors for sets that are
+//! easier to tell apart, and outlines around every entity. Also where players ask for reduced
+//! motion, which the camera consults.
+
+use crate::game::GameInt;
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// The Okabe-Ito colors, which stay distinguishable with the common kinds of color blindness.
+const COLORBLIND: [[GameInt; 3]; 8] = [
+    [0., 0., 0.],
+    [0.9, 0.62, 0.],
+    [0.34, 0.71, 0.91],
+    [0., 0.62, 0.45],
+    [0.94, 0.89, 0.26],
+    [0., 0.45, 0.7],
+    [0.84, 0.37, 0.],
+    [0.8, 0.47, 0.65],
+];
+/// Dark, saturated colors that stand out against the white background.
+const HIGH_CONTRAST: [[GameInt; 3]; 5] = [
+    [0., 0., 0.],
+    [0.85, 0., 0.],
+    [0., 0.2, 0.9],
+    [0., 0.45, 0.],
+    [0.5, 0., 0.6],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    /// Entities' own colors.
+    Standard,
+    Colorblind,
+    HighContrast,
+}
+
+impl Palette {
+    const ALL: [Palette; 3] = [
+        Palette::Standard,
+        Palette::Colorblind,
+        Palette::HighContrast,
+    ];
+
+    /// The palette after this one, to pick in the settings screen.
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|&palette| palette == self);
+        Self::ALL[(i.unwrap_or(0) + 1) % Self::ALL.len()]
+    }
+
+    fn colors(self) -> &'static [[GameInt; 3]] {
+        match self {
+            Palette::Standard => &[],
+            Palette::Colorblind => &COLORBLIND,
+            Palette::HighContrast => &HIGH_CONTRAST,
+        }
+    }
+
+    /// The palette's colors, then lighter and darker shades of them, without repeats.
+    fn shades(self) -> Vec<[GameInt; 3]> {
+        let mut shades: Vec<[GameInt; 3]> = vec![];
+        let shade_by: [fn(GameInt) -> GameInt; 3] = [|c| c, |c| c + (1. - c) / 2., |c| c / 2.];
+        for shade in &shade_by {
+            for &[r, g, b] in self.colors() {
+                let color = [shade(r), shade(g), shade(b)];
+                if !shades.contains(&color) {
+                    shades.push(color);
+                }
+            }
+        }
+        shades
+    }
+
+    /// Picks which of the palette's colors to swap each of `colors` for, for drawing them
+    /// together. Each color gets the closest one not taken by a color closer to it, or a shade of
+    /// one once they're all taken, so different colors stay different until the shades run out
+    /// too. Swapped colors are opaque, since faded colors are harder to tell apart.
+    pub fn remap(self, colors: impl IntoIterator<Item = [GameInt; 4]>) -> Remap {
+        let mut remap = Remap::default();
+        let shades = self.shades();
+        if shades.is_empty() {
+            return remap;
+        }
+        let mut sources: Vec<[GameInt; 4]> = vec![];
+        // Colors that aren't numbers can't be compared, so they're drawn as they are.
+        for color in colors {
+            if color.iter().all(|c| c.is_finite()) && !sources.contains(&color) {
+                sources.push(color);
+            }
+        }
+        let distance = |[r, g, b, _]: [GameInt; 4], [sr, sg, sb]: [GameInt; 3]| {
+            (sr - r).powi(2) + (sg - g).powi(2) + (sb - b).powi(2)
+        };
+        // Pairs of colors and shades, the palette's own colors first, then the closest first.
+        let palette_len = self.colors().len();
+        let mut pairs: Vec<(bool, GameInt, usize, usize)> = sources
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &color)| {
+                let shades = &shades;
+                (0..shades.len()).map(move |j| (j >= palette_len, distance(color, shades[j]), i, j))
+            })
+            .collect();
+        pairs.sort_by(|x, y| {
+            x.0.cmp(&y.0)
+                .then(x.1.partial_cmp(&y.1).unwrap_or(Ordering::Equal))
+                .then((x.2, x.3).cmp(&(y.2, y.3)))
+        });
+        let mut swapped: Vec<Option<[GameInt; 3]>> = vec![None; sources.len()];
+        let mut taken = vec![false; shades.len()];
+        for &(_, _, i, j) in &pairs {
+            if swapped[i].is_none() && !taken[j] {
+                swapped[i] = Some(shades[j]);
+                taken[j] = true;
+            }
+        }
+        // Once every shade is taken, the rest share the closest of the palette's colors.
+        for &(_, _, i, j) in &pairs {
+            if swapped[i].is_none() {
+                swapped[i] = Some(shades[j]);
+            }
+        }
+        remap.colors = sources
+            .into_iter()
+            .zip(swapped)
+            .filter_map(|(color, swapped)| {
+                let [r, g, b] = swapped?;
+                Some((color, [r, g, b, 1.]))
+            })
+            .collect();
+        remap
+    }
+}
+
+/// Which color each color is drawn as, from [`Palette::remap`].
+#[derive(Clone, Debug, Default)]
+pub struct Remap {
+    colors: Vec<([GameInt; 4], [GameInt; 4])>,
+}
+
+impl Remap {
+    /// The color to draw `color` as. Colors that weren't remapped are drawn as they are.
+    pub fn get(&self, color: [GameInt; 4]) -> [GameInt; 4] {
+        self.colors
+            .iter()
+            .find(|(from, _)| *from == color)
+            .map_or(color, |&(_, to)| to)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Standard
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Palette::Standard => "standard",
+            Palette::Colorblind => "colorblind",
+            Palette::HighContrast => "high-contrast",
+        })
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|palette| palette.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "expected one of standard, colorblind or high-contrast, not {:?}",
+                    s
+                )
+            })
+    }
+}
+
+/// How entities are drawn, and how the view moves over them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub palette: Palette,
+    /// Whether to draw a dark outline around every entity, so that entities stand out from the
+    /// background and each other whatever their colors.
+    pub outlines: bool,
+    /// Whether to keep the view as still as possible, for players sensitive to motion: the camera
+    /// pans less often and more gently, as [`crate::camera::Settings::with_reduced_motion`] says.
+    pub reduced_motion: bool,
+}
+
+#[test]
+fn palettes_remap_to_closest_color() {
+    let faded_orange = [1., 0.6, 0.1, 0.3];
+    let remap = |palette: Palette| palette.remap(vec![faded_orange]).get(faded_orange);
+    assert_eq!(remap(Palette::Standard), faded_orange);
+    assert_eq!(remap(Palette::Colorblind), [0.9, 0.62, 0., 1.]);
+    assert_eq!(remap(Palette::HighContrast), [0.85, 0., 0., 1.]);
+
+    // Both are closest to the same red, but stay different.
+    let orange = [0.9, 0.1, 0., 1.];
+    let remap = Palette::HighContrast.remap(vec![faded_orange, orange, orange]);
+    assert_eq!(remap.get(orange), [0.85, 0., 0., 1.]);
+    assert_ne!(remap.get(faded_orange), remap.get(orange));
+
+    let nan = [GameInt::NAN, 0., 0., 1.];
+    assert!(Palette::Colorblind.remap(vec![nan]).get(nan)[0].is_nan());
+}
+
+#[test]
+fn palettes_parse_their_names() {
+    for &palette in &Palette::ALL {
+        assert_eq!(palette.to_string().parse(), Ok(palette));
+        assert_eq!(palette.next().next().next(), palette);
+    }
+    assert!("grayscale".parse::<Palette>().is_err());
+}
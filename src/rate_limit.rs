@@ -0,0 +1,201 @@
+//! Limiting how often clients can do things.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many inputs a player can push per second, and in a burst. Players press keys far less
+/// often than this.
+const INPUTS_PER_SECOND: f64 = 30.;
+const INPUT_BURST: u32 = 60;
+/// How long an input over the rate limit can be delayed before it's dropped instead.
+const MAX_INPUT_DELAY: Duration = Duration::from_millis(100);
+/// How many inputs a player can have dropped per second, and in a burst, before being
+/// disconnected.
+const DROPPED_INPUTS_PER_SECOND: f64 = 5.;
+const DROPPED_INPUT_BURST: u32 = 50;
+
+/// A token bucket: allows bursts of up to `capacity` events, refilled at `rate` events per second.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u32, rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity: f64::from(capacity),
+            rate,
+            tokens: f64::from(capacity),
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token, borrowing against future refills if it would be available within
+    /// `max_wait`. Returns how long to wait before acting on the token, or `None` if none could
+    /// be taken.
+    pub(crate) fn take(&mut self, now: Instant, max_wait: Duration) -> Option<Duration> {
+        if let Some(elapsed) = now.checked_duration_since(self.last_refill) {
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+            self.last_refill = now;
+        }
+        let wait = Duration::from_secs_f64((1. - self.tokens).max(0.) / self.rate);
+        if wait > max_wait {
+            return None;
+        }
+        self.tokens -= 1.;
+        Some(wait)
+    }
+}
+
+/// What to do with an input a player pushed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InputCheck {
+    /// Apply it after waiting this long.
+    Apply(Duration),
+    /// Drop it, because it's over the rate limit.
+    Drop,
+    /// Drop it, and disconnect the player, who keeps going over the rate limit.
+    Flooding,
+}
+
+/// Limits how often a player's inputs are applied, however they're sent.
+#[derive(Clone, Debug)]
+pub(crate) struct InputLimit {
+    inputs: TokenBucket,
+    /// Players who keep going over `inputs` run out of this.
+    dropped_inputs: TokenBucket,
+}
+
+impl InputLimit {
+    pub(crate) fn new(now: Instant) -> Self {
+        InputLimit {
+            inputs: TokenBucket::new(INPUT_BURST, INPUTS_PER_SECOND, now),
+            dropped_inputs: TokenBucket::new(DROPPED_INPUT_BURST, DROPPED_INPUTS_PER_SECOND, now),
+        }
+    }
+
+    /// Checks an input pushed at `now`.
+    pub(crate) fn check(&mut self, now: Instant) -> InputCheck {
+        if let Some(wait) = self.inputs.take(now, MAX_INPUT_DELAY) {
+            return InputCheck::Apply(wait);
+        }
+        match self.dropped_inputs.take(now, Duration::from_secs(0)) {
+            Some(_) => InputCheck::Drop,
+            None => InputCheck::Flooding,
+        }
+    }
+}
+
+/// What to do with a chat message a player sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChatCheck {
+    Say,
+    /// Drop it, because the player said the same thing recently.
+    Duplicate,
+    /// Drop it, because it's over the rate limit.
+    Drop,
+    /// Drop it, because it's over the rate limit, and report the player, who just went over it.
+    Throttle,
+}
+
+/// Limits how often a player can chat, and keeps them from repeating themselves.
+#[derive(Clone, Debug)]
+pub(crate) struct ChatLimit {
+    messages: TokenBucket,
+    /// How long a player has to wait to say the same thing again.
+    repeat_window: Duration,
+    /// The messages said within `repeat_window`, oldest first.
+    said: VecDeque<(Instant, String)>,
+    /// Whether the player's last message was over the rate limit, so that a flood of messages is
+    /// reported once.
+    throttled: bool,
+}
+
+impl ChatLimit {
+    pub(crate) fn new(burst: u32, rate: f64, repeat_window: Duration, now: Instant) -> Self {
+        ChatLimit {
+            messages: TokenBucket::new(burst, rate, now),
+            repeat_window,
+            said: VecDeque::new(),
+            throttled: false,
+        }
+    }
+
+    /// Checks `message`, sent at `now`. Repeated messages count toward the rate limit too, so
+    /// that players spamming them are reported.
+    pub(crate) fn check(&mut self, now: Instant, message: &str) -> ChatCheck {
+        if self.messages.take(now, Duration::from_secs(0)).is_none() {
+            let first = !self.throttled;
+            self.throttled = true;
+            return if first {
+                ChatCheck::Throttle
+            } else {
+                ChatCheck::Drop
+            };
+        }
+        self.throttled = false;
+        while let Some(&(at, _)) = self.said.front() {
+            if now.saturating_duration_since(at) < self.repeat_window {
+                break;
+            }
+            self.said.pop_front();
+        }
+        if self.said.iter().any(|(_, said)| said == message) {
+            return ChatCheck::Duplicate;
+        }
+        self.said.push_back((now, String::from(message)));
+        ChatCheck::Say
+    }
+}
+
+#[test]
+fn token_bucket_allows_bursts_then_limits_rate() {
+    let start = Instant::now();
+    let no_wait = Duration::from_secs(0);
+    let mut bucket = TokenBucket::new(2, 10., start);
+    assert_eq!(bucket.take(start, no_wait), Some(no_wait));
+    assert_eq!(bucket.take(start, no_wait), Some(no_wait));
+    assert_eq!(bucket.take(start, no_wait), None);
+
+    // The next token is 100ms away, and can be borrowed by waiting for it.
+    let wait = bucket.take(start, Duration::from_millis(200)).unwrap();
+    assert!(wait > Duration::from_millis(99) && wait <= Duration::from_millis(100));
+    // Now in debt until 200ms.
+    assert_eq!(
+        bucket.take(start + Duration::from_millis(150), no_wait),
+        None
+    );
+    assert_eq!(
+        bucket.take(start + Duration::from_millis(200), no_wait),
+        Some(no_wait)
+    );
+
+    // Refills stop at the capacity.
+    let later = start + Duration::from_secs(10);
+    assert_eq!(bucket.take(later, no_wait), Some(no_wait));
+    assert_eq!(bucket.take(later, no_wait), Some(no_wait));
+    assert_eq!(bucket.take(later, no_wait), None);
+}
+
+#[test]
+fn chat_limit_drops_repeats_and_reports_floods_once() {
+    let start = Instant::now();
+    let mut limit = ChatLimit::new(3, 1., Duration::from_secs(30), start);
+    assert_eq!(limit.check(start, "hi"), ChatCheck::Say);
+    assert_eq!(limit.check(start, "hi"), ChatCheck::Duplicate);
+    assert_eq!(limit.check(start, "gg"), ChatCheck::Say);
+    assert_eq!(limit.check(start, "wp"), ChatCheck::Throttle);
+    assert_eq!(limit.check(start, "wp"), ChatCheck::Drop);
+
+    // Messages can be repeated once the window has passed.
+    let later = start + Duration::from_secs(30);
+    assert_eq!(limit.check(later, "hi"), ChatCheck::Say);
+    assert_eq!(limit.check(later, "wp"), ChatCheck::Say);
+    assert_eq!(limit.check(later, "ok"), ChatCheck::Say);
+    assert_eq!(limit.check(later, "ok"), ChatCheck::Throttle);
+}
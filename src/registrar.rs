@@ -0,0 +1,113 @@
+//! Keeps a game registered with the game list, registering it again whenever the game list
+//! forgets it or can't be reached, like when the game list restarts.
+
+use crate::{error::Error, game_list, status::Status, transport};
+use futures::prelude::*;
+use log::{info, warn};
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use tarpc::context;
+use tokio::time;
+
+/// How long to wait before registering again after the first failure. Each failure in a row
+/// doubles the wait, up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long the game list has to unregister the game, so that shutting down isn't held up by a
+/// game list that can't be reached.
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) struct Registrar {
+    /// Where the game list accepts registrations.
+    pub registry_addr: SocketAddr,
+    pub transport_config: transport::Config,
+    /// The port the game accepts players on.
+    pub port: u16,
+    pub registration: game_list::Registration,
+    /// The key to register with, for game lists that require one.
+    pub key: Option<String>,
+    /// How often to check that the game is still registered. Each check sends a heartbeat.
+    pub interval: Duration,
+}
+
+impl Registrar {
+    /// Connects to the game list and registers the game.
+    pub async fn register(&self) -> Result<crate::GameRegistrationClient, Error> {
+        let transport = transport::connect(&self.registry_addr, &self.transport_config).await?;
+        let client =
+            crate::GameRegistrationClient::new(tarpc::client::Config::default(), transport)
+                .spawn()?;
+        if let Some(key) = &self.key {
+            if !client.authenticate(context::current(), key.clone()).await? {
+                let e = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the game list rejected the registration key",
+                );
+                return Err(e.into());
+            }
+        }
+        client
+            .register(context::current(), self.port, self.registration.clone())
+            .await??;
+        Ok(client)
+    }
+
+    /// Keeps the game registered until `stop` resolves, then unregisters it. `client` is the
+    /// client the game was registered with, if it was. Heartbeats carry what `status` returns.
+    pub async fn keep_registered<F>(
+        self,
+        mut client: Option<crate::GameRegistrationClient>,
+        status: F,
+        stop: impl Future<Output = ()>,
+    ) where
+        F: Fn() -> Status,
+    {
+        let maintained = self.maintain(&mut client, &status);
+        future::select(Box::pin(maintained), Box::pin(stop)).await;
+        if let Some(client) = client {
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + UNREGISTER_TIMEOUT;
+            if let Err(e) = client.unregister(ctx, self.port).await {
+                warn!("Failed to unregister game: {}", e);
+            }
+        }
+    }
+
+    async fn maintain<F>(&self, client: &mut Option<crate::GameRegistrationClient>, status: &F)
+    where
+        F: Fn() -> Status,
+    {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            if let Some(registered) = client {
+                match registered
+                    .heartbeat(context::current(), self.port, status())
+                    .await
+                {
+                    Ok(true) => {
+                        time::delay_for(self.interval).await;
+                        continue;
+                    }
+                    Ok(false) => warn!("Game list forgot the game; registering it again"),
+                    Err(e) => warn!("Lost the game list; registering the game again: {}", e),
+                }
+            }
+            *client = None;
+            match self.register().await {
+                Ok(registered) => {
+                    info!("Registered game with the game list");
+                    *client = Some(registered);
+                    backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    warn!("Failed to register game, retrying in {:?}: {}", backoff, e);
+                    time::delay_for(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
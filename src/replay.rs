@@ -0,0 +1,349 @@
+//! Recording games to files, and playing the recordings back, so that matches can be reviewed
+//! offline.
+//!
+//! A replay file is a sequence of bincode-encoded frames. Every few seconds a frame has the whole
+//! game state, and the frames in between only have what changed since the frame before, so that
+//! playback can seek without keeping every state in memory.
+//!
+//! Servers record more compactly: [`INPUTS_MAGIC`], then the game as it started, then every tick
+//! with the commands applied before it. Those recordings are simulated into frames when loaded.
+
+use crate::{
+    game::{Game, StateUpdate},
+    rollback::Command,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How often the game is recorded.
+const RECORD_INTERVAL: Duration = Duration::from_millis(50);
+/// How often a frame with the whole game state is recorded.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(5);
+/// What server recordings start with, to tell them apart from recordings of frames.
+const INPUTS_MAGIC: &[u8; 14] = b"shapes-inputs\n";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Frame {
+    /// How long after the recording started the frame was recorded.
+    at: Duration,
+    update: StateUpdate,
+}
+
+fn invalid_data(e: bincode::Error) -> io::Error {
+    match *e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Turns a game's states into frames, every [`RECORD_INTERVAL`].
+#[derive(Default)]
+struct Framer {
+    /// The last game framed, and when.
+    last: Option<(Duration, Box<Game>)>,
+    last_keyframe: Duration,
+}
+
+impl Framer {
+    /// The frame for `game` as it was at `at`, unless the last frame was too recent.
+    fn frame(&mut self, at: Duration, game: &Game) -> Option<Frame> {
+        let update = match &self.last {
+            Some((last_at, _)) if at - *last_at < RECORD_INTERVAL => return None,
+            Some((_, last)) if at - self.last_keyframe < KEYFRAME_INTERVAL => {
+                StateUpdate::Delta(game.delta_since(last))
+            }
+            _ => {
+                self.last_keyframe = at;
+                StateUpdate::Full(Box::new(game.clone()))
+            }
+        };
+        self.last = Some((at, Box::new(game.clone())));
+        Some(Frame { at, update })
+    }
+}
+
+/// Records a game to a replay file as it's played.
+pub struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+    framer: Framer,
+}
+
+impl Recorder {
+    /// Creates the replay file at `path`, replacing any file already there.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Recorder {
+            file: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+            framer: Framer::default(),
+        })
+    }
+
+    /// Records `game` as it is now, unless it was last recorded too recently.
+    pub fn record(&mut self, game: &Game) -> io::Result<()> {
+        let frame = match self.framer.frame(self.started.elapsed(), game) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        bincode::serialize_into(&mut self.file, &frame).map_err(invalid_data)?;
+        // Flushed every frame so that the recording is usable even if the client doesn't exit
+        // cleanly.
+        self.file.flush()
+    }
+}
+
+/// A tick recorded by the server: the commands applied since the tick before, and how long the
+/// tick was.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RecordedTick {
+    /// The game's tick count before the tick.
+    tick: u64,
+    commands: Vec<Command>,
+    dt: f32,
+}
+
+/// Records the game as the server started it and every tick since, which is enough to simulate
+/// the whole match again.
+pub(crate) struct InputRecorder {
+    file: BufWriter<File>,
+}
+
+impl InputRecorder {
+    /// Creates the recording at `path`, replacing any file already there, starting from `game`.
+    pub(crate) fn create(path: &Path, game: &Game) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(INPUTS_MAGIC)?;
+        bincode::serialize_into(&mut file, game).map_err(invalid_data)?;
+        Ok(InputRecorder { file })
+    }
+
+    /// Records that `commands` were applied to the game at `tick`, which then ticked for `dt`.
+    /// Ticks must be recorded in order, once they're too old to be changed by late inputs.
+    pub(crate) fn record(&mut self, tick: u64, commands: &[Command], dt: f32) -> io::Result<()> {
+        let tick = RecordedTick {
+            tick,
+            commands: commands.to_vec(),
+            dt,
+        };
+        bincode::serialize_into(&mut self.file, &tick).map_err(invalid_data)
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A recording loaded from a replay file.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    frames: Vec<Frame>,
+}
+
+impl Replay {
+    /// Loads a replay recorded by a client, or by a server with `--record`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        if file.fill_buf()?.starts_with(INPUTS_MAGIC) {
+            file.consume(INPUTS_MAGIC.len());
+            return Self::simulate(file);
+        }
+        let mut frames = vec![];
+        while !file.fill_buf()?.is_empty() {
+            frames.push(bincode::deserialize_from(&mut file).map_err(invalid_data)?);
+        }
+        match frames.first() {
+            Some(Frame {
+                update: StateUpdate::Full(_),
+                ..
+            }) => Ok(Replay { frames }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "replay doesn't start with a whole game state",
+            )),
+        }
+    }
+
+    /// Plays a server's recording through from the start, framing the game as it goes.
+    fn simulate(mut file: impl BufRead) -> io::Result<Self> {
+        let mut game: Game = bincode::deserialize_from(&mut file).map_err(invalid_data)?;
+        let mut framer = Framer::default();
+        let mut at = Duration::default();
+        let mut frames: Vec<_> = framer.frame(at, &game).into_iter().collect();
+        while !file.fill_buf()?.is_empty() {
+            let tick: RecordedTick = bincode::deserialize_from(&mut file).map_err(invalid_data)?;
+            if tick.tick != game.ticks() {
+                warn!(
+                    "Recorded tick {} doesn't follow tick {}",
+                    tick.tick,
+                    game.ticks()
+                );
+            }
+            for command in &tick.commands {
+                command.apply(&mut game);
+            }
+            game.tick(tick.dt, &mut 0., &mut 0);
+            at += Duration::from_secs_f32(tick.dt);
+            frames.extend(framer.frame(at, &game));
+        }
+        Ok(Replay { frames })
+    }
+
+    /// How long the recording is.
+    pub fn duration(&self) -> Duration {
+        self.frames
+            .last()
+            .map_or_else(Duration::default, |frame| frame.at)
+    }
+}
+
+/// Plays a replay back, keeping the game as it was at the current position in the recording.
+pub struct Playback {
+    replay: Replay,
+    /// The index of the latest frame applied to `game`.
+    index: usize,
+    game: Box<Game>,
+    position: Duration,
+    /// How many times faster than real time the replay plays.
+    pub speed: f32,
+    pub paused: bool,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        let mut playback = Playback {
+            replay,
+            index: 0,
+            game: Box::new(Game::default()),
+            position: Duration::default(),
+            speed: 1.,
+            paused: false,
+        };
+        playback.apply(0);
+        playback
+    }
+
+    /// The game as it was at the current position.
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    /// How far into the recording playback is.
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.replay.duration()
+    }
+
+    /// Plays `dt` of real time worth of the recording, unless paused.
+    pub fn advance(&mut self, dt: Duration) {
+        if !self.paused {
+            let position = self.position + dt.mul_f32(self.speed);
+            self.seek(position);
+        }
+    }
+
+    /// Moves playback to `position`, or to the end if it's past the end.
+    pub fn seek(&mut self, position: Duration) {
+        self.position = position.min(self.duration());
+        if self.position < self.replay.frames[self.index].at {
+            // Start over from the last whole game state before the new position.
+            let position = self.position;
+            self.index = self
+                .replay
+                .frames
+                .iter()
+                .rposition(|frame| match frame.update {
+                    StateUpdate::Full(_) => frame.at <= position,
+                    StateUpdate::Delta(_) => false,
+                })
+                .unwrap_or(0);
+            self.apply(self.index);
+        }
+        while self
+            .replay
+            .frames
+            .get(self.index + 1)
+            .map_or(false, |next| next.at <= self.position)
+        {
+            self.index += 1;
+            self.apply(self.index);
+        }
+    }
+
+    fn apply(&mut self, index: usize) {
+        let update = self.replay.frames[index].update.clone();
+        if let Err(e) = self.game.apply_update(update) {
+            warn!("Replay frame {} doesn't apply: {:?}", index, e);
+        }
+    }
+}
+
+#[test]
+fn playback_seeks_back_to_keyframes() {
+    let mut games = vec![Game::default()];
+    for _ in 0..3 {
+        let mut game = games.last().unwrap().clone();
+        game.tick(0.1, &mut 0., &mut 0);
+        games.push(game);
+    }
+    let frame = |i: usize, update| Frame {
+        at: Duration::from_secs(i as u64),
+        update,
+    };
+    let frames = vec![
+        frame(0, StateUpdate::Full(Box::new(games[0].clone()))),
+        frame(1, StateUpdate::Delta(games[1].delta_since(&games[0]))),
+        frame(2, StateUpdate::Full(Box::new(games[2].clone()))),
+        frame(3, StateUpdate::Delta(games[3].delta_since(&games[2]))),
+    ];
+    let mut playback = Playback::new(Replay { frames });
+    assert_eq!(playback.game().ticks(), 0);
+
+    playback.advance(Duration::from_millis(3500));
+    assert_eq!(playback.game().ticks(), 3);
+    assert_eq!(playback.position(), Duration::from_secs(3));
+
+    playback.seek(Duration::from_millis(1500));
+    assert_eq!(playback.game().ticks(), 1);
+
+    playback.paused = true;
+    playback.advance(Duration::from_secs(1));
+    assert_eq!(playback.game().ticks(), 1);
+}
+
+#[test]
+fn server_recordings_simulate_the_match() {
+    use crate::game::{Component, Input, Point, Sign};
+
+    let game = Game::new(Point::new(1000., 500.), 50.);
+    let mut played = game.clone();
+    let player = game.new_player_square();
+    let id = played.add_player(player);
+    let input = Input::Move(Component::X, Some(Sign::Positive));
+    played.process_input(id, input);
+    let path = std::env::temp_dir().join(format!("shapes-inputs-{}.bin", std::process::id()));
+    let mut recorder = InputRecorder::create(&path, &game).unwrap();
+    let commands = [Command::AddPlayer(player), Command::Input(id, input)];
+    recorder.record(0, &commands, 0.1).unwrap();
+    played.tick(0.1, &mut 0., &mut 0);
+    for tick in 1..10 {
+        recorder.record(tick, &[], 0.1).unwrap();
+        played.tick(0.1, &mut 0., &mut 0);
+    }
+    recorder.finish().unwrap();
+
+    let replay = Replay::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    let mut playback = Playback::new(replay.unwrap());
+    playback.seek(Duration::from_secs(2));
+    assert_eq!(playback.game().ticks(), 10);
+    assert_eq!(playback.game().entity(id), played.entity(id));
+}
@@ -0,0 +1,379 @@
+//! Recent game history, so that inputs that arrive late can be applied at the tick they were made
+//! at, re-simulating the game from there.
+
+use crate::{
+    game::{Entity, EntityId, Game, GameInt, Input, Layout, Point},
+    mode::Round,
+    replay::InputRecorder,
+};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, io, mem, path::Path};
+
+/// A change made to the game between ticks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    Input(EntityId, Input),
+    AddPlayer(Entity),
+    RemovePlayer(EntityId),
+    NamePlayer(EntityId, String),
+    Chat(EntityId, String),
+    Announce(String),
+    SetColor(EntityId, [GameInt; 3]),
+    SetLayout(Layout),
+    Teleport(EntityId, Point),
+    /// Removes an entity that isn't a player's.
+    RemoveEntity(EntityId),
+    SetAfk(EntityId, bool),
+    NewRound(Round),
+}
+
+impl Command {
+    pub(crate) fn apply(&self, game: &mut Game) {
+        match *self {
+            Command::Input(id, input) => {
+                if game.contains(id) {
+                    game.process_input(id, input);
+                }
+            }
+            Command::AddPlayer(entity) => {
+                game.add_player(entity);
+            }
+            Command::RemovePlayer(id) => {
+                if game.contains(id) {
+                    game.remove_player(id);
+                }
+            }
+            Command::NamePlayer(id, ref name) => {
+                if game.contains(id) {
+                    game.name_player(id, name.clone());
+                }
+            }
+            Command::Chat(id, ref message) => {
+                if game.contains(id) {
+                    game.chat(id, message.clone());
+                }
+            }
+            Command::Announce(ref message) => game.announce(message.clone()),
+            Command::SetLayout(ref layout) => game.set_layout(layout),
+            Command::NewRound(ref round) => {
+                round.start(game);
+            }
+            Command::SetAfk(id, afk) => {
+                if game.contains(id) {
+                    game.set_afk(id, afk);
+                }
+            }
+            Command::RemoveEntity(id) => {
+                if game.contains(id) {
+                    game.remove_entity(id);
+                }
+            }
+            Command::Teleport(id, point) => {
+                if game.contains(id) {
+                    game.teleport(id, point);
+                }
+            }
+            Command::SetColor(id, rgb) => {
+                if game.contains(id) {
+                    game.set_color(id, rgb);
+                }
+            }
+        }
+    }
+}
+
+/// The commands applied between two ticks, and the duration of the second tick.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Frame {
+    commands: Vec<Command>,
+    dt: f32,
+    /// Which entities ran into which during the second tick, as last simulated.
+    #[serde(skip)]
+    collisions: Vec<(EntityId, EntityId)>,
+}
+
+/// A tick that's left the history, so that late inputs can't change it anymore.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfirmedTick {
+    pub tick: u64,
+    /// Which entities ran into which during the tick, the moving one first.
+    pub collisions: Vec<(EntityId, EntityId)>,
+}
+
+/// How many ticks apart the game is snapshotted, to re-simulate from. Snapshotting copies the
+/// whole game, so it isn't done every tick; late inputs re-simulate from the snapshot before them.
+const SNAPSHOT_INTERVAL: u64 = 8;
+
+/// A game along with its last few ticks of history.
+pub struct History {
+    /// The game as of the latest tick, with the pending commands applied.
+    game: Game,
+    /// The game every [`SNAPSHOT_INTERVAL`] ticks, oldest first. The oldest is never newer than
+    /// the oldest tick late inputs can still be applied at.
+    snapshots: VecDeque<Game>,
+    /// The commands and durations of every tick since the oldest snapshot: `frames[i]` applied
+    /// to the game at tick `snapshots[0].ticks() + i` produces the next tick.
+    frames: VecDeque<Frame>,
+    /// Commands applied since the latest tick.
+    pending: Vec<Command>,
+    max_ticks: usize,
+    /// Every tick before this one has been confirmed: recorded, and returned from
+    /// [`History::tick`].
+    confirmed_tick: u64,
+    /// The earliest tick a late input was applied at since the latest tick. The ticks since are
+    /// re-simulated once, at the next tick, however many late inputs arrive in between.
+    rollback_from: Option<u64>,
+    /// Where ticks are recorded once they're too old to change, if anywhere.
+    recorder: Option<InputRecorder>,
+}
+
+impl History {
+    /// Keeps enough history to apply inputs up to `max_ticks` late.
+    pub fn new(game: Game, max_ticks: usize) -> Self {
+        let mut snapshots = VecDeque::new();
+        snapshots.push_back(game.clone());
+        History {
+            confirmed_tick: game.ticks(),
+            game,
+            snapshots,
+            frames: VecDeque::with_capacity(max_ticks + SNAPSHOT_INTERVAL as usize),
+            pending: vec![],
+            max_ticks,
+            rollback_from: None,
+            recorder: None,
+        }
+    }
+
+    /// The tick of the oldest snapshot, which `frames[0]` is applied to.
+    fn base_tick(&self) -> u64 {
+        self.snapshots[0].ticks()
+    }
+
+    /// Keeps enough history to apply inputs up to `max_ticks` late from now on, like when the
+    /// tick rate changes. A longer window fills in as the game ticks, since confirmed ticks stay
+    /// confirmed; the ticks a shorter one leaves out are confirmed at the next tick.
+    pub(crate) fn set_max_ticks(&mut self, max_ticks: usize) {
+        self.max_ticks = max_ticks;
+    }
+
+    /// The oldest tick late inputs can still be applied at. The ticks up to it can't change
+    /// anymore.
+    fn oldest_tick(&self) -> u64 {
+        self.game
+            .ticks()
+            .saturating_sub(self.max_ticks as u64)
+            .max(self.confirmed_tick)
+    }
+
+    /// How many of the frames, from the oldest, produce ticks that have been confirmed.
+    fn confirmed_frames(&self) -> usize {
+        (self.confirmed_tick - self.base_tick()) as usize
+    }
+
+    /// Records the game to `path`, from the oldest snapshot on. Ticks are recorded once they
+    /// can't change anymore, since until then late inputs can change them.
+    pub(crate) fn record(&mut self, path: &Path) -> io::Result<()> {
+        let mut recorder = InputRecorder::create(path, &self.snapshots[0])?;
+        let base = self.base_tick();
+        for (i, frame) in self.frames.iter().take(self.confirmed_frames()).enumerate() {
+            recorder.record(base + i as u64, &frame.commands, frame.dt)?;
+        }
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Records the ticks that could still change, and stops recording.
+    pub(crate) fn finish_recording(&mut self) -> io::Result<()> {
+        self.rollback();
+        if let Some(mut recorder) = self.recorder.take() {
+            let base = self.base_tick();
+            let confirmed = self.confirmed_frames();
+            for (i, frame) in self.frames.iter().enumerate().skip(confirmed) {
+                recorder.record(base + i as u64, &frame.commands, frame.dt)?;
+            }
+            recorder.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn apply(&mut self, command: Command) {
+        command.apply(&mut self.game);
+        self.pending.push(command);
+    }
+
+    pub fn add_player(&mut self, entity: Entity) -> EntityId {
+        let id = self.game.add_player(entity);
+        self.pending.push(Command::AddPlayer(entity));
+        id
+    }
+
+    /// Logs that the server is shutting down. This isn't a command, since no ticks are
+    /// re-simulated after the final one.
+    pub fn announce_closing(&mut self, reason: String) {
+        self.game.announce_closing(reason);
+    }
+
+    /// Ticks the game, returning the ticks that can't change anymore because of it, oldest first.
+    /// That's one tick at a time, except after the history was shortened.
+    pub fn tick(
+        &mut self,
+        dt: f32,
+        time_in_current_bucket: &mut f32,
+        ticks_in_current_bucket: &mut i32,
+    ) -> Vec<ConfirmedTick> {
+        self.rollback();
+        self.game
+            .tick(dt, time_in_current_bucket, ticks_in_current_bucket);
+        self.frames.push_back(Frame {
+            commands: mem::take(&mut self.pending),
+            dt,
+            collisions: self.game.collisions().to_vec(),
+        });
+        let latest_snapshot = self.snapshots.back().unwrap().ticks();
+        if self.game.ticks() - latest_snapshot >= SNAPSHOT_INTERVAL {
+            self.snapshots.push_back(self.game.clone());
+        }
+
+        let mut confirmed = vec![];
+        let oldest = self.oldest_tick();
+        while self.confirmed_tick < oldest {
+            let tick = self.confirmed_tick;
+            let frame = &self.frames[(tick - self.base_tick()) as usize];
+            if let Some(recorder) = &mut self.recorder {
+                if let Err(e) = recorder.record(tick, &frame.commands, frame.dt) {
+                    error!("Failed to record tick {}; recording stopped: {}", tick, e);
+                    self.recorder = None;
+                }
+            }
+            confirmed.push(ConfirmedTick {
+                tick: tick + 1,
+                collisions: frame.collisions.clone(),
+            });
+            self.confirmed_tick += 1;
+        }
+        // Snapshots are only needed from the one before the oldest tick that can change.
+        while self.snapshots.len() > 1 && self.snapshots[1].ticks() <= self.oldest_tick() {
+            let dropped = self.snapshots[1].ticks() - self.base_tick();
+            self.snapshots.pop_front();
+            self.frames.drain(..dropped as usize);
+        }
+        confirmed
+    }
+
+    /// Applies `input` as though it arrived right after `tick`. The ticks since are re-simulated
+    /// at the next tick. Inputs for ticks older than the retained history are applied now.
+    pub fn apply_input_at(&mut self, tick: u64, id: EntityId, input: Input) {
+        let command = Command::Input(id, input);
+        let now = self.game.ticks();
+        // Shooting inserts an entity; doing that in the past would change the ids of every entity
+        // inserted since, which connections and commands refer to.
+        if tick >= now || tick < self.oldest_tick() || input == Input::Shoot {
+            self.apply(command);
+            return;
+        }
+        debug!("Rolling back {} ticks to apply {:?}", now - tick, input);
+        let start = (tick - self.base_tick()) as usize;
+        self.frames[start].commands.push(command);
+        self.rollback_from = Some(self.rollback_from.map_or(tick, |from| from.min(tick)));
+    }
+
+    /// Re-simulates the ticks since the earliest late input, from the snapshot before it.
+    fn rollback(&mut self) {
+        let tick = match self.rollback_from.take() {
+            Some(tick) => tick,
+            None => return,
+        };
+        let base = self.base_tick();
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.ticks() <= tick)
+            .unwrap();
+        let mut game = self.snapshots[snapshot].clone();
+        let start = (game.ticks() - base) as usize;
+        for frame in self.frames.iter_mut().skip(start) {
+            for command in &frame.commands {
+                command.apply(&mut game);
+            }
+            game.tick(frame.dt, &mut 0., &mut 0);
+            frame.collisions = game.collisions().to_vec();
+            if let Some(later) = self
+                .snapshots
+                .iter_mut()
+                .skip(snapshot + 1)
+                .find(|later| later.ticks() == game.ticks())
+            {
+                *later = game.clone();
+            }
+        }
+        for command in &self.pending {
+            command.apply(&mut game);
+        }
+        self.game = game;
+    }
+}
+
+#[test]
+fn history_late_input_matches_on_time_input() {
+    use crate::game::{Component, Point, Sign};
+
+    let game = Game::new(Point::new(1000., 500.), 50.);
+    let mut on_time = History::new(game.clone(), 20);
+    let mut late = History::new(game, 20);
+    let player = on_time.game().new_player_square();
+    let id = on_time.add_player(player);
+    assert_eq!(id, late.add_player(player));
+    let input = Input::Move(Component::X, Some(Sign::Positive));
+
+    // Late enough to be re-simulated from a snapshot a few ticks before it.
+    for tick in 0..30 {
+        if tick == 13 {
+            on_time.apply(Command::Input(id, input));
+        }
+        on_time.tick(0.1, &mut 0., &mut 0);
+    }
+    let mut confirmed = vec![];
+    for _ in 0..30 {
+        confirmed.extend(late.tick(0.1, &mut 0., &mut 0));
+    }
+    late.apply_input_at(13, id, input);
+    on_time.tick(0.1, &mut 0., &mut 0);
+    confirmed.extend(late.tick(0.1, &mut 0., &mut 0));
+
+    // Each tick is confirmed once, in order, once it's too old to change.
+    let ticks: Vec<_> = confirmed.iter().map(|confirmed| confirmed.tick).collect();
+    assert_eq!(ticks, (1..=11).collect::<Vec<_>>());
+    assert_eq!(late.game().ticks(), on_time.game().ticks());
+    for id in on_time.game().entity_ids() {
+        assert_eq!(late.game().entity(id), on_time.game().entity(id));
+    }
+}
+
+#[test]
+fn history_confirms_every_tick_when_its_window_changes() {
+    use crate::game::Point;
+
+    let mut history = History::new(Game::new(Point::new(1000., 500.), 50.), 20);
+    let mut confirmed = vec![];
+    for _ in 0..30 {
+        confirmed.extend(history.tick(0.1, &mut 0., &mut 0));
+    }
+    // Shortening the window confirms the ticks it leaves out all at once.
+    history.set_max_ticks(5);
+    confirmed.extend(history.tick(0.1, &mut 0., &mut 0));
+    let ticks: Vec<_> = confirmed.iter().map(|confirmed| confirmed.tick).collect();
+    assert_eq!(ticks, (1..=26).collect::<Vec<_>>());
+
+    // Lengthening it doesn't take back ticks that were confirmed.
+    history.set_max_ticks(20);
+    for _ in 0..20 {
+        confirmed.extend(history.tick(0.1, &mut 0., &mut 0));
+    }
+    let ticks: Vec<_> = confirmed.iter().map(|confirmed| confirmed.tick).collect();
+    assert_eq!(ticks, (1..=31).collect::<Vec<_>>());
+}
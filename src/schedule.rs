@@ -0,0 +1,249 @@
+//! Tasks games run on a schedule, kept as `[[schedule]]` tables in the server's config file:
+//!
+//! ```toml
+//! # Restart every night at 4am UTC, warning players 10 minutes and a minute before.
+//! [[schedule]]
+//! task = "restart"
+//! at = "04:00"
+//! warnings = [600, 60]
+//!
+//! # Move on to the next map in the rotation every hour.
+//! [[schedule]]
+//! task = "next_round"
+//! every = 3600
+//!
+//! # Save the game every 5 minutes.
+//! [[schedule]]
+//! task = "save"
+//! every = 300
+//! ```
+//!
+//! Tasks run through the game's [`Admin`], like the console's commands do. Restarting shuts the
+//! server down, for whatever runs it, like a service manager, to start it again.
+
+use crate::server::Admin;
+use log::{error, info};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Something a game can do on a schedule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    /// Shuts the server down, to be started again.
+    Restart,
+    /// Ends the round, starting the next one on the next map in the rotation.
+    NextRound,
+    /// Saves the game to where it's autosaved.
+    Save,
+}
+
+impl Task {
+    /// What players are told `warning` before the task runs.
+    fn warning(self, warning: Duration) -> String {
+        let secs = warning.as_secs();
+        let (count, unit) = if secs >= 3600 && secs % 3600 == 0 {
+            (secs / 3600, "hour")
+        } else if secs >= 60 && secs % 60 == 0 {
+            (secs / 60, "minute")
+        } else {
+            (secs, "second")
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        let task = match self {
+            Task::Restart => "The server restarts",
+            Task::NextRound => "The round ends",
+            Task::Save => "The game is saved",
+        };
+        format!("{} in {} {}{}", task, count, unit, plural)
+    }
+}
+
+/// A time of day in UTC, like `04:00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    secs_since_midnight: u64,
+}
+
+impl TimeOfDay {
+    /// The first time after `after` that it's this time of day.
+    fn next_after(self, after: SystemTime) -> SystemTime {
+        let now = after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut next = now - now % SECS_PER_DAY + self.secs_since_midnight;
+        if next <= now {
+            next += SECS_PER_DAY;
+        }
+        UNIX_EPOCH + Duration::from_secs(next)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a time of day like 04:00, not {:?}", s);
+        let i = s.find(':').ok_or_else(invalid)?;
+        let hours: u64 = s[..i].parse().map_err(|_| invalid())?;
+        let minutes: u64 = s[i + 1..].parse().map_err(|_| invalid())?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            secs_since_midnight: hours * 3600 + minutes * 60,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A task, and when to run it: either `every` so many seconds, or `at` a time every day.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scheduled {
+    pub task: Task,
+    /// Runs the task this many seconds apart, the first time this long after the game starts.
+    #[serde(default)]
+    pub every: Option<u64>,
+    /// Runs the task every day at this time.
+    #[serde(default)]
+    pub at: Option<TimeOfDay>,
+    /// How many seconds before each run of the task players are warned that it's coming.
+    #[serde(default)]
+    pub warnings: Vec<u64>,
+}
+
+impl Scheduled {
+    /// Checks that the task says when to run, one way.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.every, self.at) {
+            (Some(0), None) => Err(format!("{:?} can't run every 0 seconds", self.task)),
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err(format!(
+                "{:?} needs to run either every so many seconds or at a time of day",
+                self.task
+            )),
+        }
+    }
+
+    /// When the task runs next, after last running, or the game starting, at `last`.
+    fn next_after(&self, last: SystemTime) -> SystemTime {
+        match (self.every, self.at) {
+            (Some(every), _) => last + Duration::from_secs(every.max(1)),
+            (None, Some(at)) => at.next_after(last),
+            (None, None) => last + Duration::from_secs(SECS_PER_DAY),
+        }
+    }
+}
+
+/// Waits until `at`, by the system clock.
+async fn delay_until(at: SystemTime) {
+    if let Ok(wait) = at.duration_since(SystemTime::now()) {
+        time::delay_for(wait).await;
+    }
+}
+
+/// Runs `scheduled` through `admin` whenever it's due, warning players beforehand, until the
+/// game ends.
+pub(crate) async fn run(admin: Admin, scheduled: Scheduled) {
+    let mut warnings: Vec<_> = scheduled
+        .warnings
+        .iter()
+        .copied()
+        .map(Duration::from_secs)
+        .collect();
+    // The furthest ahead goes first.
+    warnings.sort_by(|a, b| b.cmp(a));
+    let mut last = SystemTime::now();
+    loop {
+        let due = scheduled.next_after(last);
+        for &warning in &warnings {
+            let warn_at = match due.checked_sub(warning) {
+                Some(warn_at) if warn_at >= SystemTime::now() => warn_at,
+                _ => continue,
+            };
+            delay_until(warn_at).await;
+            admin.say(&scheduled.task.warning(warning));
+        }
+        delay_until(due).await;
+        info!("Running the scheduled {:?}", scheduled.task);
+        match scheduled.task {
+            Task::Restart => admin.shutdown("the server is restarting"),
+            Task::NextRound => admin.next_round(),
+            Task::Save => {
+                if let Err(e) = admin.save(None) {
+                    error!("Failed to save the game on schedule: {}", e);
+                }
+            }
+        }
+        last = due;
+    }
+}
+
+#[test]
+fn schedules_parse_and_come_due() {
+    #[derive(Deserialize)]
+    struct Config {
+        schedule: Vec<Scheduled>,
+    }
+    let config: Config = toml::from_str(
+        r#"
+        [[schedule]]
+        task = "restart"
+        at = "04:30"
+        warnings = [600, 60]
+
+        [[schedule]]
+        task = "next_round"
+        every = 3600
+        "#,
+    )
+    .unwrap();
+    let (restart, next_round) = (&config.schedule[0], &config.schedule[1]);
+    assert_eq!(restart.validate(), Ok(()));
+    assert_eq!(next_round.validate(), Ok(()));
+
+    let day = Duration::from_secs(SECS_PER_DAY);
+    let morning = UNIX_EPOCH + day * 3 + Duration::from_secs(4 * 3600);
+    let half_past = morning + Duration::from_secs(1800);
+    assert_eq!(restart.next_after(morning), half_past);
+    assert_eq!(restart.next_after(half_past), half_past + day);
+    assert_eq!(
+        next_round.next_after(morning),
+        morning + Duration::from_secs(3600)
+    );
+    assert_eq!(
+        Task::Restart.warning(Duration::from_secs(600)),
+        "The server restarts in 10 minutes"
+    );
+    assert_eq!(
+        Task::NextRound.warning(Duration::from_secs(60)),
+        "The round ends in 1 minute"
+    );
+
+    assert!("24:00".parse::<TimeOfDay>().is_err());
+    assert!("4am".parse::<TimeOfDay>().is_err());
+    let unscheduled = Scheduled {
+        task: Task::Save,
+        every: None,
+        at: None,
+        warnings: vec![],
+    };
+    assert!(unscheduled.validate().is_err());
+}
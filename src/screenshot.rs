@@ -0,0 +1,65 @@
+//! Saving what's drawn in the window to PNG files, for bug reports about how the game is drawn.
+
+use piston_window::{OpenGLWindow, PistonWindow, Window};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of a screenshot taken at `time`, like `shapes-1580000000.123.png`, so that
+/// screenshots sort in the order they were taken.
+fn file_name(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "shapes-{}.{:03}.png",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Reads what's been drawn for the current frame. Must be called after drawing the frame and
+/// before it's shown, while it's still in the back buffer.
+fn capture(window: &mut PistonWindow) -> image::RgbaImage {
+    gl::load_with(|name| window.window.get_proc_address(name) as *const _);
+    let size = window.draw_size();
+    let (width, height) = (size.width as u32, size.height as u32);
+    let mut pixels = vec![0; 4 * width as usize * height as usize];
+    unsafe {
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(
+            0,
+            0,
+            width as i32,
+            height as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut _,
+        );
+    }
+    let image = image::RgbaImage::from_raw(width, height, pixels)
+        .expect("read exactly enough pixels for the frame");
+    // OpenGL's rows go from the bottom up, and images' from the top down.
+    image::imageops::flip_vertical(&image)
+}
+
+/// Saves the current frame to a new PNG file in `dir`, creating `dir` if needed. Returns the
+/// file's path.
+pub fn save(window: &mut PistonWindow, dir: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(file_name(SystemTime::now()));
+    capture(window).save(&path).map_err(|e| match e {
+        image::ImageError::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    })?;
+    Ok(path)
+}
+
+#[test]
+fn screenshot_names_sort_by_time() {
+    use std::time::Duration;
+
+    let name = |millis| file_name(UNIX_EPOCH + Duration::from_millis(millis));
+    assert_eq!(name(1_580_000_000_123), "shapes-1580000000.123.png");
+    assert!(name(1_580_000_000_009) < name(1_580_000_000_010));
+}
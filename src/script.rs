@@ -0,0 +1,283 @@
+//! Scripts that change the rules of a server's game, written in [Rhai](https://rhai.rs). A script
+//! defines any of these hooks, which the server calls after each tick:
+//!
+//! - `on_tick(tick)`, every tick.
+//! - `on_join(player)`, when a player joins.
+//! - `on_collision(mover, other)`, when an entity moves into another.
+//!
+//! Entities are passed as maps of their `id`, the `x` and `y` of their top left corner, their
+//! `red`, `green` and `blue`, and whether they're a `player`'s. Hooks change the game by
+//! calling `teleport(id, x, y)`, `set_color(id, red, green, blue)` and `announce(message)`, which
+//! take effect at the next tick. For example, to send anyone who touches a red block back to the
+//! start:
+//!
+//! ```text
+//! fn on_collision(mover, other) {
+//!     if mover.player && other.red > 0.9 && other.green < 0.1 && other.blue < 0.1 {
+//!         teleport(mover.id, 0.0, 0.0);
+//!     }
+//! }
+//! ```
+//!
+//! Scripts can only reach the game through these functions, which check their arguments the same
+//! way the server checks players' requests. A hook that runs for too long is stopped, and a hook
+//! that fails is disabled. Hooks that don't fit in a tick's time budget are skipped for that tick.
+
+use crate::{
+    game::{EntityId, Game, GameInt, Point},
+    rollback::Command,
+    server::{self, MAX_CHAT_LENGTH},
+};
+use log::{error, warn};
+use rhai::{Dynamic, Engine, FuncArgs, Map, Scope, AST};
+use std::{
+    collections::HashSet,
+    fs, io, mem,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The hooks scripts can define, with how many arguments they take.
+const HOOKS: [(&str, usize); 3] = [("on_tick", 1), ("on_join", 1), ("on_collision", 2)];
+/// How long a hook can run before it's stopped.
+const MAX_HOOK_DURATION: Duration = Duration::from_millis(2);
+/// How long all the hooks for a tick can run together. A hook runs for every collision, so there
+/// can be many in a tick.
+const MAX_TICK_DURATION: Duration = Duration::from_millis(5);
+/// How many operations a hook can run before it's stopped, however quickly.
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+/// A compiled script.
+#[derive(Clone, Debug)]
+pub struct Script {
+    ast: AST,
+}
+
+impl Script {
+    pub fn compile(script: &str) -> Result<Self, String> {
+        let ast = Engine::new().compile(script).map_err(|e| e.to_string())?;
+        Ok(Script { ast })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Script::compile(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// A script's hooks into a running game.
+pub(crate) struct Hooks {
+    engine: Engine,
+    scope: Scope<'static>,
+    ast: AST,
+    /// The hooks the script defines, less the ones that failed.
+    hooks: HashSet<&'static str>,
+    /// The commands the hooks made, for the game loop to apply.
+    commands: Arc<Mutex<Vec<Command>>>,
+    /// When the running hook has to stop.
+    deadline: Arc<Mutex<Instant>>,
+    /// When the hooks for the current tick have to stop.
+    tick_deadline: Instant,
+    /// How many hooks were skipped in the current tick, for running out of time.
+    skipped: usize,
+}
+
+impl Hooks {
+    pub(crate) fn new(script: Script) -> Self {
+        let commands = Arc::new(Mutex::new(vec![]));
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS);
+        let hook_deadline = deadline.clone();
+        engine.on_progress(move |operations| {
+            // Checking the time is slow next to an operation, so it's only checked now and then.
+            if operations % 1024 == 0 && Instant::now() > *hook_deadline.lock().unwrap() {
+                Some("the hook ran for too long".into())
+            } else {
+                None
+            }
+        });
+        let queue = commands.clone();
+        engine.register_fn("teleport", move |id: i64, x: f64, y: f64| {
+            let point = Point::new(x as GameInt, y as GameInt);
+            if !point.x.is_finite() || !point.y.is_finite() {
+                return;
+            }
+            queue
+                .lock()
+                .unwrap()
+                .push(Command::Teleport(id as EntityId, point));
+        });
+        let queue = commands.clone();
+        engine.register_fn(
+            "set_color",
+            move |id: i64, red: f64, green: f64, blue: f64| {
+                let rgb = match server::color([red as GameInt, green as GameInt, blue as GameInt]) {
+                    Some(rgb) => rgb,
+                    None => return,
+                };
+                queue
+                    .lock()
+                    .unwrap()
+                    .push(Command::SetColor(id as EntityId, rgb));
+            },
+        );
+        let queue = commands.clone();
+        engine.register_fn("announce", move |message: &str| {
+            let message = server::printable(message, MAX_CHAT_LENGTH);
+            if message.is_empty() {
+                return;
+            }
+            queue.lock().unwrap().push(Command::Announce(message));
+        });
+
+        let hooks = script
+            .ast
+            .iter_functions()
+            .filter_map(|function| {
+                HOOKS
+                    .iter()
+                    .find(|&&(name, args)| function.name == name && function.params.len() == args)
+                    .map(|&(name, _)| name)
+            })
+            .collect();
+        Hooks {
+            engine,
+            scope: Scope::new(),
+            ast: script.ast,
+            hooks,
+            commands,
+            deadline,
+            tick_deadline: Instant::now(),
+            skipped: 0,
+        }
+    }
+
+    /// Runs the hooks for the tick `game` just finished, returning the commands they made.
+    pub(crate) fn run(&mut self, game: &Game) -> Vec<Command> {
+        self.tick_deadline = Instant::now() + MAX_TICK_DURATION;
+        self.skipped = 0;
+        self.call("on_tick", (game.ticks() as i64,));
+        for id in game.joined() {
+            self.call("on_join", (entity(game, id),));
+        }
+        for &(mover, other) in game.collisions() {
+            if game.contains(mover) && game.contains(other) {
+                self.call("on_collision", (entity(game, mover), entity(game, other)));
+            }
+        }
+        if self.skipped > 0 {
+            warn!(
+                "Skipped {} script hooks, which didn't fit in tick {}",
+                self.skipped,
+                game.ticks()
+            );
+        }
+        mem::take(&mut *self.commands.lock().unwrap())
+    }
+
+    fn call(&mut self, hook: &'static str, args: impl FuncArgs) {
+        if !self.hooks.contains(hook) {
+            return;
+        }
+        let now = Instant::now();
+        if now >= self.tick_deadline {
+            self.skipped += 1;
+            return;
+        }
+        *self.deadline.lock().unwrap() = (now + MAX_HOOK_DURATION).min(self.tick_deadline);
+        let called = self
+            .engine
+            .call_fn::<Dynamic>(&mut self.scope, &self.ast, hook, args);
+        if let Err(e) = called {
+            // Stopped for the tick running out of time, rather than for the hook's own fault.
+            if Instant::now() >= self.tick_deadline {
+                self.skipped += 1;
+                return;
+            }
+            error!("Disabling the script's {} hook, which failed: {}", hook, e);
+            self.hooks.remove(hook);
+        }
+    }
+}
+
+/// Entity `id` of `game`, for scripts.
+fn entity(game: &Game, id: EntityId) -> Map {
+    let entity = game.entity(id);
+    let [red, green, blue, _] = entity.color;
+    let mut map = Map::new();
+    map.insert("id".into(), Dynamic::from(id as i64));
+    map.insert("x".into(), Dynamic::from(entity.position.top_left.x as f64));
+    map.insert("y".into(), Dynamic::from(entity.position.top_left.y as f64));
+    map.insert("red".into(), Dynamic::from(red as f64));
+    map.insert("green".into(), Dynamic::from(green as f64));
+    map.insert("blue".into(), Dynamic::from(blue as f64));
+    map.insert("player".into(), Dynamic::from(game.is_player(id)));
+    map
+}
+
+#[test]
+fn scripts_hook_into_collisions() {
+    use crate::game::{Component, Input, Sign};
+
+    let script = Script::compile(
+        "fn on_collision(mover, other) {
+            if mover.player && other.red > 0.9 {
+                teleport(mover.id, 0.0, 0.0);
+            }
+        }
+        fn on_tick(tick) {
+            loop {}
+        }",
+    )
+    .unwrap();
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    for id in game.entity_ids().collect::<Vec<_>>() {
+        game.remove_entity(id);
+    }
+    let player = game.insert_new_player_square();
+    game.name_player(player, String::from("ada"));
+    let mut block = game.new_player_square();
+    block.position.top_left = Point::new(60., 0.);
+    block.moveable = false;
+    let block = game.insert_entity(block);
+    game.set_color(block, [1., 0., 0.]);
+    game.process_input(player, Input::Move(Component::X, Some(Sign::Positive)));
+
+    let mut hooks = Hooks::new(script);
+    let mut commands = vec![];
+    for _ in 0..200 {
+        game.tick(1. / 200., &mut 0., &mut 0);
+        commands.extend(hooks.run(&game));
+    }
+    // The endless loop was stopped, and its hook disabled.
+    assert!(!hooks.hooks.contains("on_tick"));
+    assert!(commands.contains(&Command::Teleport(player, Point::default())));
+}
+
+#[test]
+fn script_commands_are_checked() {
+    let script = Script::compile(
+        r#"fn on_tick(tick) {
+            set_color(0, 2.0, -1.0, 0.5);
+            set_color(0, 0.0 / 0.0, 0.0, 0.0);
+            teleport(0, 1.0 / 0.0, 0.0);
+            announce("  hi\n ");
+            announce("\t");
+        }"#,
+    )
+    .unwrap();
+    let game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let commands = Hooks::new(script).run(&game);
+    assert_eq!(
+        commands,
+        vec![
+            Command::SetColor(0, [1., 0., 0.5]),
+            Command::Announce(String::from("hi")),
+        ]
+    );
+}
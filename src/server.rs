@@ -1,197 +1,2327 @@
 use crate::{
-    game::{self, EntityId, Point},
-    Game as _,
+    access::{self, Access, AccessLists},
+    admin_log::{self, Actor, AdminLog},
+    bans::Bans,
+    bots::Bots,
+    clock::ServerTime,
+    console, datagram,
+    error::Error,
+    game::{self, EntityId, GameInt, Point},
+    game_list, lan,
+    map::Map,
+    metrics,
+    mode::{Mode, Rules, Score},
+    moderators::Moderators,
+    rate_limit::{ChatCheck, ChatLimit, InputCheck, InputLimit},
+    registrar::Registrar,
+    replay::{Playback, Replay},
+    rollback::{Command, History},
+    schedule::{self, Scheduled},
+    script::Script,
+    session::Sessions,
+    snapshot,
+    states::{self, Publisher, States, Subscriber},
+    stats::{PlayerStats, Stats},
+    status::{self, Status},
+    transport, Game as _,
 };
-use futures::prelude::*;
-use log::{debug, error, info};
+use futures::{channel::oneshot, prelude::*};
+use log::{debug, error, info, warn};
 use once_cell::sync::OnceCell;
-use piston_window::{Event, EventLoop, EventSettings, Events, Loop, NoWindow, WindowSettings};
+use rand::{rngs::StdRng, SeedableRng};
+use ring::constant_time;
+use serde::{Deserialize, Serialize};
 use std::{
-    io,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use tarpc::{
     context,
     server::{self, Channel},
 };
-use tokio::{runtime::Runtime, sync::watch};
-use tokio_serde::formats::Json;
+use tokio::{runtime::Runtime, sync::watch, task, time};
 
+/// How many times a second games tick by default.
 const UPDATES_PER_SECOND: u64 = 200;
+/// How late an input can arrive and still be applied at the tick it was made at.
+const MAX_ROLLBACK: Duration = Duration::from_millis(250);
+/// How long players have to authenticate in games that require join tokens.
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many wrong passwords or moderator keys a player can try before being disconnected, and how
+/// long each wrong one takes to be answered, so that guessing them is slow.
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+const WRONG_PASSWORD_DELAY: Duration = Duration::from_secs(1);
+/// How far from a player, along each axis, entities are sent to them. Comfortably more than half
+/// the size of a window.
+pub(crate) const VIEW_DISTANCE: Point = Point::new(1000., 1000.);
+/// How often to remove the entities of players whose sessions have expired.
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to look for entities left behind, in [`collect_garbage`].
+const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to check for players who are away from the keyboard.
+const AFK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often bots are added or removed, and steered.
+const BOT_INTERVAL: Duration = Duration::from_millis(500);
+/// The most transient entities, like projectiles, kept in the game at once.
+const MAX_TRANSIENT_ENTITIES: usize = 1000;
+/// How long the tick rate in the game's status is measured over, so that it shows the game slowing
+/// down or stalling.
+const TICK_RATE_WINDOW: Duration = Duration::from_secs(10);
+/// How often the game loop logs a summary of how its ticks went.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long players have to receive the final state once the server starts shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often games that don't send heartbeats check that they're still registered.
+const REGISTRATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// The most characters of a player's name that are kept, so that name tags stay small.
+const MAX_NAME_LENGTH: usize = 16;
+/// The most characters of a chat message that are kept.
+pub(crate) const MAX_CHAT_LENGTH: usize = 200;
+/// How often the game is saved by default, when it's saved at all.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// How many chat messages a player can send per second, and in a burst, by default.
+const CHAT_MESSAGES_PER_SECOND: f64 = 1.;
+const CHAT_BURST: u32 = 5;
+/// How long players have to wait to say the same thing again, by default.
+const CHAT_REPEAT_WINDOW: Duration = Duration::from_secs(30);
+/// How often the map is checked for changes.
+const MAP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Game settings.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The tokens players can join with. If empty, anyone can join.
+    pub join_tokens: HashSet<String>,
+    /// The password players have to join with, if any. Games with one are listed as locked.
+    pub password: Option<String>,
+    /// The addresses players are refused from, like those loaded with [`crate::bans::load`].
+    pub banned: BTreeSet<IpAddr>,
+    /// Where to save the banned addresses whenever they change, if anywhere.
+    pub ban_list: Option<PathBuf>,
+    /// The networks players are let in from.
+    pub access: AccessLists,
+    /// The moderators' keys, by name, like those loaded with [`crate::moderators::load`].
+    pub moderators: BTreeMap<String, String>,
+    /// Where to save the moderators whenever they change, if anywhere.
+    pub moderator_list: Option<PathBuf>,
+    /// The config file the settings came from, if any, which the access lists are reloaded from
+    /// by [`Admin::reload_access`].
+    pub config: Option<PathBuf>,
+    /// How many players can play at once.
+    pub max_players: usize,
+    /// Bots join while fewer people than this are playing, and leave as people join. Bots don't
+    /// count toward `max_players`.
+    pub min_players: usize,
+    /// Where the game is hosted, like `us-east`, for players looking for a nearby game.
+    pub region: Option<String>,
+    /// Labels describing the game, like `casual`, for players looking for a certain kind of game.
+    pub tags: Vec<String>,
+    /// A message of the day, like the rules of the game, shown in listings and to players when
+    /// they join.
+    pub motd: Option<String>,
+    /// Players who make no requests for this long are disconnected. Their entities are removed
+    /// once their sessions expire, unless they reconnect first.
+    pub idle_timeout: Duration,
+    /// Players who make no inputs for this long are marked away from the keyboard: they stop,
+    /// other entities pass through them, and game modes leave them out of team balancing.
+    pub afk_timeout: Duration,
+    /// Players who stay away from the keyboard for this long are disconnected, freeing their
+    /// place in the game.
+    pub afk_removal_timeout: Duration,
+    /// How many chat messages a player can send per second, and in a burst. Players who go over
+    /// are reported in the admin log.
+    pub chat_rate: f64,
+    pub chat_burst: u32,
+    /// Chat messages players repeat within this long are dropped.
+    pub chat_repeat_window: Duration,
+    /// Whether to announce the game on the local network, so that players can find it without
+    /// the game list. Such games keep running if the game list can't be reached.
+    pub lan: bool,
+    /// The key to register the game with, for game lists that require one.
+    pub registration_key: Option<String>,
+    /// If given, the game sends the game list a heartbeat this often instead of having the game
+    /// list connect to it for health checks, which works for games behind NAT.
+    pub heartbeat_interval: Option<Duration>,
+    /// Where the game list accepts registrations.
+    pub registry_addr: SocketAddr,
+    /// The size of the world.
+    pub world_size: Point,
+    /// The size of the squares in the world, and of players' squares.
+    pub square_size: GameInt,
+    /// How many times a second the game ticks. Clients predict the game at 200 ticks a second,
+    /// so their predictions are less accurate at other rates.
+    pub tick_rate: u64,
+    /// How many times a second the game's state is sent to players, at most once a tick. Players
+    /// see the game less smoothly at lower rates, but the server does less work.
+    pub broadcast_rate: u64,
+    /// The world to start with, like one restored with [`snapshot::load`], instead of a new one
+    /// of `world_size`.
+    pub world: Option<game::Game>,
+    /// Where to save the game every `autosave_interval` and when the server shuts down, if
+    /// anywhere.
+    pub autosave: Option<PathBuf>,
+    pub autosave_interval: Duration,
+    /// The database to keep players' stats in, so that they survive restarts. Without one, stats
+    /// are only kept until the game ends.
+    pub stats: Option<PathBuf>,
+    /// Where to record every tick of the game and the inputs applied at it, if anywhere, to
+    /// watch the match again or debug desyncs.
+    pub record: Option<PathBuf>,
+    /// A recorded match to play back instead of running a game. Players can only watch a
+    /// playback, over the default transport; their inputs are ignored, and they can't chat.
+    pub playback: Option<Replay>,
+    /// The map to lay the world out with and take the tick and broadcast rates from, if any.
+    /// Changes to it are applied to the running game; bad ones are logged and ignored.
+    pub map: Option<PathBuf>,
+    /// The maps to cycle through, one each round, taking only their walls and spawn points. Bad
+    /// ones are logged and left out.
+    pub map_rotation: Vec<PathBuf>,
+    /// The rules of the match.
+    pub mode: Mode,
+    /// A script hooked into the game's ticks, joins and collisions, to change its rules.
+    pub script: Option<Script>,
+    /// Tasks to run on a schedule, like restarting nightly.
+    pub schedule: Vec<Scheduled>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            join_tokens: HashSet::new(),
+            password: None,
+            banned: BTreeSet::new(),
+            ban_list: None,
+            access: AccessLists::default(),
+            moderators: BTreeMap::new(),
+            moderator_list: None,
+            config: None,
+            max_players: 10,
+            min_players: 0,
+            region: None,
+            tags: vec![],
+            motd: None,
+            idle_timeout: Duration::from_secs(10),
+            afk_timeout: Duration::from_secs(60),
+            afk_removal_timeout: Duration::from_secs(240),
+            chat_rate: CHAT_MESSAGES_PER_SECOND,
+            chat_burst: CHAT_BURST,
+            chat_repeat_window: CHAT_REPEAT_WINDOW,
+            lan: false,
+            registration_key: None,
+            heartbeat_interval: None,
+            registry_addr: ([0, 0, 0, 0u8], 23304).into(),
+            world_size: Point::new(10_000., 500.),
+            square_size: 50.,
+            tick_rate: UPDATES_PER_SECOND,
+            broadcast_rate: UPDATES_PER_SECOND,
+            world: None,
+            autosave: None,
+            autosave_interval: AUTOSAVE_INTERVAL,
+            stats: None,
+            record: None,
+            playback: None,
+            map: None,
+            map_rotation: vec![],
+            mode: Mode::FreeForAll,
+            script: None,
+            schedule: vec![],
+        }
+    }
+}
 
 pub struct Server {
-    game: Arc<Mutex<game::Game>>,
-    game_rx: watch::Receiver<game::Game>,
+    history: Arc<Mutex<History>>,
+    states: States,
+    datagram_peers: datagram::Peers,
+    /// The tokens players can join with. If empty, anyone can join.
+    join_tokens: Arc<HashSet<String>>,
+    password: Option<String>,
+    max_players: usize,
+    bots: Bots,
+    idle_timeout: Duration,
+    afk_timeout: Duration,
+    afk_removal_timeout: Duration,
+    chat_rate: f64,
+    chat_burst: u32,
+    chat_repeat_window: Duration,
+    region: Option<String>,
+    tags: Vec<String>,
+    motd: Option<String>,
+    lan: bool,
+    registration_key: Option<String>,
+    heartbeat_interval: Option<Duration>,
+    registry_addr: SocketAddr,
+    /// Whether the game is a playback, which players only watch.
+    read_only: bool,
+    autosave: Option<PathBuf>,
+    autosave_interval: Duration,
+    players: Arc<AtomicUsize>,
+    sessions: Sessions,
+    connections: Connections,
+    bans: Bans,
+    access: Access,
+    moderators: Moderators,
+    /// Set while a moderator has the game paused.
+    paused: Arc<AtomicBool>,
+    /// Where players' stats are kept, unless they couldn't be.
+    stats: Option<Stats>,
+    status: StatusReporter,
+    admin_log: AdminLog,
+    /// Holds the reason once the server starts shutting down.
+    shutdown_rx: watch::Receiver<Option<String>>,
+    /// The rates the game ticks and publishes its state at, as they change.
+    rates_rx: watch::Receiver<Rates>,
+}
+
+/// Why a player couldn't join a game.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum JoinError {
+    /// The game already has as many players as it allows.
+    #[error("server full ({players}/{max_players})")]
+    ServerFull { players: usize, max_players: usize },
+    /// The game requires a join token, and the player hasn't presented one that was accepted.
+    #[error("the game requires a join token")]
+    NotAuthenticated,
+    /// The game requires a password, and the player didn't give one.
+    #[error("the game requires a password")]
+    PasswordRequired,
+    #[error("wrong password")]
+    WrongPassword,
+    /// The player was kicked, and can't come back to the session they were kicked from.
+    #[error("{0}")]
+    Kicked(KickReason),
+}
+
+/// Why a player was kicked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum KickReason {
+    /// The server's operator, or a moderator, kicked them.
+    #[error("kicked from the game")]
+    Kicked,
+    /// The server's operator banned their address.
+    #[error("banned from the game")]
+    Banned,
+    /// The game's access lists stopped letting their network in.
+    #[error("your network isn't let in anymore")]
+    NotLetIn,
+    /// They were away from the keyboard for too long.
+    #[error("away from the keyboard for too long")]
+    Away,
+}
+
+/// What a moderator can do to a game. Stopping the game and changing its map are left to the
+/// server's operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Moderation {
+    /// Drops the chat messages of the player controlling the entity, until they're unmuted.
+    Mute(EntityId),
+    Unmute(EntityId),
+    /// Disconnects the player controlling the entity, who can't resume their session.
+    Kick(EntityId),
+    /// Stops time in the game, so that nothing moves until it's resumed. Players can still chat.
+    Pause,
+    Resume,
+}
+
+/// Why a moderator couldn't moderate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum ModerationError {
+    /// Moderators have to join the game first.
+    #[error("join the game before moderating it")]
+    NotJoined,
+    /// The key isn't any moderator's.
+    #[error("not a moderator's key")]
+    WrongKey,
+    /// No player controls the entity, or none who's connected, for kicks.
+    #[error("no player controls entity {0}")]
+    NoSuchPlayer(EntityId),
+}
+
+/// What a player is told when they join a game.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Welcome {
+    /// The player's session, which they can resume if they're disconnected.
+    pub session_id: u64,
+    /// The game's message of the day.
+    pub motd: Option<String>,
+    /// How long the game goes between publishing states, as of joining, so that the player knows
+    /// how long to wait for the next one.
+    pub broadcast_interval: Duration,
+}
+
+/// Describes the game's [`Status`].
+#[derive(Clone)]
+struct StatusReporter {
+    name: String,
+    started: Instant,
+    players: Arc<AtomicUsize>,
+    max_players: usize,
+    states: States,
+    /// The tick the game was at as of each report, oldest first, starting from the tick it started
+    /// at, which isn't 0 for games loaded or played back.
+    ticks: Arc<Mutex<VecDeque<(Instant, u64)>>>,
+}
+
+impl StatusReporter {
+    fn new(name: String, players: Arc<AtomicUsize>, max_players: usize, states: States) -> Self {
+        let started = Instant::now();
+        let ticks = VecDeque::from(vec![(started, states.latest().ticks())]);
+        StatusReporter {
+            name,
+            started,
+            players,
+            max_players,
+            states,
+            ticks: Arc::new(Mutex::new(ticks)),
+        }
+    }
+
+    fn report(&self) -> Status {
+        let uptime = self.started.elapsed();
+        let now = Instant::now();
+        let tick = self.states.latest().ticks();
+        let mut ticks = self.ticks.lock().unwrap();
+        // The rate is measured from the latest report at least a window ago, or the first.
+        while ticks.len() > 1 && now.duration_since(ticks[1].0) >= TICK_RATE_WINDOW {
+            ticks.pop_front();
+        }
+        let (since, since_tick) = ticks[0];
+        ticks.push_back((now, tick));
+        let elapsed = now.duration_since(since).as_secs_f64();
+        Status {
+            name: self.name.clone(),
+            // Players turned away for the game being full hold a slot until they disconnect.
+            players: self.players.load(Ordering::SeqCst).min(self.max_players),
+            max_players: self.max_players,
+            tick_rate: if elapsed > 0. {
+                tick.saturating_sub(since_tick) as f64 / elapsed
+            } else {
+                0.
+            },
+            uptime_secs: uptime.as_secs(),
+            protocol_version: crate::PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// A place in a game, given up when dropped.
+struct PlayerSlot(Arc<AtomicUsize>);
+
+impl PlayerSlot {
+    fn take(players: &Arc<AtomicUsize>, max_players: usize) -> Option<PlayerSlot> {
+        if players.fetch_add(1, Ordering::SeqCst) < max_players {
+            Some(PlayerSlot(players.clone()))
+        } else {
+            players.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for PlayerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A connected player, as the server's operator sees them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectedPlayer {
+    pub addr: SocketAddr,
+    /// The player's entity, once they have one.
+    pub entity_id: Option<EntityId>,
+    pub name: Option<String>,
+}
+
+struct Connection {
+    entity_id: Arc<OnceCell<EntityId>>,
+    /// Disconnects the player when sent why.
+    kick: oneshot::Sender<KickReason>,
+}
+
+/// Every connected player, by address, so that the server's operator can list and kick them.
+#[derive(Clone, Default)]
+struct Connections(Arc<Mutex<HashMap<SocketAddr, Connection>>>);
+
+impl Connections {
+    /// Adds the player connected from `addr`, returning a future that resolves if they're kicked.
+    fn add(
+        &self,
+        addr: SocketAddr,
+        entity_id: Arc<OnceCell<EntityId>>,
+    ) -> oneshot::Receiver<KickReason> {
+        let (kick, kicked) = oneshot::channel();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(addr, Connection { entity_id, kick });
+        kicked
+    }
+
+    fn remove(&self, addr: SocketAddr) {
+        self.0.lock().unwrap().remove(&addr);
+    }
+
+    /// Disconnects the player connected from `addr`, returning whether there was one.
+    fn kick(&self, addr: SocketAddr, reason: KickReason) -> bool {
+        match self.0.lock().unwrap().remove(&addr) {
+            Some(connection) => connection.kick.send(reason).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Disconnects the player controlling entity `id`, returning whether there was one.
+    fn kick_entity(&self, id: EntityId, reason: KickReason) -> bool {
+        let mut connections = self.0.lock().unwrap();
+        let addr = connections
+            .iter()
+            .find(|(_, connection)| connection.entity_id.get() == Some(&id))
+            .map(|(&addr, _)| addr);
+        match addr.and_then(|addr| connections.remove(&addr)) {
+            Some(connection) => connection.kick.send(reason).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Disconnects every player connected from `ip`, returning how many there were.
+    fn kick_ip(&self, ip: IpAddr, reason: KickReason) -> usize {
+        let ip = access::canonical(ip);
+        self.kick_where(|kicked| access::canonical(kicked) == ip, reason)
+    }
+
+    /// Disconnects every player connected from an address that's `kicked`, returning how many
+    /// there were.
+    fn kick_where(&self, kicked: impl Fn(IpAddr) -> bool, reason: KickReason) -> usize {
+        let mut connections = self.0.lock().unwrap();
+        let addrs: Vec<_> = connections
+            .keys()
+            .copied()
+            .filter(|addr| kicked(addr.ip()))
+            .collect();
+        addrs
+            .into_iter()
+            .filter_map(|addr| connections.remove(&addr))
+            .filter(|connection| connection.kick.send(reason).is_ok())
+            .count()
+    }
+
+    /// The connected players' addresses and entities, ordered by address.
+    fn list(&self) -> Vec<(SocketAddr, Option<EntityId>)> {
+        let mut connections: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&addr, connection)| (addr, connection.entity_id.get().copied()))
+            .collect();
+        connections.sort();
+        connections
+    }
 }
 
 struct Disconnect {
-    game: Arc<Mutex<game::Game>>,
+    history: Arc<Mutex<History>>,
+    datagram_peers: datagram::Peers,
+    sessions: Sessions,
+    connections: Connections,
     peer_addr: SocketAddr,
     client_id: Arc<OnceCell<EntityId>>,
+    session_id: Arc<OnceCell<u64>>,
+    joined: Arc<OnceCell<SystemTime>>,
+    stats: Option<Stats>,
+    /// Why the player was kicked, if they were.
+    kicked: Arc<OnceCell<KickReason>>,
 }
 
 impl Drop for Disconnect {
     fn drop(&mut self) {
         info!("Player {} has disconnected.", self.peer_addr);
+        metrics::CONNECTED_PLAYERS.dec();
+        self.connections.remove(self.peer_addr);
         if let Some(id) = self.client_id.get() {
-            self.game.lock().unwrap().remove_entity(*id);
+            self.datagram_peers.close(*id);
+            if let (Some(stats), Some(&joined)) = (&self.stats, self.joined.get()) {
+                let played = joined.elapsed().unwrap_or_default();
+                // Writing to the stats can take a while, so it's done without holding up the game.
+                let name = self
+                    .history
+                    .lock()
+                    .unwrap()
+                    .game()
+                    .name(*id)
+                    .map(String::from);
+                if let Some(name) = name {
+                    if let Err(e) = stats.record_session(&name, joined, played) {
+                        error!("Failed to record {}'s session in the stats: {}", name, e);
+                    }
+                }
+            }
+            match self.session_id.get() {
+                // The entity is removed when the session expires, unless it's resumed first. That
+                // goes for players who went idle too, whose clients may yet come back.
+                Some(session_id) if self.kicked.get().is_none() => {
+                    self.sessions.disconnect(*session_id, Instant::now())
+                }
+                // Players without a session, or who were kicked, are removed right away.
+                session_id => {
+                    match (session_id, self.kicked.get()) {
+                        // Kept to tell the player why, if they try to resume it.
+                        (Some(session_id), Some(&reason)) => {
+                            self.sessions.kick(*session_id, reason, Instant::now())
+                        }
+                        (Some(session_id), None) => self.sessions.end(*session_id),
+                        (None, _) => {}
+                    }
+                    self.history
+                        .lock()
+                        .unwrap()
+                        .apply(Command::RemovePlayer(*id));
+                }
+            }
         }
     }
 }
 
 impl Server {
-    pub fn new(game: Arc<Mutex<game::Game>>, game_rx: watch::Receiver<game::Game>) -> Self {
-        Server { game, game_rx }
+    pub(crate) fn new(
+        history: Arc<Mutex<History>>,
+        states: States,
+        name: String,
+        settings: Settings,
+        stats: Option<Stats>,
+        shutdown_rx: watch::Receiver<Option<String>>,
+        rates_rx: watch::Receiver<Rates>,
+    ) -> Self {
+        let players = Arc::new(AtomicUsize::new(0));
+        Server {
+            history,
+            states: states.clone(),
+            datagram_peers: datagram::Peers::default(),
+            join_tokens: Arc::new(settings.join_tokens),
+            password: settings.password,
+            max_players: settings.max_players,
+            bots: Bots::new(settings.min_players),
+            idle_timeout: settings.idle_timeout,
+            afk_timeout: settings.afk_timeout,
+            afk_removal_timeout: settings.afk_removal_timeout,
+            chat_rate: settings.chat_rate,
+            chat_burst: settings.chat_burst,
+            chat_repeat_window: settings.chat_repeat_window,
+            region: settings.region,
+            tags: settings.tags,
+            motd: settings.motd,
+            lan: settings.lan,
+            registration_key: settings.registration_key,
+            heartbeat_interval: settings.heartbeat_interval,
+            registry_addr: settings.registry_addr,
+            read_only: settings.playback.is_some(),
+            autosave: settings.autosave,
+            autosave_interval: settings.autosave_interval,
+            players: players.clone(),
+            sessions: Sessions::default(),
+            connections: Connections::default(),
+            bans: Bans::new(settings.banned, settings.ban_list),
+            access: Access::new(settings.access, settings.config),
+            moderators: Moderators::new(settings.moderators, settings.moderator_list),
+            paused: Arc::new(AtomicBool::new(false)),
+            stats,
+            status: StatusReporter::new(name, players, settings.max_players, states),
+            admin_log: AdminLog::default(),
+            shutdown_rx,
+            rates_rx,
+        }
     }
 
-    pub fn new_handler(&self) -> ConnectionHandler {
+    pub(crate) fn new_handler(&self) -> ConnectionHandler {
         ConnectionHandler {
             entity_id: Arc::new(OnceCell::new()),
-            game: self.game.clone(),
-            game_rx: self.game_rx.clone(),
-        }
-    }
-
-    async fn run(&mut self, server_addr: SocketAddr, name: String) -> io::Result<()> {
-        let listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
-        let registration =
-            tarpc::serde_transport::tcp::connect("0.0.0.0:23304", Json::default()).await?;
-        let registration =
-            crate::GameRegistrationClient::new(tarpc::client::Config::default(), registration)
-                .spawn()?;
-        registration
-            .register(context::current(), server_addr.port(), name)
-            .await?;
-        listener
+            session_id: Arc::new(OnceCell::new()),
+            joined: Arc::new(OnceCell::new()),
+            sessions: self.sessions.clone(),
+            stats: self.stats.clone(),
+            history: self.history.clone(),
+            states: self.states.subscribe(),
+            datagram_peers: self.datagram_peers.clone(),
+            join_tokens: self.join_tokens.clone(),
+            password: self.password.clone(),
+            authenticated: Arc::new(AtomicBool::new(self.join_tokens.is_empty())),
+            admitted: Arc::new(AtomicBool::new(false)),
+            wrong_passwords: 0,
+            guessing: Arc::new(AtomicBool::new(false)),
+            input_limit: InputLimit::new(Instant::now()),
+            flooding: Arc::new(AtomicBool::new(false)),
+            chat_limit: ChatLimit::new(
+                self.chat_burst,
+                self.chat_rate,
+                self.chat_repeat_window,
+                Instant::now(),
+            ),
+            admin_log: self.admin_log.clone(),
+            idle_timeout: self.idle_timeout,
+            status: self.status.clone(),
+            connections: self.connections.clone(),
+            moderators: self.moderators.clone(),
+            paused: self.paused.clone(),
+            motd: self.motd.clone(),
+            rates_rx: self.rates_rx.clone(),
+            read_only: self.read_only,
+            players: self.players.clone(),
+            max_players: self.max_players,
+            slot: Arc::new(Mutex::new(None)),
+            last_sent: None,
+            last_input_sequence: 0,
+        }
+    }
+
+    async fn run(
+        &mut self,
+        server_addr: SocketAddr,
+        websocket_addr: Option<SocketAddr>,
+        status_addr: Option<SocketAddr>,
+        transport_config: transport::Config,
+    ) -> Result<(), Error> {
+        let listener = transport::listen(&server_addr).await?;
+        let websocket_listener = match websocket_addr {
+            Some(addr) => Some(transport::listen(&addr).await?),
+            None => None,
+        };
+        if !self.read_only {
+            let datagrams = datagram::serve(
+                server_addr,
+                self.datagram_peers.clone(),
+                self.history.clone(),
+                self.states.subscribe(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = datagrams.await {
+                    error!("Datagram channel died: {:?}", e);
+                }
+            });
+        }
+        let sessions = self.sessions.clone();
+        let history = self.history.clone();
+        tokio::spawn(async move {
+            loop {
+                time::delay_for(SESSION_EXPIRY_INTERVAL).await;
+                for id in sessions.expire(Instant::now()) {
+                    info!("Session for entity {} expired", id);
+                    history.lock().unwrap().apply(Command::RemovePlayer(id));
+                }
+            }
+        });
+        if !self.read_only {
+            let sessions = self.sessions.clone();
+            let connections = self.connections.clone();
+            let history = self.history.clone();
+            let afk_timeout = self.afk_timeout;
+            let removal_timeout = self.afk_removal_timeout;
+            let afk_connections = connections.clone();
+            let afk_history = history.clone();
+            tokio::spawn(async move {
+                loop {
+                    time::delay_for(AFK_CHECK_INTERVAL).await;
+                    check_afk(&afk_history, &afk_connections, afk_timeout, removal_timeout);
+                }
+            });
+            let bots = self.bots.clone();
+            let bot_history = history.clone();
+            tokio::spawn(async move {
+                let mut rng = StdRng::from_entropy();
+                loop {
+                    time::delay_for(BOT_INTERVAL).await;
+                    let mut history = bot_history.lock().unwrap();
+                    bots.backfill(&mut history);
+                    bots.steer(&mut history, &mut rng);
+                }
+            });
+            let bots = self.bots.clone();
+            tokio::spawn(async move {
+                let mut orphans = HashSet::new();
+                loop {
+                    time::delay_for(GARBAGE_COLLECTION_INTERVAL).await;
+                    orphans = collect_garbage(&history, &connections, &sessions, &bots, &orphans);
+                }
+            });
+        }
+        // Playbacks have no game of their own to save.
+        let mut saved = None;
+        if let (Some(path), false) = (self.autosave.clone(), self.read_only) {
+            let sessions = self.sessions.clone();
+            let history = self.history.clone();
+            let mut autosaves = time::interval_at(
+                time::Instant::now() + self.autosave_interval,
+                self.autosave_interval,
+            );
+            let stopped = shutting_down(self.shutdown_rx.clone());
+            saved = Some(tokio::spawn(async move {
+                let autosaving = async {
+                    loop {
+                        autosaves.tick().await;
+                        autosave(&history, &sessions, &path).await;
+                    }
+                };
+                future::select(Box::pin(autosaving), Box::pin(stopped)).await;
+                // Saved once more so that nothing since the last autosave is lost.
+                autosave(&history, &sessions, &path).await;
+            }));
+        }
+        if let Some(status_addr) = status_addr {
+            let reporter = self.status.clone();
+            let status = status::serve(status_addr, move || reporter.report());
+            tokio::spawn(async move {
+                if let Err(e) = status.await {
+                    error!("Status endpoint died: {:?}", e);
+                }
+            });
+        }
+        if self.lan {
+            let reporter = self.status.clone();
+            let port = server_addr.port();
+            let locked = self.locked();
+            let announcements = lan::announce(move || lan::Announcement {
+                port,
+                locked,
+                status: reporter.report(),
+            });
+            let stopped = shutting_down(self.shutdown_rx.clone());
+            tokio::spawn(async move {
+                let announced = future::select(Box::pin(announcements), Box::pin(stopped)).await;
+                if let future::Either::Left((Err(e), _)) = announced {
+                    error!("LAN announcements died: {:?}", e);
+                }
+            });
+        }
+        let registrar = Registrar {
+            registry_addr: self.registry_addr,
+            transport_config: transport_config.clone(),
+            port: server_addr.port(),
+            registration: self.registration(),
+            key: self.registration_key.clone(),
+            interval: self
+                .heartbeat_interval
+                .unwrap_or(REGISTRATION_CHECK_INTERVAL),
+        };
+        let registered = match registrar.register().await {
+            Ok(registered) => Some(registered),
+            Err(e) if self.lan => {
+                warn!(
+                    "Failed to register game; announcing it on the LAN meanwhile: {}",
+                    e
+                );
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        // Registers the game again if the game list forgets it, until the server shuts down.
+        let reporter = self.status.clone();
+        let registration = tokio::spawn(registrar.keep_registered(
+            registered,
+            move || reporter.report(),
+            shutting_down(self.shutdown_rx.clone()).map(drop),
+        ));
+        let websocket_streams = stream::iter(websocket_listener)
+            .flatten()
+            .map(|r| r.map(|stream| (stream, true)));
+        let streams = listener.map(|r| r.map(|stream| (stream, false)));
+        let accepted = stream::select(streams, websocket_streams)
             // Ignore accept errors.
             .filter_map(|r| future::ready(r.ok()))
-            .map(server::BaseChannel::with_defaults)
-            .map(move |channel| {
+            .map(Some);
+        // Stop accepting connections once the server starts shutting down.
+        let shutdown_rx = self.shutdown_rx.clone();
+        let closing = stream::once(shutting_down(shutdown_rx.clone())).map(|_| None);
+        let serving = stream::select(accepted, closing)
+            .take_while(|accepted| future::ready(accepted.is_some()))
+            .filter_map(future::ready)
+            .map(move |(stream, websocket)| {
                 info!("Cloning server");
-                let game = self.game.clone();
+                let history = self.history.clone();
+                let datagram_peers = self.datagram_peers.clone();
+                let sessions = self.sessions.clone();
+                let connections = self.connections.clone();
+                let bans = self.bans.clone();
+                let access = self.access.clone();
                 let handler = self.new_handler();
+                let transport_config = transport_config.clone();
                 async move {
-                    let peer = channel.get_ref().peer_addr()?;
+                    let peer = stream.peer_addr()?;
+                    if bans.contains(peer.ip()) {
+                        info!("Refusing banned player {}", peer);
+                        return Ok(());
+                    }
+                    if !access.permits(peer.ip()) {
+                        info!("Refusing player {} from a network that isn't let in", peer);
+                        return Ok(());
+                    }
                     info!("Handler for player {} created", peer);
 
                     // When this future is dropped, the player will be disconnected.
+                    metrics::CONNECTED_PLAYERS.inc();
+                    let kicked = Arc::new(OnceCell::new());
+                    let kick = connections.add(peer, handler.entity_id.clone());
                     let _disconnect = Disconnect {
-                        game,
+                        history,
+                        datagram_peers,
+                        sessions,
+                        connections,
                         client_id: handler.entity_id.clone(),
+                        session_id: handler.session_id.clone(),
+                        joined: handler.joined.clone(),
+                        stats: handler.stats.clone(),
+                        kicked: kicked.clone(),
                         peer_addr: peer,
                     };
 
-                    let mut handler = handler.serve();
-                    let mut response_stream = channel.requests();
-                    while let Some(response) = response_stream.next().await {
-                        // No need to do response handling concurrently, because these futures are
-                        // very short-lived.
-                        response?.execute(&mut handler).await;
+                    let serving = async {
+                        if websocket {
+                            let transport =
+                                transport::accept_websocket(stream, &transport_config).await?;
+                            serve_player(handler, transport).await
+                        } else {
+                            let transport = transport::accept(stream, &transport_config).await?;
+                            serve_player(handler, transport).await
+                        }
+                    };
+                    match future::select(Box::pin(serving), kick).await {
+                        future::Either::Left((result, _)) => result,
+                        future::Either::Right((reason, _)) => {
+                            info!("Kicked player {}", peer);
+                            if let Ok(reason) = reason {
+                                kicked.get_or_init(|| reason);
+                            }
+                            Ok(())
+                        }
                     }
-                    Ok::<_, io::Error>(())
                 }
             })
-            .buffer_unordered(10)
-            .for_each(|_| async {})
-            .await;
+            .for_each_concurrent(None, |serve| serve.map(drop));
+        let closed = async {
+            let reason = shutting_down(shutdown_rx).await;
+            info!("Shutting down: {}", reason);
+            // The registration finishes once the game is unregistered.
+            future::join(registration, time::delay_for(SHUTDOWN_TIMEOUT)).await;
+        };
+        future::select(Box::pin(serving), Box::pin(closed)).await;
+        // The runtime stops once the server's done, so the final save has to finish first.
+        if let Some(saved) = saved {
+            let _ = saved.await;
+        }
 
         Ok(())
     }
 
-    pub fn run_game(server_addr: SocketAddr, name: String) -> io::Result<()> {
-        let game = game::Game::new(Point::new(10_000., 500.), 50.);
-        let (game_tx, game_rx) = watch::channel(game.clone());
-        let game = Arc::new(Mutex::new(game));
-        let mut server = Server::new(game.clone(), game_rx);
+    /// Whether players need a join token or a password to play.
+    fn locked(&self) -> bool {
+        !self.join_tokens.is_empty() || self.password.is_some()
+    }
+
+    /// What the game registers with the game list.
+    fn registration(&self) -> game_list::Registration {
+        game_list::Registration {
+            name: self.status.name.clone(),
+            region: self.region.clone(),
+            tags: self.tags.clone(),
+            motd: self.motd.clone(),
+            locked: self.locked(),
+            heartbeats: self.heartbeat_interval.is_some(),
+        }
+    }
+
+    /// Starts a game accepting players on `server_addr`, and WebSocket connections from players
+    /// on `websocket_addr` if given. If `status_addr` is given, serves the game's [`Status`] over
+    /// HTTP there. The game runs on background threads until [`ServerHandle::shutdown`] is
+    /// called.
+    pub fn spawn_game(
+        server_addr: SocketAddr,
+        websocket_addr: Option<SocketAddr>,
+        status_addr: Option<SocketAddr>,
+        name: String,
+        transport_config: transport::Config,
+        settings: Settings,
+    ) -> ServerHandle {
+        let playback = settings.playback.clone().map(Playback::new);
+        let mut game = match &playback {
+            Some(playback) => playback.game().clone(),
+            None => settings
+                .world
+                .clone()
+                .unwrap_or_else(|| game::Game::new(settings.world_size, settings.square_size)),
+        };
 
-        std::thread::spawn(move || {
+        let default_rates = Rates {
+            tick: settings.tick_rate,
+            broadcast: settings.broadcast_rate,
+        };
+        let mut rates = default_rates;
+        let map = settings.map.clone().filter(|_| playback.is_none());
+        if let Some(path) = &map {
+            match load_map(path, game.world_size(), game.square_side_length()) {
+                Ok(map) => {
+                    game.set_layout(&map.layout());
+                    rates = default_rates.overridden_by(&map);
+                }
+                Err(e) => error!("Not using the map {}: {}", path.display(), e),
+            }
+        }
+        let rotation = settings
+            .map_rotation
+            .iter()
+            .filter_map(
+                |path| match load_map(path, game.world_size(), game.square_side_length()) {
+                    Ok(map) => Some(map.layout()),
+                    Err(e) => {
+                        error!(
+                            "Leaving the map {} out of the rotation: {}",
+                            path.display(),
+                            e
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+        // Only games that are played have stats to keep.
+        let stats = match (&playback, &settings.stats) {
+            (Some(_), _) => None,
+            (None, Some(path)) => Stats::open(path)
+                .map_err(|e| error!("Not keeping players' stats in {}: {}", path.display(), e))
+                .ok(),
+            (None, None) => Stats::temporary()
+                .map_err(|e| error!("Not keeping players' stats: {}", e))
+                .ok(),
+        };
+        let mut rules = Rules::new(
+            settings.mode,
+            settings.script.clone(),
+            rotation,
+            stats.clone(),
+        );
+        if playback.is_none() {
+            rules.start(&mut game);
+        }
+
+        let (publisher, states) = states::channel(game.clone());
+        let (shutdown_tx, shutdown_rx) = watch::channel(None);
+        let (rates_tx, rates_rx) = watch::channel(rates);
+        let (scores_tx, scores_rx) = watch::channel(vec![]);
+        let record = settings.record.clone();
+        let schedule = settings.schedule.clone();
+        let next_round = Arc::new(AtomicBool::new(false));
+        let history = Arc::new(Mutex::new(History::new(
+            game,
+            max_rollback_ticks(rates.tick),
+        )));
+        let mut server = Server::new(
+            history.clone(),
+            states.clone(),
+            name,
+            settings,
+            stats,
+            shutdown_rx.clone(),
+            rates_rx.clone(),
+        );
+        let shutdown_tx = Arc::new(shutdown_tx);
+        let paused = server.paused.clone();
+        let admin_log = server.admin_log.clone();
+        let admin = Admin {
+            history: history.clone(),
+            sessions: server.sessions.clone(),
+            connections: server.connections.clone(),
+            bans: server.bans.clone(),
+            access: server.access.clone(),
+            moderators: server.moderators.clone(),
+            next_round: next_round.clone(),
+            autosave: server.autosave.clone(),
+            log: server.admin_log.clone(),
+            shutdown_tx: shutdown_tx.clone(),
+            scores_rx,
+        };
+
+        let scheduler = admin.clone();
+        let final_states = states.clone();
+        let runtime = thread::spawn(move || -> Result<(), Error> {
             info!("Starting server.");
-            Runtime::new().unwrap().block_on(async move {
-                match server.run(server_addr, name).await {
-                    Err(err) => error!("Server died: {:?}", err),
-                    Ok(()) => info!("Server done."),
+            let ended = Runtime::new()?.block_on(async move {
+                let game_loop = match playback {
+                    Some(playback) => tokio::spawn(run_playback_loop(
+                        playback,
+                        publisher,
+                        shutdown_rx,
+                        rates.broadcast,
+                    )),
+                    None => {
+                        if let Some(path) = map {
+                            tokio::spawn(watch_map(
+                                path,
+                                history.clone(),
+                                admin_log,
+                                rates_tx,
+                                default_rates,
+                                shutdown_rx.clone(),
+                            ));
+                        }
+                        for scheduled in schedule {
+                            tokio::spawn(schedule::run(scheduler.clone(), scheduled));
+                        }
+                        tokio::spawn(run_game_loop(
+                            history,
+                            publisher,
+                            paused,
+                            next_round,
+                            shutdown_rx,
+                            rates_rx,
+                            record,
+                            rules,
+                            scores_tx,
+                        ))
+                    }
+                }
+                .map(|ended| {
+                    ended.unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::Other, "game loop panicked"))
+                    })
+                });
+                let serving = server
+                    .run(server_addr, websocket_addr, status_addr, transport_config)
+                    .map(|served| match served {
+                        Err(err) => error!("Server died: {:?}", err),
+                        Ok(()) => info!("Server done."),
+                    });
+                match future::select(Box::pin(serving), game_loop).await {
+                    // The game goes on without the server.
+                    future::Either::Left(((), game_loop)) => game_loop.await,
+                    future::Either::Right((ended, serving)) => {
+                        // The final state announces that the server is closing if it was shut
+                        // down, in which case the server finishes shutting down.
+                        if final_states.latest().closing_reason().is_some() {
+                            serving.await;
+                        }
+                        ended
+                    }
                 }
             });
+            Ok(ended?)
         });
 
-        let mut window: NoWindow = WindowSettings::new("shapes", [0; 2]).build().unwrap();
-
-        let mut events = Events::new(EventSettings::new().ups(UPDATES_PER_SECOND).ups_reset(0));
-        let mut time_in_current_bucket = 0.;
-        let mut ticks_in_current_bucket = 0;
-        info!("start!");
-
-        while let Some(event) = events.next(&mut window) {
-            if let Event::Loop(ref lp) = event {
-                let now = Instant::now();
-
-                let mut game = game.lock().unwrap();
-                match lp {
-                    Loop::Idle(_) => {}
-                    Loop::Update(args) => {
-                        game.tick(
-                            args.dt as f32,
-                            &mut time_in_current_bucket,
-                            &mut ticks_in_current_bucket,
-                        );
-                    }
-                    lp => panic!("Didn't expect {:?}", lp),
-                }
-                let game = game.clone();
-                game_tx.broadcast(game).unwrap();
+        ServerHandle {
+            states,
+            shutdown_tx,
+            admin,
+            runtime,
+        }
+    }
+
+    /// Runs a game until it's interrupted with ctrl-c or stopped from the console, then shuts it
+    /// down; see [`Server::spawn_game`]. Commands typed into standard input are run as console
+    /// commands.
+    pub fn run_game(
+        server_addr: SocketAddr,
+        websocket_addr: Option<SocketAddr>,
+        status_addr: Option<SocketAddr>,
+        name: String,
+        transport_config: transport::Config,
+        settings: Settings,
+    ) -> Result<(), Error> {
+        let handle = Server::spawn_game(
+            server_addr,
+            websocket_addr,
+            status_addr,
+            name,
+            transport_config,
+            settings,
+        );
+        console::spawn(handle.admin());
+        let shutdown_tx = handle.shutdown_tx.clone();
+        thread::spawn(move || match Runtime::new().unwrap().block_on(stopped()) {
+            Ok(()) => {
+                let _ = shutdown_tx.broadcast(Some(String::from("the server was stopped")));
+            }
+            Err(e) => error!("Failed to listen for signals: {}", e),
+        });
+        handle.join()
+    }
+}
+
+/// Resolves once the process is asked to stop, with ctrl-c or, on Unix, `SIGTERM`.
+pub(crate) async fn stopped() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        let terminated = terminate.recv().map(|_| Ok(()));
+        let (stopped, _) = future::select(Box::pin(tokio::signal::ctrl_c()), Box::pin(terminated))
+            .await
+            .factor_first();
+        stopped
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Marks players who haven't made an input for `afk_timeout` as away from the keyboard, and kicks
+/// the ones who stayed away for `removal_timeout` after that, so that they can't resume their
+/// sessions. Players who are disconnected already are left to their sessions to expire.
+fn check_afk(
+    history: &Mutex<History>,
+    connections: &Connections,
+    afk_timeout: Duration,
+    removal_timeout: Duration,
+) {
+    let mut history = history.lock().unwrap();
+    let game = history.game();
+    let mut away = vec![];
+    let mut gone = vec![];
+    for id in game.players() {
+        let idle = Duration::from_secs_f32(game.idle_time(id).max(0.));
+        if idle >= afk_timeout + removal_timeout {
+            gone.push(id);
+        } else if idle >= afk_timeout && !game.is_afk(id) {
+            away.push((id, format!("{} is away", game.name(id).unwrap_or_default())));
+        }
+    }
+    for (id, announcement) in away {
+        history.apply(Command::SetAfk(id, true));
+        history.apply(Command::Announce(announcement));
+    }
+    drop(history);
+    for id in gone {
+        if connections.kick_entity(id, KickReason::Away) {
+            info!(
+                "Disconnected the player of entity {}, who was away too long",
+                id
+            );
+        }
+    }
+}
+
+/// Removes entities left behind: players' entities with neither a connection, a session nor a bot
+/// controlling them, which are left when a connection dies while its entity is being made, and the
+/// transient entities over [`MAX_TRANSIENT_ENTITIES`] with the least time left to live.
+///
+/// An entity can be made just before its connection takes it, so players' entities are only
+/// removed once they've been found without an owner twice in a row. Returns the entities found
+/// without an owner this time, to pass in as `suspects` next time.
+fn collect_garbage(
+    history: &Mutex<History>,
+    connections: &Connections,
+    sessions: &Sessions,
+    bots: &Bots,
+    suspects: &HashSet<EntityId>,
+) -> HashSet<EntityId> {
+    let mut owned: HashSet<_> = sessions.entity_ids().into_iter().collect();
+    owned.extend(connections.list().into_iter().filter_map(|(_, id)| id));
+    let mut history = history.lock().unwrap();
+    let orphans: HashSet<_> = history
+        .game()
+        .players()
+        .filter(|&id| !owned.contains(&id) && !bots.contains(id))
+        .collect();
+    for &id in orphans.intersection(suspects) {
+        warn!("Removing entity {}, which no player owns", id);
+        history.apply(Command::RemovePlayer(id));
+    }
+    let transients = history.game().excess_transients(MAX_TRANSIENT_ENTITIES);
+    if !transients.is_empty() {
+        info!(
+            "Removing {} transient entities over the limit",
+            transients.len()
+        );
+    }
+    for id in transients {
+        history.apply(Command::RemoveEntity(id));
+    }
+    orphans
+}
+
+/// The game without the players, who have to join again once it's restored.
+fn snapshot_game(history: &Mutex<History>, sessions: &Sessions) -> game::Game {
+    history
+        .lock()
+        .unwrap()
+        .game()
+        .snapshot(sessions.entity_ids())
+}
+
+/// Saves the game to `path`, without the players.
+fn save_snapshot(history: &Mutex<History>, sessions: &Sessions, path: &Path) -> io::Result<()> {
+    snapshot::save(&snapshot_game(history, sessions), path)
+}
+
+/// Saves the game to `path` like [`save_snapshot`], writing and syncing the file on a thread
+/// meant for blocking, and logs how it went.
+async fn autosave(history: &Mutex<History>, sessions: &Sessions, path: &Path) {
+    let game = snapshot_game(history, sessions);
+    let saving = path.to_path_buf();
+    let saved = task::spawn_blocking(move || snapshot::save(&game, &saving))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)));
+    log_saved(path, saved);
+}
+
+fn log_saved(path: &Path, saved: io::Result<()>) {
+    match saved {
+        Ok(()) => info!("Saved the game to {}", path.display()),
+        Err(e) => error!("Failed to save the game to {}: {}", path.display(), e),
+    }
+}
+
+/// Resolves with the reason once the server starts shutting down, or never if it never does.
+async fn shutting_down(mut shutdown_rx: watch::Receiver<Option<String>>) -> String {
+    while let Some(reason) = shutdown_rx.recv().await {
+        if let Some(reason) = reason {
+            return reason;
+        }
+    }
+    future::pending().await
+}
+
+/// Controls a running game on behalf of its operator.
+#[derive(Clone)]
+pub struct Admin {
+    history: Arc<Mutex<History>>,
+    sessions: Sessions,
+    connections: Connections,
+    bans: Bans,
+    access: Access,
+    moderators: Moderators,
+    /// Set to have the game loop start the next round.
+    next_round: Arc<AtomicBool>,
+    /// Where the game is saved by default.
+    autosave: Option<PathBuf>,
+    log: AdminLog,
+    shutdown_tx: Arc<watch::Sender<Option<String>>>,
+    scores_rx: watch::Receiver<Vec<Score>>,
+}
+
+impl Admin {
+    /// The connected players, ordered by address.
+    pub fn players(&self) -> Vec<ConnectedPlayer> {
+        let history = self.history.lock().unwrap();
+        self.connections
+            .list()
+            .into_iter()
+            .map(|(addr, entity_id)| ConnectedPlayer {
+                addr,
+                entity_id,
+                name: entity_id
+                    .and_then(|id| history.game().name(id))
+                    .map(String::from),
+            })
+            .collect()
+    }
+
+    /// Disconnects the player connected from `addr` and removes their entity, returning whether
+    /// there was one.
+    pub fn kick(&self, addr: SocketAddr) -> bool {
+        let kicked = self.connections.kick(addr, KickReason::Kicked);
+        if kicked {
+            self.log.record(Actor::Operator, format!("kicked {}", addr));
+        }
+        kicked
+    }
+
+    /// Bans `ip`: disconnects the players connected from it, removing their entities, and refuses
+    /// players from it from now on. Returns how many players were disconnected. The ban takes
+    /// effect even if it can't be saved to the ban list.
+    pub fn ban(&self, ip: IpAddr) -> io::Result<usize> {
+        let saved = self.bans.ban(ip);
+        let kicked = self.connections.kick_ip(ip, KickReason::Banned);
+        self.log.record(
+            Actor::Operator,
+            format!("banned {}, disconnecting {} players", ip, kicked),
+        );
+        saved.map(|()| kicked)
+    }
+
+    /// Lifts the ban on `ip`, returning whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> io::Result<bool> {
+        let unbanned = self.bans.unban(ip);
+        if let Ok(true) = unbanned {
+            self.log.record(Actor::Operator, format!("unbanned {}", ip));
+        }
+        unbanned
+    }
+
+    /// The banned addresses, in order.
+    pub fn bans(&self) -> Vec<IpAddr> {
+        self.bans.list()
+    }
+
+    /// Reloads the access lists from the game's config file, and disconnects the players whose
+    /// networks aren't let in anymore. Returns the new lists and how many players were
+    /// disconnected.
+    pub fn reload_access(&self) -> io::Result<(AccessLists, usize)> {
+        let lists = self.access.reload()?;
+        let kicked = self
+            .connections
+            .kick_where(|ip| !lists.permits(ip), KickReason::NotLetIn);
+        self.log.record(
+            Actor::Operator,
+            format!(
+                "reloaded the access lists, disconnecting {} players",
+                kicked
+            ),
+        );
+        Ok((lists, kicked))
+    }
+
+    /// Makes the player named `name` a moderator, with a new key to moderate with, replacing any
+    /// key they had. Returns the key, and whether the moderator list was saved; they're a
+    /// moderator either way.
+    pub fn add_moderator(&self, name: &str) -> (String, io::Result<()>) {
+        self.log
+            .record(Actor::Operator, format!("made {} a moderator", name));
+        self.moderators.grant(name)
+    }
+
+    /// Takes moderating away from the player named `name`, returning whether they were a
+    /// moderator.
+    pub fn remove_moderator(&self, name: &str) -> io::Result<bool> {
+        let revoked = self.moderators.revoke(name);
+        self.log
+            .record(Actor::Operator, format!("removed moderator {}", name));
+        revoked
+    }
+
+    /// The moderators' names, in order.
+    pub fn moderators(&self) -> Vec<String> {
+        self.moderators.list()
+    }
+
+    /// Ends the round, starting the next one on the next map in the rotation, or else on the same
+    /// one.
+    pub fn next_round(&self) {
+        self.log
+            .record(Actor::Operator, String::from("started the next round"));
+        self.next_round.store(true, Ordering::SeqCst);
+    }
+
+    /// The scores in the match, best first, as of the latest state.
+    pub fn scoreboard(&self) -> Vec<Score> {
+        self.scores_rx.borrow().clone()
+    }
+
+    /// Says `message` to everyone in the game, alongside the chat.
+    pub fn say(&self, message: &str) {
+        let message = printable(message, MAX_CHAT_LENGTH);
+        self.log
+            .record(Actor::Operator, format!("said {:?}", message));
+        self.history
+            .lock()
+            .unwrap()
+            .apply(Command::Announce(message));
+    }
+
+    /// Saves the game to `path`, or to where it's autosaved without a path, returning where it
+    /// was saved.
+    pub fn save(&self, path: Option<&Path>) -> io::Result<PathBuf> {
+        let path = match path.or_else(|| self.autosave.as_deref()) {
+            Some(path) => path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no path given, and the game isn't autosaved",
+                ))
+            }
+        };
+        save_snapshot(&self.history, &self.sessions, path)?;
+        self.log.record(
+            Actor::Operator,
+            format!("saved the game to {}", path.display()),
+        );
+        Ok(path.to_path_buf())
+    }
+
+    /// The latest entries in the admin log, oldest first.
+    pub fn log(&self) -> Vec<admin_log::Entry> {
+        self.log.entries()
+    }
+
+    /// See [`ServerHandle::shutdown`].
+    pub fn shutdown(&self, reason: &str) {
+        self.log
+            .record(Actor::Operator, format!("shut the game down: {}", reason));
+        // Only fails if the game is already over.
+        let _ = self.shutdown_tx.broadcast(Some(String::from(reason)));
+    }
+}
+
+/// A game started by [`Server::spawn_game`].
+pub struct ServerHandle {
+    states: States,
+    shutdown_tx: Arc<watch::Sender<Option<String>>>,
+    admin: Admin,
+    /// Runs the game loop and the server.
+    runtime: thread::JoinHandle<Result<(), Error>>,
+}
+
+impl ServerHandle {
+    /// The latest state of the game.
+    pub fn game(&self) -> Arc<game::Game> {
+        self.states.latest()
+    }
+
+    /// Shuts the game down: stops accepting players, and sends the players still connected a
+    /// final state announcing that the server is closing, for `reason`.
+    pub fn shutdown(&self, reason: &str) {
+        self.admin.shutdown(reason);
+    }
+
+    /// Controls for the game's operator.
+    pub fn admin(&self) -> Admin {
+        self.admin.clone()
+    }
+
+    /// Waits for the game loop to end, and the server to finish shutting down if it was asked to.
+    pub fn join(self) -> Result<(), Error> {
+        self.runtime
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "server panicked").into()))
+    }
+}
+
+/// How many times a second the game ticks and publishes its state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Rates {
+    tick: u64,
+    broadcast: u64,
+}
+
+impl Rates {
+    /// These rates, with the ones `map` sets instead.
+    fn overridden_by(self, map: &Map) -> Self {
+        Rates {
+            tick: map.tick_rate.unwrap_or(self.tick),
+            broadcast: map.broadcast_rate.unwrap_or(self.broadcast),
+        }
+    }
+
+    /// How long each tick lasts.
+    fn dt(self) -> Duration {
+        Duration::from_secs(1) / self.tick.max(1) as u32
+    }
+
+    /// How long the game goes between publishing states.
+    fn broadcast_interval(self) -> Duration {
+        Duration::from_secs(1) / self.broadcast.max(1) as u32
+    }
+}
+
+/// Reads the map at `path`, checking that it fits `game`'s world.
+fn load_map(path: &Path, world_size: Point, square_side_length: GameInt) -> io::Result<Map> {
+    let map = Map::read(path)?;
+    map.validate(world_size, square_side_length)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(map)
+}
+
+/// Applies changes to the map at `path` to the game, and publishes its rates, or
+/// `default_rates` where it doesn't set them, to `rates_tx`, until the server shuts down. Maps
+/// that can't be read or don't fit the world are ignored, and the game keeps the last good one.
+async fn watch_map(
+    path: PathBuf,
+    history: Arc<Mutex<History>>,
+    admin_log: AdminLog,
+    rates_tx: watch::Sender<Rates>,
+    default_rates: Rates,
+    shutdown_rx: watch::Receiver<Option<String>>,
+) {
+    let modified_at = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let mut last_modified: Option<SystemTime> = modified_at(&path).ok();
+    let mut checks = time::interval(MAP_CHECK_INTERVAL);
+    while shutdown_rx.borrow().is_none() {
+        checks.tick().await;
+        let modified = modified_at(&path).ok();
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        // The world's size never changes, so the map can be read and checked without holding up
+        // the game.
+        let (world_size, square_side_length) = {
+            let history = history.lock().unwrap();
+            let game = history.game();
+            (game.world_size(), game.square_side_length())
+        };
+        match load_map(&path, world_size, square_side_length) {
+            Ok(map) => {
+                history
+                    .lock()
+                    .unwrap()
+                    .apply(Command::SetLayout(map.layout()));
+                let _ = rates_tx.broadcast(default_rates.overridden_by(&map));
+                admin_log.record(
+                    Actor::Server,
+                    format!("reloaded the map from {}", path.display()),
+                );
+            }
+            Err(e) => error!(
+                "Not reloading the map from {}, keeping the last one: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// How many ticks late inputs can be, at `tick_rate`, to be at most [`MAX_ROLLBACK`] late.
+fn max_rollback_ticks(tick_rate: u64) -> usize {
+    (tick_rate as f64 * MAX_ROLLBACK.as_secs_f64()) as usize
+}
+
+/// Ticks the game and publishes its state to `publisher` at the latest rates from `rates_rx`,
+/// until the server shuts down. Records the game to `record` if given. Runs the `rules` after
+/// each tick, and publishes the scores with each state to `scores_tx`.
+async fn run_game_loop(
+    history: Arc<Mutex<History>>,
+    publisher: Publisher,
+    paused: Arc<AtomicBool>,
+    next_round: Arc<AtomicBool>,
+    shutdown_rx: watch::Receiver<Option<String>>,
+    rates_rx: watch::Receiver<Rates>,
+    record: Option<PathBuf>,
+    mut rules: Rules,
+    scores_tx: watch::Sender<Vec<Score>>,
+) -> io::Result<()> {
+    if let Some(path) = record {
+        history.lock().unwrap().record(&path)?;
+        info!("Recording the game to {}", path.display());
+    }
+    let mut rates = *rates_rx.borrow();
+    let mut dt = rates.dt();
+    // Each tick is due a tick after the last one was due, not after it ran, so ticks that run
+    // late are caught up on and the game keeps time with the clock.
+    let mut ticks = time::interval(dt);
+    let mut time_in_current_bucket = 0.;
+    let mut ticks_in_current_bucket = 0;
+    // Goes up by the broadcast rate each tick, and the state is broadcast each time it reaches
+    // the tick rate, which spreads the broadcasts evenly between ticks.
+    let mut broadcast_credit = rates.tick;
+    // How many ticks of history to keep once the tick rate changes, set at the next tick.
+    let mut max_ticks_changed = None;
+    let mut summary = metrics::TickSummary::new(dt);
+    let mut last_summary = Instant::now();
+    info!("start!");
+
+    loop {
+        ticks.tick().await;
+        let now = Instant::now();
+
+        let mut history = history.lock().unwrap();
+        if let Some(max_ticks) = max_ticks_changed.take() {
+            history.set_max_ticks(max_ticks);
+        }
+        // The announcement goes out in one final state.
+        let closing = match shutdown_rx.borrow().clone() {
+            Some(reason) => {
+                history.announce_closing(reason);
+                true
+            }
+            None => false,
+        };
+        // Paused games keep ticking with no time passing, so that players still get states, and
+        // chat and inputs still go through.
+        let tick_dt = if paused.load(Ordering::SeqCst) {
+            0.
+        } else {
+            dt.as_secs_f32()
+        };
+        let confirmed = history.tick(
+            tick_dt,
+            &mut time_in_current_bucket,
+            &mut ticks_in_current_bucket,
+        );
+        let timings = history.game().tick_timings();
+        for command in rules.run(history.game()) {
+            history.apply(command);
+        }
+        if next_round.swap(false, Ordering::SeqCst) {
+            for command in rules.next_round(history.game()) {
+                history.apply(command);
+            }
+        }
+        for confirmed in &confirmed {
+            rules.confirm(confirmed);
+        }
+        metrics::observe_phase("movement", timings.movement);
+        metrics::observe_phase("collisions", timings.collisions);
+        broadcast_credit += rates.broadcast;
+        let mut broadcast = Duration::default();
+        if broadcast_credit >= rates.tick || closing {
+            let broadcasting = Instant::now();
+            broadcast_credit = broadcast_credit.saturating_sub(rates.tick).min(rates.tick);
+            let game = history.game();
+            metrics::ENTITIES.set(game.entity_ids().count() as i64);
+            let _ = scores_tx.broadcast(rules.scoreboard(game));
+            publisher.publish(game);
+            broadcast = broadcasting.elapsed();
+            metrics::observe_phase("broadcast", broadcast);
+        }
+        drop(history);
+        rules.record_kills();
 
-                let elapsed = now.elapsed();
-                const TWO_MILLIS: Duration = Duration::from_millis(2);
-                if elapsed > TWO_MILLIS {
-                    info!("one game loop took {:?}", elapsed);
+        let elapsed = now.elapsed();
+        metrics::TICK_DURATION.observe(elapsed.as_secs_f64());
+        let late = summary.record(elapsed);
+        const TWO_MILLIS: Duration = Duration::from_millis(2);
+        if elapsed > TWO_MILLIS {
+            let breakdown = format!(
+                "movement {:?}, collisions {:?}, broadcast {:?}",
+                timings.movement, timings.collisions, broadcast
+            );
+            if late {
+                warn!(
+                    "one game loop took {:?}, over budget: {}",
+                    elapsed, breakdown
+                );
+            } else {
+                info!("one game loop took {:?}: {}", elapsed, breakdown);
+            }
+        }
+        if last_summary.elapsed() >= SUMMARY_INTERVAL {
+            info!(
+                "Over the last {:?}: {}",
+                last_summary.elapsed(),
+                summary.report()
+            );
+            last_summary = Instant::now();
+        }
+        if closing {
+            break;
+        }
+        let latest = *rates_rx.borrow();
+        if latest != rates {
+            rates = latest;
+            dt = rates.dt();
+            ticks = time::interval_at(time::Instant::now() + dt, dt);
+            // Inputs can be as late, in time, at any tick rate.
+            max_ticks_changed = Some(max_rollback_ticks(rates.tick));
+            summary = metrics::TickSummary::new(dt);
+            broadcast_credit = rates.tick;
+            info!(
+                "Ticking {} times a second, broadcasting {} times a second",
+                rates.tick, rates.broadcast
+            );
+        }
+    }
+    info!("end :(");
+    history.lock().unwrap().finish_recording()
+}
+
+/// Plays `playback` back in real time, publishing each new state to `publisher` up to
+/// `broadcast_rate` times a second, until the server shuts down. The last state is kept once
+/// playback ends.
+async fn run_playback_loop(
+    mut playback: Playback,
+    publisher: Publisher,
+    shutdown_rx: watch::Receiver<Option<String>>,
+    broadcast_rate: u64,
+) -> io::Result<()> {
+    info!("Playing back {:?} of a recorded game", playback.duration());
+    let mut broadcasts = time::interval(Duration::from_secs(1) / broadcast_rate.max(1) as u32);
+    let mut last_update = Instant::now();
+    let mut last_tick = playback.game().ticks();
+    loop {
+        broadcasts.tick().await;
+        let now = Instant::now();
+        playback.advance(now - last_update);
+        last_update = now;
+        if let Some(reason) = shutdown_rx.borrow().clone() {
+            // The announcement goes out in one final state, after the playback's.
+            let mut game = playback.game().clone();
+            game.announce_closing(reason);
+            game.tick(0., &mut 0., &mut 0);
+            publisher.publish(&game);
+            break;
+        }
+        if playback.game().ticks() != last_tick {
+            last_tick = playback.game().ticks();
+            publisher.publish(playback.game());
+        }
+    }
+    info!("Playback over");
+    Ok(())
+}
+
+/// Whether `message` may be sent by a player who isn't allowed to play yet.
+fn allowed_before_joining(message: &tarpc::ClientMessage<crate::GameRequest>) -> bool {
+    match message {
+        tarpc::ClientMessage::Request(request) => match request.message {
+            crate::GameRequest::Ping { .. }
+            | crate::GameRequest::Status { .. }
+            | crate::GameRequest::Authenticate { .. }
+            | crate::GameRequest::Join { .. } => true,
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+/// Whether `message` is from a player, rather than only checking on the game, like the game
+/// list's health checks do.
+fn is_player_request(message: &tarpc::ClientMessage<crate::GameRequest>) -> bool {
+    match message {
+        tarpc::ClientMessage::Request(request) => match request.message {
+            crate::GameRequest::Ping { .. } | crate::GameRequest::Status { .. } => false,
+            _ => true,
+        },
+        _ => false,
+    }
+}
+
+/// Serves a player's requests until they disconnect. Players who haven't joined are disconnected
+/// when they make any request besides pinging, getting the status, authenticating, or joining,
+/// and [`AUTHENTICATION_TIMEOUT`] after their first request besides pinging or getting the
+/// status, so that health checks stay connected to games that are locked or full. Players who
+/// keep pushing inputs over the rate limit or guessing passwords are disconnected too, as are
+/// players who make no requests for the idle timeout.
+async fn serve_player<T>(handler: ConnectionHandler, transport: T) -> io::Result<()>
+where
+    T: tarpc::Transport<
+            tarpc::Response<crate::GameResponse>,
+            tarpc::ClientMessage<crate::GameRequest>,
+        > + Unpin,
+{
+    let admitted = handler.admitted.clone();
+    let flooding = handler.flooding.clone();
+    let guessing = handler.guessing.clone();
+    let idle_timeout = handler.idle_timeout;
+    let last_message = Arc::new(Mutex::new(Instant::now()));
+    let (first_player_request_tx, first_player_request) = oneshot::channel();
+    let transport = transport.map({
+        let admitted = admitted.clone();
+        let last_message = last_message.clone();
+        let mut first_player_request_tx = Some(first_player_request_tx);
+        move |message| {
+            let message = message?;
+            *last_message.lock().unwrap() = Instant::now();
+            if is_player_request(&message) {
+                if let Some(tx) = first_player_request_tx.take() {
+                    let _ = tx.send(());
                 }
             }
+            if !admitted.load(Ordering::SeqCst) && !allowed_before_joining(&message) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "request made before joining",
+                ));
+            }
+            Ok(message)
+        }
+    });
+    let channel = server::BaseChannel::with_defaults(transport);
+    let mut handler = handler.serve();
+    let mut response_stream = channel.requests();
+    let serve = async move {
+        while let Some(response) = response_stream.next().await {
+            // No need to do response handling concurrently, because these futures are very
+            // short-lived.
+            response?.execute(&mut handler).await;
+            if flooding.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "pushed too many inputs",
+                ));
+            }
+            if guessing.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "tried too many wrong passwords or moderator keys",
+                ));
+            }
         }
-        info!("end :(");
         Ok(())
+    };
+    let authentication_timeout = async move {
+        if first_player_request.await.is_err() {
+            // The connection closed without the player asking for anything.
+            future::pending::<()>().await;
+        }
+        time::delay_for(AUTHENTICATION_TIMEOUT).await;
+        if admitted.load(Ordering::SeqCst) {
+            future::pending::<()>().await;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "player didn't join in time",
+        ))
+    };
+    let idle_timeout = async move {
+        loop {
+            let deadline = *last_message.lock().unwrap() + idle_timeout;
+            if Instant::now() >= deadline {
+                break;
+            }
+            time::delay_until(deadline.into()).await;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("player made no requests for {:?}", idle_timeout),
+        ))
+    };
+    let timeouts = future::select(Box::pin(authentication_timeout), Box::pin(idle_timeout))
+        .map(|either| either.factor_first().0);
+    match future::select(Box::pin(serve), timeouts).await {
+        future::Either::Left((result, _)) | future::Either::Right((result, _)) => result,
     }
 }
 
 #[derive(Clone)]
-pub struct ConnectionHandler {
+pub(crate) struct ConnectionHandler {
     entity_id: Arc<OnceCell<EntityId>>,
-    game: Arc<Mutex<game::Game>>,
-    game_rx: watch::Receiver<game::Game>,
+    /// Set once the player has joined.
+    session_id: Arc<OnceCell<u64>>,
+    /// When the player joined, for their stats.
+    joined: Arc<OnceCell<SystemTime>>,
+    sessions: Sessions,
+    stats: Option<Stats>,
+    history: Arc<Mutex<History>>,
+    states: Subscriber,
+    datagram_peers: datagram::Peers,
+    join_tokens: Arc<HashSet<String>>,
+    /// The password players have to join with, if any.
+    password: Option<String>,
+    /// Whether the player has presented a join token, or doesn't need to.
+    authenticated: Arc<AtomicBool>,
+    /// Set once the player has joined, with the password if the game has one. Until then, they
+    /// can't play.
+    admitted: Arc<AtomicBool>,
+    /// How many wrong passwords and moderator keys the player has tried.
+    wrong_passwords: u32,
+    /// Set when the player has tried [`MAX_PASSWORD_ATTEMPTS`] wrong passwords or moderator keys.
+    guessing: Arc<AtomicBool>,
+    input_limit: InputLimit,
+    /// Set when the player keeps going over `input_limit`.
+    flooding: Arc<AtomicBool>,
+    chat_limit: ChatLimit,
+    /// Where the player is reported when they flood the chat.
+    admin_log: AdminLog,
+    /// Players who make no requests for this long are disconnected.
+    idle_timeout: Duration,
+    status: StatusReporter,
+    /// Where moderators kick players from.
+    connections: Connections,
+    moderators: Moderators,
+    /// Set while a moderator has the game paused.
+    paused: Arc<AtomicBool>,
+    motd: Option<String>,
+    rates_rx: watch::Receiver<Rates>,
+    /// Whether the game is a playback, in which the player only watches, following whichever
+    /// recorded player was created first.
+    read_only: bool,
+    /// How many players have joined the game, and how many can.
+    players: Arc<AtomicUsize>,
+    max_players: usize,
+    /// The player's place in the game, taken when they join and given up when they disconnect.
+    slot: Arc<Mutex<Option<PlayerSlot>>>,
+    /// The last game state returned to the client, which deltas are computed against.
+    last_sent: Option<Arc<game::Game>>,
+    /// The sequence of the latest input applied from the client.
+    last_input_sequence: u64,
 }
 
 #[tarpc::server]
 impl crate::Game for ConnectionHandler {
-    async fn ping(&mut self, _: &mut context::Context) {}
+    async fn ping(&mut self, _: &mut context::Context) -> ServerTime {
+        let _timer = metrics::time_rpc("ping");
+        ServerTime::now(self.status.states.latest().ticks())
+    }
+
+    async fn status(&mut self, _: &mut context::Context) -> Status {
+        let _timer = metrics::time_rpc("status");
+        self.status.report()
+    }
+
+    async fn authenticate(&mut self, _: &mut context::Context, token: String) -> bool {
+        let _timer = metrics::time_rpc("authenticate");
+        if self.join_tokens.is_empty() || self.join_tokens.contains(&token) {
+            self.authenticated.store(true, Ordering::SeqCst);
+            true
+        } else {
+            warn!("Rejected join token");
+            false
+        }
+    }
+
+    async fn join(
+        &mut self,
+        _: &mut context::Context,
+        resume: Option<u64>,
+        name: Option<String>,
+        password: Option<String>,
+    ) -> Result<Welcome, JoinError> {
+        let _timer = metrics::time_rpc("join");
+        if !self.authenticated.load(Ordering::SeqCst) {
+            return Err(JoinError::NotAuthenticated);
+        }
+        if let Some(reason) = resume.and_then(|session_id| self.sessions.kick_reason(session_id)) {
+            return Err(JoinError::Kicked(reason));
+        }
+        match (&self.password, password) {
+            (Some(_), None) => return Err(JoinError::PasswordRequired),
+            // Compared in constant time, so that how long rejecting a password takes doesn't give
+            // away how much of it was right.
+            (Some(expected), Some(password))
+                if constant_time::verify_slices_are_equal(
+                    expected.as_bytes(),
+                    password.as_bytes(),
+                )
+                .is_err() =>
+            {
+                warn!("Rejected a wrong password");
+                self.wrong_passwords += 1;
+                if self.wrong_passwords >= MAX_PASSWORD_ATTEMPTS {
+                    warn!("Disconnecting player guessing the password");
+                    self.guessing.store(true, Ordering::SeqCst);
+                }
+                time::delay_for(WRONG_PASSWORD_DELAY).await;
+                return Err(JoinError::WrongPassword);
+            }
+            _ => {}
+        }
+        {
+            let mut slot = self.slot.lock().unwrap();
+            if slot.is_none() {
+                *slot = PlayerSlot::take(&self.players, self.max_players);
+                if slot.is_none() {
+                    info!("Game is full; rejecting a player");
+                    return Err(JoinError::ServerFull {
+                        players: self.players.load(Ordering::SeqCst).min(self.max_players),
+                        max_players: self.max_players,
+                    });
+                }
+            }
+        }
+        self.admitted.store(true, Ordering::SeqCst);
+        let broadcast_interval = self.rates_rx.borrow().broadcast_interval();
+        let welcome = |session_id| Welcome {
+            session_id,
+            motd: self.motd.clone(),
+            broadcast_interval,
+        };
+        // Watching doesn't need a session; there's no entity to keep.
+        if self.read_only {
+            return Ok(welcome(0));
+        }
+        if let Some(session_id) = self.session_id.get() {
+            return Ok(welcome(*session_id));
+        }
+        // Only players who don't have an entity yet can take over a session's.
+        if let (Some(session_id), None) = (resume, self.entity_id.get()) {
+            if let Some(entity_id) = self.sessions.resume(session_id) {
+                info!("Resuming session for entity {}", entity_id);
+                self.entity_id.get_or_init(|| entity_id);
+                self.session_id.get_or_init(|| session_id);
+                self.joined.get_or_init(SystemTime::now);
+                return Ok(welcome(session_id));
+            }
+        }
+        let entity_id = self.get_or_make_entity_id();
+        let name = match printable(&name.unwrap_or_default(), MAX_NAME_LENGTH) {
+            name if name.is_empty() => format!("player {}", entity_id),
+            name => name,
+        };
+        self.history
+            .lock()
+            .unwrap()
+            .apply(Command::NamePlayer(entity_id, name));
+        let session_id = self.sessions.open(entity_id);
+        self.joined.get_or_init(SystemTime::now);
+        Ok(welcome(*self.session_id.get_or_init(|| session_id)))
+    }
 
     async fn get_entity_id(&mut self, _: &mut context::Context) -> game::EntityId {
+        let _timer = metrics::time_rpc("get_entity_id");
+        if self.read_only {
+            return self
+                .status
+                .states
+                .latest()
+                .players()
+                .next()
+                .unwrap_or_default();
+        }
         self.get_or_make_entity_id()
     }
 
-    async fn push_input(&mut self, _: &mut context::Context, input: game::Input) {
-        debug!("push_input({:?})", input);
-        self.game
+    async fn push_input(
+        &mut self,
+        _: &mut context::Context,
+        sequence: u64,
+        tick: u64,
+        input: game::Input,
+    ) -> u64 {
+        let _timer = metrics::time_rpc("push_input");
+        debug!("push_input({}, {}, {:?})", sequence, tick, input);
+        if sequence <= self.last_input_sequence {
+            debug!(
+                "Dropping input {}, already applied {}",
+                sequence, self.last_input_sequence
+            );
+            return self.last_input_sequence;
+        }
+        if self.read_only {
+            // Acknowledged so that the client stops resending it.
+            self.last_input_sequence = sequence;
+            return sequence;
+        }
+        match self.input_limit.check(Instant::now()) {
+            InputCheck::Apply(wait) if wait > Duration::from_secs(0) => time::delay_for(wait).await,
+            InputCheck::Apply(_) => {}
+            InputCheck::Drop => {
+                debug!("Dropping input {}, over the rate limit", sequence);
+                return self.last_input_sequence;
+            }
+            InputCheck::Flooding => {
+                warn!("Disconnecting player flooding inputs");
+                self.flooding.store(true, Ordering::SeqCst);
+                return self.last_input_sequence;
+            }
+        }
+        let entity_id = self.get_or_make_entity_id();
+        self.history
             .lock()
             .unwrap()
-            .process_input(self.get_or_make_entity_id(), input)
+            .apply_input_at(tick, entity_id, input);
+        self.last_input_sequence = sequence;
+        sequence
     }
 
-    async fn poll_game_state(&mut self, _: &mut context::Context) -> Box<game::Game> {
-        loop {
-            let game = self.game_rx.recv().await.unwrap();
-            if game.positions.contains(self.get_or_make_entity_id()) {
-                return Box::new(game);
+    async fn poll_game_state(
+        &mut self,
+        _: &mut context::Context,
+        last_seen_tick: Option<u64>,
+    ) -> game::StateUpdate {
+        let _timer = metrics::time_rpc("poll_game_state");
+        let game = loop {
+            let game = match self.states.recv().await {
+                Some(game) => game,
+                // The game is over, and its final state, announcing that the server is closing,
+                // was already sent. The server will disconnect the player shortly.
+                None => future::pending().await,
+            };
+            if self.read_only {
+                break game;
+            }
+            let entity_id = self.get_or_make_entity_id();
+            if game.contains(entity_id) {
+                break Arc::new(game.visible_to(entity_id, VIEW_DISTANCE));
+            }
+        };
+        let update = match &self.last_sent {
+            Some(last_sent) if Some(last_sent.ticks()) == last_seen_tick => {
+                game::StateUpdate::Delta(game.delta_since(last_sent))
             }
+            _ => {
+                debug!("Sending full game state at tick {}", game.ticks());
+                game::StateUpdate::Full(Box::new((*game).clone()))
+            }
+        };
+        self.last_sent = Some(game);
+        metrics::sample_state_update_size(|| bincode::serialized_size(&update).ok());
+        update
+    }
+
+    async fn open_datagram_channel(&mut self, _: &mut context::Context) -> u64 {
+        let _timer = metrics::time_rpc("open_datagram_channel");
+        if self.read_only {
+            warn!("Datagram channels aren't served during playback");
+            return 0;
+        }
+        let session_id = match self.session_id.get() {
+            Some(&session_id) => session_id,
+            None => {
+                warn!("Datagram channels are only opened for players who joined");
+                return 0;
+            }
+        };
+        let entity_id = self.get_or_make_entity_id();
+        self.datagram_peers.open(entity_id, session_id)
+    }
+
+    async fn chat(&mut self, _: &mut context::Context, message: String) -> bool {
+        let _timer = metrics::time_rpc("chat");
+        let entity_id = match self.entity_id.get() {
+            Some(&entity_id) if self.session_id.get().is_some() => entity_id,
+            _ => return false,
+        };
+        let message = printable(&message, MAX_CHAT_LENGTH);
+        if message.is_empty() {
+            return false;
+        }
+        if self
+            .session_id
+            .get()
+            .map_or(false, |&session_id| self.sessions.is_muted(session_id))
+        {
+            debug!("Dropping chat message from {}, who's muted", entity_id);
+            return false;
         }
+        match self.chat_limit.check(Instant::now(), &message) {
+            ChatCheck::Say => {}
+            ChatCheck::Duplicate => {
+                debug!("Dropping chat message from {}, a repeat", entity_id);
+                return false;
+            }
+            ChatCheck::Drop => {
+                debug!(
+                    "Dropping chat message from {}, over the rate limit",
+                    entity_id
+                );
+                return false;
+            }
+            ChatCheck::Throttle => {
+                let name = self
+                    .history
+                    .lock()
+                    .unwrap()
+                    .game()
+                    .name(entity_id)
+                    .map(String::from);
+                self.admin_log.record(
+                    Actor::Server,
+                    format!(
+                        "throttled the chat of {} (entity {}), over the rate limit",
+                        name.unwrap_or_default(),
+                        entity_id
+                    ),
+                );
+                return false;
+            }
+        }
+        self.history
+            .lock()
+            .unwrap()
+            .apply(Command::Chat(entity_id, message));
+        true
+    }
+
+    async fn set_color(&mut self, _: &mut context::Context, rgb: [GameInt; 3]) -> bool {
+        let _timer = metrics::time_rpc("set_color");
+        let entity_id = match self.entity_id.get() {
+            Some(&entity_id) if self.session_id.get().is_some() => entity_id,
+            _ => return false,
+        };
+        let rgb = match color(rgb) {
+            Some(rgb) => rgb,
+            None => return false,
+        };
+        self.history
+            .lock()
+            .unwrap()
+            .apply(Command::SetColor(entity_id, rgb));
+        true
     }
+
+    async fn get_stats(&mut self, _: &mut context::Context, name: String) -> Option<PlayerStats> {
+        let _timer = metrics::time_rpc("get_stats");
+        match self.stats.as_ref()?.get(&name) {
+            Ok(stats) => stats,
+            Err(e) => {
+                error!("Failed to look up {}'s stats: {}", name, e);
+                None
+            }
+        }
+    }
+
+    async fn moderate(
+        &mut self,
+        _: &mut context::Context,
+        key: String,
+        action: Moderation,
+    ) -> Result<(), ModerationError> {
+        let _timer = metrics::time_rpc("moderate");
+        let entity_id = match self.entity_id.get() {
+            Some(&entity_id) if self.session_id.get().is_some() => entity_id,
+            _ => return Err(ModerationError::NotJoined),
+        };
+        let moderator = match self.moderators.name(&key) {
+            Some(moderator) => moderator,
+            None => {
+                warn!("Rejected a wrong moderator key");
+                self.wrong_passwords += 1;
+                if self.wrong_passwords >= MAX_PASSWORD_ATTEMPTS {
+                    warn!("Disconnecting player guessing moderator keys");
+                    self.guessing.store(true, Ordering::SeqCst);
+                }
+                time::delay_for(WRONG_PASSWORD_DELAY).await;
+                return Err(ModerationError::WrongKey);
+            }
+        };
+        let event = match action {
+            Moderation::Mute(id) | Moderation::Unmute(id) => {
+                let mute = action == Moderation::Mute(id);
+                if !self.sessions.set_muted(id, mute) {
+                    return Err(ModerationError::NoSuchPlayer(id));
+                }
+                format!("{} entity {}", if mute { "muted" } else { "unmuted" }, id)
+            }
+            Moderation::Kick(id) => {
+                if !self.connections.kick_entity(id, KickReason::Kicked) {
+                    return Err(ModerationError::NoSuchPlayer(id));
+                }
+                format!("kicked entity {}", id)
+            }
+            Moderation::Pause | Moderation::Resume => {
+                let pause = action == Moderation::Pause;
+                let event = if pause {
+                    "paused the game"
+                } else {
+                    "resumed the game"
+                };
+                if self.paused.swap(pause, Ordering::SeqCst) != pause {
+                    self.history
+                        .lock()
+                        .unwrap()
+                        .apply(Command::Announce(format!("{} {}", moderator, event)));
+                }
+                String::from(event)
+            }
+        };
+        self.admin_log.record(
+            Actor::Moderator(moderator),
+            format!("{}, from entity {}", event, entity_id),
+        );
+        Ok(())
+    }
+}
+
+/// `rgb` with each channel clamped between 0 and 1, or `None` if any is NaN, which isn't a color.
+pub(crate) fn color(rgb: [GameInt; 3]) -> Option<[GameInt; 3]> {
+    if rgb.iter().any(|channel| channel.is_nan()) {
+        return None;
+    }
+    Some([
+        rgb[0].max(0.).min(1.),
+        rgb[1].max(0.).min(1.),
+        rgb[2].max(0.).min(1.),
+    ])
+}
+
+/// `text` without control characters or surrounding whitespace, cut to `max_length` characters.
+pub(crate) fn printable(text: &str, max_length: usize) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_length)
+        .collect();
+    String::from(text.trim())
 }
 
 impl ConnectionHandler {
     fn get_or_make_entity_id(&self) -> EntityId {
         *self.entity_id.get_or_init(|| {
-            let mut game = self.game.lock().unwrap();
-            game.insert_new_player_square()
+            let mut history = self.history.lock().unwrap();
+            let square = history.game().new_player_square();
+            history.add_player(square)
         })
     }
 }
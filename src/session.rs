@@ -0,0 +1,172 @@
+//! Player sessions, which outlive connections so that players who reconnect soon enough get their
+//! entity back.
+
+use crate::{game::EntityId, server::KickReason};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a disconnected player's entity is kept for them to resume their session.
+pub(crate) const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+struct Session {
+    entity_id: EntityId,
+    /// When the player disconnected, if they aren't connected.
+    disconnected_at: Option<Instant>,
+    /// Why the player was kicked, if they were. Kicked players' sessions can't be resumed, and
+    /// are only kept to tell them why.
+    kicked: Option<KickReason>,
+    /// Whether a moderator muted the player, so that their chat messages are dropped.
+    muted: bool,
+}
+
+/// Every player's session, by session id.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions(Arc<Mutex<HashMap<u64, Session>>>);
+
+impl Sessions {
+    /// Starts a session for the player controlling `entity_id` and returns its id.
+    pub(crate) fn open(&self, entity_id: EntityId) -> u64 {
+        let mut sessions = self.0.lock().unwrap();
+        loop {
+            if let Entry::Vacant(entry) = sessions.entry(rand::random()) {
+                let session_id = *entry.key();
+                entry.insert(Session {
+                    entity_id,
+                    disconnected_at: None,
+                    kicked: None,
+                    muted: false,
+                });
+                return session_id;
+            }
+        }
+    }
+
+    /// Reattaches a player to session `session_id`, returning its entity, if the session's player
+    /// has disconnected and the session hasn't expired.
+    pub(crate) fn resume(&self, session_id: u64) -> Option<EntityId> {
+        let mut sessions = self.0.lock().unwrap();
+        let session = sessions.get_mut(&session_id)?;
+        if session.kicked.is_some() {
+            return None;
+        }
+        session.disconnected_at.take()?;
+        Some(session.entity_id)
+    }
+
+    /// Marks the player of session `session_id` as disconnected at `now`.
+    pub(crate) fn disconnect(&self, session_id: u64, now: Instant) {
+        if let Some(session) = self.0.lock().unwrap().get_mut(&session_id) {
+            session.disconnected_at = Some(now);
+        }
+    }
+
+    /// Marks the player of session `session_id` as kicked at `now`, for `reason`. Their entity is
+    /// no longer theirs.
+    pub(crate) fn kick(&self, session_id: u64, reason: KickReason, now: Instant) {
+        if let Some(session) = self.0.lock().unwrap().get_mut(&session_id) {
+            session.disconnected_at = Some(now);
+            session.kicked = Some(reason);
+        }
+    }
+
+    /// Why the player of session `session_id` was kicked, if they were.
+    pub(crate) fn kick_reason(&self, session_id: u64) -> Option<KickReason> {
+        self.0.lock().unwrap().get(&session_id)?.kicked
+    }
+
+    /// Mutes or unmutes the player controlling `entity_id`, returning whether there is one.
+    pub(crate) fn set_muted(&self, entity_id: EntityId, muted: bool) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        let session = sessions
+            .values_mut()
+            .find(|session| session.entity_id == entity_id && session.kicked.is_none());
+        match session {
+            Some(session) => {
+                session.muted = muted;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the player of session `session_id` is muted.
+    pub(crate) fn is_muted(&self, session_id: u64) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .map_or(false, |session| session.muted)
+    }
+
+    /// The entities of every player with a session, connected or not.
+    pub(crate) fn entity_ids(&self) -> Vec<EntityId> {
+        let sessions = self.0.lock().unwrap();
+        sessions
+            .values()
+            .filter(|session| session.kicked.is_none())
+            .map(|session| session.entity_id)
+            .collect()
+    }
+
+    /// Ends session `session_id` right away.
+    pub(crate) fn end(&self, session_id: u64) {
+        self.0.lock().unwrap().remove(&session_id);
+    }
+
+    /// Ends sessions whose players disconnected more than [`GRACE_PERIOD`] before `now`, returning
+    /// the entities of the ones who weren't kicked.
+    pub(crate) fn expire(&self, now: Instant) -> Vec<EntityId> {
+        let mut expired = vec![];
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, session| match session.disconnected_at {
+                Some(disconnected_at) if now >= disconnected_at + GRACE_PERIOD => {
+                    if session.kicked.is_none() {
+                        expired.push(session.entity_id);
+                    }
+                    false
+                }
+                _ => true,
+            });
+        expired
+    }
+}
+
+#[test]
+fn sessions_resume_until_expired() {
+    let sessions = Sessions::default();
+    let start = Instant::now();
+    let first = sessions.open(1);
+    let second = sessions.open(2);
+
+    // Connected players' sessions can't be taken over.
+    assert_eq!(sessions.resume(first), None);
+
+    sessions.disconnect(first, start);
+    sessions.disconnect(second, start);
+    assert_eq!(sessions.resume(first), Some(1));
+    assert_eq!(sessions.resume(first), None);
+
+    assert_eq!(sessions.expire(start + GRACE_PERIOD / 2), vec![]);
+    assert_eq!(sessions.expire(start + GRACE_PERIOD), vec![2]);
+    assert_eq!(sessions.resume(second), None);
+}
+
+#[test]
+fn kicked_sessions_cant_be_resumed() {
+    let sessions = Sessions::default();
+    let start = Instant::now();
+    let kicked = sessions.open(1);
+    sessions.kick(kicked, KickReason::Kicked, start);
+    assert_eq!(sessions.resume(kicked), None);
+    assert_eq!(sessions.kick_reason(kicked), Some(KickReason::Kicked));
+    assert_eq!(sessions.entity_ids(), vec![]);
+
+    // The entity was removed when the player was kicked.
+    assert_eq!(sessions.expire(start + GRACE_PERIOD), vec![]);
+    assert_eq!(sessions.kick_reason(kicked), None);
+}
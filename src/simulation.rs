@@ -0,0 +1,162 @@
+//! Running games headlessly: ticked by hand with a fixed tick length, with no window and no
+//! networking, so that gameplay can be tested and benchmarked anywhere.
+//!
+//! Inputs and checks are scheduled by tick count, so a simulation plays out the same way every
+//! time, given a world made with [`Game::with_seed`].
+
+use crate::game::{EntityId, Game, Input};
+use std::{collections::BTreeMap, fmt};
+
+/// How long each tick of a simulation lasts by default: as long as the server's ticks.
+pub const DEFAULT_DT: f32 = 1. / 200.;
+
+type Check = Box<dyn Fn(&Game) -> bool>;
+
+/// A game ticked by hand, with inputs and checks scheduled at given ticks.
+pub struct Simulation {
+    game: Game,
+    dt: f32,
+    /// Inputs to apply before ticking, by the tick count they're applied at.
+    inputs: BTreeMap<u64, Vec<(EntityId, Input)>>,
+    /// Checks of the game once it reaches a tick count, with what they check.
+    checks: BTreeMap<u64, Vec<(String, Check)>>,
+}
+
+/// A check that didn't hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckFailed {
+    pub tick: u64,
+    pub description: String,
+}
+
+impl fmt::Display for CheckFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at tick {}, expected {}", self.tick, self.description)
+    }
+}
+
+impl std::error::Error for CheckFailed {}
+
+impl Simulation {
+    /// Simulates `game`, ticking it for `dt` seconds at a time.
+    pub fn new(game: Game, dt: f32) -> Self {
+        Simulation {
+            game,
+            dt,
+            inputs: BTreeMap::new(),
+            checks: BTreeMap::new(),
+        }
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    /// Adds a player's square to the game, returning its entity.
+    pub fn add_player(&mut self) -> EntityId {
+        let square = self.game.new_player_square();
+        self.game.add_player(square)
+    }
+
+    /// Applies `input` from player `id` right before the game ticks at tick count `tick`.
+    pub fn input_at(&mut self, tick: u64, id: EntityId, input: Input) -> &mut Self {
+        self.inputs.entry(tick).or_default().push((id, input));
+        self
+    }
+
+    /// Checks that `check` holds once the game reaches tick count `tick`, which must be after the
+    /// current one. `description` says what's expected, for when it doesn't hold.
+    pub fn check_at(
+        &mut self,
+        tick: u64,
+        description: &str,
+        check: impl Fn(&Game) -> bool + 'static,
+    ) -> &mut Self {
+        self.checks
+            .entry(tick)
+            .or_default()
+            .push((String::from(description), Box::new(check)));
+        self
+    }
+
+    /// Applies the inputs scheduled for now, ticks the game once, then makes the checks scheduled
+    /// for the new tick count.
+    pub fn step(&mut self) -> Result<(), CheckFailed> {
+        for (id, input) in self.inputs.remove(&self.game.ticks()).unwrap_or_default() {
+            if self.game.contains(id) {
+                self.game.process_input(id, input);
+            }
+        }
+        self.game.tick(self.dt, &mut 0., &mut 0);
+        let tick = self.game.ticks();
+        for (description, check) in self.checks.remove(&tick).unwrap_or_default() {
+            if !check(&self.game) {
+                return Err(CheckFailed { tick, description });
+            }
+        }
+        Ok(())
+    }
+
+    /// Steps the game `ticks` times, stopping at the first check that doesn't hold.
+    pub fn run(&mut self, ticks: u64) -> Result<(), CheckFailed> {
+        for _ in 0..ticks {
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn simulations_play_out_the_same_way_every_time() {
+    use crate::game::{Component, Point, Sign};
+
+    let simulate = || {
+        let game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+        let mut simulation = Simulation::new(game, DEFAULT_DT);
+        let id = simulation.add_player();
+        let right = Input::Move(Component::X, Some(Sign::Positive));
+        simulation
+            .input_at(0, id, right)
+            .input_at(100, id, Input::Shoot);
+        simulation.run(400).unwrap();
+        simulation
+    };
+    let (first, second) = (simulate(), simulate());
+    assert_eq!(first.game().ticks(), 400);
+    let ids: Vec<_> = first.game().entity_ids().collect();
+    assert_eq!(ids, second.game().entity_ids().collect::<Vec<_>>());
+    // Including the player's square, whose color is random.
+    for id in ids {
+        assert_eq!(first.game().entity(id), second.game().entity(id));
+    }
+}
+
+#[test]
+fn simulations_stop_at_failed_checks() {
+    use crate::game::{Component, Point, Sign};
+
+    // Nothing in the way.
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    for id in game.entity_ids().collect::<Vec<_>>() {
+        game.remove_entity(id);
+    }
+    let mut simulation = Simulation::new(game, DEFAULT_DT);
+    let id = simulation.add_player();
+    let down = Input::Move(Component::Y, Some(Sign::Positive));
+    simulation
+        .input_at(0, id, down)
+        .check_at(100, "the player to move down", move |game| {
+            game.entity(id).position.top_left.y > 0.
+        })
+        .check_at(150, "the player to stay put", move |game| {
+            game.entity(id).position.top_left.y == 0.
+        });
+    assert_eq!(
+        simulation.run(200),
+        Err(CheckFailed {
+            tick: 150,
+            description: String::from("the player to stay put"),
+        })
+    );
+    assert_eq!(simulation.game().ticks(), 150);
+}
@@ -0,0 +1,50 @@
+//! Saving the game to disk and restoring it, so that long-running worlds survive server restarts.
+//!
+//! A snapshot is the bincode-encoded game state, without players, since their connections don't
+//! survive the restart.
+
+use crate::game::Game;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+fn invalid_data(e: bincode::Error) -> io::Error {
+    match *e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Saves `game` to `path`. The snapshot is written next to `path` first and then moved into
+/// place, so that a crash while saving leaves the previous snapshot intact.
+pub fn save(game: &Game, path: &Path) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut file = BufWriter::new(File::create(&partial)?);
+    bincode::serialize_into(&mut file, game).map_err(invalid_data)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&partial, path)
+}
+
+/// Loads the snapshot saved at `path`.
+pub fn load(path: &Path) -> io::Result<Game> {
+    bincode::deserialize_from(BufReader::new(File::open(path)?)).map_err(invalid_data)
+}
+
+#[test]
+fn snapshots_restore_the_world() {
+    use crate::game::Point;
+
+    let game = Game::new(Point::new(1000., 500.), 50.);
+    let path = std::env::temp_dir().join(format!("shapes-snapshot-{}.bin", std::process::id()));
+    save(&game, &path).unwrap();
+    let loaded = load(&path);
+    fs::remove_file(&path).unwrap();
+    let loaded = loaded.unwrap();
+    assert_eq!(
+        loaded.entity_ids().collect::<Vec<_>>(),
+        game.entity_ids().collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,152 @@
+//! How a game's states get from its game loop to the connections serving it. Each state is
+//! published once, and shared by every subscriber rather than copied for each. Subscribers that
+//! fall behind skip straight to the latest state instead of working through the ones they missed,
+//! so a slow subscriber is never served a stale state.
+
+use crate::game::Game;
+use std::sync::{Arc, RwLock, Weak};
+use tokio::sync::broadcast::{self, RecvError, TryRecvError};
+
+/// How many publications can queue up for a subscriber. Subscribers only ever need the newest,
+/// so missing some is harmless.
+const CAPACITY: usize = 16;
+
+/// Creates a channel for a game's states, starting at `game`.
+pub(crate) fn channel(game: Game) -> (Publisher, States) {
+    let (tx, _) = broadcast::channel(CAPACITY);
+    let tx = Arc::new(tx);
+    let states = States {
+        latest: Arc::new(RwLock::new(Arc::new(game))),
+        tx: Arc::downgrade(&tx),
+    };
+    let publisher = Publisher {
+        tx,
+        states: states.clone(),
+    };
+    (publisher, states)
+}
+
+/// Publishes a game's states. Subscribers see the game end when it's dropped.
+pub(crate) struct Publisher {
+    /// Wakes subscribers when a state is published.
+    tx: Arc<broadcast::Sender<()>>,
+    states: States,
+}
+
+impl Publisher {
+    pub(crate) fn publish(&self, game: &Game) {
+        *self.states.latest.write().unwrap() = Arc::new(game.clone());
+        // Nobody may be subscribed.
+        let _ = self.tx.send(());
+    }
+}
+
+/// A game's published states.
+#[derive(Clone, Debug)]
+pub(crate) struct States {
+    latest: Arc<RwLock<Arc<Game>>>,
+    /// Only the publisher holds on to the sender, so the channel closes when it's dropped.
+    tx: Weak<broadcast::Sender<()>>,
+}
+
+impl States {
+    /// The latest state published.
+    pub(crate) fn latest(&self) -> Arc<Game> {
+        self.latest.read().unwrap().clone()
+    }
+
+    /// Follows the states published from now on, starting at the latest one.
+    pub(crate) fn subscribe(&self) -> Subscriber {
+        // Subscribing first means no state is missed between the latest and the next.
+        let rx = self.tx.upgrade().map(|tx| tx.subscribe());
+        Subscriber {
+            rx,
+            game: self.latest(),
+            seen: false,
+            states: self.clone(),
+        }
+    }
+}
+
+/// Follows a game's states.
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    /// `None` once the game is over.
+    rx: Option<broadcast::Receiver<()>>,
+    game: Arc<Game>,
+    /// Whether `game` was returned by `recv` yet.
+    seen: bool,
+    states: States,
+}
+
+impl Subscriber {
+    /// Returns the latest state once there's one newer than the last returned, or `None` once the
+    /// game is over. The first call returns the state the subscriber started at.
+    pub(crate) async fn recv(&mut self) -> Option<Arc<Game>> {
+        if !self.seen {
+            self.seen = true;
+            return Some(self.game.clone());
+        }
+        loop {
+            let rx = self.rx.as_mut()?;
+            match rx.recv().await {
+                Ok(()) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    self.rx = None;
+                    return None;
+                }
+            }
+            // Publications queued up while waiting are for states no newer than the latest.
+            loop {
+                match rx.try_recv() {
+                    Ok(()) | Err(TryRecvError::Lagged(_)) => {}
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+                }
+            }
+            let latest = self.states.latest();
+            if !Arc::ptr_eq(&latest, &self.game) {
+                self.game = latest;
+                return Some(self.game.clone());
+            }
+        }
+    }
+}
+
+impl Clone for Subscriber {
+    /// Starts a new subscriber at the latest state.
+    fn clone(&self) -> Self {
+        self.states.subscribe()
+    }
+}
+
+#[test]
+fn subscribers_skip_to_the_latest_state() {
+    use crate::game::Point;
+    use futures::executor::block_on;
+
+    let mut game = Game::with_seed(Point::new(1000., 500.), 50., 7);
+    let (publisher, states) = channel(game.clone());
+    let mut following = states.subscribe();
+    let mut lagging = states.subscribe();
+    assert_eq!(block_on(following.recv()).unwrap().ticks(), game.ticks());
+    assert_eq!(block_on(lagging.recv()).unwrap().ticks(), game.ticks());
+    for _ in 0..CAPACITY * 2 {
+        game.insert_new_player_square();
+        game.tick(0.01, &mut 0., &mut 0);
+        publisher.publish(&game);
+        let state = block_on(following.recv()).unwrap();
+        assert_eq!(state.ticks(), game.ticks());
+        assert_eq!(
+            state.entity_ids().collect::<Vec<_>>(),
+            game.entity_ids().collect::<Vec<_>>()
+        );
+    }
+    // The lagging subscriber gets the latest state, and shares it with the others.
+    let state = block_on(lagging.recv()).unwrap();
+    assert_eq!(state.ticks(), game.ticks());
+    assert!(Arc::ptr_eq(&state, &states.latest()));
+
+    drop(publisher);
+    assert!(block_on(lagging.recv()).is_none());
+    assert!(block_on(states.subscribe().recv()).is_some());
+}
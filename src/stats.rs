@@ -0,0 +1,150 @@
+//! Players' stats, kept by name across sessions. Servers keep them in a [sled] database, so that
+//! they survive restarts. Only names players picked themselves have stats: the ones the game gives
+//! out, to players who didn't pick one and to bots, go to whoever gets the same id next.
+
+use crate::game::EntityId;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// How many of each player's sessions are kept.
+pub const MAX_RECENT_SESSIONS: usize = 20;
+
+/// What a player, by name, has done in a game.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    /// How many times the player has played.
+    pub sessions: u32,
+    /// How long the player has played for, over every session.
+    pub playtime: Duration,
+    /// How many times the player got the better of another, like by tagging them.
+    pub kills: u32,
+    /// How many times another player got the better of the player.
+    pub deaths: u32,
+    /// The player's latest sessions, oldest first.
+    pub recent_sessions: Vec<Session>,
+}
+
+/// One time a player played, from joining until they disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub joined: SystemTime,
+    pub duration: Duration,
+}
+
+/// A game's player stats.
+#[derive(Clone, Debug)]
+pub(crate) struct Stats {
+    db: sled::Db,
+}
+
+impl Stats {
+    /// Opens the stats database at `path`, creating it if there isn't one.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let db = sled::open(path).map_err(to_io_error)?;
+        Ok(Stats { db })
+    }
+
+    /// Keeps stats only until the game ends.
+    pub(crate) fn temporary() -> io::Result<Self> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(to_io_error)?;
+        Ok(Stats { db })
+    }
+
+    /// The stats of the player named `name`, if they've played.
+    pub(crate) fn get(&self, name: &str) -> io::Result<Option<PlayerStats>> {
+        match self.db.get(name).map_err(to_io_error)? {
+            Some(bytes) => bincode::deserialize(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Records that the player named `name` played for `duration` after joining at `joined`.
+    pub(crate) fn record_session(
+        &self,
+        name: &str,
+        joined: SystemTime,
+        duration: Duration,
+    ) -> io::Result<()> {
+        self.update(name, |stats| {
+            stats.sessions += 1;
+            stats.playtime += duration;
+            stats.recent_sessions.push(Session { joined, duration });
+            let excess = stats
+                .recent_sessions
+                .len()
+                .saturating_sub(MAX_RECENT_SESSIONS);
+            stats.recent_sessions.drain(..excess);
+        })
+    }
+
+    /// Records that the player named `killer` got the better of the one named `victim`.
+    pub(crate) fn record_kill(&self, killer: &str, victim: &str) -> io::Result<()> {
+        self.update(killer, |stats| stats.kills += 1)?;
+        self.update(victim, |stats| stats.deaths += 1)
+    }
+
+    fn update(&self, name: &str, f: impl Fn(&mut PlayerStats)) -> io::Result<()> {
+        if !is_picked(name) {
+            return Ok(());
+        }
+        // Run again if another update to the same player got in first.
+        self.db
+            .update_and_fetch(name, |bytes| {
+                // Stats that can't be read start over rather than stop being kept.
+                let mut stats: PlayerStats = bytes
+                    .and_then(|bytes| bincode::deserialize(bytes).ok())
+                    .unwrap_or_default();
+                f(&mut stats);
+                Some(bincode::serialize(&stats).expect("stats always serialize"))
+            })
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+}
+
+/// Whether `name` was picked by a player, rather than given out by the game like "player 3" or
+/// "bot 3".
+fn is_picked(name: &str) -> bool {
+    let given_out =
+        |prefix: &str| name.starts_with(prefix) && name[prefix.len()..].parse::<EntityId>().is_ok();
+    !name.is_empty() && !given_out("player ") && !given_out("bot ")
+}
+
+fn to_io_error(e: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[test]
+fn stats_add_up() {
+    let stats = Stats::temporary().unwrap();
+    assert_eq!(stats.get("ada").unwrap(), None);
+    let joined = SystemTime::UNIX_EPOCH;
+    for _ in 0..MAX_RECENT_SESSIONS + 1 {
+        stats
+            .record_session("ada", joined, Duration::from_secs(60))
+            .unwrap();
+    }
+    stats.record_kill("ada", "grace").unwrap();
+    let ada = stats.get("ada").unwrap().unwrap();
+    assert_eq!(ada.sessions, MAX_RECENT_SESSIONS as u32 + 1);
+    assert_eq!(ada.playtime, Duration::from_secs(60) * ada.sessions);
+    assert_eq!(ada.recent_sessions.len(), MAX_RECENT_SESSIONS);
+    assert_eq!((ada.kills, ada.deaths), (1, 0));
+    assert_eq!(stats.get("grace").unwrap().unwrap().deaths, 1);
+
+    stats.record_kill("ada", "player 3").unwrap();
+    stats.record_kill("bot 4", "ada").unwrap();
+    assert_eq!(stats.get("player 3").unwrap(), None);
+    assert_eq!(stats.get("bot 4").unwrap(), None);
+    let ada = stats.get("ada").unwrap().unwrap();
+    assert_eq!((ada.kills, ada.deaths), (2, 1));
+}
@@ -0,0 +1,38 @@
+//! A tiny HTTP endpoint reporting a game server's health, so that operators and the game list can
+//! check on it without speaking tarpc.
+
+use crate::http;
+use hyper::{header::CONTENT_TYPE, Body, Method, Response};
+use serde::{Deserialize, Serialize};
+use std::{io, net::SocketAddr};
+
+/// What `GET /status` responds with, as JSON.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    /// The name the game registered with.
+    pub name: String,
+    pub players: usize,
+    pub max_players: usize,
+    /// Ticks per second, over about the last ten seconds.
+    pub tick_rate: f64,
+    pub uptime_secs: u64,
+    /// The [`crate::PROTOCOL_VERSION`] the server speaks.
+    pub protocol_version: u32,
+}
+
+/// Serves `GET /status` on `addr`, responding with what `status` returns at the time.
+pub(crate) async fn serve<F>(addr: SocketAddr, status: F) -> io::Result<()>
+where
+    F: Fn() -> Status + Clone + Send + Sync + 'static,
+{
+    http::serve(addr, move |request| {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/status") => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&status()).unwrap()))
+                .unwrap(),
+            _ => http::not_found(),
+        }
+    })
+    .await
+}
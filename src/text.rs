@@ -0,0 +1,120 @@
+//! Drawing text in the window: the HUD, players' names, chat and menus.
+
+use crate::{
+    chat,
+    game::{Game, GameInt, Point},
+};
+use log::warn;
+use piston_window::{
+    character::CharacterCache, context::Context, G2d, Glyphs, PistonWindow, Text, TextureSettings,
+    Transformed,
+};
+use std::{io, path::Path};
+
+/// The size of the HUD's text, in points.
+pub const HUD_FONT_SIZE: u32 = 14;
+/// The size of players' names, in points.
+pub const NAME_FONT_SIZE: u32 = 10;
+/// The size of chat messages, in points.
+pub const CHAT_FONT_SIZE: u32 = 12;
+/// The size of menu text, in points.
+pub const MENU_FONT_SIZE: u32 = 16;
+
+/// The font text is drawn in when no other is given.
+const BUNDLED_FONT: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+
+/// Loads the TrueType font at `path` for drawing text in `window`, or the bundled font without a
+/// path. Fonts are loaded for a particular window, so they need loading again for a new one.
+pub fn load_font(window: &mut PistonWindow, path: Option<&Path>) -> io::Result<Glyphs> {
+    match path {
+        Some(path) => window.load_font(path),
+        None => Glyphs::from_bytes(
+            BUNDLED_FONT,
+            window.create_texture_context(),
+            TextureSettings::new(),
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bundled font is invalid")),
+    }
+}
+
+/// Draws `text` with its baseline starting at `x`, `y`.
+pub fn draw(
+    text: &str,
+    size: u32,
+    opacity: f32,
+    [x, y]: [f64; 2],
+    glyphs: &mut Glyphs,
+    c: Context,
+    g: &mut G2d,
+) {
+    let drawn = Text::new_color([0., 0., 0., opacity], size).draw(
+        text,
+        glyphs,
+        &c.draw_state,
+        c.transform.trans(x, y),
+        g,
+    );
+    if let Err(e) = drawn {
+        warn!("Failed to draw {:?}: {:?}", text, e);
+    }
+}
+
+/// Draws `text` with its baseline centered on `x`, `y`.
+pub fn draw_centered(
+    text: &str,
+    size: u32,
+    [x, y]: [f64; 2],
+    glyphs: &mut Glyphs,
+    c: Context,
+    g: &mut G2d,
+) {
+    let width = glyphs.width(size, text).unwrap_or(0.);
+    draw(text, size, 1., [x - width / 2., y], glyphs, c, g);
+}
+
+/// Draws the HUD in the top left corner.
+pub fn draw_hud(hud: &str, glyphs: &mut Glyphs, c: Context, g: &mut G2d) {
+    let at = [5., 5. + f64::from(HUD_FONT_SIZE)];
+    draw(hud, HUD_FONT_SIZE, 1., at, glyphs, c, g);
+}
+
+/// Draws every named player's name just above them, in a view centered on `center`.
+pub fn draw_names(game: &Game, center: Point, glyphs: &mut Glyphs, c: Context, g: &mut G2d) {
+    let [x, y] = c.get_view_size();
+    let view_size = Point::new(x as GameInt, y as GameInt);
+    game.for_each_name(center, view_size, |above, name| {
+        let at = [f64::from(above.x), f64::from(above.y) - 2.];
+        draw_centered(name, NAME_FONT_SIZE, at, glyphs, c, g);
+    });
+}
+
+/// Draws the chat in the bottom left corner, newest at the bottom, under what's being typed into
+/// the chat box if it's open.
+pub fn draw_chat(
+    typing: Option<&str>,
+    messages: &[chat::Message],
+    glyphs: &mut Glyphs,
+    c: Context,
+    g: &mut G2d,
+) {
+    let line_height = 1.5 * f64::from(CHAT_FONT_SIZE);
+    let mut line_y = c.get_view_size()[1] - 5.;
+    if let Some(text) = typing {
+        let typing = format!("say: {}_", text);
+        draw(&typing, CHAT_FONT_SIZE, 1., [5., line_y], glyphs, c, g);
+        line_y -= line_height;
+    }
+    for message in messages.iter().rev() {
+        let at = [5., line_y];
+        draw(
+            &message.text,
+            CHAT_FONT_SIZE,
+            message.opacity,
+            at,
+            glyphs,
+            c,
+            g,
+        );
+        line_y -= line_height;
+    }
+}
@@ -0,0 +1,196 @@
+//! Optional TLS for TCP connections, so games can be played over untrusted networks.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client,
+    rustls::{
+        internal::pemfile, Certificate, ClientConfig, NoClientAuth, RootCertStore,
+        ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+    },
+    server,
+    webpki::{DNSName, DNSNameRef},
+    TlsAcceptor, TlsConnector,
+};
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Accepts any certificate the server presents.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _: &RootCertStore,
+        _: &[Certificate],
+        _: DNSNameRef,
+        _: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Encrypts outgoing connections.
+#[derive(Clone)]
+pub struct Client {
+    connector: TlsConnector,
+    server_name: DNSName,
+}
+
+impl Client {
+    /// Trusts servers with certificates for `server_name` signed by the PEM certificates in
+    /// `ca_path`. If `ca_path` is `None`, trusts any server, which protects against eavesdroppers
+    /// but not impersonators.
+    pub fn new(ca_path: Option<&Path>, server_name: &str) -> io::Result<Client> {
+        let mut config = ClientConfig::new();
+        match ca_path {
+            Some(ca_path) => {
+                let (valid, _) = config
+                    .root_store
+                    .add_pem_file(&mut open(ca_path)?)
+                    .map_err(|()| invalid_input(format!("{}: bad PEM file", ca_path.display())))?;
+                if valid == 0 {
+                    return Err(invalid_input(format!(
+                        "{}: no valid certificates",
+                        ca_path.display()
+                    )));
+                }
+            }
+            None => config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification)),
+        }
+        let server_name = DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| invalid_input(format!("invalid server name {}", server_name)))?
+            .to_owned();
+        Ok(Client {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+        let stream = self
+            .connector
+            .connect(self.server_name.as_ref(), stream)
+            .await?;
+        Ok(MaybeTlsStream::Client(Box::new(stream)))
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let server_name: &str = self.server_name.as_ref().into();
+        f.debug_struct("Client")
+            .field("server_name", &server_name)
+            .finish()
+    }
+}
+
+/// Encrypts incoming connections.
+#[derive(Clone)]
+pub struct Server {
+    acceptor: TlsAcceptor,
+}
+
+impl Server {
+    /// Serves the PEM certificate chain in `cert_path`, whose private key is the PEM PKCS #8 or
+    /// RSA key in `key_path`.
+    pub fn new(cert_path: &Path, key_path: &Path) -> io::Result<Server> {
+        let certs = pemfile::certs(&mut open(cert_path)?)
+            .map_err(|()| invalid_input(format!("{}: bad PEM file", cert_path.display())))?;
+        let bad_key = || invalid_input(format!("{}: bad PEM file", key_path.display()));
+        let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).map_err(|()| bad_key())?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(key_path)?).map_err(|()| bad_key())?;
+        }
+        let key = keys
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid_input(format!("{}: no private key", key_path.display())))?;
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(certs, key)
+            .map_err(|e| invalid_input(format!("{}: {}", cert_path.display(), e)))?;
+        Ok(Server {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+        let stream = self.acceptor.accept(stream).await?;
+        Ok(MaybeTlsStream::Server(Box::new(stream)))
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server").finish()
+    }
+}
+
+/// A TCP stream, which may be encrypted.
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Client(Box<client::TlsStream<TcpStream>>),
+    Server(Box<server::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Client(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Server(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Client(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Server(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Client(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Server(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Client(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Server(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,630 @@
+//! The transport shared by game servers, the game list, and their clients.
+//!
+//! If TLS is configured, the TLS handshake happens first, and everything after it is encrypted.
+//! Right after connecting, the client writes a byte naming the [`Format`] it will speak and a byte
+//! naming the [`Compression`] it will use, and the server uses the same for the rest of the
+//! connection.
+//!
+//! Servers can also accept WebSocket connections, for clients that can't open raw TCP connections,
+//! like browsers. Each message is sent as a binary WebSocket message, and the first message
+//! carries the same two bytes.
+
+use crate::{
+    error::Error,
+    metrics,
+    tls::{self, MaybeTlsStream},
+};
+use bytes::{Bytes, BytesMut};
+use clap::{Arg, ArgMatches};
+use futures::prelude::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Read},
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tarpc::serde_transport;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_serde::{Deserializer, Serializer};
+use tokio_tungstenite::{tungstenite, WebSocketStream};
+
+/// How messages are serialized on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Bincode,
+    /// Compact like bincode, but self-describing like JSON, so easy to read from other languages.
+    /// Structs are encoded as maps keyed by field name.
+    MessagePack,
+}
+
+impl Format {
+    fn from_byte(byte: u8) -> io::Result<Format> {
+        match byte {
+            0 => Ok(Format::Json),
+            1 => Ok(Format::Bincode),
+            2 => Ok(Format::MessagePack),
+            byte => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown wire format {}", byte),
+            )),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Format::Json => 0,
+            Format::Bincode => 1,
+            Format::MessagePack => 2,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "bincode" => Ok(Format::Bincode),
+            "msgpack" => Ok(Format::MessagePack),
+            _ => Err(format!("expected json, bincode or msgpack, got {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Json => f.write_str("json"),
+            Format::Bincode => f.write_str("bincode"),
+            Format::MessagePack => f.write_str("msgpack"),
+        }
+    }
+}
+
+/// How large messages are compressed on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    fn from_byte(byte: u8) -> io::Result<Compression> {
+        match byte {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            byte => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression {}", byte),
+            )),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("expected none, zstd or lz4, got {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Zstd => f.write_str("zstd"),
+            Compression::Lz4 => f.write_str("lz4"),
+        }
+    }
+}
+
+/// Messages smaller than this are sent uncompressed, since compressing them isn't worth it.
+const COMPRESSION_THRESHOLD: usize = 1024;
+const ZSTD_LEVEL: i32 = 3;
+/// How many compressed messages to send between logging compression stats.
+const COMPRESSION_STATS_INTERVAL: u64 = 1000;
+
+/// The largest message a peer can send, decompressed. The same as the length limit on frames, so
+/// a small compressed frame can't decompress into more memory than an uncompressed one can take.
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Marks whether a message on a connection using compression was compressed.
+const UNCOMPRESSED: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Options for the connections made and accepted by this process.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The format used on outgoing connections. Incoming connections use whatever format the
+    /// client chose.
+    pub format: Format,
+    /// The compression used on outgoing connections. Incoming connections use whatever
+    /// compression the client chose.
+    pub compression: Compression,
+    /// Encrypts outgoing connections, if set.
+    pub tls_client: Option<tls::Client>,
+    /// Encrypts incoming connections, if set. Clients must then connect with TLS too.
+    pub tls_server: Option<tls::Server>,
+    /// Whether to send small writes right away instead of batching them with Nagle's algorithm,
+    /// which delays inputs noticeably.
+    pub nodelay: bool,
+    /// How long a connection can be idle before TCP keepalive probes are sent, if at all.
+    pub keepalive: Option<Duration>,
+}
+
+impl Config {
+    /// Flags for configuring the transport; see [`Config::from_flags`].
+    pub fn flags<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::from_usage("--format [format] Sets the wire format, json or bincode or msgpack.")
+                .default_value("json"),
+            Arg::from_usage(
+                "--compression [compression] Compresses large messages, none or zstd or lz4.",
+            )
+            .default_value("none"),
+            Arg::from_usage(
+                "--tls_cert [file] Accepts TLS connections using this PEM certificate chain.",
+            )
+            .requires("tls_key"),
+            Arg::from_usage("--tls_key [file] The PEM private key for the TLS certificate.")
+                .requires("tls_cert"),
+            Arg::from_usage(
+                "--tls_ca [file] Connects with TLS, trusting servers signed by this PEM certificate.",
+            ),
+            Arg::from_usage(
+                "--tls_insecure Connects with TLS without checking the server certificate.",
+            )
+            .conflicts_with("tls_ca"),
+            Arg::from_usage("--tls_server_name [name] The name expected on server certificates.")
+                .default_value("localhost"),
+            Arg::from_usage("--tcp_delay Batches small writes with Nagle's algorithm."),
+            Arg::from_usage(
+                "--tcp_keepalive [seconds] Probes connections that are idle for this long.",
+            ),
+        ]
+    }
+
+    pub fn from_flags(flags: &ArgMatches) -> Result<Config, Error> {
+        let format = flags.value_of("format").unwrap();
+        let format: Format = format
+            .parse()
+            .map_err(|e| Error::invalid_flag("format", format, e))?;
+        let compression = flags.value_of("compression").unwrap();
+        let compression: Compression = compression
+            .parse()
+            .map_err(|e| Error::invalid_flag("compression", compression, e))?;
+        let tls_client = if flags.is_present("tls_ca") || flags.is_present("tls_insecure") {
+            let server_name = flags.value_of("tls_server_name").unwrap();
+            let tls_client =
+                tls::Client::new(flags.value_of("tls_ca").map(Path::new), server_name)?;
+            Some(tls_client)
+        } else {
+            None
+        };
+        let tls_server = match (flags.value_of("tls_cert"), flags.value_of("tls_key")) {
+            (Some(cert), Some(key)) => Some(tls::Server::new(Path::new(cert), Path::new(key))?),
+            _ => None,
+        };
+        let keepalive = match flags.value_of("tcp_keepalive") {
+            Some(seconds) => {
+                let seconds: u64 = seconds
+                    .parse()
+                    .map_err(|e| Error::invalid_flag("tcp_keepalive", seconds, e))?;
+                Some(Duration::from_secs(seconds))
+            }
+            None => None,
+        };
+        Ok(Config {
+            format,
+            compression,
+            tls_client,
+            tls_server,
+            nodelay: !flags.is_present("tcp_delay"),
+            keepalive,
+        })
+    }
+
+    /// Applies the TCP options to `stream`.
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_keepalive(self.keepalive)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CompressionStats {
+    messages: u64,
+    uncompressed_bytes: u64,
+    compressed_bytes: u64,
+}
+
+/// Serializes and deserializes messages in the format negotiated for a connection.
+pub struct Codec<Item, SinkItem> {
+    format: Format,
+    compression: Compression,
+    stats: CompressionStats,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<Item, SinkItem> Codec<Item, SinkItem> {
+    pub fn new(format: Format, compression: Compression) -> Self {
+        Codec {
+            format,
+            compression,
+            stats: CompressionStats::default(),
+            ghost: PhantomData,
+        }
+    }
+
+    fn compress(&mut self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        if self.compression == Compression::None {
+            return Ok(bytes);
+        }
+        if bytes.len() < COMPRESSION_THRESHOLD {
+            let mut framed = Vec::with_capacity(bytes.len() + 1);
+            framed.push(UNCOMPRESSED);
+            framed.extend_from_slice(&bytes);
+            return Ok(framed);
+        }
+        let mut framed = vec![COMPRESSED];
+        match self.compression {
+            Compression::None => unreachable!(),
+            Compression::Zstd => framed.extend(zstd::stream::encode_all(&bytes[..], ZSTD_LEVEL)?),
+            Compression::Lz4 => framed.extend(lz4_flex::compress_prepend_size(&bytes)),
+        }
+
+        let stats = &mut self.stats;
+        stats.messages += 1;
+        stats.uncompressed_bytes += bytes.len() as u64;
+        stats.compressed_bytes += framed.len() as u64;
+        if stats.messages % COMPRESSION_STATS_INTERVAL == 0 {
+            info!(
+                "{} compressed {} messages: {} bytes => {} bytes ({:.1}%)",
+                self.compression,
+                stats.messages,
+                stats.uncompressed_bytes,
+                stats.compressed_bytes,
+                100. * stats.compressed_bytes as f64 / stats.uncompressed_bytes as f64
+            );
+        }
+        Ok(framed)
+    }
+
+    fn decompress<'a>(&self, src: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        if self.compression == Compression::None {
+            return Ok(Cow::Borrowed(src));
+        }
+        match src.split_first() {
+            Some((&UNCOMPRESSED, bytes)) => Ok(Cow::Borrowed(bytes)),
+            Some((&COMPRESSED, bytes)) => match self.compression {
+                Compression::None => unreachable!(),
+                Compression::Zstd => {
+                    let mut decompressed = Vec::new();
+                    zstd::stream::read::Decoder::new(bytes)?
+                        .take(MAX_FRAME_LEN as u64 + 1)
+                        .read_to_end(&mut decompressed)?;
+                    check_frame_len(decompressed.len())?;
+                    Ok(Cow::Owned(decompressed))
+                }
+                Compression::Lz4 => {
+                    // The decompressed size is prepended, and space for all of it is allocated up
+                    // front.
+                    let mut size = [0; 4];
+                    size.copy_from_slice(bytes.get(..4).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "missing lz4 size")
+                    })?);
+                    check_frame_len(u32::from_le_bytes(size) as usize)?;
+                    Ok(Cow::Owned(
+                        lz4_flex::decompress_size_prepended(bytes).map_err(invalid_data)?,
+                    ))
+                }
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing compression marker",
+            )),
+        }
+    }
+}
+
+fn check_frame_len(len: usize) -> io::Result<()> {
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message decompresses to over {} bytes", MAX_FRAME_LEN),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl<Item, SinkItem: Serialize> Serializer<SinkItem> for Codec<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn serialize(mut self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        let _timer = metrics::time_phase("serialization");
+        let bytes = match self.format {
+            Format::Json => serde_json::to_vec(item).map_err(invalid_data)?,
+            Format::Bincode => bincode::serialize(item).map_err(invalid_data)?,
+            Format::MessagePack => rmp_serde::to_vec_named(item).map_err(invalid_data)?,
+        };
+        Ok(self.compress(bytes)?.into())
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for Codec<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        let src = self.decompress(src)?;
+        match self.format {
+            Format::Json => serde_json::from_slice(&src).map_err(invalid_data),
+            Format::Bincode => bincode::deserialize(&src).map_err(invalid_data),
+            Format::MessagePack => rmp_serde::from_read_ref(&src).map_err(invalid_data),
+        }
+    }
+}
+
+pub type Transport<Item, SinkItem> =
+    serde_transport::Transport<MaybeTlsStream, Item, SinkItem, Codec<Item, SinkItem>>;
+
+/// The port game servers listen on when not told otherwise, and that clients connect to when
+/// given a server address without a port.
+pub const DEFAULT_PORT: u16 = 7777;
+
+/// Resolves `addr`, an IPv4 or IPv6 address or a host name, with a port, to a socket address.
+pub fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} didn't resolve to any address", addr),
+        )
+    })
+}
+
+/// Whether `addr` ends with a port, rather than being a bare host name or IP address.
+fn has_port(addr: &str) -> bool {
+    addr.parse::<IpAddr>().is_err() && !addr.ends_with(']') && addr.contains(':')
+}
+
+/// Resolves `addr`, an IPv4 or IPv6 address or a host name, to every socket address it names,
+/// in the order to try them in. Without a port, `default_port` is used.
+pub fn resolve_all(addr: &str, default_port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = if has_port(addr) {
+        addr.to_socket_addrs()?.collect()
+    } else {
+        let host = addr.trim_start_matches('[').trim_end_matches(']');
+        (host, default_port).to_socket_addrs()?.collect()
+    };
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} didn't resolve to any address", addr),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Connects to `addr`, telling the server which format and compression this connection will use.
+pub async fn connect<Item, SinkItem>(
+    addr: &SocketAddr,
+    config: &Config,
+) -> io::Result<Transport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let stream = TcpStream::connect(addr).await?;
+    config.configure(&stream)?;
+    let mut stream = match &config.tls_client {
+        Some(tls_client) => tls_client.connect(stream).await?,
+        None => MaybeTlsStream::Plain(stream),
+    };
+    stream
+        .write_all(&[config.format.to_byte(), config.compression.to_byte()])
+        .await?;
+    Ok(serde_transport::Transport::from((
+        stream,
+        Codec::new(config.format, config.compression),
+    )))
+}
+
+/// Listens for connections on `addr`. Each accepted stream should be passed to [`accept`].
+pub async fn listen(addr: &SocketAddr) -> io::Result<impl Stream<Item = io::Result<TcpStream>>> {
+    TcpListener::bind(addr).await
+}
+
+/// Applies the TCP options in `config`, completes the TLS handshake if `config` has a TLS server,
+/// then reads the format and compression the client chose and returns a transport using them.
+pub async fn accept<Item, SinkItem>(
+    stream: TcpStream,
+    config: &Config,
+) -> io::Result<Transport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    config.configure(&stream)?;
+    let mut stream = match &config.tls_server {
+        Some(tls_server) => tls_server.accept(stream).await?,
+        None => MaybeTlsStream::Plain(stream),
+    };
+    let format = Format::from_byte(stream.read_u8().await?)?;
+    let compression = Compression::from_byte(stream.read_u8().await?)?;
+    Ok(serde_transport::Transport::from((
+        stream,
+        Codec::new(format, compression),
+    )))
+}
+
+fn websocket_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// A transport that sends each message as a binary WebSocket message.
+pub struct WebSocketTransport<Item, SinkItem> {
+    websocket: WebSocketStream<TcpStream>,
+    codec: Codec<Item, SinkItem>,
+}
+
+impl<Item, SinkItem> Stream for WebSocketTransport<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Item>>> {
+        let this = self.get_mut();
+        loop {
+            let message = match futures::ready!(this.websocket.poll_next_unpin(cx)) {
+                Some(message) => message.map_err(websocket_error)?,
+                None => return Poll::Ready(None),
+            };
+            match message {
+                tungstenite::Message::Binary(bytes) => {
+                    let item = Pin::new(&mut this.codec).deserialize(&BytesMut::from(&bytes[..]));
+                    return Poll::Ready(Some(item));
+                }
+                tungstenite::Message::Close(_) => return Poll::Ready(None),
+                // Pings are answered by tungstenite itself.
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<Item, SinkItem: Serialize> Sink<SinkItem> for WebSocketTransport<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .websocket
+            .poll_ready_unpin(cx)
+            .map_err(websocket_error)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let this = self.get_mut();
+        let bytes = Pin::new(&mut this.codec).serialize(&item)?;
+        this.websocket
+            .start_send_unpin(tungstenite::Message::Binary(bytes.to_vec()))
+            .map_err(websocket_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .websocket
+            .poll_flush_unpin(cx)
+            .map_err(websocket_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .websocket
+            .poll_close_unpin(cx)
+            .map_err(websocket_error)
+    }
+}
+
+/// Applies the TCP options in `config`, completes the WebSocket handshake, then reads the format
+/// and compression the client chose from its first message and returns a transport using them.
+pub async fn accept_websocket<Item, SinkItem>(
+    stream: TcpStream,
+    config: &Config,
+) -> io::Result<WebSocketTransport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    config.configure(&stream)?;
+    let mut websocket = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(websocket_error)?;
+    let (format, compression) = match websocket.next().await {
+        Some(Ok(tungstenite::Message::Binary(bytes))) if bytes.len() == 2 => (
+            Format::from_byte(bytes[0])?,
+            Compression::from_byte(bytes[1])?,
+        ),
+        Some(Err(e)) => return Err(websocket_error(e)),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected the format and compression as the first message",
+            ))
+        }
+    };
+    Ok(WebSocketTransport {
+        websocket,
+        codec: Codec::new(format, compression),
+    })
+}
+
+#[test]
+fn resolve_all_applies_default_port() {
+    let resolve = |addr| resolve_all(addr, 80).unwrap();
+    assert_eq!(
+        resolve("127.0.0.1"),
+        vec![SocketAddr::from(([127, 0, 0, 1], 80))]
+    );
+    assert_eq!(
+        resolve("127.0.0.1:90"),
+        vec![SocketAddr::from(([127, 0, 0, 1], 90))]
+    );
+    let localhost_v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 80));
+    assert_eq!(resolve("::1"), vec![localhost_v6]);
+    assert_eq!(resolve("[::1]"), vec![localhost_v6]);
+    assert_eq!(resolve("[::1]:80"), vec![localhost_v6]);
+}
+
+#[test]
+fn decompression_is_limited_to_a_frame() {
+    let codec = Codec::<(), ()>::new(Format::Bincode, Compression::Zstd);
+    let mut frame = vec![COMPRESSED];
+    frame.extend(zstd::stream::encode_all(&vec![0; MAX_FRAME_LEN][..], ZSTD_LEVEL).unwrap());
+    assert_eq!(codec.decompress(&frame).unwrap().len(), MAX_FRAME_LEN);
+
+    let mut bomb = vec![COMPRESSED];
+    bomb.extend(zstd::stream::encode_all(&vec![0; MAX_FRAME_LEN + 1][..], ZSTD_LEVEL).unwrap());
+    assert_eq!(
+        codec.decompress(&bomb).unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
@@ -0,0 +1,322 @@
+//! A client that draws the game as characters in a terminal, for playing over SSH or checking that
+//! a server is reachable without opening a window.
+
+use crate::{
+    camera::Camera,
+    chat::{self, ChatBox},
+    client::{self, Connection, Settings},
+    error::Error,
+    game::{self, Component, EntityId, GameInt, Point, Sign},
+    hud::{FrameRate, Hud},
+    transport,
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute, terminal,
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Alignment, Rect},
+    style::{Color, Style},
+    symbols::Marker,
+    text::{Span, Spans},
+    widgets::{
+        canvas::{Canvas, Rectangle},
+        Block, Borders, Clear, Paragraph,
+    },
+    Frame, Terminal,
+};
+use std::{
+    io::{self, Stdout},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Game units drawn per terminal column. Rows are about twice as tall as columns are wide.
+const UNITS_PER_COLUMN: GameInt = 10.;
+const UNITS_PER_ROW: GameInt = 2. * UNITS_PER_COLUMN;
+const FRAMES_PER_SECOND: u64 = 30;
+/// How long the latest event is shown in the title for. Why the server closed is shown for good.
+const EVENT_DISPLAY_TICKS: u64 = 5 * client::UPDATES_PER_SECOND;
+
+/// The direction the player asked to move in along each component.
+///
+/// Terminals don't report key releases, so pressing a direction starts moving that way, and
+/// pressing the opposite direction stops. Holding a key down repeats it, which changes nothing.
+#[derive(Default)]
+struct Movement {
+    x: Option<Sign>,
+    y: Option<Sign>,
+}
+
+impl Movement {
+    fn press(&mut self, component: Component, sign: Sign) -> game::Input {
+        let current = match component {
+            Component::X => &mut self.x,
+            Component::Y => &mut self.y,
+        };
+        *current = match *current {
+            Some(current) if current != sign => None,
+            _ => Some(sign),
+        };
+        game::Input::Move(component, *current)
+    }
+
+    fn input_for(&mut self, key: KeyCode) -> Option<game::Input> {
+        Some(match key {
+            KeyCode::Char('w') | KeyCode::Up => self.press(Component::Y, Sign::Negative),
+            KeyCode::Char('a') | KeyCode::Left => self.press(Component::X, Sign::Negative),
+            KeyCode::Char('s') | KeyCode::Down => self.press(Component::Y, Sign::Positive),
+            KeyCode::Char('d') | KeyCode::Right => self.press(Component::X, Sign::Positive),
+            KeyCode::Char(' ') => game::Input::Shoot,
+            _ => return None,
+        })
+    }
+}
+
+fn to_color(color: [GameInt; 4]) -> Color {
+    // Float to int casts saturate, so out of range channels are clamped.
+    let channel = |c: GameInt| (c * 255.) as u8;
+    Color::Rgb(channel(color[0]), channel(color[1]), channel(color[2]))
+}
+
+/// What's drawn over the game.
+struct Overlay<'a> {
+    hud: Hud,
+    banner: Option<String>,
+    messages: Vec<chat::Message>,
+    /// What's been typed into the chat box, if it's open.
+    typing: Option<&'a str>,
+    /// Why the player can't play, like the connection being lost.
+    notice: Option<String>,
+}
+
+fn title(game: &game::Game, banner: Option<&str>) -> String {
+    let title = String::from("fakeblok (enter to chat, q to quit)");
+    if let Some(reason) = game.closing_reason() {
+        return format!("{}: server closing: {}", title, reason);
+    }
+    if let Some(banner) = banner {
+        return format!("{}: {}", title, banner);
+    }
+    // Chat messages are shown separately.
+    let latest = game
+        .events()
+        .filter(|logged| match logged.event {
+            game::Event::Chat(..) | game::Event::Announcement(_) => false,
+            _ => true,
+        })
+        .last();
+    match latest {
+        Some(logged) if logged.tick + EVENT_DISPLAY_TICKS > game.ticks() => {
+            format!("{}: {}", title, logged.event)
+        }
+        _ => title,
+    }
+}
+
+fn draw(
+    frame: &mut Frame<CrosstermBackend<Stdout>>,
+    game: &game::Game,
+    camera: &mut Camera,
+    pov_id: EntityId,
+    dt: Duration,
+    overlay: &Overlay,
+) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title(game, overlay.banner.as_deref()));
+    let area = frame.size();
+    let inner = block.inner(area);
+    let view_size = Point::new(
+        GameInt::from(inner.width) * UNITS_PER_COLUMN,
+        GameInt::from(inner.height) * UNITS_PER_ROW,
+    );
+    let center = camera.follow(game, pov_id, view_size, dt);
+    let mut rectangles = vec![];
+    game.for_each_visible(center, view_size, |rect, color| {
+        // The canvas's y axis points up.
+        rectangles.push(Rectangle {
+            x: f64::from(rect.top_left.x),
+            y: f64::from(view_size.y - rect.top_left.y - rect.height),
+            width: f64::from(rect.width),
+            height: f64::from(rect.height),
+            color: to_color(color),
+        });
+    });
+    let mut names = vec![];
+    game.for_each_name(center, view_size, |above, name| {
+        // Centered on the row above the entity.
+        let width = name.chars().count() as GameInt * UNITS_PER_COLUMN;
+        let x = f64::from(above.x - width / 2.);
+        let y = f64::from(view_size.y - above.y + UNITS_PER_ROW);
+        names.push((x, y, String::from(name)));
+    });
+    let canvas = Canvas::default()
+        .block(block)
+        .marker(Marker::Braille)
+        .x_bounds([0., f64::from(view_size.x)])
+        .y_bounds([0., f64::from(view_size.y)])
+        .paint(|ctx| {
+            for rectangle in &rectangles {
+                ctx.draw(rectangle);
+            }
+            for (x, y, name) in &names {
+                ctx.print(*x, *y, name.clone());
+            }
+        });
+    frame.render_widget(canvas, area);
+    let hud_area = Rect {
+        height: inner.height.min(1),
+        ..inner
+    };
+    frame.render_widget(Paragraph::new(overlay.hud.to_string()), hud_area);
+
+    // Chat goes in the bottom left corner, newest at the bottom.
+    let mut lines: Vec<_> = overlay
+        .messages
+        .iter()
+        .map(|message| {
+            // Terminals can't fade text out, so it dims instead.
+            let style = if message.opacity < 1. {
+                Style::default().fg(Color::DarkGray)
+            } else {
+                Style::default()
+            };
+            Spans::from(Span::styled(message.text.clone(), style))
+        })
+        .collect();
+    if let Some(text) = overlay.typing {
+        lines.push(Spans::from(format!("say: {}_", text)));
+    }
+    let height = (lines.len() as u16).min(inner.height);
+    let chat_area = Rect {
+        y: inner.y + inner.height - height,
+        height,
+        ..inner
+    };
+    frame.render_widget(Paragraph::new(lines), chat_area);
+
+    if let Some(notice) = &overlay.notice {
+        // Across the middle of the view, over the game.
+        let notice_area = Rect {
+            y: inner.y + inner.height / 2,
+            height: inner.height.min(1),
+            ..inner
+        };
+        let notice = Paragraph::new(notice.as_str()).alignment(Alignment::Center);
+        frame.render_widget(Clear, notice_area);
+        frame.render_widget(notice, notice_area);
+    }
+}
+
+fn play(
+    connection: &Connection,
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    mut camera: Camera,
+) -> io::Result<()> {
+    let frame_time = Duration::from_millis(1000 / FRAMES_PER_SECOND);
+    let tick_time = Duration::from_millis(1000 / client::UPDATES_PER_SECOND);
+    let mut movement = Movement::default();
+    let mut time_in_current_bucket = 0.;
+    let mut ticks_in_current_bucket = 0;
+    // How far the local game is behind real time.
+    let mut behind = Duration::from_secs(0);
+    let mut last_frame = Instant::now();
+    let mut frame_rate = FrameRate::default();
+    let mut chat_box = ChatBox::default();
+
+    loop {
+        let game = connection.render_state();
+        frame_rate.frame(Instant::now());
+        let overlay = Overlay {
+            hud: connection.hud(frame_rate.fps()),
+            banner: connection.banner(),
+            messages: chat::recent(&game),
+            typing: chat_box.text(),
+            notice: connection.notice(&game),
+        };
+        let dt = last_frame.elapsed();
+        terminal.draw(|frame| draw(frame, &game, &mut camera, connection.id(), dt, &overlay))?;
+
+        let next_frame = last_frame + frame_time;
+        while let Some(timeout) = next_frame.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout)? {
+                break;
+            }
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                code if chat_box.text().is_some() => match code {
+                    KeyCode::Enter => {
+                        if let Some(message) = chat_box.submit() {
+                            connection.chat(message);
+                        }
+                    }
+                    KeyCode::Esc => chat_box.cancel(),
+                    KeyCode::Backspace => chat_box.backspace(),
+                    KeyCode::Char(c) => chat_box.type_text(c.encode_utf8(&mut [0; 4])),
+                    _ => {}
+                },
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+                KeyCode::Enter => {
+                    chat_box.open();
+                    // Typing doesn't move the player, so stop them first.
+                    movement = Movement::default();
+                    for &component in &[Component::X, Component::Y] {
+                        connection.push_input(game::Input::Move(component, None));
+                    }
+                }
+                code => {
+                    if let Some(input) = movement.input_for(code) {
+                        connection.push_input(input);
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        behind += now - last_frame;
+        last_frame = now;
+        while behind >= tick_time {
+            connection.tick(
+                tick_time.as_secs_f32(),
+                &mut time_in_current_bucket,
+                &mut ticks_in_current_bucket,
+            );
+            behind -= tick_time;
+        }
+    }
+}
+
+/// Joins the game at the first of `server_addrs` that can be joined and plays it in the terminal
+/// until the player quits.
+pub fn run(
+    server_addrs: &[SocketAddr],
+    transport_config: transport::Config,
+    settings: Settings,
+) -> Result<(), Error> {
+    let connection = Connection::connect(server_addrs, transport_config, &settings)?;
+
+    terminal::enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, terminal::EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = play(
+        &connection,
+        &mut terminal,
+        Camera::new(settings.camera_settings()),
+    );
+
+    // Put the terminal back the way it was even if playing failed.
+    terminal::disable_raw_mode()?;
+    execute!(terminal.backend_mut(), terminal::LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result.map_err(Error::from)
+}
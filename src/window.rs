@@ -0,0 +1,675 @@
+//! Playing in a window, drawn with piston. Only built with the `client` feature.
+
+use crate::{
+    camera::Camera,
+    chat::{self, ChatBox},
+    client::{Connection, Display, Settings, UPDATES_PER_SECOND},
+    diagnostics,
+    error::Error,
+    game::{self, EntityId},
+    hud::{FrameRate, Hud},
+    lan,
+    menu::{self, Menu, Outcome},
+    replay::{Playback, Recorder, Replay},
+    screenshot,
+    text::{self, HUD_FONT_SIZE, MENU_FONT_SIZE, NAME_FONT_SIZE},
+    transport,
+};
+use log::{debug, error, info, warn};
+use piston_window::{
+    clear, context::Context, rectangle, AdvancedWindow, Button, ButtonArgs, ButtonState, Event,
+    EventLoop, EventSettings, Events, G2d, Glyphs, Input, Key, Loop, OpenGL, PistonWindow,
+    WindowSettings,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Runtime, time};
+
+/// How often to check whether to listen for games on the local network, while not listening.
+const LAN_IDLE_INTERVAL: Duration = Duration::from_millis(100);
+/// How far the arrow keys seek while watching a replay.
+const REPLAY_SEEK_STEP: Duration = Duration::from_secs(5);
+/// How fast the camera pans while watching a replay, in pixels per second.
+const REPLAY_PAN_SPEED: game::GameInt = 400.;
+
+impl TryFrom<(&ButtonState, &Key)> for game::Input {
+    type Error = game::InvalidKeyError;
+
+    fn try_from((state, key): (&ButtonState, &Key)) -> Result<game::Input, game::InvalidKeyError> {
+        use game::{Component, Input, Sign};
+        Ok(match (*state, *key) {
+            (ButtonState::Press, Key::W) => Input::Move(Component::Y, Some(Sign::Negative)),
+            (ButtonState::Press, Key::A) => Input::Move(Component::X, Some(Sign::Negative)),
+            (ButtonState::Press, Key::S) => Input::Move(Component::Y, Some(Sign::Positive)),
+            (ButtonState::Press, Key::D) => Input::Move(Component::X, Some(Sign::Positive)),
+            (ButtonState::Press, Key::Space) => Input::Shoot,
+            (ButtonState::Release, Key::W) => Input::Move(Component::Y, None),
+            (ButtonState::Release, Key::A) => Input::Move(Component::X, None),
+            (ButtonState::Release, Key::S) => Input::Move(Component::Y, None),
+            (ButtonState::Release, Key::D) => Input::Move(Component::X, None),
+            _ => return Err(game::InvalidKeyError),
+        })
+    }
+}
+
+/// Games heard on the local network, for the server browser. Only listened for while the browser
+/// is open, since only one program on the machine can listen at a time.
+struct LanGames {
+    games: Arc<Mutex<Vec<(SocketAddr, lan::Announcement)>>>,
+    listening: Arc<AtomicBool>,
+}
+
+impl LanGames {
+    fn new() -> Self {
+        let lan_games = LanGames {
+            games: Arc::new(Mutex::new(vec![])),
+            listening: Arc::new(AtomicBool::new(false)),
+        };
+        let games = lan_games.games.clone();
+        let listening = lan_games.listening.clone();
+        thread::spawn(move || {
+            Runtime::new().unwrap().block_on(async move {
+                loop {
+                    if !listening.load(Ordering::Relaxed) {
+                        time::delay_for(LAN_IDLE_INTERVAL).await;
+                        continue;
+                    }
+                    match lan::discover(2 * lan::ANNOUNCE_INTERVAL).await {
+                        Ok(found) => *games.lock().unwrap() = found,
+                        Err(e) => {
+                            warn!("Failed to listen for games on the local network: {}", e);
+                            time::delay_for(lan::ANNOUNCE_INTERVAL).await;
+                        }
+                    }
+                }
+            })
+        });
+        lan_games
+    }
+
+    fn listen(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    fn games(&self) -> Vec<(SocketAddr, lan::Announcement)> {
+        self.games.lock().unwrap().clone()
+    }
+}
+
+fn menu_action(key: Key) -> Option<menu::Action> {
+    Some(match key {
+        Key::Up => menu::Action::Up,
+        Key::Down => menu::Action::Down,
+        Key::Return => menu::Action::Select,
+        Key::Escape => menu::Action::Back,
+        _ => return None,
+    })
+}
+
+/// Draws `view` over the window.
+fn draw_menu(view: &menu::View, glyphs: &mut Glyphs, c: Context, g: &mut G2d) {
+    let [width, height] = c.get_view_size();
+    rectangle([1., 1., 1., 0.8], [0., 0., width, height], c.transform, g);
+    let line_height = 2. * f64::from(MENU_FONT_SIZE);
+    let baseline = 0.7 * line_height;
+    text::draw(
+        &view.title,
+        MENU_FONT_SIZE,
+        1.,
+        [20., baseline],
+        glyphs,
+        c,
+        g,
+    );
+    for (i, item) in view.items.iter().enumerate() {
+        let y = (i + 1) as f64 * line_height;
+        let color = if view.selected == Some(i) {
+            [0.6, 0.8, 1., 1.]
+        } else {
+            [0.9, 0.9, 0.9, 1.]
+        };
+        rectangle(
+            color,
+            [20., y, width - 40., line_height - 4.],
+            c.transform,
+            g,
+        );
+        text::draw(item, MENU_FONT_SIZE, 1., [30., y + baseline], glyphs, c, g);
+        if let Some((_, preview)) = view.preview.filter(|&(previewed, _)| previewed == i) {
+            // At the end of the item's bar.
+            let side = line_height - 12.;
+            rectangle(
+                preview,
+                [width - 30. - side, y + 4., side, side],
+                c.transform,
+                g,
+            );
+        }
+    }
+    if let Some(notice) = &view.notice {
+        text::draw(
+            notice,
+            MENU_FONT_SIZE,
+            1.,
+            [20., height - 10.],
+            glyphs,
+            c,
+            g,
+        );
+    }
+}
+
+/// What's drawn of the game being played each frame.
+struct GameFrame {
+    state: Box<game::Game>,
+    client_id: EntityId,
+    hud: Hud,
+    messages: Vec<chat::Message>,
+    /// Why the player can't play, like the connection being lost, shown over the grayed out game.
+    notice: Option<String>,
+    banner: Option<String>,
+}
+
+fn open_window(title: &str, display: Display) -> PistonWindow {
+    WindowSettings::new(title, display.resolution.size())
+        .exit_on_esc(false)
+        .fullscreen(display.fullscreen)
+        .vsync(display.vsync)
+        .graphics_api(OpenGL::V3_2)
+        .build()
+        .unwrap()
+}
+
+/// Plays in a window, starting in the game at the first of `server_addrs` that can be joined, or
+/// at the main menu if there are none.
+pub fn run_ui(
+    server_addrs: &[SocketAddr],
+    transport_config: transport::Config,
+    mut settings: Settings,
+) -> Result<(), Error> {
+    // Join a game given up front before opening the window, so that a bad server address is
+    // reported instead of leaving a blank window up.
+    let mut connection = if server_addrs.is_empty() {
+        None
+    } else {
+        Some(Connection::connect(
+            server_addrs,
+            transport_config.clone(),
+            &settings,
+        )?)
+    };
+    let mut menu = if connection.is_some() {
+        Menu::playing(settings.display, settings.color, settings.style)
+    } else {
+        Menu::new(settings.display, settings.color, settings.style)
+    };
+    let lan_games = LanGames::new();
+
+    let mut title = String::from("shapes");
+    let mut window = open_window(&title, settings.display);
+    let mut glyphs = text::load_font(&mut window, settings.font.as_deref())?;
+
+    let mut events = Events::new(
+        EventSettings::new()
+            .ups(UPDATES_PER_SECOND)
+            .ups_reset(0)
+            .max_fps(settings.display.max_fps)
+            .lazy(settings.display.lazy),
+    );
+    let mut time_in_current_bucket = 0.;
+    let mut ticks_in_current_bucket = 0;
+    let mut camera = Camera::new(settings.camera_settings());
+    let mut last_render = Instant::now();
+    let mut frame_rate = FrameRate::default();
+    let mut chat_box = ChatBox::default();
+    let mut take_screenshot = false;
+    let mut show_debug = false;
+    let mut recorder = settings
+        .record
+        .as_deref()
+        .map(Recorder::create)
+        .transpose()?;
+    // Games are joined on their own thread, so that the window keeps responding while joining.
+    let (joins_tx, joins) = mpsc::channel();
+    let mut joining = false;
+    info!("start!");
+
+    while let Some(event) = events.next(&mut window) {
+        if let Ok((address, joined)) = joins.try_recv() {
+            joining = false;
+            match joined {
+                Ok(joined) => {
+                    info!("Joined the game at {}", address);
+                    connection = Some(joined);
+                    camera = Camera::new(settings.camera_settings());
+                    chat_box = ChatBox::default();
+                    menu.joined();
+                }
+                Err(e) => {
+                    warn!("Failed to join the game at {}: {}", address, e);
+                    menu.failed(format!("couldn't join {}: {}", address, e));
+                }
+            }
+        }
+        // Resizes the window's frame buffers to match the window. Everything is drawn relative
+        // to the view's size each frame, so nothing else needs to change.
+        window.event(&event);
+        let outcome = match (&event, &connection) {
+            (Event::Input(Input::Resize(args), _), _) => {
+                debug!("Resized to {:?}", args.window_size);
+                None
+            }
+            (
+                Event::Input(
+                    Input::Button(ButtonArgs {
+                        button: Button::Keyboard(Key::F11),
+                        state: ButtonState::Press,
+                        ..
+                    }),
+                    _,
+                ),
+                _,
+            ) => {
+                menu.toggle_fullscreen();
+                Some(Outcome::ChangeDisplay)
+            }
+            (
+                Event::Input(
+                    Input::Button(ButtonArgs {
+                        button: Button::Keyboard(Key::F12),
+                        state: ButtonState::Press,
+                        ..
+                    }),
+                    _,
+                ),
+                _,
+            ) => {
+                take_screenshot = true;
+                None
+            }
+            (
+                Event::Input(
+                    Input::Button(ButtonArgs {
+                        button: Button::Keyboard(Key::F9),
+                        state: ButtonState::Press,
+                        ..
+                    }),
+                    _,
+                ),
+                connection,
+            ) => {
+                let report = match connection {
+                    Some(connection) => connection.report(frame_rate.fps()),
+                    None => diagnostics::Report {
+                        stats: String::from("status: not connected\n"),
+                        ..diagnostics::Report::default()
+                    },
+                };
+                match report.save(&settings.screenshot_dir) {
+                    Ok(path) => info!("Saved diagnostics to {}", path.display()),
+                    Err(e) => error!("Couldn't save diagnostics: {}", e),
+                }
+                None
+            }
+            (
+                Event::Input(
+                    Input::Button(ButtonArgs {
+                        button: Button::Keyboard(Key::F3),
+                        state: ButtonState::Press,
+                        ..
+                    }),
+                    _,
+                ),
+                _,
+            ) => {
+                show_debug = !show_debug;
+                None
+            }
+            (Event::Input(input, _), Some(connection)) if menu.is_playing() => match input {
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key),
+                    state: ButtonState::Press,
+                    ..
+                }) if chat_box.text().is_some() => {
+                    match key {
+                        Key::Return => {
+                            if let Some(message) = chat_box.submit() {
+                                connection.chat(message);
+                            }
+                        }
+                        Key::Backspace => chat_box.backspace(),
+                        Key::Escape => chat_box.cancel(),
+                        _ => {}
+                    }
+                    None
+                }
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key @ Key::Return),
+                    state: ButtonState::Press,
+                    ..
+                })
+                | Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key @ Key::Escape),
+                    state: ButtonState::Press,
+                    ..
+                }) => {
+                    let outcome = if *key == Key::Return {
+                        chat_box.open();
+                        None
+                    } else {
+                        menu.act(menu::Action::Back, &[])
+                    };
+                    // Keys released while typing or paused don't stop the player, so stop them
+                    // now.
+                    for &component in &[game::Component::X, game::Component::Y] {
+                        connection.push_input(game::Input::Move(component, None));
+                    }
+                    outcome
+                }
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key),
+                    state,
+                    ..
+                }) if chat_box.text().is_none() => {
+                    if let Ok(input) = game::Input::try_from((state, key)) {
+                        connection.push_input(input);
+                    }
+                    None
+                }
+                Input::Text(text) => {
+                    chat_box.type_text(text);
+                    None
+                }
+                _ => None,
+            },
+            (Event::Input(input, _), _) => match input {
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(Key::Backspace),
+                    state: ButtonState::Press,
+                    ..
+                }) => {
+                    menu.backspace();
+                    None
+                }
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key),
+                    state: ButtonState::Press,
+                    ..
+                }) => match menu_action(*key) {
+                    Some(action) => menu.act(action, &lan_games.games()),
+                    None => None,
+                },
+                Input::Text(text) => {
+                    menu.type_text(text);
+                    None
+                }
+                _ => None,
+            },
+            (Event::Loop(Loop::Render(_)), _) => {
+                lan_games.listen(match menu.screen() {
+                    menu::Screen::ServerBrowser { .. } => true,
+                    _ => false,
+                });
+                frame_rate.frame(Instant::now());
+                let frame = connection.as_ref().map(|connection| {
+                    let state = connection.render_state();
+                    GameFrame {
+                        client_id: connection.id(),
+                        hud: connection.hud(frame_rate.fps()),
+                        messages: chat::recent(&state),
+                        notice: connection.notice(&state),
+                        banner: connection.banner(),
+                        state,
+                    }
+                });
+                if let (Some(recording), Some(frame)) = (recorder.as_mut(), &frame) {
+                    if let Err(e) = recording.record(&frame.state) {
+                        error!("Stopped recording the game: {}", e);
+                        recorder = None;
+                    }
+                }
+                let view = if menu.is_playing() {
+                    None
+                } else {
+                    Some(menu.view(&lan_games.games()))
+                };
+                let new_title = match (&view, &frame) {
+                    (Some(view), _) => format!("shapes: {}", view.title),
+                    (None, None) => String::from("shapes"),
+                    (None, Some(frame)) => {
+                        if let Some(reason) = frame.state.closing_reason() {
+                            format!("shapes (server closing: {})", reason)
+                        } else if let Some(notice) = &frame.notice {
+                            format!("shapes ({})", notice)
+                        } else if let Some(banner) = &frame.banner {
+                            format!("shapes: {}", banner)
+                        } else {
+                            String::from("shapes")
+                        }
+                    }
+                };
+                if new_title != title {
+                    window.set_title(new_title.clone());
+                    title = new_title;
+                }
+                let dt = last_render.elapsed();
+                last_render = Instant::now();
+                let style = settings.style;
+                window.draw_2d(&event, |c, g, device| {
+                    clear([1.0; 4], g);
+                    let [x, y] = c.get_view_size();
+                    if let Some(frame) = &frame {
+                        let view_size = game::Point::new(x as game::GameInt, y as game::GameInt);
+                        let state = &frame.state;
+                        let center = camera.follow(state, frame.client_id, view_size, dt);
+                        state.draw(center, style, c, g);
+                        if show_debug {
+                            state.draw_debug(center, c, g);
+                            state.for_each_id(center, view_size, |top_left, id| {
+                                let at = [f64::from(top_left.x), f64::from(top_left.y) - 2.];
+                                let id = id.to_string();
+                                text::draw(&id, NAME_FONT_SIZE, 1., at, &mut glyphs, c, g);
+                            });
+                        }
+                        text::draw_names(state, center, &mut glyphs, c, g);
+                        text::draw_hud(&frame.hud.to_string(), &mut glyphs, c, g);
+                        text::draw_chat(chat_box.text(), &frame.messages, &mut glyphs, c, g);
+                        if let Some(notice) = &frame.notice {
+                            rectangle([1., 1., 1., 0.6], [0., 0., x, y], c.transform, g);
+                            let at = [x / 2., y / 2.];
+                            text::draw_centered(notice, HUD_FONT_SIZE, at, &mut glyphs, c, g);
+                        }
+                    }
+                    if let Some(view) = &view {
+                        draw_menu(view, &mut glyphs, c, g);
+                    }
+                    glyphs.factory.encoder.flush(device);
+                });
+                if take_screenshot {
+                    take_screenshot = false;
+                    save_screenshot(&mut window, &settings);
+                }
+                None
+            }
+            (Event::Loop(lp), _) => {
+                match lp {
+                    Loop::Idle(_) => {}
+                    Loop::Update(args) => {
+                        if let Some(connection) = &connection {
+                            connection.tick(
+                                args.dt as f32,
+                                &mut time_in_current_bucket,
+                                &mut ticks_in_current_bucket,
+                            );
+                        }
+                    }
+                    Loop::AfterRender(_) => {}
+                    lp => panic!("Didn't expect {:?}", lp),
+                }
+                None
+            }
+            _ => None,
+        };
+        match outcome {
+            None => {}
+            Some(Outcome::Join(address)) if joining => {
+                debug!("Not joining {} while joining another game", address);
+            }
+            Some(Outcome::Join(address)) => {
+                joining = true;
+                menu.joining(&address);
+                let joins_tx = joins_tx.clone();
+                let transport_config = transport_config.clone();
+                let settings = settings.clone();
+                thread::spawn(move || {
+                    let joined = transport::resolve_all(&address, transport::DEFAULT_PORT)
+                        .map_err(Error::from)
+                        .and_then(|server_addrs| {
+                            Connection::connect(&server_addrs, transport_config, &settings)
+                        });
+                    // Fails if the window was closed in the meantime.
+                    let _ = joins_tx.send((address, joined));
+                });
+            }
+            Some(Outcome::ChangeColor) => {
+                // Games joined later start with the new color too.
+                settings.color = menu.color();
+                if let (Some(connection), Some(rgb)) = (&connection, settings.color) {
+                    connection.set_color(rgb);
+                }
+            }
+            Some(Outcome::ChangeStyle) => {
+                settings.style = menu.style();
+                camera.set_settings(settings.camera_settings());
+            }
+            Some(Outcome::ChangeDisplay) => {
+                let display = menu.display();
+                info!("Changing display to {:?}", display);
+                window = open_window(&title, display);
+                // Fonts are loaded for a particular window.
+                glyphs = text::load_font(&mut window, settings.font.as_deref())?;
+            }
+            Some(Outcome::Leave) => connection = None,
+            Some(Outcome::Quit) => break,
+        }
+    }
+    info!("end :(");
+    Ok(())
+}
+
+/// Saves what was just drawn in `window` to the screenshot directory, logging where.
+fn save_screenshot(window: &mut PistonWindow, settings: &Settings) {
+    match screenshot::save(window, &settings.screenshot_dir) {
+        Ok(path) => info!("Saved a screenshot to {}", path.display()),
+        Err(e) => error!("Couldn't save a screenshot: {}", e),
+    }
+}
+
+/// Formats `duration` as minutes and seconds, like `3:07`.
+fn minutes_and_seconds(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Watches the replay recorded at `path` in a window. Space pauses, the left and right arrows
+/// seek, `-` and `=` change the speed, and WASD pans the camera.
+pub fn run_replay(path: &Path, settings: Settings) -> io::Result<()> {
+    let mut playback = Playback::new(Replay::load(path)?);
+    let mut title = String::from("shapes replay");
+    let mut window = open_window(&title, settings.display);
+    let mut glyphs = text::load_font(&mut window, settings.font.as_deref())?;
+    let mut events = Events::new(
+        EventSettings::new()
+            .max_fps(settings.display.max_fps)
+            .lazy(settings.display.lazy),
+    );
+    let mut center = {
+        let game = playback.game();
+        game::Point::new(game.width() / 2., game.height() / 2.)
+    };
+    let mut pan = game::Point::default();
+    let mut last_render = Instant::now();
+    let mut take_screenshot = false;
+
+    while let Some(event) = events.next(&mut window) {
+        window.event(&event);
+        match event {
+            Event::Input(
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(key),
+                    state,
+                    ..
+                }),
+                _,
+            ) => {
+                let pressed = state == ButtonState::Press;
+                let speed = if pressed { REPLAY_PAN_SPEED } else { 0. };
+                match key {
+                    Key::W => pan.y = -speed,
+                    Key::S => pan.y = speed,
+                    Key::A => pan.x = -speed,
+                    Key::D => pan.x = speed,
+                    _ if !pressed => {}
+                    Key::Space => playback.paused = !playback.paused,
+                    Key::Left => {
+                        let position = playback.position();
+                        playback.seek(position.checked_sub(REPLAY_SEEK_STEP).unwrap_or_default());
+                    }
+                    Key::Right => playback.seek(playback.position() + REPLAY_SEEK_STEP),
+                    Key::Minus => playback.speed /= 2.,
+                    Key::Equals => playback.speed *= 2.,
+                    Key::Escape => break,
+                    Key::F12 => take_screenshot = true,
+                    _ => {}
+                }
+            }
+            Event::Loop(Loop::Render(_)) => {
+                let dt = last_render.elapsed();
+                last_render = Instant::now();
+                playback.advance(dt);
+                let game = playback.game();
+                center += pan * dt.as_secs_f32();
+                center.x = (center.x + game.width()) % game.width();
+                center.y = (center.y + game.height()) % game.height();
+
+                let mut status = format!(
+                    "{} / {}",
+                    minutes_and_seconds(playback.position()),
+                    minutes_and_seconds(playback.duration())
+                );
+                if playback.paused {
+                    status.push_str(" paused");
+                } else if playback.speed != 1. {
+                    status.push_str(&format!(" at {}x", playback.speed));
+                }
+                let new_title = format!("shapes replay ({})", status);
+                if new_title != title {
+                    window.set_title(new_title.clone());
+                    title = new_title;
+                }
+                window.draw_2d(&event, |c, g, device| {
+                    clear([1.0; 4], g);
+                    game.draw(center, settings.style, c, g);
+                    text::draw_hud(&status, &mut glyphs, c, g);
+                    glyphs.factory.encoder.flush(device);
+                });
+                if take_screenshot {
+                    take_screenshot = false;
+                    save_screenshot(&mut window, &settings);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}